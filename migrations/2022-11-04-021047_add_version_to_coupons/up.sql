@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE coupons ADD COLUMN version UNSIGNED BIG INT NOT NULL DEFAULT 0;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE couponapplications;
+ALTER TABLE coupons DROP COLUMN priority;
+ALTER TABLE coupons DROP COLUMN stacking;
@@ -0,0 +1,19 @@
+-- Your SQL goes here
+ALTER TABLE coupons ADD COLUMN stacking TEXT CHECK(stacking IN ('exclusive', 'stackable')) NOT NULL DEFAULT "exclusive";
+-- Coupons with lower priority are applied first
+ALTER TABLE coupons ADD COLUMN priority BIG INT NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS couponapplications (
+  id VARCHAR(60) NOT NULL PRIMARY KEY,
+  transaction_id VARCHAR(60) NOT NULL,
+  coupon VARCHAR(60) NOT NULL,
+  discount BIG INT NOT NULL,
+  -- The order in which the coupon is applied to the transaction
+  position UNSIGNED BIG INT NOT NULL,
+  FOREIGN KEY (transaction_id) REFERENCES transactions(id)
+);
+
+-- Existing transactions have at most one coupon applied
+INSERT INTO couponapplications (id, transaction_id, coupon, discount, position)
+  SELECT lower(hex(randomblob(16))), id, coupon, discount, 0 FROM transactions
+  WHERE coupon <> '_NO_COUPON_APPLIED_';
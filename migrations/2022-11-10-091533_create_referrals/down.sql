@@ -0,0 +1,23 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE credits_old (
+  id VARCHAR(60) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(60) NOT NULL,
+  currency TEXT NOT NULL,
+  -- Positive for credits granted, negative for credits spent
+  amount BIG INT NOT NULL,
+  kind TEXT CHECK(kind IN ('gift_card', 'refund', 'compensation', 'prize', 'purchase')) NOT NULL,
+  -- Gift card code or transaction ID the entry results from
+  reference VARCHAR(60),
+  note VARCHAR(400),
+  time_created TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users(id)
+);
+INSERT INTO credits_old
+  SELECT id, user_id, currency, amount, CASE kind WHEN 'referral' THEN 'prize' ELSE kind END, reference, note, time_created
+  FROM credits;
+DROP TABLE credits;
+ALTER TABLE credits_old RENAME TO credits;
+
+DROP TABLE referralrewards;
+DROP TABLE referrals;
+DROP TABLE referralcodes;
@@ -0,0 +1,51 @@
+-- Your SQL goes here
+-- Every user has at most one referral code
+CREATE TABLE IF NOT EXISTS referralcodes (
+  id VARCHAR(60) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(60) NOT NULL UNIQUE,
+  time_created TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+-- A user can only be referred once, at the time the account is created
+CREATE TABLE IF NOT EXISTS referrals (
+  referee VARCHAR(60) NOT NULL PRIMARY KEY,
+  referrer VARCHAR(60) NOT NULL,
+  code VARCHAR(60) NOT NULL,
+  time_created TIMESTAMP NOT NULL,
+  -- The first order of the referee that got finished
+  transaction_id VARCHAR(60),
+  time_rewarded TIMESTAMP,
+  -- Comma-separated IDs of the coupons granted as rewards
+  referrer_coupons TEXT,
+  referee_coupons TEXT,
+  FOREIGN KEY (referee) REFERENCES users(id),
+  FOREIGN KEY (referrer) REFERENCES users(id)
+);
+
+-- Rewards granted once the first order of a referee gets finished
+CREATE TABLE IF NOT EXISTS referralrewards (
+  id VARCHAR(60) NOT NULL PRIMARY KEY,
+  recipient TEXT CHECK(recipient IN ('referrer', 'referee')) NOT NULL,
+  kind TEXT CHECK(kind IN ('credit', 'coupon')) NOT NULL,
+  currency TEXT NOT NULL,
+  amount BIG INT NOT NULL
+);
+
+-- SQLite cannot alter CHECK constraints, so we rebuild the table to allow referral credits
+CREATE TABLE credits_new (
+  id VARCHAR(60) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(60) NOT NULL,
+  currency TEXT NOT NULL,
+  -- Positive for credits granted, negative for credits spent
+  amount BIG INT NOT NULL,
+  kind TEXT CHECK(kind IN ('gift_card', 'refund', 'compensation', 'prize', 'purchase', 'referral')) NOT NULL,
+  -- Gift card code, transaction ID or referee the entry results from
+  reference VARCHAR(60),
+  note VARCHAR(400),
+  time_created TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users(id)
+);
+INSERT INTO credits_new SELECT * FROM credits;
+DROP TABLE credits;
+ALTER TABLE credits_new RENAME TO credits;
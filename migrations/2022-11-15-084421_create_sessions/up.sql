@@ -0,0 +1,11 @@
+-- Your SQL goes here
+-- Server-side sessions referenced by the session cookie
+CREATE TABLE IF NOT EXISTS sessions (
+  id VARCHAR(60) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(60) NOT NULL,
+  time_created TIMESTAMP NOT NULL,
+  time_last_seen TIMESTAMP NOT NULL,
+  ip TEXT,
+  user_agent TEXT,
+  FOREIGN KEY (user_id) REFERENCES users(id)
+);
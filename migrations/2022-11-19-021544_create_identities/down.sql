@@ -0,0 +1,76 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS identities;
+
+-- Users are keyed by their emails again
+CREATE TEMPORARY TABLE userids (
+  old_id VARCHAR(60) NOT NULL PRIMARY KEY COLLATE NOCASE,
+  new_id VARCHAR(60) NOT NULL
+);
+INSERT INTO userids (old_id, new_id) SELECT id, email FROM users WHERE email <> '';
+
+-- Foreign keys are checked once the migration is committed, after every reference has been rewritten
+PRAGMA defer_foreign_keys = ON;
+
+UPDATE products SET seller_id = (SELECT new_id FROM userids WHERE old_id = seller_id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = seller_id);
+UPDATE messages SET send = (SELECT new_id FROM userids WHERE old_id = send)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = send);
+UPDATE messages SET recv = (SELECT new_id FROM userids WHERE old_id = recv)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = recv);
+UPDATE transactions SET seller = (SELECT new_id FROM userids WHERE old_id = seller)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = seller);
+UPDATE transactions SET buyer = (SELECT new_id FROM userids WHERE old_id = buyer)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = buyer);
+UPDATE digicons SET creator_id = (SELECT new_id FROM userids WHERE old_id = creator_id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = creator_id);
+UPDATE credits SET user_id = (SELECT new_id FROM userids WHERE old_id = user_id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = user_id);
+-- Referral credits reference the referee
+UPDATE credits SET reference = (SELECT new_id FROM userids WHERE old_id = reference)
+  WHERE kind = 'referral' AND EXISTS (SELECT 1 FROM userids WHERE old_id = reference);
+UPDATE giftcards SET redeemed_by = (SELECT new_id FROM userids WHERE old_id = redeemed_by)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = redeemed_by);
+UPDATE referralcodes SET user_id = (SELECT new_id FROM userids WHERE old_id = user_id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = user_id);
+UPDATE referrals SET referee = (SELECT new_id FROM userids WHERE old_id = referee)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = referee);
+UPDATE referrals SET referrer = (SELECT new_id FROM userids WHERE old_id = referrer)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = referrer);
+UPDATE roleassignments SET user_id = (SELECT new_id FROM userids WHERE old_id = user_id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = user_id);
+UPDATE apitokens SET user_id = (SELECT new_id FROM userids WHERE old_id = user_id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = user_id);
+UPDATE sessions SET user_id = (SELECT new_id FROM userids WHERE old_id = user_id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = user_id);
+UPDATE addresses SET user_id = (SELECT new_id FROM userids WHERE old_id = user_id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = user_id);
+
+-- Sellers allowed by the coupons are comma-separated
+WITH RECURSIVE split(coupon, position, rest, seller) AS (
+  SELECT id, 0, allowed_sellers || ',', NULL FROM coupons WHERE allowed_sellers IS NOT NULL
+  UNION ALL
+  SELECT coupon, position + 1, substr(rest, instr(rest, ',') + 1), substr(rest, 1, instr(rest, ',') - 1)
+  FROM split WHERE rest <> ''
+)
+UPDATE coupons SET allowed_sellers = (
+  SELECT group_concat(seller, ',') FROM (
+    SELECT coalesce(userids.new_id, split.seller) AS seller
+    FROM split LEFT JOIN userids ON userids.old_id = split.seller
+    WHERE split.coupon = coupons.id AND split.seller IS NOT NULL
+    ORDER BY split.position
+  )
+) WHERE allowed_sellers IS NOT NULL;
+-- Scripts comparing the ID of a user as a string literal, like the ones of referral rewards.
+-- Only one user per script is rewritten. Scripts should compare emails instead if more are needed.
+UPDATE coupons SET script = (
+  SELECT replace(script, '"' || old_id || '"', '"' || new_id || '"') FROM userids
+  WHERE instr(script, '"' || old_id || '"') > 0
+) WHERE EXISTS (SELECT 1 FROM userids WHERE instr(script, '"' || old_id || '"') > 0);
+
+UPDATE users SET id = (SELECT new_id FROM userids WHERE old_id = id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = id);
+DROP TABLE userids;
+
+DROP INDEX IF EXISTS users_email;
+ALTER TABLE users DROP COLUMN email_verified;
+ALTER TABLE users DROP COLUMN email;
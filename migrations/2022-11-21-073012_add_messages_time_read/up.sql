@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- NULL until the receiver has read the message
+ALTER TABLE messages ADD COLUMN time_read TIMESTAMP;
+CREATE INDEX messages_unread ON messages (recv, time_read);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS messageattachments_message;
+DROP TABLE IF EXISTS messageattachments;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- Messages about an order form the thread of the order
+ALTER TABLE messages ADD COLUMN transaction_id VARCHAR(60) REFERENCES transactions(id);
+-- Posted automatically, e.g. when the status of the order changes
+ALTER TABLE messages ADD COLUMN is_system BOOLEAN NOT NULL DEFAULT 0;
+-- Status of the order before and after the change, for messages posted when the status changes
+ALTER TABLE messages ADD COLUMN old_status TEXT CHECK(old_status IN ('refunded', 'placed', 'paid', 'finished'));
+ALTER TABLE messages ADD COLUMN new_status TEXT CHECK(new_status IN ('refunded', 'placed', 'paid', 'finished'));
+CREATE INDEX messages_transaction ON messages (transaction_id);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS ticketreplies;
+DROP INDEX IF EXISTS tickets_status;
+DROP TABLE IF EXISTS tickets;
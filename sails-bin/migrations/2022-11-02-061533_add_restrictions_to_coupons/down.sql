@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE coupons DROP COLUMN description;
+ALTER TABLE coupons DROP COLUMN valid_from;
+ALTER TABLE coupons DROP COLUMN valid_until;
+ALTER TABLE coupons DROP COLUMN max_total_uses;
+ALTER TABLE coupons DROP COLUMN max_buyer_uses;
+ALTER TABLE coupons DROP COLUMN min_subtotal;
+ALTER TABLE coupons DROP COLUMN allowed_categories;
+ALTER TABLE coupons DROP COLUMN allowed_tags;
+ALTER TABLE coupons DROP COLUMN allowed_sellers;
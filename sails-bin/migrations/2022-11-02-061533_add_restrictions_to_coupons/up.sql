@@ -0,0 +1,10 @@
+-- Your SQL goes here
+ALTER TABLE coupons ADD COLUMN description TEXT;
+ALTER TABLE coupons ADD COLUMN valid_from TIMESTAMP;
+ALTER TABLE coupons ADD COLUMN valid_until TIMESTAMP;
+ALTER TABLE coupons ADD COLUMN max_total_uses UNSIGNED BIG INT;
+ALTER TABLE coupons ADD COLUMN max_buyer_uses UNSIGNED BIG INT;
+ALTER TABLE coupons ADD COLUMN min_subtotal UNSIGNED BIG INT;
+ALTER TABLE coupons ADD COLUMN allowed_categories TEXT;
+ALTER TABLE coupons ADD COLUMN allowed_tags TEXT;
+ALTER TABLE coupons ADD COLUMN allowed_sellers TEXT;
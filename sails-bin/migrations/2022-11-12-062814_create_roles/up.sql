@@ -0,0 +1,31 @@
+-- Your SQL goes here
+-- Named sets of permissions (bits of UserStatus)
+CREATE TABLE IF NOT EXISTS userroles (
+  id VARCHAR(60) NOT NULL PRIMARY KEY,
+  name VARCHAR(60) NOT NULL UNIQUE,
+  permissions BIGINT NOT NULL,
+  time_created TIMESTAMP NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS roleassignments (
+  id VARCHAR(60) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(60) NOT NULL,
+  role_id VARCHAR(60) NOT NULL,
+  UNIQUE(user_id, role_id),
+  FOREIGN KEY (user_id) REFERENCES users(id),
+  FOREIGN KEY (role_id) REFERENCES userroles(id)
+);
+
+-- The role profiles that used to be hard-coded
+INSERT INTO userroles (id, name, permissions, time_created) VALUES
+  ('normal', 'Normal', 1245335, CURRENT_TIMESTAMP),
+  ('customer_service', 'Customer Service', 18022551, CURRENT_TIMESTAMP),
+  ('store_keeper', 'Storekeeper', 85131415, CURRENT_TIMESTAMP),
+  ('content_creator', 'Content Creator', 1252599, CURRENT_TIMESTAMP),
+  ('admin', 'Admin', 1073741823, CURRENT_TIMESTAMP);
+
+-- Existing users holding exactly one of the profiles get the corresponding role.
+-- Others keep their permissions until roles are assigned.
+INSERT INTO roleassignments (id, user_id, role_id)
+  SELECT lower(hex(randomblob(16))), users.id, userroles.id
+  FROM users INNER JOIN userroles ON users.user_status = userroles.permissions;
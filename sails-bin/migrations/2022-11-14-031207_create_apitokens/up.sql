@@ -0,0 +1,13 @@
+-- Your SQL goes here
+-- Personal API tokens. Only the bcrypt hash of the secret part is stored.
+CREATE TABLE IF NOT EXISTS apitokens (
+  id VARCHAR(60) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(60) NOT NULL,
+  name VARCHAR(60) NOT NULL,
+  secret_hash TEXT NOT NULL,
+  -- Bits of UserStatus the token is allowed to exercise
+  scopes BIGINT NOT NULL,
+  time_created TIMESTAMP NOT NULL,
+  time_last_used TIMESTAMP,
+  FOREIGN KEY (user_id) REFERENCES users(id)
+);
@@ -0,0 +1,107 @@
+-- Your SQL goes here
+-- Users used to be keyed by their email. Users are given a random ID instead, and the email becomes an attribute.
+-- Emails are case insensitive, as the IDs they used to be
+ALTER TABLE users ADD COLUMN email TEXT NOT NULL DEFAULT '' COLLATE NOCASE;
+ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT 0;
+-- Emails used as IDs were all verified by the identity provider. Tombstones have none.
+UPDATE users SET email = id, email_verified = 1 WHERE id NOT LIKE '\_deleted\_%' ESCAPE '\';
+-- Only verified emails are reserved, so that unverified ones could not lock out their owners
+CREATE UNIQUE INDEX users_email ON users(email) WHERE email <> '' AND email_verified;
+
+-- Existing users get random IDs (UUID v4) as well, so that no email is kept as an ID anywhere.
+-- IDs are case insensitive, and so are the old ones here.
+CREATE TEMPORARY TABLE userids (
+  old_id VARCHAR(60) NOT NULL PRIMARY KEY COLLATE NOCASE,
+  new_id VARCHAR(60) NOT NULL
+);
+INSERT INTO userids (old_id, new_id)
+  SELECT id, lower(
+    hex(randomblob(4)) || '-' || hex(randomblob(2)) || '-4' || substr(hex(randomblob(2)), 2) || '-'
+    || substr('89AB', 1 + (abs(random()) % 4), 1) || substr(hex(randomblob(2)), 2) || '-' || hex(randomblob(6))
+  )
+  FROM users WHERE email <> '';
+
+-- Foreign keys are checked once the migration is committed, after every reference has been rewritten
+PRAGMA defer_foreign_keys = ON;
+
+UPDATE products SET seller_id = (SELECT new_id FROM userids WHERE old_id = seller_id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = seller_id);
+UPDATE messages SET send = (SELECT new_id FROM userids WHERE old_id = send)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = send);
+UPDATE messages SET recv = (SELECT new_id FROM userids WHERE old_id = recv)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = recv);
+UPDATE transactions SET seller = (SELECT new_id FROM userids WHERE old_id = seller)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = seller);
+UPDATE transactions SET buyer = (SELECT new_id FROM userids WHERE old_id = buyer)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = buyer);
+UPDATE digicons SET creator_id = (SELECT new_id FROM userids WHERE old_id = creator_id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = creator_id);
+UPDATE credits SET user_id = (SELECT new_id FROM userids WHERE old_id = user_id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = user_id);
+-- Referral credits reference the referee
+UPDATE credits SET reference = (SELECT new_id FROM userids WHERE old_id = reference)
+  WHERE kind = 'referral' AND EXISTS (SELECT 1 FROM userids WHERE old_id = reference);
+UPDATE giftcards SET redeemed_by = (SELECT new_id FROM userids WHERE old_id = redeemed_by)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = redeemed_by);
+UPDATE referralcodes SET user_id = (SELECT new_id FROM userids WHERE old_id = user_id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = user_id);
+UPDATE referrals SET referee = (SELECT new_id FROM userids WHERE old_id = referee)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = referee);
+UPDATE referrals SET referrer = (SELECT new_id FROM userids WHERE old_id = referrer)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = referrer);
+UPDATE roleassignments SET user_id = (SELECT new_id FROM userids WHERE old_id = user_id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = user_id);
+UPDATE apitokens SET user_id = (SELECT new_id FROM userids WHERE old_id = user_id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = user_id);
+UPDATE sessions SET user_id = (SELECT new_id FROM userids WHERE old_id = user_id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = user_id);
+UPDATE addresses SET user_id = (SELECT new_id FROM userids WHERE old_id = user_id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = user_id);
+
+-- Sellers allowed by the coupons are comma-separated
+WITH RECURSIVE split(coupon, position, rest, seller) AS (
+  SELECT id, 0, allowed_sellers || ',', NULL FROM coupons WHERE allowed_sellers IS NOT NULL
+  UNION ALL
+  SELECT coupon, position + 1, substr(rest, instr(rest, ',') + 1), substr(rest, 1, instr(rest, ',') - 1)
+  FROM split WHERE rest <> ''
+)
+UPDATE coupons SET allowed_sellers = (
+  SELECT group_concat(seller, ',') FROM (
+    SELECT coalesce(userids.new_id, split.seller) AS seller
+    FROM split LEFT JOIN userids ON userids.old_id = split.seller
+    WHERE split.coupon = coupons.id AND split.seller IS NOT NULL
+    ORDER BY split.position
+  )
+) WHERE allowed_sellers IS NOT NULL;
+-- Scripts comparing the ID of a user as a string literal, like the ones of referral rewards.
+-- Every user mentioned is rewritten in turn, in the order of their old IDs.
+CREATE TEMPORARY TABLE mentions AS
+  SELECT coupons.id AS coupon, old_id, new_id FROM coupons JOIN userids
+  ON instr(coupons.script, '"' || userids.old_id || '"') > 0;
+WITH RECURSIVE rewrite(coupon, script, last_id, step) AS (
+  SELECT id, script, '', 0 FROM coupons WHERE id IN (SELECT coupon FROM mentions)
+  UNION ALL
+  SELECT rewrite.coupon, replace(rewrite.script, '"' || mentions.old_id || '"', '"' || mentions.new_id || '"'), mentions.old_id, step + 1
+  FROM rewrite JOIN mentions ON mentions.coupon = rewrite.coupon
+  AND mentions.old_id = (SELECT min(m.old_id) FROM mentions AS m WHERE m.coupon = rewrite.coupon AND m.old_id > rewrite.last_id)
+)
+UPDATE coupons SET script = (
+  SELECT script FROM rewrite WHERE rewrite.coupon = coupons.id ORDER BY step DESC LIMIT 1
+) WHERE id IN (SELECT coupon FROM mentions);
+DROP TABLE mentions;
+
+UPDATE users SET id = (SELECT new_id FROM userids WHERE old_id = id)
+  WHERE EXISTS (SELECT 1 FROM userids WHERE old_id = id);
+DROP TABLE userids;
+
+-- Identities at OpenID Connect providers linked to users, given by the issuer and the subject (`sub`).
+-- Existing users are linked on their next sign-in by their verified email.
+CREATE TABLE IF NOT EXISTS identities (
+  id VARCHAR(60) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(60) NOT NULL,
+  issuer TEXT NOT NULL,
+  subject TEXT NOT NULL,
+  time_created TIMESTAMP NOT NULL,
+  UNIQUE(issuer, subject),
+  FOREIGN KEY (user_id) REFERENCES users(id)
+);
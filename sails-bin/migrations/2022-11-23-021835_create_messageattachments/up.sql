@@ -0,0 +1,12 @@
+-- Your SQL goes here
+-- Files attached to messages. They are kept by the image hosting, and only referred to here.
+CREATE TABLE IF NOT EXISTS messageattachments (
+  id VARCHAR(60) NOT NULL PRIMARY KEY,
+  message_id VARCHAR(60) NOT NULL,
+  content_type TEXT NOT NULL,
+  location TEXT NOT NULL,
+  nonce TEXT NOT NULL,
+  time_created TIMESTAMP NOT NULL,
+  FOREIGN KEY (message_id) REFERENCES messages(id)
+);
+CREATE INDEX messageattachments_message ON messageattachments (message_id);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS messages_transaction;
+ALTER TABLE messages DROP COLUMN new_status;
+ALTER TABLE messages DROP COLUMN old_status;
+ALTER TABLE messages DROP COLUMN is_system;
+ALTER TABLE messages DROP COLUMN transaction_id;
@@ -0,0 +1,33 @@
+-- Your SQL goes here
+-- Support tickets opened by users, optionally about an order, worked by the customer service
+CREATE TABLE IF NOT EXISTS tickets (
+  id VARCHAR(60) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(60) NOT NULL,
+  transaction_id VARCHAR(60),
+  subject VARCHAR(200) NOT NULL,
+  status TEXT CHECK(status IN ('open', 'pending', 'resolved')) NOT NULL,
+  -- The customer service working on the ticket
+  assignee VARCHAR(60),
+  time_created TIMESTAMP NOT NULL,
+  time_updated TIMESTAMP NOT NULL,
+  -- When the customer service first replied to the user, for response-time metrics
+  time_first_response TIMESTAMP,
+  time_resolved TIMESTAMP,
+  FOREIGN KEY (user_id) REFERENCES users(id),
+  FOREIGN KEY (transaction_id) REFERENCES transactions(id),
+  FOREIGN KEY (assignee) REFERENCES users(id)
+);
+
+CREATE INDEX tickets_status ON tickets (status, time_updated);
+
+CREATE TABLE IF NOT EXISTS ticketreplies (
+  id VARCHAR(60) NOT NULL PRIMARY KEY,
+  ticket_id VARCHAR(60) NOT NULL,
+  author VARCHAR(60) NOT NULL,
+  body TEXT NOT NULL,
+  -- Internal notes are only visible to the customer service
+  is_internal BOOLEAN NOT NULL DEFAULT 0,
+  time_created TIMESTAMP NOT NULL,
+  FOREIGN KEY (ticket_id) REFERENCES tickets(id),
+  FOREIGN KEY (author) REFERENCES users(id)
+);
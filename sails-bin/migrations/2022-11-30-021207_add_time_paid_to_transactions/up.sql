@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- NULL until the order is paid, so that orders cancelled before payment could be told apart from refunded ones
+ALTER TABLE transactions ADD COLUMN time_paid TIMESTAMP;
+-- Existing refunded orders are assumed to have been paid, as there is no way to tell
+UPDATE transactions SET time_paid = time_sent WHERE transaction_status IN ('paid', 'finished', 'refunded');
@@ -2,7 +2,7 @@ use ammonia::Builder;
 use once_cell::sync::Lazy;
 use rocket::{
     fairing::{AdHoc, Fairing},
-    http::{uri::Reference, Header, Status},
+    http::{uri::Reference, ContentType, Header, Status},
     request::FlashMessage,
     response::{self, Flash, Redirect},
 };
@@ -84,6 +84,26 @@ impl<'r, 'o: 'r> rocket::response::Responder<'r, 'o> for StaticFile {
     }
 }
 
+// A file generated on the fly which the browser should download rather than display
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: ContentType,
+    pub body: String,
+}
+
+impl<'r, 'o: 'r> rocket::response::Responder<'r, 'o> for Attachment {
+    fn respond_to(self, _: &'r rocket::request::Request<'_>) -> rocket::response::Result<'o> {
+        response::Response::build()
+            .header(self.content_type)
+            .header(Header::new(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.filename),
+            ))
+            .sized_body(self.body.len(), Cursor::new(self.body))
+            .ok()
+    }
+}
+
 pub fn create_fairing<'a, T: Deserialize<'a> + Sync + Send + 'static>(
     name: &'static str,
 ) -> impl Fairing {
@@ -99,3 +119,24 @@ pub fn create_fairing<'a, T: Deserialize<'a> + Sync + Send + 'static>(
         Ok(rocket.manage(config))
     })
 }
+
+// Like `create_fairing`, but the section may be left out of the configuration entirely
+pub fn create_optional_fairing<'a, T: Deserialize<'a> + Sync + Send + 'static>(
+    name: &'static str,
+) -> impl Fairing {
+    AdHoc::try_on_ignite(name, move |rocket| async move {
+        if rocket.figment().find_value(name).is_err() {
+            log::info!("`{}` is not configured, skipping", name);
+            return Ok(rocket);
+        }
+        let config: T = match rocket.figment().extract_inner(name) {
+            Ok(c) => c,
+            Err(e) => {
+                log::error!("Invalid configuration: {:?}", e);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket.manage(config))
+    })
+}
@@ -13,6 +13,7 @@ use std::marker::PhantomData;
 // Misc
 pub struct OrderWithPaypal;
 pub struct OrderWithAlipay;
+pub struct OrderWithCredit;
 pub struct TagWritable;
 pub struct CanCreateProduct;
 pub struct CanCreateDigicon;
@@ -95,6 +96,28 @@ impl<'r> FromRequest<'r> for Auth<OrderWithPaypal> {
     }
 }
 
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Auth<OrderWithCredit> {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        let db = try_outcome!(request.guard::<DbConn>().await);
+        let order = try_outcome!(request
+            .query_value::<OrderGuard>("order_id")
+            .and_then(|x| x.ok())
+            .or_forward(()));
+        let order = try_outcome!(order.to_info(&db).await.ok().or_forward(()));
+
+        if *order.order_info.get_payment() == Payment::Credit {
+            Outcome::Success(Auth { plhdr: PhantomData })
+        } else {
+            Outcome::Forward(())
+        }
+    }
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Auth<TagWritable> {
     type Error = ();
@@ -102,7 +125,7 @@ impl<'r> FromRequest<'r> for Auth<TagWritable> {
     async fn from_request(
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        let user = try_outcome!(request.guard::<UserInfoGuard<Cookie>>().await);
+        let user = try_outcome!(request.guard::<UserInfoGuard<CookieOrToken>>().await);
 
         if user
             .info
@@ -123,7 +146,7 @@ impl<'r> FromRequest<'r> for Auth<CanCreateProduct> {
     async fn from_request(
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        let user = try_outcome!(request.guard::<UserInfoGuard<Cookie>>().await);
+        let user = try_outcome!(request.guard::<UserInfoGuard<CookieOrToken>>().await);
 
         if user
             .info
@@ -144,7 +167,7 @@ impl<'r> FromRequest<'r> for Auth<CanCreateDigicon> {
     async fn from_request(
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        let user = try_outcome!(request.guard::<UserInfoGuard<Cookie>>().await);
+        let user = try_outcome!(request.guard::<UserInfoGuard<CookieOrToken>>().await);
 
         if user
             .info
@@ -166,7 +189,7 @@ impl<'r> FromRequest<'r> for Auth<ProdAdmin> {
     async fn from_request(
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        let user = try_outcome!(request.guard::<UserInfoGuard<Cookie>>().await);
+        let user = try_outcome!(request.guard::<UserInfoGuard<CookieOrToken>>().await);
 
         if user.info.get_user_status().contains(UserStatus::PROD_ADMIN) {
             Outcome::Success(Auth { plhdr: PhantomData })
@@ -446,7 +469,7 @@ impl<'r> FromRequest<'r> for Auth<UserReadable> {
     async fn from_request(
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        let user = try_outcome!(request.guard::<UserInfoGuard<Cookie>>().await);
+        let user = try_outcome!(request.guard::<UserInfoGuard<CookieOrToken>>().await);
 
         let db = try_outcome!(request.guard::<DbConn>().await);
         let user_param = try_outcome!(request
@@ -478,7 +501,7 @@ impl<'r> FromRequest<'r> for Auth<UserWritable> {
     async fn from_request(
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        let user = try_outcome!(request.guard::<UserInfoGuard<Cookie>>().await);
+        let user = try_outcome!(request.guard::<UserInfoGuard<CookieOrToken>>().await);
 
         let db = try_outcome!(request.guard::<DbConn>().await);
         let user_param = try_outcome!(request
@@ -504,6 +527,7 @@ impl<'r> FromRequest<'r> for Auth<UserWritable> {
 }
 
 // Orders
+// Checked against the status of the signed-in user, so that the scopes of an API token apply
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Auth<OrderReadable> {
     type Error = ();
@@ -511,7 +535,7 @@ impl<'r> FromRequest<'r> for Auth<OrderReadable> {
     async fn from_request(
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        let user = try_outcome!(request.guard::<UserIdGuard<Cookie>>().await);
+        let user = try_outcome!(request.guard::<UserInfoGuard<CookieOrToken>>().await);
 
         let db = try_outcome!(request.guard::<DbConn>().await);
         let order = try_outcome!(request
@@ -520,11 +544,7 @@ impl<'r> FromRequest<'r> for Auth<OrderReadable> {
             .or_forward(()));
         let order = try_outcome!(order.to_info(&db).await.ok().or_forward(()));
 
-        if db
-            .run(move |c| order.order_info.readable(c, &user.id))
-            .await
-            .unwrap_or(false)
-        {
+        if order.order_info.readable_by(&user.info) {
             Outcome::Success(Auth { plhdr: PhantomData })
         } else {
             Outcome::Forward(())
@@ -539,7 +559,7 @@ impl<'r> FromRequest<'r> for Auth<OrderProgressable> {
     async fn from_request(
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        let user = try_outcome!(request.guard::<UserIdGuard<Cookie>>().await);
+        let user = try_outcome!(request.guard::<UserInfoGuard<CookieOrToken>>().await);
 
         let db = try_outcome!(request.guard::<DbConn>().await);
         let order = try_outcome!(request
@@ -548,11 +568,7 @@ impl<'r> FromRequest<'r> for Auth<OrderProgressable> {
             .or_forward(()));
         let order = try_outcome!(order.to_info(&db).await.ok().or_forward(()));
 
-        if db
-            .run(move |c| order.order_info.progressable(c, &user.id))
-            .await
-            .unwrap_or(false)
-        {
+        if order.order_info.progressable_by(&user.info) {
             Outcome::Success(Auth { plhdr: PhantomData })
         } else {
             Outcome::Forward(())
@@ -567,7 +583,7 @@ impl<'r> FromRequest<'r> for Auth<OrderFinishable> {
     async fn from_request(
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        let user = try_outcome!(request.guard::<UserIdGuard<Cookie>>().await);
+        let user = try_outcome!(request.guard::<UserInfoGuard<CookieOrToken>>().await);
 
         let db = try_outcome!(request.guard::<DbConn>().await);
         let order = try_outcome!(request
@@ -576,11 +592,7 @@ impl<'r> FromRequest<'r> for Auth<OrderFinishable> {
             .or_forward(()));
         let order = try_outcome!(order.to_info(&db).await.ok().or_forward(()));
 
-        if db
-            .run(move |c| order.order_info.finishable(c, &user.id))
-            .await
-            .unwrap_or(false)
-        {
+        if order.order_info.finishable_by(&user.info) {
             Outcome::Success(Auth { plhdr: PhantomData })
         } else {
             Outcome::Forward(())
@@ -595,7 +607,7 @@ impl<'r> FromRequest<'r> for Auth<OrderRefundable> {
     async fn from_request(
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        let user = try_outcome!(request.guard::<UserIdGuard<Cookie>>().await);
+        let user = try_outcome!(request.guard::<UserInfoGuard<CookieOrToken>>().await);
 
         let db = try_outcome!(request.guard::<DbConn>().await);
         let order = try_outcome!(request
@@ -604,11 +616,7 @@ impl<'r> FromRequest<'r> for Auth<OrderRefundable> {
             .or_forward(()));
         let order = try_outcome!(order.to_info(&db).await.ok().or_forward(()));
 
-        if db
-            .run(move |c| order.order_info.refundable(c, &user.id))
-            .await
-            .unwrap_or(false)
-        {
+        if order.order_info.refundable_by(&user.info) {
             Outcome::Success(Auth { plhdr: PhantomData })
         } else {
             Outcome::Forward(())
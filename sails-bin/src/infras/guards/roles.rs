@@ -1,4 +1,5 @@
 use super::users::*;
+use crate::infras::root_auth::ROOT_COOKIE_NAME;
 use rocket::{
     outcome::{try_outcome, Outcome},
     request::FromRequest,
@@ -13,6 +14,8 @@ pub struct CustomerService;
 pub struct Normal;
 pub struct Disabled;
 
+// The user status checked here holds the effective permissions, i.e. the union of the roles assigned (see `sails_db::roles`),
+// narrowed down to the scopes of the token if an API token is used.
 pub struct Role<T> {
     plhdr: PhantomData<T>,
 }
@@ -24,12 +27,15 @@ impl<'r> FromRequest<'r> for Role<Root> {
     async fn from_request(
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
+        // The cookie holds the time the root session expires
         match request
             .cookies()
-            .get_private("root_challenge")
-            .map(|cookie| cookie.value().to_string())
+            .get_private(ROOT_COOKIE_NAME)
+            .and_then(|cookie| cookie.value().parse::<i64>().ok())
         {
-            Some(s) if s == "ROOT" => Outcome::Success(Role { plhdr: PhantomData }),
+            Some(expiry) if expiry > chrono::Utc::now().timestamp() => {
+                Outcome::Success(Role { plhdr: PhantomData })
+            }
             _ => Outcome::Forward(()),
         }
     }
@@ -42,7 +48,7 @@ impl<'r> FromRequest<'r> for Role<Admin> {
     async fn from_request(
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        let user = try_outcome!(request.guard::<UserInfoGuard<Cookie>>().await);
+        let user = try_outcome!(request.guard::<UserInfoGuard<CookieOrToken>>().await);
         if user.info.get_user_status().contains(UserStatus::ADMIN) {
             Outcome::Success(Role { plhdr: PhantomData })
         } else {
@@ -58,7 +64,7 @@ impl<'r> FromRequest<'r> for Role<StoreKeeper> {
     async fn from_request(
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        let user = try_outcome!(request.guard::<UserInfoGuard<Cookie>>().await);
+        let user = try_outcome!(request.guard::<UserInfoGuard<CookieOrToken>>().await);
         if user
             .info
             .get_user_status()
@@ -78,7 +84,7 @@ impl<'r> FromRequest<'r> for Role<CustomerService> {
     async fn from_request(
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        let user = try_outcome!(request.guard::<UserInfoGuard<Cookie>>().await);
+        let user = try_outcome!(request.guard::<UserInfoGuard<CookieOrToken>>().await);
         if user
             .info
             .get_user_status()
@@ -98,7 +104,7 @@ impl<'r> FromRequest<'r> for Role<Normal> {
     async fn from_request(
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        let user = try_outcome!(request.guard::<UserInfoGuard<Cookie>>().await);
+        let user = try_outcome!(request.guard::<UserInfoGuard<CookieOrToken>>().await);
         if user.info.get_user_status().contains(UserStatus::NORMAL) {
             Outcome::Success(Role { plhdr: PhantomData })
         } else {
@@ -4,16 +4,25 @@ use rocket::{
     http::uri::fmt::{FromUriParam, Query},
     outcome::{try_outcome, IntoOutcome, Outcome},
     request::FromRequest,
+    State,
 };
-use sails_db::{error::SailsDbError, users::*};
+use sails_db::{error::SailsDbError, sessions::*, tokens::*, users::*};
 use std::marker::PhantomData;
 
 // User ID specified in param
 pub struct Param;
 
-// User ID stored in private cookie
+// User ID looked up from the session referenced by the private cookie
 pub struct Cookie;
 
+pub const SESSION_COOKIE_NAME: &str = "sid";
+
+// User authenticated by an API token in the `Authorization: Bearer` header
+pub struct Token;
+
+// Either of the above, with the cookie tried first
+pub struct CookieOrToken;
+
 #[derive(UriDisplayQuery)]
 pub struct UserGuard(String);
 
@@ -72,16 +81,22 @@ impl<'r> FromRequest<'r> for UserIdGuard<Cookie> {
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
         let db = try_outcome!(request.guard::<DbConn>().await);
-        let uid = request
+        let policy = try_outcome!(request.guard::<&State<SessionPolicy>>().await)
+            .inner()
+            .clone();
+        let sid = request
             .cookies()
-            .get_private("uid")
+            .get_private(SESSION_COOKIE_NAME)
             .map(|cookie| cookie.value().to_string());
-        if let Some(uid) = uid {
-            let uid_inner = uid.clone();
+        if let Some(sid) = sid {
             db.run(move |c| -> Result<UserIdGuard<_>, SailsDbError> {
+                let session = Sessions::verify(c, &sid, &policy)?;
                 Ok(UserIdGuard {
                     // Disabled user will be treated as if he is not logged in
-                    id: UserFinder::new(c, None).id(&uid_inner).allowed().first()?,
+                    id: UserFinder::new(c, None)
+                        .id(session.get_user_id())
+                        .allowed()
+                        .first()?,
                     plhdr: PhantomData,
                 })
             })
@@ -94,6 +109,29 @@ impl<'r> FromRequest<'r> for UserIdGuard<Cookie> {
     }
 }
 
+// Where a request comes from, recorded along with the session
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientInfo {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        Outcome::Success(ClientInfo {
+            ip: request.client_ip().map(|x| x.to_string()),
+            user_agent: request
+                .headers()
+                .get_one("User-Agent")
+                .map(ToString::to_string),
+        })
+    }
+}
+
 pub struct UserInfoGuard<T> {
     pub info: UserInfo,
     plhdr: PhantomData<T>,
@@ -119,3 +157,85 @@ impl<'r> FromRequest<'r> for UserInfoGuard<Cookie> {
         .or_forward(())
     }
 }
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserIdGuard<Token> {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        let info = try_outcome!(request.guard::<UserInfoGuard<Token>>().await);
+        Outcome::Success(UserIdGuard {
+            id: info.info.to_id(),
+            plhdr: PhantomData,
+        })
+    }
+}
+
+// The user status is narrowed down to the scopes of the token
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserInfoGuard<Token> {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        let db = try_outcome!(request.guard::<DbConn>().await);
+        let token = try_outcome!(request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|x| x.strip_prefix("Bearer "))
+            .map(|x| x.trim().to_string())
+            .or_forward(()));
+        db.run(move |c| -> Result<UserInfoGuard<Token>, SailsDbError> {
+            let token = ApiTokens::verify(c, &token)?;
+            // Disabled user will be treated as if he is not logged in
+            let info = UserFinder::new(c, None)
+                .id(token.get_user_id())
+                .allowed()
+                .first_info()?;
+            let status = token.effective(info.get_user_status());
+            Ok(UserInfoGuard {
+                info: info.set_user_status(status),
+                plhdr: PhantomData,
+            })
+        })
+        .await
+        .ok()
+        .or_forward(())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserInfoGuard<CookieOrToken> {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        let info = match request.guard::<UserInfoGuard<Cookie>>().await {
+            Outcome::Success(user) => user.info,
+            _ => try_outcome!(request.guard::<UserInfoGuard<Token>>().await).info,
+        };
+        Outcome::Success(UserInfoGuard {
+            info,
+            plhdr: PhantomData,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserIdGuard<CookieOrToken> {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        let info = try_outcome!(request.guard::<UserInfoGuard<CookieOrToken>>().await);
+        Outcome::Success(UserIdGuard {
+            id: info.info.to_id(),
+            plhdr: PhantomData,
+        })
+    }
+}
@@ -1,11 +1,15 @@
 use bytes::Bytes;
-use reqwest::{header::CONTENT_TYPE, Response};
+use reqwest::{
+    header::{ACCEPT, CONTENT_TYPE},
+    Response,
+};
 use rocket::{
     data::ToByteUnit,
     form::{self, error::ErrorKind, DataField, FromFormField},
-    http::{ContentType, Header},
+    http::{ContentType, Header, Status},
 };
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::{io::Cursor, str::FromStr};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -13,6 +17,73 @@ pub struct ImageHosting {
     pub gh_token: String,
 }
 
+impl ImageHosting {
+    // Store the bytes under the directory named after their hash, returning the hash.
+    pub async fn store(&self, bytes: &[u8], name: &str) -> Result<String, Status> {
+        use sha2::{Digest, Sha256};
+
+        // Calculate the hash of the content
+        let mut hasher = Sha256::new();
+        hasher.update(bytes);
+        let hash = format!("{:x}", hasher.finalize());
+
+        let client = reqwest::Client::builder()
+            .user_agent("curl")
+            .build()
+            .unwrap();
+
+        // If the content doesn't exist yet, we have to create it
+        if !client
+            .get(format!(
+                "https://api.github.com/repos/flibrary/images/contents/{}/",
+                hash,
+            ))
+            .header(ACCEPT, "application/vnd.github.v3+json")
+            // If we don't auth, probably we will get limited further
+            .bearer_auth(&self.gh_token)
+            .send()
+            .await
+            .map_err(|_| Status::new(503))?
+            .status()
+            .is_success()
+        {
+            let params = json!({
+                "content": base64::encode(bytes),
+                "message": format!("add: {}", hash),
+            });
+            // If not successful, we have to return wrong types
+            if !client
+                .put(format!(
+                    "https://api.github.com/repos/flibrary/images/contents/{}/{}",
+                    hash, name
+                ))
+                .header(ACCEPT, "application/vnd.github.v3+json")
+                .bearer_auth(&self.gh_token)
+                .json(&params)
+                .send()
+                .await
+                .map_err(|_| Status::new(502))?
+                .status()
+                .is_success()
+            {
+                return Err(Status::new(400));
+            }
+        }
+        Ok(hash)
+    }
+
+    // Fetch what has been stored
+    pub async fn fetch(hash: &str, name: &str) -> anyhow::Result<Bytes> {
+        let resp = reqwest::get(format!(
+            "https://raw.githubusercontent.com/flibrary/images/main/{}/{}",
+            hash, name
+        ))
+        .await?
+        .error_for_status()?;
+        Ok(resp.bytes().await?)
+    }
+}
+
 pub struct Image {
     pub bytes: Bytes,
     pub ctt_type: ContentType,
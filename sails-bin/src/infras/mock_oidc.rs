@@ -0,0 +1,520 @@
+// An embedded OpenID Connect provider for local development and tests, enabled by the `mock-oidc` feature
+// together with the `mock_oidc` section in the config. Anyone could sign in as any of the test users,
+// so it must never be enabled in production.
+
+use super::oidc::{ClaimMapping, OIDCProvider, ProviderConfig};
+use anyhow::anyhow;
+use askama::Template;
+use openidconnect::{
+    core::{
+        CoreGenderClaim, CoreJsonWebKeySet, CoreJsonWebKeyType, CoreJweContentEncryptionAlgorithm,
+        CoreJwsSigningAlgorithm, CoreProviderMetadata, CoreResponseType, CoreRsaPrivateSigningKey,
+        CoreSubjectIdentifierType,
+    },
+    AdditionalClaims, Audience, AuthUrl, EmptyAdditionalProviderMetadata, EndUserEmail,
+    EndUserName, IdToken, IdTokenClaims, IssuerUrl, JsonWebKeyId, JsonWebKeySetUrl, LocalizedClaim,
+    Nonce, PrivateSigningKey, ResponseTypes, StandardClaims, SubjectIdentifier, TokenUrl,
+};
+use rocket::{
+    figment::Figment,
+    form::{Form, FromForm},
+    http::Status,
+    response::Redirect,
+    serde::json::Json,
+    Build, Rocket, State,
+};
+use rsa::{pkcs1::ToRsaPrivateKey, RsaPrivateKey};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Mutex};
+
+// Where the endpoints are mounted, which the issuer URL shall end with
+const MOUNT_POINT: &str = "/mock_oidc";
+const PROVIDER_NAME: &str = "mock";
+const CLIENT_ID: &str = "sails";
+const CLIENT_SECRET: &str = "mock";
+const KEY_ID: &str = "mock";
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MockUser {
+    pub subject: String,
+    pub name: String,
+    pub email: String,
+    #[serde(default)]
+    pub school: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Config {
+    #[serde(default = "Config::default_issuer")]
+    issuer: String,
+    #[serde(default = "Config::default_redirect_uri")]
+    redirect_uri: String,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default = "Config::default_users")]
+    users: Vec<MockUser>,
+}
+
+impl Config {
+    fn default_issuer() -> String {
+        format!("http://127.0.0.1:8000{}", MOUNT_POINT)
+    }
+
+    fn default_redirect_uri() -> String {
+        "http://127.0.0.1:8000/user/signin_callback".to_string()
+    }
+
+    fn default_users() -> Vec<MockUser> {
+        vec![
+            MockUser {
+                subject: "alice".to_string(),
+                name: "Alice".to_string(),
+                email: "alice@example.org".to_string(),
+                school: Some("NFLS".to_string()),
+            },
+            MockUser {
+                subject: "bob".to_string(),
+                name: "Bob".to_string(),
+                email: "bob@example.org".to_string(),
+                school: None,
+            },
+        ]
+    }
+}
+
+// The school is not a standard claim
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct MockClaims {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    school: Option<String>,
+}
+
+impl AdditionalClaims for MockClaims {}
+
+type MockIdToken = IdToken<
+    MockClaims,
+    CoreGenderClaim,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+>;
+
+// An authorization code waiting to be exchanged
+struct Grant {
+    client_id: String,
+    nonce: Option<String>,
+    user: MockUser,
+}
+
+pub struct MockIssuer {
+    issuer: String,
+    redirect_uri: String,
+    roles: Vec<String>,
+    users: Vec<MockUser>,
+    signing_key: CoreRsaPrivateSigningKey,
+    grants: Mutex<HashMap<String, Grant>>,
+}
+
+impl MockIssuer {
+    // Returns None if the mock provider is not configured
+    pub fn from_figment(figment: &Figment) -> Result<Option<Self>, anyhow::Error> {
+        if figment.find_value("mock_oidc").is_err() {
+            return Ok(None);
+        }
+        let config: Config = figment.extract_inner("mock_oidc")?;
+        if !config.issuer.ends_with(MOUNT_POINT) {
+            return Err(anyhow!("The mock issuer must end with {}", MOUNT_POINT));
+        }
+
+        // A new key every time, so tokens never outlive the process
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048)?;
+        let signing_key = CoreRsaPrivateSigningKey::from_pem(
+            &key.to_pkcs1_pem()?,
+            Some(JsonWebKeyId::new(KEY_ID.to_string())),
+        )
+        .map_err(|e| anyhow!("Failed to load the signing key: {}", e))?;
+
+        Ok(Some(Self {
+            issuer: config.issuer,
+            redirect_uri: config.redirect_uri,
+            roles: config.roles,
+            users: config.users,
+            signing_key,
+            grants: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    fn jwks(&self) -> CoreJsonWebKeySet {
+        CoreJsonWebKeySet::new(vec![self.signing_key.as_verification_key()])
+    }
+
+    fn metadata(&self) -> Result<CoreProviderMetadata, anyhow::Error> {
+        Ok(CoreProviderMetadata::new(
+            IssuerUrl::new(self.issuer.clone())?,
+            AuthUrl::new(format!("{}/authorize", self.issuer))?,
+            JsonWebKeySetUrl::new(format!("{}/jwks", self.issuer))?,
+            vec![ResponseTypes::new(vec![CoreResponseType::Code])],
+            vec![CoreSubjectIdentifierType::Public],
+            vec![CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256],
+            EmptyAdditionalProviderMetadata {},
+        )
+        .set_token_endpoint(Some(TokenUrl::new(format!("{}/token", self.issuer))?)))
+    }
+
+    // The provider signing in with the mock issuer. The metadata is given directly, so no discovery is needed.
+    pub fn provider(&self) -> Result<OIDCProvider, anyhow::Error> {
+        OIDCProvider::from_metadata(
+            ProviderConfig {
+                name: PROVIDER_NAME.to_string(),
+                display_name: Some("Test users".to_string()),
+                discovery_uri: self.issuer.clone(),
+                redirect_uri: self.redirect_uri.clone(),
+                logout_redirect_uri: None,
+                client_id: CLIENT_ID.to_string(),
+                client_secret: CLIENT_SECRET.to_string(),
+                claims: ClaimMapping {
+                    school: Some("school".to_string()),
+                    ..ClaimMapping::default()
+                },
+                roles: self.roles.clone(),
+                link_legacy_by_email: false,
+            },
+            self.metadata()?.set_jwks(self.jwks()),
+        )
+    }
+
+    pub fn mount(self, rocket: Rocket<Build>) -> Rocket<Build> {
+        rocket
+            .mount(
+                MOUNT_POINT,
+                routes![discovery, jwks, authorize, grant, token],
+            )
+            .manage(self)
+    }
+
+    fn issue(&self, grant: Grant) -> Result<String, anyhow::Error> {
+        let now = chrono::Utc::now();
+        let mut name = LocalizedClaim::new();
+        name.insert(None, EndUserName::new(grant.user.name));
+        let claims = IdTokenClaims::new(
+            IssuerUrl::new(self.issuer.clone())?,
+            vec![Audience::new(grant.client_id)],
+            now + chrono::Duration::minutes(5),
+            now,
+            StandardClaims::new(SubjectIdentifier::new(grant.user.subject))
+                .set_name(Some(name))
+                .set_email(Some(EndUserEmail::new(grant.user.email)))
+                .set_email_verified(Some(true)),
+            MockClaims {
+                school: grant.user.school,
+            },
+        )
+        .set_nonce(grant.nonce.map(Nonce::new));
+        let id_token = MockIdToken::new(
+            claims,
+            &self.signing_key,
+            CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+            None,
+            None,
+        )?;
+        Ok(id_token.to_string())
+    }
+}
+
+#[derive(Template)]
+#[template(path = "mock_oidc/authorize.html")]
+pub struct AuthorizePage {
+    client_id: String,
+    redirect_uri: String,
+    state: String,
+    nonce: Option<String>,
+    users: Vec<MockUser>,
+}
+
+impl AuthorizePage {
+    fn grant_link(&self, subject: &str) -> String {
+        format!(
+            "{}/grant?client_id={}&redirect_uri={}&state={}&nonce={}&subject={}",
+            MOUNT_POINT,
+            urlencoding::encode(&self.client_id),
+            urlencoding::encode(&self.redirect_uri),
+            urlencoding::encode(&self.state),
+            urlencoding::encode(self.nonce.as_deref().unwrap_or_default()),
+            urlencoding::encode(subject)
+        )
+    }
+}
+
+#[get("/.well-known/openid-configuration")]
+pub async fn discovery(issuer: &State<MockIssuer>) -> Result<Json<CoreProviderMetadata>, Status> {
+    issuer
+        .metadata()
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[get("/jwks")]
+pub async fn jwks(issuer: &State<MockIssuer>) -> Json<CoreJsonWebKeySet> {
+    Json(issuer.jwks())
+}
+
+// Let the developer choose the test user to sign in as
+#[get("/authorize?<client_id>&<redirect_uri>&<state>&<nonce>")]
+pub async fn authorize(
+    client_id: String,
+    redirect_uri: String,
+    state: String,
+    nonce: Option<String>,
+    issuer: &State<MockIssuer>,
+) -> Result<AuthorizePage, Status> {
+    if redirect_uri != issuer.redirect_uri {
+        return Err(Status::BadRequest);
+    }
+    Ok(AuthorizePage {
+        client_id,
+        redirect_uri,
+        state,
+        nonce,
+        users: issuer.users.clone(),
+    })
+}
+
+#[get("/grant?<client_id>&<redirect_uri>&<state>&<nonce>&<subject>")]
+pub async fn grant(
+    client_id: String,
+    redirect_uri: String,
+    state: String,
+    nonce: Option<String>,
+    subject: String,
+    issuer: &State<MockIssuer>,
+) -> Result<Redirect, Status> {
+    if redirect_uri != issuer.redirect_uri {
+        return Err(Status::BadRequest);
+    }
+    let user = issuer
+        .users
+        .iter()
+        .find(|u| u.subject == subject)
+        .ok_or(Status::NotFound)?
+        .clone();
+
+    let code = uuid::Uuid::new_v4().to_string();
+    issuer.grants.lock().unwrap().insert(
+        code.clone(),
+        Grant {
+            client_id,
+            nonce: nonce.filter(|n| !n.is_empty()),
+            user,
+        },
+    );
+
+    Ok(Redirect::to(format!(
+        "{}?code={}&state={}",
+        redirect_uri,
+        urlencoding::encode(&code),
+        urlencoding::encode(&state)
+    )))
+}
+
+#[derive(FromForm)]
+pub struct TokenRequest {
+    grant_type: String,
+    code: String,
+}
+
+// Client credentials are not checked, the mock issuer trusts everyone
+#[post("/token", data = "<request>")]
+pub async fn token(
+    request: Form<TokenRequest>,
+    issuer: &State<MockIssuer>,
+) -> Result<Json<serde_json::Value>, Status> {
+    if request.grant_type != "authorization_code" {
+        return Err(Status::BadRequest);
+    }
+    // Codes could only be used once
+    let grant = issuer
+        .grants
+        .lock()
+        .unwrap()
+        .remove(&request.code)
+        .ok_or(Status::BadRequest)?;
+    let id_token = issuer
+        .issue(grant)
+        .map_err(|_| Status::InternalServerError)?;
+
+    Ok(Json(serde_json::json!({
+        "access_token": uuid::Uuid::new_v4().to_string(),
+        "token_type": "Bearer",
+        "expires_in": 300,
+        "id_token": id_token,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        infras::{
+            database::run_migrations,
+            guards::SESSION_COOKIE_NAME,
+            i18n::{Catalog, Translations},
+            oidc::OIDCProviders,
+        },
+        services::users::{signin, signin_callback},
+        DbConn,
+    };
+    use diesel::{Connection, SqliteConnection};
+    use reqwest::{header, redirect::Policy, Client, Response, StatusCode};
+    use rocket::{fairing::AdHoc, figment::Figment};
+    use sails_db::{identities::Identities, sessions::SessionPolicy, users::UserId};
+    use std::{collections::HashMap, net::TcpListener, time::Duration};
+
+    // Keep the cookies set by the responses, as a browser would
+    fn keep_cookies(resp: &Response, jar: &mut HashMap<String, String>) {
+        for cookie in resp.headers().get_all(header::SET_COOKIE) {
+            let cookie = cookie.to_str().unwrap();
+            let pair = cookie.split(';').next().unwrap();
+            let (name, value) = pair.split_once('=').unwrap();
+            if value.is_empty() {
+                jar.remove(name);
+            } else {
+                jar.insert(name.to_string(), value.to_string());
+            }
+        }
+    }
+
+    fn cookie_header(jar: &HashMap<String, String>) -> String {
+        jar.iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    fn location(resp: &Response) -> String {
+        resp.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    // The code is exchanged over HTTP, so the provider has to actually listen on a port
+    #[rocket::async_test]
+    async fn sign_in_through_mock_provider() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let base = format!("http://127.0.0.1:{}", port);
+        let issuer = format!("{}{}", base, super::MOUNT_POINT);
+        let db = std::env::temp_dir().join(format!("sails-mock-oidc-{}.db", uuid::Uuid::new_v4()));
+
+        let figment = Figment::from(rocket::Config::default())
+            .merge(("address", "127.0.0.1"))
+            .merge(("port", port))
+            .merge(("log_level", "off"))
+            .merge(("secret_key", "a1".repeat(32)))
+            .merge(("databases.flibrary.url", db.to_str().unwrap()))
+            .merge(("mock_oidc.issuer", &issuer))
+            .merge((
+                "mock_oidc.redirect_uri",
+                format!("{}/user/signin_callback", base),
+            ));
+        let translations: Translations = vec![("en", Catalog::empty())];
+        let server = rocket::custom(figment)
+            .attach(DbConn::fairing())
+            .attach(AdHoc::config::<SessionPolicy>())
+            .attach(OIDCProviders::fairing())
+            .attach(AdHoc::on_ignite("Run database migrations", run_migrations))
+            .manage(translations)
+            .mount("/user", routes![signin, signin_callback])
+            .ignite()
+            .await
+            .unwrap();
+        let shutdown = server.shutdown();
+        rocket::tokio::spawn(server.launch());
+
+        let client = Client::builder().redirect(Policy::none()).build().unwrap();
+        let mut jar = HashMap::new();
+
+        // Discovery, which also tells us when the server is up
+        let mut metadata = None;
+        for _ in 0..50 {
+            match client
+                .get(format!("{}/.well-known/openid-configuration", issuer))
+                .send()
+                .await
+            {
+                Ok(resp) => {
+                    metadata = Some(resp.json::<serde_json::Value>().await.unwrap());
+                    break;
+                }
+                Err(_) => rocket::tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+        let metadata = metadata.expect("the server never came up");
+        assert_eq!(metadata["issuer"], issuer.as_str());
+        assert_eq!(metadata["token_endpoint"], format!("{}/token", issuer));
+
+        // Sign in redirects to the authorization page of the provider
+        let resp = client
+            .get(format!(
+                "{}/user/signin?provider={}",
+                base,
+                super::PROVIDER_NAME
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        keep_cookies(&resp, &mut jar);
+        let authorize = location(&resp);
+        assert!(authorize.starts_with(&format!("{}/authorize?", issuer)));
+
+        // Choose alice on the authorization page
+        let page = client.get(&authorize).send().await.unwrap();
+        assert_eq!(page.status(), StatusCode::OK);
+        let page = page.text().await.unwrap();
+        let grant = page
+            .split('"')
+            .find(|s| s.starts_with("/mock_oidc/grant?") && s.ends_with("subject=alice"))
+            .expect("no link to sign in as alice")
+            .replace("&amp;", "&");
+
+        // Granting redirects back to us with the code
+        let resp = client
+            .get(format!("{}{}", base, grant))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let callback = location(&resp);
+        assert!(callback.starts_with(&format!("{}/user/signin_callback?code=", base)));
+
+        // The callback exchanges the code for the tokens and signs alice in
+        let resp = client
+            .get(&callback)
+            .header(header::COOKIE, cookie_header(&jar))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        keep_cookies(&resp, &mut jar);
+        assert!(jar.contains_key(SESSION_COOKIE_NAME));
+
+        shutdown.notify();
+
+        let conn = SqliteConnection::establish(db.to_str().unwrap()).unwrap();
+        let identity = Identities::find(&conn, &issuer, "alice")
+            .unwrap()
+            .expect("alice has no identity");
+        let info = UserId::find(&conn, identity.get_user_id())
+            .unwrap()
+            .get_info(&conn)
+            .unwrap();
+        assert_eq!(info.get_email(), "alice@example.org");
+        assert_eq!(info.get_name(), "Alice");
+        assert_eq!(info.get_school(), "NFLS");
+        drop(conn);
+        std::fs::remove_file(db).unwrap();
+    }
+}
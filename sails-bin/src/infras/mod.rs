@@ -12,12 +12,19 @@ pub mod aead;
 pub mod images;
 // Rocket-based OpenID Connect infra
 pub mod oidc;
+// An embedded OpenID Connect provider with test users, for local development and tests
+#[cfg(feature = "mock-oidc")]
+pub mod mock_oidc;
 // Rocket-based Google ReCaptcha infra
 pub mod recaptcha;
+// Root authentication
+pub mod root_auth;
 // Rocket-based Google mailbox infra
 pub mod smtp;
 // Rocket-based Telegram bot infra
 pub mod tg_bot;
+// Broadcasting new messages to the chat streams
+pub mod msg_hub;
 // Digital content hosting
 pub mod digicons;
 
@@ -0,0 +1,73 @@
+// An in-process hub broadcasting new messages to the chat streams
+
+use rocket::tokio::sync::broadcast::{self, Receiver, Sender};
+use sails_db::{
+    error::SailsDbError,
+    messages::{Attachments, Message},
+};
+use serde::Serialize;
+
+// Messages kept for subscribers falling behind
+const CAPACITY: usize = 1024;
+
+// A message with the IDs of its attachments, as sent to the chat page
+#[derive(Serialize, Clone)]
+pub struct MessageView {
+    #[serde(flatten)]
+    pub msg: Message,
+    pub attachments: Vec<String>,
+}
+
+impl MessageView {
+    pub fn new(msg: Message, attachments: Vec<String>) -> Self {
+        Self { msg, attachments }
+    }
+
+    pub fn load(
+        conn: &diesel::SqliteConnection,
+        msgs: Vec<Message>,
+    ) -> Result<Vec<Self>, SailsDbError> {
+        let attachments = Attachments::of_messages(conn, &msgs)?;
+        Ok(msgs
+            .into_iter()
+            .map(|msg| MessageView {
+                attachments: attachments
+                    .iter()
+                    .filter(|a| a.get_message_id() == msg.get_id())
+                    .map(|a| a.get_id().to_string())
+                    .collect(),
+                msg,
+            })
+            .collect())
+    }
+}
+
+// Messages are published along with their attachments, so that the streams don't need a database connection
+pub struct MsgHub {
+    sender: Sender<MessageView>,
+}
+
+impl Default for MsgHub {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+}
+
+impl MsgHub {
+    // Nobody listening is not an error
+    pub fn publish(&self, msg: MessageView) {
+        let _ = self.sender.send(msg);
+    }
+
+    // Status changes are posted to the threads by the services changing them, if the status has changed
+    pub fn publish_status_change(&self, msg: Option<Message>) {
+        if let Some(msg) = msg {
+            self.publish(MessageView::new(msg, Vec::new()));
+        }
+    }
+
+    pub fn subscribe(&self) -> Receiver<MessageView> {
+        self.sender.subscribe()
+    }
+}
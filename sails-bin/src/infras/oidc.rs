@@ -20,25 +20,87 @@ use rocket::{
 pub const STATE_COOKIE_NAME: &str = "sails_oidc_state";
 pub const NONCE_COOKIE_NAME: &str = "sails_oidc_nonce";
 pub const ID_TOKEN_COOKIE_NAME: &str = "sails_oidc_id_token";
+// The provider the user chose to sign in with, kept for the callback and logging out
+pub const PROVIDER_COOKIE_NAME: &str = "sails_oidc_provider";
 
-pub struct OIDCClient {
-    client: CoreClient,
-    pub logout_redirect_uri: String,
+// Names of the claims in the ID token to take the profile from
+#[derive(Debug, serde::Deserialize, Clone)]
+pub struct ClaimMapping {
+    #[serde(default = "ClaimMapping::default_name")]
+    pub name: String,
+    #[serde(default = "ClaimMapping::default_email")]
+    pub email: String,
+    #[serde(default = "ClaimMapping::default_email_verified")]
+    pub email_verified: String,
+    // Providers of a single school may not have such a claim
+    #[serde(default)]
+    pub school: Option<String>,
 }
 
-impl OIDCClient {
-    async fn from_figment(figment: &Figment) -> Result<Self, anyhow::Error> {
-        #[derive(serde::Deserialize)]
-        struct Config {
-            discovery_uri: String,
-            redirect_uri: String,
-            logout_redirect_uri: String,
-            client_id: String,
-            client_secret: String,
+impl ClaimMapping {
+    fn default_name() -> String {
+        "name".to_string()
+    }
+
+    fn default_email() -> String {
+        "email".to_string()
+    }
+
+    fn default_email_verified() -> String {
+        "email_verified".to_string()
+    }
+}
+
+impl Default for ClaimMapping {
+    fn default() -> Self {
+        Self {
+            name: Self::default_name(),
+            email: Self::default_email(),
+            email_verified: Self::default_email_verified(),
+            school: None,
         }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub(super) struct ProviderConfig {
+    #[serde(default = "ProviderConfig::default_name")]
+    pub name: String,
+    pub display_name: Option<String>,
+    pub discovery_uri: String,
+    pub redirect_uri: String,
+    pub logout_redirect_uri: Option<String>,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default)]
+    pub claims: ClaimMapping,
+    // IDs of the roles granted to users created through this provider
+    #[serde(default)]
+    pub roles: Vec<String>,
+    // Whether users from before identities were introduced could be linked by their verified emails.
+    // Only turn it on for the issuer the users used to sign in with, as others may verify emails they don't own.
+    #[serde(default)]
+    pub link_legacy_by_email: bool,
+}
+
+impl ProviderConfig {
+    fn default_name() -> String {
+        "flibrary".to_string()
+    }
+}
 
-        let config: Config = figment.extract_inner("oidc")?;
+pub struct OIDCProvider {
+    name: String,
+    display_name: String,
+    client: CoreClient,
+    pub logout_redirect_uri: Option<String>,
+    pub claims: ClaimMapping,
+    pub roles: Vec<String>,
+    pub link_legacy_by_email: bool,
+}
 
+impl OIDCProvider {
+    async fn from_config(config: ProviderConfig) -> Result<Self, anyhow::Error> {
         let provider_metadata = loop {
             use tokio::time::{sleep, Duration};
 
@@ -50,12 +112,23 @@ impl OIDCClient {
             {
                 Ok(metadata) => break metadata,
                 Err(e) => {
-                    log::error!("Failed to fetch provider metadata: {}", e);
+                    log::error!(
+                        "Failed to fetch provider metadata of {}: {}",
+                        config.name,
+                        e
+                    );
                     sleep(Duration::from_secs(5)).await;
                 }
             }
         };
 
+        Self::from_metadata(config, provider_metadata)
+    }
+
+    pub(super) fn from_metadata(
+        config: ProviderConfig,
+        provider_metadata: CoreProviderMetadata,
+    ) -> Result<Self, anyhow::Error> {
         // Create an OpenID Connect client by specifying the client ID, client secret, authorization URL
         // and token URL.
         let client = CoreClient::from_provider_metadata(
@@ -67,22 +140,22 @@ impl OIDCClient {
         .set_redirect_uri(RedirectUrl::new(config.redirect_uri)?);
 
         Ok(Self {
+            display_name: config.display_name.unwrap_or_else(|| config.name.clone()),
+            name: config.name,
             client,
             logout_redirect_uri: config.logout_redirect_uri,
+            claims: config.claims,
+            roles: config.roles,
+            link_legacy_by_email: config.link_legacy_by_email,
         })
     }
 
-    pub fn fairing() -> impl Fairing {
-        AdHoc::try_on_ignite("oidc", move |rocket| async move {
-            let client = match Self::from_figment(rocket.figment()).await {
-                Ok(c) => c,
-                Err(e) => {
-                    log::error!("Failed on constructing OpenID Connect Client: {:?}", e);
-                    return Err(rocket);
-                }
-            };
-            Ok(rocket.manage(client))
-        })
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_display_name(&self) -> &str {
+        &self.display_name
     }
 
     pub fn get_redirect(&self, cookies: &CookieJar<'_>, scopes: &[&str]) -> Redirect {
@@ -111,22 +184,120 @@ impl OIDCClient {
                 .finish(),
         );
 
+        cookies.add_private(
+            HttpCookie::build(PROVIDER_COOKIE_NAME, self.name.clone())
+                .same_site(SameSite::Lax)
+                .finish(),
+        );
+
         Redirect::to(auth_url.as_str().to_string())
     }
 }
 
+// The providers users could sign in with, in the order configured
+pub struct OIDCProviders {
+    providers: Vec<OIDCProvider>,
+}
+
+impl OIDCProviders {
+    async fn from_figment(figment: &Figment) -> Result<Self, anyhow::Error> {
+        // Either a list of providers under `oidc.providers`, or a single one directly under `oidc`
+        let configs: Vec<ProviderConfig> = if figment.find_value("oidc.providers").is_ok() {
+            figment.extract_inner("oidc.providers")?
+        } else if figment.find_value("oidc").is_ok() {
+            vec![figment.extract_inner("oidc")?]
+        } else {
+            Vec::new()
+        };
+
+        let mut providers: Vec<OIDCProvider> = Vec::new();
+        for config in configs {
+            if providers.iter().any(|p| p.name == config.name) {
+                return Err(anyhow!(
+                    "Duplicate OpenID Connect provider name: {}",
+                    config.name
+                ));
+            }
+            providers.push(OIDCProvider::from_config(config).await?);
+        }
+        Ok(Self { providers })
+    }
+
+    pub fn fairing() -> impl Fairing {
+        AdHoc::try_on_ignite("oidc", move |rocket| async move {
+            #[allow(unused_mut)]
+            let mut providers = match Self::from_figment(rocket.figment()).await {
+                Ok(c) => c,
+                Err(e) => {
+                    log::error!("Failed on constructing OpenID Connect Client: {:?}", e);
+                    return Err(rocket);
+                }
+            };
+
+            #[cfg(feature = "mock-oidc")]
+            let rocket = match super::mock_oidc::MockIssuer::from_figment(rocket.figment()) {
+                Ok(Some(issuer)) => match issuer.provider() {
+                    Ok(provider) => {
+                        log::warn!("The mock OpenID Connect provider is enabled. Never use it in production!");
+                        providers.providers.push(provider);
+                        issuer.mount(rocket)
+                    }
+                    Err(e) => {
+                        log::error!(
+                            "Failed on constructing the mock OpenID Connect provider: {:?}",
+                            e
+                        );
+                        return Err(rocket);
+                    }
+                },
+                Ok(None) => rocket,
+                Err(e) => {
+                    log::error!(
+                        "Failed on constructing the mock OpenID Connect provider: {:?}",
+                        e
+                    );
+                    return Err(rocket);
+                }
+            };
+
+            if providers.providers.is_empty() {
+                log::error!("No OpenID Connect provider configured");
+                return Err(rocket);
+            }
+            Ok(rocket.manage(providers))
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&OIDCProvider> {
+        self.providers.iter().find(|p| p.name == name)
+    }
+
+    pub fn list(&self) -> &[OIDCProvider] {
+        &self.providers
+    }
+
+    // The provider the current user signed in with
+    pub fn of_cookies(&self, cookies: &CookieJar<'_>) -> Option<&OIDCProvider> {
+        cookies
+            .get_private(PROVIDER_COOKIE_NAME)
+            .and_then(|c| self.get(c.value()))
+    }
+}
+
 pub struct OIDCTokenResponse {
+    pub provider: String,
     pub id_token: CoreIdToken,
     pub claims: CoreIdTokenClaims,
+    // All the claims in the ID token, for those not defined by the standard
+    raw_claims: serde_json::Value,
 }
 
 impl OIDCTokenResponse {
     async fn from_request<'r>(request: &'r Request<'_>) -> Result<Self, anyhow::Error> {
-        let client = &request
+        let providers = request
             .rocket()
-            .state::<OIDCClient>()
-            .ok_or_else(|| anyhow!("Missing OIDC client"))?
-            .client;
+            .state::<OIDCProviders>()
+            .ok_or_else(|| anyhow!("Missing OIDC providers"))?;
 
         // Parse the query data.
         let query = match request.uri().query() {
@@ -175,6 +346,11 @@ impl OIDCTokenResponse {
             }
         }
 
+        let provider = providers
+            .of_cookies(cookies)
+            .ok_or_else(|| anyhow!("Unknown OpenID Connect provider"))?;
+        let client = &provider.client;
+
         // Get Nonce from cookie
         let nonce = match cookies.get_private(NONCE_COOKIE_NAME) {
             Some(cookie) => {
@@ -199,6 +375,14 @@ impl OIDCTokenResponse {
             .id_token()
             .ok_or_else(|| anyhow!("Server did not return an ID token"))?;
         let claims = id_token.claims(&client.id_token_verifier(), &nonce)?;
+        // The token has been verified, so the payload could be read as is
+        let raw_claims = id_token
+            .to_string()
+            .split('.')
+            .nth(1)
+            .and_then(|payload| base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok())
+            .and_then(|payload| serde_json::from_slice(&payload).ok())
+            .ok_or_else(|| anyhow!("Malformed ID token payload"))?;
 
         // Add back id_token for future retrieval
         cookies.add_private(
@@ -209,10 +393,26 @@ impl OIDCTokenResponse {
         );
 
         Ok(Self {
+            provider: provider.name.clone(),
             id_token: id_token.clone(),
             claims: claims.clone(),
+            raw_claims,
         })
     }
+
+    // A string claim of the ID token by its name
+    pub fn claim(&self, name: &str) -> Option<&str> {
+        self.raw_claims.get(name).and_then(|v| v.as_str())
+    }
+
+    // A boolean claim of the ID token by its name. Some providers give booleans as strings.
+    pub fn flag(&self, name: &str) -> Option<bool> {
+        match self.raw_claims.get(name)? {
+            serde_json::Value::Bool(b) => Some(*b),
+            serde_json::Value::String(s) => s.parse().ok(),
+            _ => None,
+        }
+    }
 }
 
 #[rocket::async_trait]
@@ -0,0 +1,320 @@
+// Root authentication: a bcrypt hash of the password, a TOTP (RFC 6238) second factor, and throttling of failed attempts.
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Deserializer};
+use sha1::Sha1;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub const ROOT_COOKIE_NAME: &str = "root_challenge";
+
+// Failed attempts allowed from a single client within the window
+const MAX_FAILURES: usize = 5;
+// Failed attempts allowed from all clients together within the window, including those without an IP
+const MAX_GLOBAL_FAILURES: usize = 20;
+const FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+// TOTP time step in seconds, and the steps of clock skew tolerated on either side
+const TOTP_STEP: u64 = 30;
+const TOTP_SKEW: u64 = 1;
+
+fn deserialize_totp_key<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let buf = String::deserialize(deserializer)?;
+    base32_decode(&buf).ok_or_else(|| serde::de::Error::custom("invalid base32 TOTP secret"))
+}
+
+// RFC 4648 base32, which is how authenticator apps take secrets. Padding and whitespaces are ignored.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut bits, mut n) = (0u64, 0u32);
+    for c in s
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .map(|c| c.to_ascii_uppercase())
+    {
+        let v = match c {
+            'A'..='Z' => c as u8 - b'A',
+            '2'..='7' => c as u8 - b'2' + 26,
+            _ => return None,
+        };
+        bits = (bits << 5) | v as u64;
+        n += 5;
+        if n >= 8 {
+            n -= 8;
+            out.push((bits >> n) as u8);
+            bits &= (1 << n) - 1;
+        }
+    }
+    if out.is_empty() {
+        None
+    } else {
+        Some(out)
+    }
+}
+
+// RFC 4226 HOTP with 6 digits
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0xf) as usize;
+    let code = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    code % 1_000_000
+}
+
+#[derive(Clone, Deserialize)]
+pub struct RootAuth {
+    // bcrypt hash of the root password, e.g. `htpasswd -nbBC 12 "" <password> | tr -d ':\n'`
+    #[serde(rename = "root_passwd_hash")]
+    passwd_hash: String,
+    // base32 secret shared with the authenticator app
+    #[serde(rename = "root_totp_secret", deserialize_with = "deserialize_totp_key")]
+    totp_key: Vec<u8>,
+    // How long root stays signed in, in minutes
+    #[serde(rename = "root_session_ttl", default = "RootAuth::default_session_ttl")]
+    session_ttl: i64,
+}
+
+impl RootAuth {
+    fn default_session_ttl() -> i64 {
+        15
+    }
+
+    pub fn verify_passwd(&self, passwd: &str) -> bool {
+        bcrypt::verify(passwd, &self.passwd_hash).unwrap_or(false)
+    }
+
+    // Returns the time step the code is valid for, used to reject replays
+    pub fn verify_totp(&self, code: &str, unix_time: u64) -> Option<u64> {
+        let code = code.trim();
+        if code.len() != 6 {
+            return None;
+        }
+        let current = unix_time / TOTP_STEP;
+        (current.saturating_sub(TOTP_SKEW)..=current + TOTP_SKEW)
+            .find(|step| format!("{:06}", hotp(&self.totp_key, *step)) == code)
+    }
+
+    pub fn session_ttl(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.session_ttl)
+    }
+}
+
+// Root login attempts, kept in memory
+#[derive(Default)]
+pub struct RootLoginAttempts {
+    inner: Mutex<Attempts>,
+}
+
+#[derive(Default)]
+struct Attempts {
+    // Failures by client IP. Clients without an IP are only counted towards the global failures.
+    failures: HashMap<String, Vec<Instant>>,
+    global_failures: Vec<Instant>,
+    last_totp_step: u64,
+}
+
+impl RootLoginAttempts {
+    pub fn is_throttled(&self, ip: &Option<String>) -> bool {
+        let mut attempts = self.inner.lock().unwrap();
+        attempts
+            .global_failures
+            .retain(|t| t.elapsed() < FAILURE_WINDOW);
+        if attempts.global_failures.len() >= MAX_GLOBAL_FAILURES {
+            return true;
+        }
+        match ip.as_ref().and_then(|ip| attempts.failures.get_mut(ip)) {
+            Some(v) => {
+                v.retain(|t| t.elapsed() < FAILURE_WINDOW);
+                v.len() >= MAX_FAILURES
+            }
+            None => false,
+        }
+    }
+
+    pub fn fail(&self, ip: &Option<String>) {
+        let mut attempts = self.inner.lock().unwrap();
+        let now = Instant::now();
+        attempts.global_failures.push(now);
+        if let Some(ip) = ip {
+            attempts.failures.entry(ip.clone()).or_default().push(now);
+        }
+    }
+
+    // Record a successful login. Returns false if the TOTP code has already been used.
+    pub fn succeed(&self, ip: &Option<String>, totp_step: u64) -> bool {
+        let mut attempts = self.inner.lock().unwrap();
+        if totp_step <= attempts.last_totp_step {
+            return false;
+        }
+        attempts.last_totp_step = totp_step;
+        if let Some(ip) = ip {
+            attempts.failures.remove(ip);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The secret used by the test vectors of RFC 4226 and RFC 6238 (SHA-1)
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    fn base32_encode(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+        let mut out = String::new();
+        let (mut bits, mut n) = (0u64, 0u32);
+        for b in bytes {
+            bits = (bits << 8) | *b as u64;
+            n += 8;
+            while n >= 5 {
+                n -= 5;
+                out.push(ALPHABET[((bits >> n) & 0x1f) as usize] as char);
+            }
+        }
+        if n > 0 {
+            out.push(ALPHABET[((bits << (5 - n)) & 0x1f) as usize] as char);
+        }
+        while out.len() % 8 != 0 {
+            out.push('=');
+        }
+        out
+    }
+
+    fn root_auth() -> RootAuth {
+        RootAuth {
+            passwd_hash: String::new(),
+            totp_key: SECRET.to_vec(),
+            session_ttl: RootAuth::default_session_ttl(),
+        }
+    }
+
+    #[test]
+    fn base32() {
+        // RFC 4648 section 10
+        for (plain, encoded) in [
+            ("f", "MY======"),
+            ("fo", "MZXQ===="),
+            ("foo", "MZXW6==="),
+            ("foob", "MZXW6YQ="),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI======"),
+        ] {
+            assert_eq!(base32_encode(plain.as_bytes()), encoded);
+            assert_eq!(base32_decode(encoded).unwrap(), plain.as_bytes());
+        }
+        assert_eq!(
+            base32_decode("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap(),
+            SECRET
+        );
+
+        let bytes = (0..=255u8).collect::<Vec<u8>>();
+        for len in 1..bytes.len() {
+            assert_eq!(
+                base32_decode(&base32_encode(&bytes[..len])).unwrap(),
+                &bytes[..len]
+            );
+        }
+
+        // Secrets are often shown in lowercase groups
+        assert_eq!(
+            base32_decode("gezd gnbv gy3t qojq gezd gnbv gy3t qojq").unwrap(),
+            SECRET
+        );
+        assert!(base32_decode("GEZDGNB1").is_none());
+        assert!(base32_decode("").is_none());
+    }
+
+    #[test]
+    fn hotp_vectors() {
+        // RFC 4226 appendix D
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(SECRET, counter as u64), *code);
+        }
+    }
+
+    #[test]
+    fn totp_vectors() {
+        // RFC 6238 appendix B (SHA-1), with the last 6 of the 8 digits
+        let auth = root_auth();
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            assert_eq!(auth.verify_totp(code, time), Some(time / TOTP_STEP));
+        }
+    }
+
+    #[test]
+    fn totp_skew() {
+        let auth = root_auth();
+        // The code of the step covering 59
+        let code = "287082";
+        assert_eq!(auth.verify_totp(code, 0), Some(1));
+        assert_eq!(auth.verify_totp(code, 89), Some(1));
+        assert_eq!(auth.verify_totp(&format!(" {} ", code), 59), Some(1));
+        // Two steps away
+        assert_eq!(auth.verify_totp(code, 90), None);
+        assert_eq!(auth.verify_totp(code, 119), None);
+
+        assert_eq!(auth.verify_totp("94287082", 59), None);
+        assert_eq!(auth.verify_totp("000000", 59), None);
+    }
+
+    #[test]
+    fn replay() {
+        let attempts = RootLoginAttempts::default();
+        let ip = Some("127.0.0.1".to_string());
+
+        for _ in 0..MAX_FAILURES {
+            assert!(!attempts.is_throttled(&ip));
+            attempts.fail(&ip);
+        }
+        assert!(attempts.is_throttled(&ip));
+        // Other clients are not affected
+        assert!(!attempts.is_throttled(&None));
+
+        assert!(attempts.succeed(&ip, 5));
+        assert!(!attempts.is_throttled(&ip));
+        // Codes of the same or earlier steps could not be used again
+        assert!(!attempts.succeed(&ip, 5));
+        assert!(!attempts.succeed(&None, 4));
+        assert!(attempts.succeed(&ip, 6));
+    }
+
+    #[test]
+    fn global_throttle() {
+        let attempts = RootLoginAttempts::default();
+        let ip = Some("127.0.0.1".to_string());
+
+        // Clients without an IP could not get around the throttle
+        for _ in 0..MAX_GLOBAL_FAILURES - 1 {
+            assert!(!attempts.is_throttled(&None));
+            attempts.fail(&None);
+        }
+        assert!(!attempts.is_throttled(&ip));
+        attempts.fail(&None);
+        assert!(attempts.is_throttled(&None));
+        // Every client is throttled once there are too many failures in total
+        assert!(attempts.is_throttled(&ip));
+    }
+}
@@ -56,20 +56,22 @@ compile_i18n!();
 
 #[launch]
 fn rocket() -> Rocket<Build> {
-    use crate::{
-        infras::{digicons::DigiconHosting, oidc::OIDCClient},
-        services::root::RootPasswd,
+    use crate::infras::{
+        digicons::DigiconHosting,
+        msg_hub::MsgHub,
+        oidc::OIDCProviders,
+        root_auth::{RootAuth, RootLoginAttempts},
     };
     use infras::{
         aead::AeadKey,
         alipay::{AlipayAppPrivKey, AlipayClient},
-        basics::create_fairing,
+        basics::{create_fairing, create_optional_fairing},
         images::ImageHosting,
         recaptcha::ReCaptcha,
         smtp::SmtpCreds,
         tg_bot::TelegramBot,
     };
-    use sails_db::{categories::CtgBuilder, tags::TagsBuilder};
+    use sails_db::{categories::CtgBuilder, sessions::SessionPolicy, tags::TagsBuilder};
     use services::orders::PaypalAuth;
 
     let args: DcompassOpts = DcompassOpts::from_args();
@@ -84,8 +86,10 @@ fn rocket() -> Rocket<Build> {
         .attach(Shield::new())
         .attach(AdHoc::config::<CtgBuilder>())
         .attach(AdHoc::config::<TagsBuilder>())
-        .attach(AdHoc::config::<RootPasswd>())
-        .attach(create_fairing::<ReCaptcha>("recaptcha"))
+        .attach(AdHoc::config::<RootAuth>())
+        .attach(AdHoc::config::<SessionPolicy>())
+        // reCAPTCHA on root login is optional
+        .attach(create_optional_fairing::<ReCaptcha>("recaptcha"))
         .attach(create_fairing::<SmtpCreds>("mailbox"))
         .attach(create_fairing::<AeadKey>("encryption"))
         .attach(create_fairing::<ImageHosting>("images"))
@@ -94,11 +98,13 @@ fn rocket() -> Rocket<Build> {
         .attach(create_fairing::<AlipayClient>("alipay"))
         .attach(create_fairing::<PaypalAuth>("paypal"))
         .attach(create_fairing::<TelegramBot>("telegram"))
-        .attach(OIDCClient::fairing())
+        .attach(OIDCProviders::fairing())
         .attach(AdHoc::on_ignite(
             "Run database migrations",
             infras::database::run_migrations,
         ))
+        .manage(RootLoginAttempts::default())
+        .manage(MsgHub::default())
         .manage(include_i18n!())
         .mount(
             "/",
@@ -117,11 +123,27 @@ fn rocket() -> Rocket<Build> {
                 pages::users::portal_guest,
                 pages::users::update_user_page,
                 pages::users::portal_unsigned,
+                pages::users::credits,
+                pages::users::addresses,
+                pages::users::tokens,
+                pages::users::sessions,
+                pages::users::referrals,
                 services::users::signin,
+                services::users::signin_chooser,
                 services::users::signin_callback,
                 services::users::logout,
                 services::users::logout_fallback,
                 services::users::update_user,
+                services::users::redeem_giftcard,
+                services::users::export,
+                services::users::create_address,
+                services::users::update_address,
+                services::users::set_default_address,
+                services::users::delete_address,
+                services::users::create_token,
+                services::users::revoke_token,
+                services::users::revoke_session,
+                services::users::revoke_all_sessions,
             ],
         )
         .mount(
@@ -158,7 +180,14 @@ fn rocket() -> Rocket<Build> {
                 pages::msgs::portal,
                 pages::msgs::chat,
                 pages::msgs::chat_error,
-                services::msgs::send
+                services::msgs::send,
+                services::msgs::send_order,
+                services::msgs::send_attachment,
+                services::msgs::attachment,
+                services::msgs::unread,
+                services::msgs::stream,
+                services::msgs::read,
+                services::msgs::conv
             ],
         )
         .mount(
@@ -168,10 +197,15 @@ fn rocket() -> Rocket<Build> {
                 pages::root::unverified_root,
                 pages::root::root_verify,
                 pages::root::user_status,
+                pages::root::roles,
                 services::root::logout,
                 services::root::validate,
-                services::root::update_user_status,
+                services::root::update_user_roles,
+                services::root::create_role,
+                services::root::update_role,
+                services::root::delete_role,
                 services::root::delete_user,
+                services::root::purge_user,
             ],
         )
         .mount(
@@ -187,6 +221,13 @@ fn rocket() -> Rocket<Build> {
                 pages::admin::update_coupon_page,
                 pages::admin::create_coupon_page,
                 pages::admin::coupons_page,
+                pages::admin::coupon_stats_page,
+                pages::admin::coupon_stats_csv,
+                pages::admin::credits_page,
+                pages::admin::referrals_page,
+                pages::admin::sessions_page,
+                pages::admin::tickets_page,
+                pages::admin::ticket_page,
                 services::admin::refund_order,
                 services::admin::finish_order,
                 services::admin::verify_prod,
@@ -196,6 +237,27 @@ fn rocket() -> Rocket<Build> {
                 services::admin::update_coupon,
                 services::admin::create_coupon,
                 services::admin::delete_coupon,
+                services::admin::dry_run_coupon,
+                services::admin::refund_order_to_credit,
+                services::admin::create_giftcard,
+                services::admin::grant_credit,
+                services::admin::create_referral_reward,
+                services::admin::delete_referral_reward,
+                services::admin::export_user,
+                services::admin::revoke_session,
+                services::admin::revoke_user_sessions,
+                services::admin::reply_ticket,
+                services::admin::assign_ticket,
+                services::admin::ticket_status,
+            ],
+        )
+        .mount(
+            "/tickets",
+            routes![
+                pages::tickets::tickets,
+                pages::tickets::ticket,
+                services::tickets::open,
+                services::tickets::reply,
             ],
         )
         .mount(
@@ -205,6 +267,7 @@ fn rocket() -> Rocket<Build> {
                 pages::orders::order_info_seller,
                 pages::orders::order_info_alipay,
                 pages::orders::order_info_paypal,
+                pages::orders::order_info_credit,
                 services::orders::purchase,
                 services::orders::progress_alipay,
                 services::orders::cancel_order_alipay,
@@ -212,6 +275,7 @@ fn rocket() -> Rocket<Build> {
                 services::orders::create_paypal_order,
                 services::orders::capture_paypal_order,
                 services::orders::cancel_order_paypal,
+                services::orders::cancel_order_credit,
             ],
         )
         .mount(
@@ -1,18 +1,28 @@
 use crate::{
     infras::{guards::*, i18n::I18n},
-    DbConn, IntoFlash,
+    pages::orders::OrderThread,
+    DbConn, IntoFlash, Msg,
 };
 use askama::Template;
-use rocket::response::{Flash, Redirect};
+use chrono::NaiveDate;
+use rocket::{
+    http::ContentType,
+    request::FlashMessage,
+    response::{Flash, Redirect},
+};
 use sails_db::{
     coupons::*,
-    enums::{ProductStatus, TransactionStatus},
+    credits::*,
+    enums::{Currency, Payment, ProductStatus, TicketStatus, TransactionStatus, UserStatus},
     error::SailsDbError,
     products::{ProductFinder, ProductInfo},
+    referrals::*,
+    sessions::*,
     tags::*,
+    tickets::*,
     transactions::*,
-    users::{UserFinder, UserStats},
-    Cmp,
+    users::{UserFinder, UserInfo, UserNames, UserStats},
+    Cmp, Order,
 };
 
 #[derive(Template)]
@@ -21,6 +31,16 @@ pub struct AdminMetricsPage {
     i18n: I18n,
     pub order: TxStats,
     pub user: UserStats,
+    pub ticket: TicketStats,
+}
+
+impl AdminMetricsPage {
+    fn display_duration(&self, d: Option<chrono::Duration>) -> String {
+        match d {
+            Some(d) => format!("{}h {}m", d.num_hours(), d.num_minutes() % 60),
+            None => "N/A".to_string(),
+        }
+    }
 }
 
 // To prevent deadlock, redirect all errors back to index as this is the default route for `/admin`
@@ -40,6 +60,10 @@ pub async fn admin_metrics(
             .run(|c| UserFinder::stats(c))
             .await
             .into_flash(uri!("/"))?,
+        ticket: conn
+            .run(|c| TicketFinder::stats(c))
+            .await
+            .into_flash(uri!("/"))?,
     })
 }
 
@@ -51,6 +75,8 @@ pub struct AdminOrdersPage {
     placed_tx: Vec<(ProductInfo, TransactionInfo)>,
     refunded_tx: Vec<(ProductInfo, TransactionInfo)>,
     finished_tx: Vec<(ProductInfo, TransactionInfo)>,
+    // Names of the buyers
+    names: UserNames,
 }
 
 // CustomerService or above can READ all orders
@@ -148,12 +174,22 @@ pub async fn admin_orders(
         .await
         .into_flash(uri!("/"))?;
 
+    let buyers = [&paid_tx, &placed_tx, &refunded_tx, &finished_tx]
+        .iter()
+        .flat_map(|tx| tx.iter().map(|x| x.1.get_buyer().to_string()))
+        .collect::<Vec<String>>();
+    let names = conn
+        .run(move |c| UserFinder::names(c, buyers.iter().map(String::as_str)))
+        .await
+        .into_flash(uri!("/"))?;
+
     Ok(AdminOrdersPage {
         i18n,
         paid_tx,
         placed_tx,
         refunded_tx,
         finished_tx,
+        names,
     })
 }
 
@@ -274,6 +310,7 @@ pub struct AdminOrderInfoPage {
     i18n: I18n,
     prod: ProductInfo,
     order: TransactionInfo,
+    thread: OrderThread,
 }
 
 #[get("/order_info?<order_id>")]
@@ -281,21 +318,29 @@ pub async fn order_info(
     i18n: I18n,
     // CustomerService imply OrderOthersReadable, which is what this admin page is for.
     _auth: Role<CustomerService>,
+    user: UserIdGuard<Cookie>,
     order_id: OrderGuard,
     conn: DbConn,
 ) -> Result<AdminOrderInfoPage, Flash<Redirect>> {
     let order = order_id.to_info(&conn).await.into_flash(uri!("/"))?;
+    let thread = OrderThread::load(&conn, user.id, &order.order_info)
+        .await
+        .into_flash(uri!("/"))?;
     Ok(AdminOrderInfoPage {
         i18n,
         prod: order.prod_info,
         order: order.order_info,
+        thread,
     })
 }
 
 #[derive(Template)]
 #[template(path = "admin/coupons/create_coupon.html")]
 pub struct AdminCreateCouponPage {
-    i18n: I18n,
+    pub i18n: I18n,
+    // Values to fill in, kept when the submission is rejected
+    pub form: CouponForm,
+    pub error: Option<String>,
 }
 
 #[get("/create_coupon")]
@@ -303,14 +348,63 @@ pub async fn create_coupon_page(
     i18n: I18n,
     _role: Role<Admin>,
 ) -> Result<AdminCreateCouponPage, Flash<Redirect>> {
-    Ok(AdminCreateCouponPage { i18n })
+    Ok(AdminCreateCouponPage {
+        i18n,
+        form: CouponForm::default(),
+        error: None,
+    })
+}
+
+// Buyer, product, quantity and payment to evaluate a coupon against
+#[derive(Debug, Clone, FromForm)]
+pub struct DryRunForm {
+    pub buyer: String,
+    pub product: String,
+    pub quantity: u32,
+    pub payment: Payment,
+}
+
+impl Default for DryRunForm {
+    fn default() -> Self {
+        Self {
+            buyer: String::new(),
+            product: String::new(),
+            quantity: 1,
+            payment: Payment::Paypal,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DryRunView {
+    // Reason why the coupon would be rejected at checkout
+    pub restriction: Option<String>,
+    pub discount: Result<i64, String>,
+    pub operations: u64,
+    pub max_operations: u64,
+}
+
+impl From<DryRun> for DryRunView {
+    fn from(report: DryRun) -> Self {
+        Self {
+            restriction: report.restriction.err().map(|e| e.to_string()),
+            discount: report.discount.map_err(|e| e.to_string()),
+            operations: report.operations,
+            max_operations: MAX_SCRIPT_OPERATIONS,
+        }
+    }
 }
 
 #[derive(Template)]
 #[template(path = "admin/coupons/update_coupon.html")]
 pub struct AdminUpdateCouponPage {
-    i18n: I18n,
-    coupon: Coupon,
+    pub i18n: I18n,
+    // ID of the coupon as saved
+    pub id: String,
+    pub form: CouponForm,
+    pub error: Option<String>,
+    pub dry_run_form: DryRunForm,
+    pub dry_run: Option<DryRunView>,
 }
 
 #[get("/create_coupon?<coupon_id>")]
@@ -321,7 +415,14 @@ pub async fn update_coupon_page(
     conn: DbConn,
 ) -> Result<AdminUpdateCouponPage, Flash<Redirect>> {
     let coupon = coupon_id.to_coupon(&conn).await.into_flash(uri!("/"))?;
-    Ok(AdminUpdateCouponPage { i18n, coupon })
+    Ok(AdminUpdateCouponPage {
+        i18n,
+        id: coupon.get_id().to_string(),
+        form: CouponForm::from(&coupon),
+        error: None,
+        dry_run_form: DryRunForm::default(),
+        dry_run: None,
+    })
 }
 
 #[derive(Template)]
@@ -344,6 +445,270 @@ pub async fn coupons_page(
     Ok(AdminCouponsPage { i18n, coupons })
 }
 
+#[derive(Template)]
+#[template(path = "admin/coupons/coupon_stats.html")]
+pub struct AdminCouponStatsPage {
+    i18n: I18n,
+    stats: Vec<CouponStats>,
+    // Time series of the coupon selected
+    coupon_id: Option<String>,
+    daily: Vec<(NaiveDate, CouponStats)>,
+    // The largest daily usage, used to scale the bars
+    max_uses: usize,
+}
+
+#[get("/coupon_stats?<coupon_id>")]
+pub async fn coupon_stats_page(
+    i18n: I18n,
+    _role: Role<Admin>,
+    coupon_id: Option<String>,
+    conn: DbConn,
+) -> Result<AdminCouponStatsPage, Flash<Redirect>> {
+    let id = coupon_id.clone();
+    let (stats, daily) = conn
+        .run(move |c| -> Result<_, SailsDbError> {
+            Ok(match id {
+                Some(id) => (
+                    vec![Coupons::stats_of(c, &id)?],
+                    Coupons::daily_stats(c, &id)?,
+                ),
+                None => (Coupons::stats(c)?, Vec::new()),
+            })
+        })
+        .await
+        .into_flash(uri!("/admin", coupons_page))?;
+    let max_uses = daily.iter().map(|(_, s)| s.uses).max().unwrap_or(0).max(1);
+    Ok(AdminCouponStatsPage {
+        i18n,
+        stats,
+        coupon_id,
+        daily,
+        max_uses,
+    })
+}
+
+// Export the report of all coupons, or the time series of the coupon given
+#[get("/coupon_stats_csv?<coupon_id>")]
+pub async fn coupon_stats_csv(
+    _role: Role<Admin>,
+    coupon_id: Option<String>,
+    conn: DbConn,
+) -> Result<(ContentType, String), Flash<Redirect>> {
+    let rows = conn
+        .run(move |c| -> Result<_, SailsDbError> {
+            Ok(match coupon_id {
+                Some(id) => Coupons::daily_stats(c, &id)?
+                    .into_iter()
+                    .map(|(date, s)| (date.to_string(), s))
+                    .collect::<Vec<(String, CouponStats)>>(),
+                None => Coupons::stats(c)?
+                    .into_iter()
+                    .map(|s| (s.coupon.clone(), s))
+                    .collect(),
+            })
+        })
+        .await
+        .into_flash(uri!("/admin", coupons_page))?;
+
+    // Amounts in different currencies are separated by semicolons, e.g. `USD 10; CNY 5`
+    let amounts = |amounts: Vec<(Currency, i64)>| {
+        amounts
+            .iter()
+            .map(|(c, a)| format!("{:?} {}", c, a))
+            .collect::<Vec<String>>()
+            .join("; ")
+    };
+    let escape = |field: &str| format!("\"{}\"", field.replace('"', "\"\""));
+
+    let mut csv = String::from("key,uses,unique_buyers,refunded,refund_rate,discount,revenue\n");
+    for (key, s) in rows {
+        csv.push_str(&format!(
+            "{},{},{},{},{:.4},{},{}\n",
+            escape(&key),
+            s.uses,
+            s.get_unique_buyers(),
+            s.refunded,
+            s.get_refund_rate(),
+            escape(&amounts(s.get_discount())),
+            escape(&amounts(s.get_revenue()))
+        ));
+    }
+    Ok((ContentType::CSV, csv))
+}
+
+#[derive(Template)]
+#[template(path = "admin/credits.html")]
+pub struct AdminCreditsPage {
+    i18n: I18n,
+    giftcards: Vec<GiftCard>,
+    entries: Vec<CreditEntry>,
+}
+
+#[get("/credits")]
+pub async fn credits_page(
+    i18n: I18n,
+    _role: Role<Admin>,
+    conn: DbConn,
+) -> Result<AdminCreditsPage, Flash<Redirect>> {
+    let (giftcards, entries) = conn
+        .run(move |c| -> Result<_, SailsDbError> {
+            Ok((GiftCards::list(c)?, CreditFinder::new(c, None).search()?))
+        })
+        .await
+        .into_flash(uri!("/"))?;
+    Ok(AdminCreditsPage {
+        i18n,
+        giftcards,
+        entries,
+    })
+}
+
+#[derive(Template)]
+#[template(path = "admin/sessions.html")]
+pub struct AdminSessionsPage {
+    i18n: I18n,
+    sessions: Vec<Session>,
+}
+
+#[get("/sessions")]
+pub async fn sessions_page(
+    i18n: I18n,
+    _role: Role<Admin>,
+    conn: DbConn,
+) -> Result<AdminSessionsPage, Flash<Redirect>> {
+    let sessions = conn
+        .run(|c| Sessions::list(c))
+        .await
+        .into_flash(uri!("/"))?;
+    Ok(AdminSessionsPage { i18n, sessions })
+}
+
+#[derive(Template)]
+#[template(path = "admin/referrals.html")]
+pub struct AdminReferralsPage {
+    i18n: I18n,
+    report: Vec<ReferralReport>,
+    rewards: Vec<ReferralReward>,
+    referrals: Vec<Referral>,
+}
+
+#[get("/referrals")]
+pub async fn referrals_page(
+    i18n: I18n,
+    _role: Role<Admin>,
+    conn: DbConn,
+) -> Result<AdminReferralsPage, Flash<Redirect>> {
+    let (report, rewards, referrals) = conn
+        .run(move |c| -> Result<_, SailsDbError> {
+            Ok((
+                Referrals::report(c)?,
+                ReferralRewards::list(c)?,
+                ReferralFinder::new(c, None).search()?,
+            ))
+        })
+        .await
+        .into_flash(uri!("/"))?;
+    Ok(AdminReferralsPage {
+        i18n,
+        report,
+        rewards,
+        referrals,
+    })
+}
+
+#[derive(Template)]
+#[template(path = "admin/tickets.html")]
+pub struct AdminTicketsPage {
+    i18n: I18n,
+    tickets: Vec<Ticket>,
+    // Unresolved tickets are shown if no status is given
+    status: Option<TicketStatus>,
+    mine: bool,
+}
+
+// The queue of the customer service, the ones waiting the longest first
+#[get("/tickets?<status>&<mine>")]
+pub async fn tickets_page(
+    i18n: I18n,
+    _role: Role<CustomerService>,
+    user: UserIdGuard<Cookie>,
+    status: Option<TicketStatus>,
+    mine: Option<bool>,
+    conn: DbConn,
+) -> Result<AdminTicketsPage, Flash<Redirect>> {
+    let mine = mine.unwrap_or(false);
+    let status_c = status.clone();
+    let tickets = conn
+        .run(move |c| {
+            let mut finder = TicketFinder::new(c, None).order_by_time(Order::Asc);
+            finder = match status_c {
+                Some(s) => finder.status(s),
+                None => finder.unresolved(),
+            };
+            if mine {
+                finder = finder.assignee(&user.id);
+            }
+            finder.search()
+        })
+        .await
+        .into_flash(uri!("/"))?;
+    Ok(AdminTicketsPage {
+        i18n,
+        tickets,
+        status,
+        mine,
+    })
+}
+
+#[derive(Template)]
+#[template(path = "admin/ticket.html")]
+pub struct AdminTicketPage {
+    i18n: I18n,
+    ticket: Ticket,
+    // Internal notes included
+    replies: Vec<TicketReply>,
+    // The customer service the ticket could be assigned to
+    staff: Vec<UserInfo>,
+    inner: Msg,
+}
+
+impl AdminTicketPage {
+    fn status_is(&self, status: TicketStatus) -> bool {
+        self.ticket.get_status() == &status
+    }
+}
+
+#[get("/ticket?<ticket_id>")]
+pub async fn ticket_page(
+    i18n: I18n,
+    _role: Role<CustomerService>,
+    ticket_id: String,
+    conn: DbConn,
+    flash: Option<FlashMessage<'_>>,
+) -> Result<AdminTicketPage, Flash<Redirect>> {
+    let (ticket, replies, staff) = conn
+        .run(move |c| -> Result<_, SailsDbError> {
+            let ticket = Tickets::find(c, &ticket_id)?;
+            let replies = Tickets::replies(c, &ticket, true)?;
+            let staff = UserFinder::new(c, None)
+                .status(&UserStatus::CUSTOMER_SERVICE, Cmp::GreaterEqual)
+                .search_info()?
+                .into_iter()
+                .filter(|u| u.get_user_status().contains(UserStatus::CUSTOMER_SERVICE))
+                .collect();
+            Ok((ticket, replies, staff))
+        })
+        .await
+        .into_flash(uri!("/admin", tickets_page(_, _)))?;
+    Ok(AdminTicketPage {
+        i18n,
+        ticket,
+        replies,
+        staff,
+        inner: Msg::from_flash(flash),
+    })
+}
+
 #[get("/")]
 pub async fn admin(_guard: Auth<ProdAdmin>) -> Redirect {
     Redirect::to(uri!("/admin", admin_metrics))
@@ -8,4 +8,5 @@ pub mod orders;
 pub mod root;
 pub mod search;
 pub mod store;
+pub mod tickets;
 pub mod users;
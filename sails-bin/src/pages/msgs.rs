@@ -5,16 +5,30 @@ use crate::{
 use askama::Template;
 use rocket::response::{Flash, Redirect};
 use sails_db::{
-    messages::{Message, Messages},
+    error::SailsDbError,
+    messages::{Attachment, Attachments, Message, Messages},
     users::*,
 };
+use std::collections::HashMap;
 
 #[derive(Template)]
 #[template(path = "messages/chat.html")]
 pub struct ChatPage {
     i18n: I18n,
     messages: Vec<Message>,
+    attachments: Vec<Attachment>,
     receiver: UserInfo,
+    // The user viewing the chat
+    user: UserInfo,
+}
+
+impl ChatPage {
+    fn attachments_of(&self, msg: &Message) -> Vec<&Attachment> {
+        self.attachments
+            .iter()
+            .filter(|a| a.get_message_id() == msg.get_id())
+            .collect()
+    }
 }
 
 #[get("/chat", rank = 2)]
@@ -29,20 +43,29 @@ pub async fn chat_error() -> Flash<Redirect> {
 pub async fn chat(
     i18n: I18n,
     conn: DbConn,
-    user: UserIdGuard<Cookie>,
+    user: UserInfoGuard<Cookie>,
     user_id: UserGuard,
 ) -> Result<ChatPage, Flash<Redirect>> {
     let receiver = user_id.to_info_param(&conn).await.into_flash(uri!("/"))?;
 
     let receiver_id = receiver.info.to_id();
-    let messages = conn
-        .run(move |c| Messages::get_conv(c, &user.id, &receiver_id))
+    let uid = user.info.to_id();
+    // Opening the chat reads all the messages from the receiver
+    let (messages, attachments) = conn
+        .run(move |c| -> Result<_, SailsDbError> {
+            Messages::mark_read(c, &uid, &receiver_id)?;
+            let messages = Messages::get_conv(c, &uid, &receiver_id)?;
+            let attachments = Attachments::of_messages(c, &messages)?;
+            Ok((messages, attachments))
+        })
         .await
         .into_flash(uri!("/"))?;
     Ok(ChatPage {
         i18n,
         messages,
+        attachments,
         receiver: receiver.info,
+        user: user.info,
     })
 }
 
@@ -51,6 +74,16 @@ pub async fn chat(
 pub struct PortalPage {
     i18n: I18n,
     message_list: Vec<Message>,
+    // Unread messages by the sender
+    unread: HashMap<String, i64>,
+    // Names of the senders
+    names: UserNames,
+}
+
+impl PortalPage {
+    fn unread_of(&self, sender: &str) -> i64 {
+        self.unread.get(sender).copied().unwrap_or(0)
+    }
 }
 
 #[get("/")]
@@ -60,11 +93,20 @@ pub async fn portal(
     conn: DbConn,
 ) -> Result<PortalPage, Flash<Redirect>> {
     if let Some(user) = user.map(|u| u.id) {
-        let message_list = conn
-            .run(move |c| Messages::get_list(c, &user))
+        let (message_list, unread, names) = conn
+            .run(move |c| -> Result<_, SailsDbError> {
+                let message_list = Messages::get_list(c, &user)?;
+                let names = UserFinder::names(c, message_list.iter().map(Message::get_send))?;
+                Ok((message_list, Messages::unread_by_sender(c, &user)?, names))
+            })
             .await
             .into_flash(uri!("/"))?;
-        Ok(PortalPage { i18n, message_list })
+        Ok(PortalPage {
+            i18n,
+            message_list,
+            unread,
+            names,
+        })
     } else {
         Err(Flash::error(
             Redirect::to(uri!("/")),
@@ -1,3 +1,4 @@
+use super::OrderThread;
 use crate::{
     infras::{
         alipay::{AlipayAppPrivKey, AlipayClient, Precreate, PrecreateResp, SignedResponse},
@@ -21,6 +22,7 @@ pub struct OrderInfoBuyerAlipay {
     order: TransactionInfo,
     // Alipay precreate API response
     resp: Option<Result<PrecreateResp, SignedResponse<PrecreateResp>>>,
+    thread: OrderThread,
 }
 
 #[get("/order_info?<order_id>", rank = 1)]
@@ -30,12 +32,16 @@ pub async fn order_info_alipay(
     // This page contains progressable information
     // TODO: this is not a good enough distinguishment
     _auth: Auth<OrderProgressable>,
+    user: UserIdGuard<CookieOrToken>,
     order_id: OrderGuard,
     conn: DbConn,
     priv_key: &State<AlipayAppPrivKey>,
     client: &State<AlipayClient>,
 ) -> Result<OrderInfoBuyerAlipay, Flash<Redirect>> {
     let order = order_id.to_info(&conn).await.into_flash(uri!("/"))?;
+    let thread = OrderThread::load(&conn, user.id, &order.order_info)
+        .await
+        .into_flash(uri!("/"))?;
     if order.order_info.get_transaction_status() == &TransactionStatus::Placed {
         // It seems like we could request precreation even if the user has already paid the bill or the trade has already been created.
         // If, in the future, this behavior changes, we have to come up with a better mechanism.
@@ -47,7 +53,8 @@ pub async fn order_info_alipay(
                     order.order_info.get_id(),
                     // Alipay doesn't play well with UTF-8
                     order.prod_info.get_prodname(),
-                    order.order_info.get_total(),
+                    // The part paid with store credit is excluded
+                    order.order_info.get_payable(),
                 ),
             )
             .into_flash(uri!("/"))?
@@ -59,6 +66,7 @@ pub async fn order_info_alipay(
             prod: order.prod_info,
             order: order.order_info,
             resp: Some(resp),
+            thread,
         })
     } else {
         Ok(OrderInfoBuyerAlipay {
@@ -66,6 +74,7 @@ pub async fn order_info_alipay(
             prod: order.prod_info,
             order: order.order_info,
             resp: None,
+            thread,
         })
     }
 }
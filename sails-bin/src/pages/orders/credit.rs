@@ -0,0 +1,38 @@
+use super::OrderThread;
+use crate::{
+    infras::{guards::*, i18n::I18n},
+    DbConn, IntoFlash,
+};
+use askama::Template;
+use rocket::response::{Flash, Redirect};
+use sails_db::{products::*, transactions::*};
+
+#[derive(Template)]
+#[template(path = "orders/order_info_credit.html")]
+pub struct OrderInfoBuyerCredit {
+    i18n: I18n,
+    prod: ProductInfo,
+    order: TransactionInfo,
+    thread: OrderThread,
+}
+
+#[get("/order_info?<order_id>", rank = 3)]
+pub async fn order_info_credit(
+    i18n: I18n,
+    _is_credit: Auth<OrderWithCredit>,
+    _auth: Auth<OrderProgressable>,
+    user: UserIdGuard<CookieOrToken>,
+    order_id: OrderGuard,
+    conn: DbConn,
+) -> Result<OrderInfoBuyerCredit, Flash<Redirect>> {
+    let order = order_id.to_info(&conn).await.into_flash(uri!("/"))?;
+    let thread = OrderThread::load(&conn, user.id, &order.order_info)
+        .await
+        .into_flash(uri!("/"))?;
+    Ok(OrderInfoBuyerCredit {
+        i18n,
+        prod: order.prod_info,
+        order: order.order_info,
+        thread,
+    })
+}
@@ -1,23 +1,78 @@
 mod alipay;
+mod credit;
 mod paypal;
 
 pub use alipay::*;
+pub use credit::*;
 pub use paypal::*;
 
 use crate::{
     infras::{guards::*, i18n::I18n},
-    DbConn, IntoFlash,
+    DbConn, IntoFlash, Msg,
 };
 use askama::Template;
-use rocket::response::{Flash, Redirect};
-use sails_db::{products::*, transactions::*};
+use rocket::{
+    request::FlashMessage,
+    response::{Flash, Redirect},
+};
+use sails_db::{
+    addresses::*,
+    credits::Credits,
+    error::SailsDbError,
+    messages::*,
+    products::*,
+    transactions::*,
+    users::{UserFinder, UserId, UserNames},
+};
+
+// Messages about the order, shown at the bottom of the order pages
+pub struct OrderThread {
+    pub messages: Vec<Message>,
+    // Only the buyer and the seller could post to the thread, others could only read it
+    pub can_post: bool,
+    // Names of the buyer, the seller and everyone posting to the thread
+    pub names: UserNames,
+}
+
+impl OrderThread {
+    // Viewing the thread reads all the messages sent to the viewer in it
+    pub async fn load(
+        conn: &DbConn,
+        viewer: UserId,
+        order: &TransactionInfo,
+    ) -> Result<Self, SailsDbError> {
+        let order = order.clone();
+        conn.run(move |c| -> Result<_, SailsDbError> {
+            Messages::mark_thread_read(c, &viewer, &order)?;
+            let messages = Messages::thread(c, &order)?;
+            let names = UserFinder::names(
+                c,
+                messages
+                    .iter()
+                    .map(Message::get_send)
+                    .chain([order.get_buyer(), order.get_seller()]),
+            )?;
+            Ok(OrderThread {
+                messages,
+                can_post: viewer.get_id() == order.get_buyer()
+                    || viewer.get_id() == order.get_seller(),
+                names,
+            })
+        })
+        .await
+    }
+}
 
 #[derive(Template)]
 #[template(path = "orders/checkout.html")]
 pub struct CheckoutPage {
     i18n: I18n,
     prod: ProductInfo,
-    recent_address: Option<String>,
+    // Address book of the buyer, the default one first
+    addresses: Vec<Address>,
+    // Store credit available in the currency of the product
+    credit_balance: i64,
+    inner: Msg,
 }
 
 #[get("/checkout?<prod_id>")]
@@ -25,17 +80,26 @@ pub async fn checkout(
     i18n: I18n,
     db: DbConn,
     prod_id: ProdGuard,
-    user: UserIdGuard<Cookie>,
+    user: UserIdGuard<CookieOrToken>,
+    flash: Option<FlashMessage<'_>>,
 ) -> Result<CheckoutPage, Flash<Redirect>> {
-    let addr = db
-        .run(move |c| TransactionFinder::most_recent_order(c, &user.id))
+    let prod = prod_id.to_info(&db).await.into_flash(uri!("/"))?.prod_info;
+    let currency = prod.get_currency().clone();
+    let uid = user.id.clone();
+    let credit_balance = db
+        .run(move |c| Credits::balance(c, &uid, &currency))
         .await
-        .map(|x| x.get_address().to_string())
-        .ok();
+        .into_flash(uri!("/"))?;
+    let addresses = db
+        .run(move |c| Addresses::list_by_user(c, &user.id))
+        .await
+        .into_flash(uri!("/"))?;
     Ok(CheckoutPage {
         i18n,
-        prod: prod_id.to_info(&db).await.into_flash(uri!("/"))?.prod_info,
-        recent_address: addr,
+        prod,
+        addresses,
+        credit_balance,
+        inner: Msg::from_flash(flash),
     })
 }
 
@@ -45,19 +109,25 @@ pub struct OrderInfoSeller {
     i18n: I18n,
     prod: ProductInfo,
     order: TransactionInfo,
+    thread: OrderThread,
 }
 
-#[get("/order_info?<order_id>", rank = 3)]
+#[get("/order_info?<order_id>", rank = 4)]
 pub async fn order_info_seller(
     i18n: I18n,
     _auth: Auth<OrderReadable>,
+    user: UserIdGuard<CookieOrToken>,
     order_id: OrderGuard,
     conn: DbConn,
 ) -> Result<OrderInfoSeller, Flash<Redirect>> {
     let order = order_id.to_info(&conn).await.into_flash(uri!("/"))?;
+    let thread = OrderThread::load(&conn, user.id, &order.order_info)
+        .await
+        .into_flash(uri!("/"))?;
     Ok(OrderInfoSeller {
         i18n,
         prod: order.prod_info,
         order: order.order_info,
+        thread,
     })
 }
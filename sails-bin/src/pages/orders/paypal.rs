@@ -1,3 +1,4 @@
+use super::OrderThread;
 use crate::{
     infras::{guards::*, i18n::I18n},
     services::orders::PaypalAuth,
@@ -18,6 +19,7 @@ pub struct OrderInfoBuyerPaypal {
     prod: ProductInfo,
     order: TransactionInfo,
     client_id: String,
+    thread: OrderThread,
 }
 
 #[get("/order_info?<order_id>", rank = 2)]
@@ -27,15 +29,20 @@ pub async fn order_info_paypal(
     // This page contains progressable information
     // TODO: this is not a good enough distinguishment
     _auth: Auth<OrderProgressable>,
+    user: UserIdGuard<CookieOrToken>,
     order_id: OrderGuard,
     conn: DbConn,
     paypal_auth: &State<PaypalAuth>,
 ) -> Result<OrderInfoBuyerPaypal, Flash<Redirect>> {
     let order = order_id.to_info(&conn).await.into_flash(uri!("/"))?;
+    let thread = OrderThread::load(&conn, user.id, &order.order_info)
+        .await
+        .into_flash(uri!("/"))?;
     Ok(OrderInfoBuyerPaypal {
         i18n,
         prod: order.prod_info,
         order: order.order_info,
         client_id: paypal_auth.client_id.clone(),
+        thread,
     })
 }
@@ -1,26 +1,33 @@
 use crate::{
     infras::{guards::*, i18n::I18n, recaptcha::ReCaptcha},
-    DbConn, IntoFlash,
+    DbConn, IntoFlash, Msg,
 };
 use askama::Template;
 use rocket::{
+    request::FlashMessage,
     response::{Flash, Redirect},
     State,
 };
-use sails_db::users::{UserFinder, UserInfo};
+use sails_db::{
+    enums::UserStatus,
+    error::SailsDbError,
+    roles::{UserRole, UserRoles},
+    users::{UserFinder, UserInfo},
+};
 
 #[derive(Template)]
 #[template(path = "root/root_verify.html")]
 pub struct RootVerifyPage {
     i18n: I18n,
-    recaptcha_key: String,
+    // None if reCAPTCHA is not configured
+    recaptcha_key: Option<String>,
 }
 
 #[get("/root_verify")]
-pub async fn root_verify<'a>(i18n: I18n, recaptcha: &State<ReCaptcha>) -> RootVerifyPage {
+pub async fn root_verify<'a>(i18n: I18n, recaptcha: Option<&State<ReCaptcha>>) -> RootVerifyPage {
     RootVerifyPage {
         i18n,
-        recaptcha_key: recaptcha.site_key().to_string(),
+        recaptcha_key: recaptcha.map(|x| x.site_key().to_string()),
     }
 }
 
@@ -49,6 +56,15 @@ pub async fn unverified_root() -> Redirect {
 pub struct UserStatusPage {
     i18n: I18n,
     user: UserInfo,
+    roles: Vec<UserRole>,
+    // IDs of the roles assigned to the user
+    assigned: Vec<String>,
+}
+
+impl UserStatusPage {
+    fn is_assigned(&self, role: &UserRole) -> bool {
+        self.assigned.iter().any(|x| x == role.get_id())
+    }
 }
 
 #[get("/user_status?<user_id>")]
@@ -59,9 +75,50 @@ pub async fn user_status(
     conn: DbConn,
 ) -> Result<UserStatusPage, Flash<Redirect>> {
     let user = user_id.to_info_param(&conn).await.into_flash(uri!("/"))?;
+    let id = user.info.to_id();
+    let (roles, assigned) = conn
+        .run(move |c| -> Result<_, SailsDbError> {
+            Ok((UserRoles::list(c)?, UserRoles::of(c, &id)?))
+        })
+        .await
+        .into_flash(uri!("/"))?;
 
     Ok(UserStatusPage {
         i18n,
         user: user.info,
+        roles,
+        assigned: assigned
+            .into_iter()
+            .map(|r| r.get_id().to_string())
+            .collect(),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "root/roles.html")]
+pub struct RolesPage {
+    i18n: I18n,
+    inner: Msg,
+    roles: Vec<UserRole>,
+    flags: Vec<(&'static str, UserStatus)>,
+}
+
+#[get("/roles")]
+pub async fn roles(
+    i18n: I18n,
+    _guard: Role<Root>,
+    flash: Option<FlashMessage<'_>>,
+    conn: DbConn,
+) -> Result<RolesPage, Flash<Redirect>> {
+    let roles = conn
+        .run(|c| UserRoles::list(c))
+        .await
+        .into_flash(uri!("/root", root))?;
+
+    Ok(RolesPage {
+        i18n,
+        inner: Msg::from_flash(flash),
+        roles,
+        flags: UserStatus::flags(),
     })
 }
@@ -4,45 +4,182 @@ use crate::{
     DbConn, IntoFlash,
 };
 use askama::Template;
+use diesel::SqliteConnection;
 use rocket::response::{Flash, Redirect};
-use sails_db::{categories::*, error::SailsDbError, products::*, tags::*, Cmp};
+use sails_db::{
+    categories::*, enums::Currency, error::SailsDbError, products::*, tags::*, users::UserFinder,
+    Cmp,
+};
+
+// Filters applied on top of the category browsing
+#[derive(Debug, Clone, Default, FromForm)]
+pub struct SearchFilter {
+    tags: Vec<String>,
+    // Price range is only meaningful in a given currency
+    currency: Option<Currency>,
+    min_price: Option<u32>,
+    max_price: Option<u32>,
+    in_stock: bool,
+    seller: Option<String>,
+}
+
+impl SearchFilter {
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+}
+
+// Facets displayed on the sidebar with the number of products each of them matches
+// The last field of each entry represents whether it is currently selected.
+#[derive(Debug, Clone, Default)]
+pub struct FacetsView {
+    total: usize,
+    tags: Vec<(Tag, usize, bool)>,
+    currencies: Vec<(Currency, usize, bool)>,
+    // (seller ID, seller name, count, selected)
+    sellers: Vec<(String, String, usize, bool)>,
+}
 
 #[derive(Template)]
 #[template(path = "search/categories.html")]
 pub struct CategoriesPage {
     i18n: I18n,
-    categories: Option<Vec<Category>>,
+    // Subcategories with the number of products matched
+    categories: Option<Vec<(Category, usize)>>,
     current_ctg: Option<Category>,
     parent_ctgs: Vec<Category>,
     products: Vec<ProductCard>,
+    filter: SearchFilter,
+    facets: FacetsView,
 }
 
-// Browse all categories
-#[get("/categories", rank = 2)]
-pub async fn categories_all(i18n: I18n, conn: DbConn) -> Result<CategoriesPage, Flash<Redirect>> {
-    let products = conn
-        .run(move |c| -> Result<Vec<ProductCard>, SailsDbError> {
-            // We only display allowed prods
-            let mut product_info = ProductFinder::new(c, None)
-                .status(sails_db::enums::ProductStatus::Verified, Cmp::Equal)
-                .search_info()?
-                .into_iter()
-                .map(|x| {
-                    let image = find_first_image(x.get_description());
-                    let category = Categories::find_by_id(c, x.get_category_id())
-                        .and_then(Category::into_leaf)?;
-                    let tags = TagMappingFinder::new(c, None)
-                        .product(&x.to_id())
-                        .search_tag()?;
-                    Ok((x, image, category, tags))
+#[allow(clippy::type_complexity)]
+fn faceted_search(
+    c: &SqliteConnection,
+    category: Option<&Category>,
+    subcategories: Option<Vec<Category>>,
+    filter: &SearchFilter,
+) -> Result<(Option<Vec<(Category, usize)>>, Vec<ProductCard>, FacetsView), SailsDbError> {
+    let seller = filter
+        .seller
+        .as_deref()
+        // An empty selection on the form means any seller
+        .filter(|s| !s.is_empty())
+        .map(|s| UserFinder::new(c, None).id(s).first())
+        .transpose()?;
+
+    let (prods, facets) = ProductFinder::facets(|| {
+        // We only display allowed prods
+        let mut finder = ProductFinder::new(c, None)
+            .status(sails_db::enums::ProductStatus::Verified, Cmp::Equal);
+        if let Some(category) = category {
+            finder = finder.category(category)?;
+        }
+        for tag in &filter.tags {
+            finder = finder.tag(tag);
+        }
+        if let Some(currency) = &filter.currency {
+            finder = finder.currency(currency.clone());
+            if let Some(min) = filter.min_price {
+                finder = finder.price(min, Cmp::GreaterEqual);
+            }
+            if let Some(max) = filter.max_price {
+                finder = finder.price(max, Cmp::LessEqual);
+            }
+        }
+        if filter.in_stock {
+            finder = finder.in_stock();
+        }
+        if let Some(seller) = &seller {
+            finder = finder.seller(seller);
+        }
+        Ok(finder)
+    })?;
+
+    let mut products = prods
+        .into_iter()
+        .map(|x| {
+            let image = find_first_image(x.get_description());
+            let category =
+                Categories::find_by_id(c, x.get_category_id()).and_then(Category::into_leaf)?;
+            let tags = TagMappingFinder::new(c, None)
+                .product(&x.to_id())
+                .search_tag()?;
+            Ok((x, image, category, tags))
+        })
+        // Reverse the prod order
+        .rev()
+        .collect::<Result<Vec<ProductCard>, SailsDbError>>()?;
+
+    // Sort the products to make ones containing image appear on top.
+    products.sort_by(cmp_product);
+
+    let subcategories = subcategories
+        .map(|ctgs| {
+            ctgs.into_iter()
+                .map(|ctg| {
+                    let count = facets.category(c, &ctg)?;
+                    Ok((ctg, count))
                 })
-                // Reverse the prod order
-                .rev()
-                .collect::<Result<Vec<ProductCard>, SailsDbError>>()?;
+                .collect::<Result<Vec<(Category, usize)>, SailsDbError>>()
+        })
+        .transpose()?;
+
+    // Keep the selected tags on the sidebar even if they match nothing so that they can be unchecked.
+    let tags = Tags::list_all(c)?
+        .into_iter()
+        .map(|t| {
+            let count = facets.tag(t.get_id());
+            let selected = filter.has_tag(t.get_id());
+            (t, count, selected)
+        })
+        .filter(|(_, count, selected)| *count > 0 || *selected)
+        .collect();
 
-            product_info.sort_by(cmp_product);
+    let mut currencies = facets
+        .get_currencies()
+        .iter()
+        .map(|(k, v)| (k.clone(), *v, filter.currency.as_ref() == Some(k)))
+        .collect::<Vec<(Currency, usize, bool)>>();
+    currencies.sort_by(|a, b| b.1.cmp(&a.1));
 
-            Ok(product_info)
+    let mut sellers = facets
+        .get_sellers()
+        .iter()
+        .map(|(k, v)| {
+            let info = UserFinder::new(c, None).id(k).first_info()?;
+            let selected = filter.seller.as_deref() == Some(k.as_str());
+            Ok((k.clone(), info.get_name().to_string(), *v, selected))
+        })
+        .collect::<Result<Vec<(String, String, usize, bool)>, SailsDbError>>()?;
+    sellers.sort_by(|a, b| b.2.cmp(&a.2));
+
+    Ok((
+        subcategories,
+        products,
+        FacetsView {
+            total: facets.get_total(),
+            tags,
+            currencies,
+            sellers,
+        },
+    ))
+}
+
+// Browse all categories
+#[get("/categories?<filter..>", rank = 2)]
+pub async fn categories_all(
+    i18n: I18n,
+    conn: DbConn,
+    filter: Option<SearchFilter>,
+) -> Result<CategoriesPage, Flash<Redirect>> {
+    let filter = filter.unwrap_or_default();
+    let filter_cloned = filter.clone();
+    let (categories, products, facets) = conn
+        .run(move |c| {
+            // We are required to list all
+            let top = Categories::list_top(c)?;
+            faceted_search(c, None, Some(top), &filter_cloned)
         })
         .await
         .into_flash(uri!("/"))?;
@@ -50,65 +187,51 @@ pub async fn categories_all(i18n: I18n, conn: DbConn) -> Result<CategoriesPage,
     Ok(CategoriesPage {
         i18n,
         current_ctg: None,
-        // We are required to list all
-        categories: Some(
-            conn.run(move |c| Categories::list_top(c))
-                .await
-                .into_flash(uri!("/"))?,
-        ),
+        categories,
         products,
         parent_ctgs: Vec::new(),
+        filter,
+        facets,
     })
 }
 
 // Category browsing
-#[get("/categories?<category>", rank = 1)]
+#[get("/categories?<category>&<filter..>", rank = 1)]
 pub async fn categories(
     i18n: I18n,
     conn: DbConn,
     category: String,
+    filter: Option<SearchFilter>,
 ) -> Result<CategoriesPage, Flash<Redirect>> {
-    // We didn't use map for that we want to throw out errors.
-    let (category, parent_ctgs, products) = conn
+    let filter = filter.unwrap_or_default();
+    let filter_cloned = filter.clone();
+    #[allow(clippy::type_complexity)]
+    let (category, parent_ctgs, categories, products, facets) = conn
         .run(
-            move |c| -> Result<(Category, Vec<Category>, Vec<ProductCard>), SailsDbError> {
+            move |c| -> Result<
+                (
+                    Category,
+                    Vec<Category>,
+                    Option<Vec<(Category, usize)>>,
+                    Vec<ProductCard>,
+                    FacetsView,
+                ),
+                SailsDbError,
+            > {
                 let category = Categories::find_by_id(c, &category)?;
 
-                let mut parent_categories = Vec::new();
-                // The parent ID of the current category
-                let mut current_parent = category.parent_id();
-                while let Some(parent_ctg) = current_parent
-                    .map(|x| Categories::find_by_id(c, x))
-                    .transpose()?
-                {
-                    parent_categories.insert(0, parent_ctg);
-                    // Change the "current parent" to the parent of the "current parent"
-                    current_parent = parent_categories[0].parent_id();
-                }
-
-                // We only display allowed prods
-                let mut product_info = ProductFinder::new(c, None)
-                    .category(&category)?
-                    .status(sails_db::enums::ProductStatus::Verified, Cmp::Equal)
-                    .search_info()?
-                    .into_iter()
-                    .map(|x| {
-                        let image = find_first_image(x.get_description());
-                        let category = Categories::find_by_id(c, x.get_category_id())
-                            .and_then(Category::into_leaf)?;
-                        let tags = TagMappingFinder::new(c, None)
-                            .product(&x.to_id())
-                            .search_tag()?;
-                        Ok((x, image, category, tags))
-                    })
-                    // Reverse the prod order
-                    .rev()
-                    .collect::<Result<Vec<ProductCard>, SailsDbError>>()?;
-
-                // Sort the products to make ones containing image appear on top.
-                product_info.sort_by(cmp_product);
-
-                Ok((category, parent_categories, product_info))
+                let parent_categories = Categories::ancestors(c, &category)?;
+
+                let subcategories = if category.is_leaf() {
+                    None
+                } else {
+                    Some(category.subcategory(c)?)
+                };
+
+                let (categories, products, facets) =
+                    faceted_search(c, Some(&category), subcategories, &filter_cloned)?;
+
+                Ok((category, parent_categories, categories, products, facets))
             },
         )
         .await
@@ -116,17 +239,11 @@ pub async fn categories(
 
     Ok(CategoriesPage {
         i18n,
-        current_ctg: Some(category.clone()),
-        categories: if category.is_leaf() {
-            None
-        } else {
-            Some(
-                conn.run(move |c| category.subcategory(c))
-                    .await
-                    .into_flash(uri!("/"))?,
-            )
-        },
+        current_ctg: Some(category),
+        categories,
         products,
         parent_ctgs,
+        filter,
+        facets,
     })
 }
@@ -81,17 +81,7 @@ pub async fn home_page(i18n: I18n, conn: DbConn) -> Result<StoreHomePage, Flash<
 
                             products.sort_by(cmp_product);
 
-                            let mut parent_categories = Vec::new();
-                            // The parent ID of the current category
-                            let mut current_parent = x.parent_id();
-                            while let Some(parent_ctg) = current_parent
-                                .map(|t| Categories::find_by_id(c, t))
-                                .transpose()?
-                            {
-                                parent_categories.insert(0, parent_ctg);
-                                // Change the "current parent" to the parent of the "current parent"
-                                current_parent = parent_categories[0].parent_id();
-                            }
+                            let parent_categories = Categories::ancestors(c, &x)?;
 
                             Ok((parent_categories, x, products))
                         },
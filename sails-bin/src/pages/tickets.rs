@@ -0,0 +1,105 @@
+use crate::{
+    infras::{guards::*, i18n::I18n},
+    DbConn, IntoFlash, Msg,
+};
+use askama::Template;
+use rocket::{
+    request::FlashMessage,
+    response::{Flash, Redirect},
+};
+use sails_db::{error::SailsDbError, tickets::*, transactions::*, Order};
+
+#[derive(Template)]
+#[template(path = "tickets/tickets.html")]
+pub struct TicketsPage {
+    i18n: I18n,
+    tickets: Vec<Ticket>,
+    // Orders the user could open a ticket about, as the buyer or the seller
+    orders: Vec<TransactionInfo>,
+    // The order selected in the form, if the user comes from an order page
+    order_id: Option<String>,
+    inner: Msg,
+}
+
+impl TicketsPage {
+    fn selected(&self, order: &TransactionInfo) -> bool {
+        self.order_id.as_deref() == Some(order.get_id())
+    }
+}
+
+#[get("/?<order_id>")]
+pub async fn tickets(
+    i18n: I18n,
+    user: UserIdGuard<Cookie>,
+    order_id: Option<String>,
+    conn: DbConn,
+    flash: Option<FlashMessage<'_>>,
+) -> Result<TicketsPage, Flash<Redirect>> {
+    let (tickets, orders) = conn
+        .run(move |c| -> Result<_, SailsDbError> {
+            let tickets = TicketFinder::new(c, None)
+                .user(&user.id)
+                .order_by_time(Order::Desc)
+                .search()?;
+            let mut orders = TransactionFinder::new(c, None)
+                .buyer(&user.id)
+                .order_by_time(Order::Desc)
+                .search_info()?;
+            orders.extend(
+                TransactionFinder::new(c, None)
+                    .seller(&user.id)
+                    .order_by_time(Order::Desc)
+                    .search_info()?,
+            );
+            Ok((tickets, orders))
+        })
+        .await
+        .into_flash(uri!("/"))?;
+
+    Ok(TicketsPage {
+        i18n,
+        tickets,
+        orders,
+        order_id,
+        inner: Msg::from_flash(flash),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "tickets/ticket.html")]
+pub struct TicketPage {
+    i18n: I18n,
+    ticket: Ticket,
+    // Internal notes are not included
+    replies: Vec<TicketReply>,
+    inner: Msg,
+}
+
+#[get("/ticket?<ticket_id>")]
+pub async fn ticket(
+    i18n: I18n,
+    user: UserInfoGuard<Cookie>,
+    ticket_id: String,
+    conn: DbConn,
+    flash: Option<FlashMessage<'_>>,
+) -> Result<TicketPage, Flash<Redirect>> {
+    let (ticket, replies) = conn
+        .run(move |c| -> Result<_, SailsDbError> {
+            let ticket = Tickets::find(c, &ticket_id)?;
+            // Tickets of others are reported as missing
+            if !ticket.readable_by(&user.info) {
+                return Err(SailsDbError::TicketNotFound);
+            }
+            let replies = Tickets::replies(c, &ticket, false)?;
+            Ok((ticket, replies))
+        })
+        .await
+        .into_flash(uri!("/tickets", tickets(_)))?;
+
+    Ok(TicketPage {
+        i18n,
+        ticket,
+        replies,
+        inner: Msg::from_flash(flash),
+    })
+}
@@ -1,14 +1,56 @@
 use crate::{
     infras::{guards::*, i18n::I18n},
     services::users::*,
-    DbConn, IntoFlash,
+    DbConn, IntoFlash, Msg,
 };
 use askama::Template;
-use rocket::response::{Flash, Redirect};
-use sails_db::{error::SailsDbError, products::*, transactions::*, users::*};
+use rocket::{
+    http::CookieJar,
+    request::FlashMessage,
+    response::{Flash, Redirect},
+};
+use sails_db::{
+    addresses::*,
+    credits::*,
+    enums::{Currency, ReferralRecipient, UserStatus},
+    error::SailsDbError,
+    products::*,
+    referrals::*,
+    sessions::*,
+    tokens::*,
+    transactions::*,
+    users::*,
+};
 
 type OrderEntry = (ProductInfo, TransactionInfo);
 
+#[derive(Template)]
+#[template(path = "user/signin.html")]
+pub struct SignInPage {
+    pub i18n: I18n,
+    // Names and display names of the providers
+    pub providers: Vec<(String, String)>,
+    pub referral: Option<String>,
+    pub inner: Msg,
+}
+
+impl SignInPage {
+    pub fn link(provider: &str, referral: Option<&str>) -> String {
+        match referral {
+            Some(code) => format!(
+                "/user/signin?provider={}&referral={}",
+                urlencoding::encode(provider),
+                urlencoding::encode(code)
+            ),
+            None => format!("/user/signin?provider={}", urlencoding::encode(provider)),
+        }
+    }
+
+    pub fn provider_link(&self, provider: &str) -> String {
+        Self::link(provider, self.referral.as_deref())
+    }
+}
+
 #[derive(Template)]
 #[template(path = "user/signin_confirmation.html")]
 pub struct SignInConfirmation {
@@ -47,6 +89,8 @@ pub struct PortalPage {
     prods_owned: Vec<ProductInfo>,
     orders_placed: Vec<OrderEntry>,
     orders_received: Vec<OrderEntry>,
+    // Names of the sellers of the orders placed
+    names: UserNames,
 }
 
 #[get("/?<user_id>", rank = 1)]
@@ -83,7 +127,7 @@ pub async fn portal(
 ) -> Result<PortalPage, Flash<Redirect>> {
     let uid = user.info.to_id();
     #[allow(clippy::type_complexity)]
-    let (prods_owned, orders_placed, orders_received) = conn
+    let (prods_owned, orders_placed, orders_received, names) = conn
         .run(move |c| -> Result<_, SailsDbError> {
             let prods_owned = ProductFinder::new(c, None).seller(&uid).search_info()?;
 
@@ -110,7 +154,8 @@ pub async fn portal(
                     Ok((product, x))
                 })
                 .collect::<Result<Vec<OrderEntry>, SailsDbError>>()?;
-            Ok((prods_owned, orders_placed, orders_received))
+            let names = UserFinder::names(c, orders_placed.iter().map(|x| x.1.get_seller()))?;
+            Ok((prods_owned, orders_placed, orders_received, names))
         })
         .await
         .into_flash(uri!("/"))?;
@@ -121,10 +166,190 @@ pub async fn portal(
         orders_placed,
         orders_received,
         prods_owned,
+        names,
+    })
+}
+
+#[derive(Template)]
+#[template(path = "user/credits.html")]
+pub struct CreditsPage {
+    i18n: I18n,
+    balances: Vec<(Currency, i64)>,
+    entries: Vec<CreditEntry>,
+    inner: Msg,
+}
+
+#[get("/credits")]
+pub async fn credits(
+    i18n: I18n,
+    user: UserIdGuard<Cookie>,
+    conn: DbConn,
+    flash: Option<FlashMessage<'_>>,
+) -> Result<CreditsPage, Flash<Redirect>> {
+    let (balances, entries) = conn
+        .run(move |c| -> Result<_, SailsDbError> {
+            let mut balances = Credits::balances(c, &user.id)?
+                .into_iter()
+                .collect::<Vec<(Currency, i64)>>();
+            balances.sort_by_key(|(currency, _)| format!("{:?}", currency));
+            let entries = CreditFinder::new(c, None).user(&user.id).search()?;
+            Ok((balances, entries))
+        })
+        .await
+        .into_flash(uri!("/"))?;
+
+    Ok(CreditsPage {
+        i18n,
+        balances,
+        entries,
+        inner: Msg::from_flash(flash),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "user/tokens.html")]
+pub struct TokensPage {
+    i18n: I18n,
+    tokens: Vec<ApiToken>,
+    // Permissions of the user that can be granted to a token
+    flags: Vec<(&'static str, UserStatus)>,
+    inner: Msg,
+}
+
+#[get("/tokens")]
+pub async fn tokens(
+    i18n: I18n,
+    user: UserInfoGuard<Cookie>,
+    conn: DbConn,
+    flash: Option<FlashMessage<'_>>,
+) -> Result<TokensPage, Flash<Redirect>> {
+    let status = user.info.get_user_status();
+    let id = user.info.to_id();
+    let tokens = conn
+        .run(move |c| ApiTokens::list_by_user(c, &id))
+        .await
+        .into_flash(uri!("/"))?;
+
+    Ok(TokensPage {
+        i18n,
+        tokens,
+        flags: UserStatus::flags()
+            .into_iter()
+            .filter(|(_, f)| status.contains(*f))
+            .collect(),
+        inner: Msg::from_flash(flash),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "user/addresses.html")]
+pub struct AddressesPage {
+    i18n: I18n,
+    addresses: Vec<Address>,
+    inner: Msg,
+}
+
+#[get("/addresses")]
+pub async fn addresses(
+    i18n: I18n,
+    user: UserIdGuard<Cookie>,
+    conn: DbConn,
+    flash: Option<FlashMessage<'_>>,
+) -> Result<AddressesPage, Flash<Redirect>> {
+    let addresses = conn
+        .run(move |c| Addresses::list_by_user(c, &user.id))
+        .await
+        .into_flash(uri!("/"))?;
+
+    Ok(AddressesPage {
+        i18n,
+        addresses,
+        inner: Msg::from_flash(flash),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "user/sessions.html")]
+pub struct SessionsPage {
+    i18n: I18n,
+    sessions: Vec<Session>,
+    // ID of the session making the request
+    current: Option<String>,
+}
+
+impl SessionsPage {
+    fn is_current(&self, session: &Session) -> bool {
+        self.current.as_deref() == Some(session.get_id())
+    }
+}
+
+#[get("/sessions")]
+pub async fn sessions(
+    i18n: I18n,
+    user: UserIdGuard<Cookie>,
+    jar: &CookieJar<'_>,
+    conn: DbConn,
+) -> Result<SessionsPage, Flash<Redirect>> {
+    let sessions = conn
+        .run(move |c| Sessions::list_by_user(c, &user.id))
+        .await
+        .into_flash(uri!("/"))?;
+
+    Ok(SessionsPage {
+        i18n,
+        sessions,
+        current: jar
+            .get_private(SESSION_COOKIE_NAME)
+            .map(|c| c.value().to_string()),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "user/referrals.html")]
+pub struct ReferralsPage {
+    i18n: I18n,
+    code: ReferralCode,
+    referrals: Vec<Referral>,
+    // Coupons granted to the user as referral rewards
+    coupons: Vec<String>,
+}
+
+#[get("/referrals")]
+pub async fn referrals(
+    i18n: I18n,
+    user: UserIdGuard<Cookie>,
+    conn: DbConn,
+) -> Result<ReferralsPage, Flash<Redirect>> {
+    let (code, referrals, coupons) = conn
+        .run(move |c| -> Result<_, SailsDbError> {
+            let code = ReferralCodes::of(c, &user.id)?;
+            let referrals = ReferralFinder::new(c, None).referrer(&user.id).search()?;
+            let mut coupons = referrals
+                .iter()
+                .flat_map(|r| r.get_coupons(&ReferralRecipient::Referrer))
+                .map(ToString::to_string)
+                .collect::<Vec<String>>();
+            if let Ok(r) = ReferralFinder::new(c, None).referee(&user.id).first() {
+                coupons.extend(
+                    r.get_coupons(&ReferralRecipient::Referee)
+                        .into_iter()
+                        .map(ToString::to_string),
+                );
+            }
+            Ok((code, referrals, coupons))
+        })
+        .await
+        .into_flash(uri!("/"))?;
+
+    Ok(ReferralsPage {
+        i18n,
+        code,
+        referrals,
+        coupons,
     })
 }
 
 #[get("/", rank = 3)]
 pub async fn portal_unsigned() -> Redirect {
-    Redirect::to(uri!("/user", signin))
+    Redirect::to(uri!("/user", signin(_)))
 }
@@ -2,19 +2,34 @@ use crate::{
     infras::{
         alipay::{AlipayAppPrivKey, AlipayClient, RefundTrade, RefundTradeResp},
         guards::*,
+        i18n::I18n,
+        msg_hub::MsgHub,
     },
     pages::admin::*,
-    DbConn, IntoFlash,
+    Attachment, DbConn, IntoFlash,
 };
 use rocket::{
     form::Form,
+    http::ContentType,
     response::{Flash, Redirect},
     State,
 };
 use sails_db::{
     coupons::*,
-    enums::{ProductStatus, TransactionStatus},
+    credits::*,
+    enums::{
+        CreditKind, Currency, ProductStatus, ReferralRecipient, ReferralRewardKind, TicketStatus,
+        TransactionStatus,
+    },
+    error::SailsDbError,
+    export::UserExport,
+    messages::Messages,
+    products::ProductFinder,
+    referrals::*,
+    sessions::Sessions,
     tags::*,
+    tickets::*,
+    users::UserId,
 };
 
 #[get("/remove_tag?<tag_id>&<prod_id>")]
@@ -99,26 +114,57 @@ pub async fn refund_order(
     conn: DbConn,
     priv_key: &State<AlipayAppPrivKey>,
     client: &State<AlipayClient>,
+    hub: &State<MsgHub>,
 ) -> Result<Redirect, Flash<Redirect>> {
     let info = order_id.to_info(&conn).await.into_flash(uri!("/"))?;
-    client
-        .request(
-            priv_key,
-            RefundTrade::new(
-                info.order_info.get_id(),
-                "平台发起退货退款",
-                info.order_info.get_total(),
-            ),
-        )
-        .into_flash(uri!("/"))?
-        .send::<RefundTradeResp>(client.client())
+    let previous = info.order_info.get_transaction_status().clone();
+    // Orders fully paid with store credit have no trade on Alipay
+    if info.order_info.get_payable() > 0u32.into() {
+        client
+            .request(
+                priv_key,
+                RefundTrade::new(
+                    info.order_info.get_id(),
+                    "平台发起退货退款",
+                    info.order_info.get_payable(),
+                ),
+            )
+            .into_flash(uri!("/"))?
+            .send::<RefundTradeResp>(client.client())
+            .await
+            .into_flash(uri!("/"))?
+            .into_flash(uri!("/"))?;
+    }
+
+    let posted = conn
+        .run(move |c| -> Result<_, SailsDbError> {
+            let order = info.order_info.refund(c)?;
+            Messages::post_status_change(c, &previous, &order)
+        })
         .await
-        .into_flash(uri!("/"))?
-        .into_flash(uri!("/"))?;
+        .into_flash(uri!("/admin", admin_orders))?;
+    hub.publish_status_change(posted);
+    Ok(Redirect::to(uri!("/admin", admin_orders)))
+}
 
-    conn.run(move |c| info.order_info.refund(c))
+// Refund the whole order into the buyer's store credit instead of the original payment method
+#[get("/refund_order_to_credit?<order_id>")]
+pub async fn refund_order_to_credit(
+    _auth: Auth<OrderRefundable>,
+    order_id: OrderGuard,
+    conn: DbConn,
+    hub: &State<MsgHub>,
+) -> Result<Redirect, Flash<Redirect>> {
+    let info = order_id.to_info(&conn).await.into_flash(uri!("/"))?;
+    let previous = info.order_info.get_transaction_status().clone();
+    let posted = conn
+        .run(move |c| -> Result<_, SailsDbError> {
+            let order = info.order_info.refund_to_credit(c)?;
+            Messages::post_status_change(c, &previous, &order)
+        })
         .await
         .into_flash(uri!("/admin", admin_orders))?;
+    hub.publish_status_change(posted);
     Ok(Redirect::to(uri!("/admin", admin_orders)))
 }
 
@@ -127,45 +173,139 @@ pub async fn finish_order(
     _auth: Auth<OrderFinishable>,
     order_id: OrderGuard,
     conn: DbConn,
+    hub: &State<MsgHub>,
 ) -> Result<Redirect, Flash<Redirect>> {
     let info = order_id.to_info(&conn).await.into_flash(uri!("/"))?;
-    conn.run(|c| {
-        info.order_info
-            .set_transaction_status(TransactionStatus::Finished)
-            .update(c)
-    })
-    .await
-    .into_flash(uri!("/"))?;
+    let previous = info.order_info.get_transaction_status().clone();
+    let posted = conn
+        .run(move |c| -> Result<_, SailsDbError> {
+            let info = info
+                .order_info
+                .set_transaction_status(TransactionStatus::Finished)
+                .update(c)?;
+            // The first finished order of a referred user triggers the referral rewards
+            Referrals::reward(c, &info)?;
+            Messages::post_status_change(c, &previous, &info)
+        })
+        .await
+        .into_flash(uri!("/"))?;
+    hub.publish_status_change(posted);
     Ok(Redirect::to(uri!("/admin", admin_orders)))
 }
 
+// Scripts are compiled before saving. Errors are shown on the form along with what has been submitted.
 #[post("/cow_coupon?<coupon_id>", data = "<info>", rank = 1)]
 pub async fn update_coupon(
+    i18n: I18n,
     coupon_id: CouponGuard,
     _role: Role<Admin>,
-    info: Form<Coupon>,
+    info: Form<CouponForm>,
     conn: DbConn,
-) -> Result<Redirect, Flash<Redirect>> {
-    let coupon = coupon_id.to_coupon(&conn).await.into_flash(uri!("/"))?;
-    let id = coupon.get_id().to_string();
-    conn.run(move |c| info.into_inner().update(c))
-        .await
-        .into_flash(uri!("/"))?;
-    Ok(Redirect::to(uri!("/admin", update_coupon_page(id))))
+) -> Result<Redirect, AdminUpdateCouponPage> {
+    let form = info.into_inner();
+    let form_cloned = form.clone();
+    let result = match coupon_id.to_coupon(&conn).await {
+        Ok(coupon) => {
+            let id = coupon.get_id().to_string();
+            conn.run(move |c| -> Result<Coupon, SailsDbError> {
+                let updated = form_cloned.to_coupon()?;
+                updated.compile()?;
+                updated.update(c)
+            })
+            .await
+            .map(|_| id.clone())
+            .map_err(|e| (e, id))
+        }
+        Err(e) => Err((e, form.id.clone())),
+    };
+
+    match result {
+        Ok(id) => Ok(Redirect::to(uri!("/admin", update_coupon_page(id)))),
+        Err((e, id)) => Err(AdminUpdateCouponPage {
+            i18n,
+            id,
+            form,
+            error: Some(e.to_string()),
+            dry_run_form: DryRunForm::default(),
+            dry_run: None,
+        }),
+    }
 }
 
 #[post("/cow_coupon", data = "<info>", rank = 2)]
 pub async fn create_coupon(
+    i18n: I18n,
     _role: Role<Admin>,
-    info: Form<Coupon>,
+    info: Form<CouponForm>,
     conn: DbConn,
-) -> Result<Redirect, Flash<Redirect>> {
-    conn.run(move |c| info.into_inner().create(c))
-        .await
-        .into_flash(uri!("/"))?;
+) -> Result<Redirect, AdminCreateCouponPage> {
+    let form = info.into_inner();
+    let form_cloned = form.clone();
+    conn.run(move |c| -> Result<usize, SailsDbError> {
+        let coupon = form_cloned.to_coupon()?;
+        coupon.compile()?;
+        coupon.create(c)
+    })
+    .await
+    .map_err(|e| AdminCreateCouponPage {
+        i18n,
+        form,
+        error: Some(e.to_string()),
+    })?;
     Ok(Redirect::to(uri!("/admin", coupons_page)))
 }
 
+// Evaluate the saved coupon against a buyer and a product without placing any order
+#[post("/dry_run_coupon?<coupon_id>", data = "<info>")]
+pub async fn dry_run_coupon(
+    i18n: I18n,
+    coupon_id: CouponGuard,
+    _role: Role<Admin>,
+    info: Form<DryRunForm>,
+    conn: DbConn,
+) -> Result<AdminUpdateCouponPage, Flash<Redirect>> {
+    let coupon = coupon_id
+        .to_coupon(&conn)
+        .await
+        .into_flash(uri!("/admin", coupons_page))?;
+    let dry_run_form = info.into_inner();
+    let dry_run_form_cloned = dry_run_form.clone();
+    let coupon_cloned = coupon.clone();
+    let dry_run = conn
+        .run(move |c| -> Result<DryRun, SailsDbError> {
+            let form = dry_run_form_cloned;
+            let buyer = UserId::find(c, &form.buyer)?;
+            let product = ProductFinder::new(c, None).id(&form.product).first_info()?;
+            let ctx = CouponContext::build(
+                c,
+                &coupon_cloned,
+                &product,
+                &buyer,
+                form.quantity,
+                &form.payment,
+            )?;
+            Ok(coupon_cloned.dry_run(ctx))
+        })
+        .await
+        .map(DryRunView::from)
+        // Failing to build the context is reported in the same way as a failed evaluation
+        .unwrap_or_else(|e| DryRunView {
+            restriction: None,
+            discount: Err(e.to_string()),
+            operations: 0,
+            max_operations: MAX_SCRIPT_OPERATIONS,
+        });
+
+    Ok(AdminUpdateCouponPage {
+        i18n,
+        id: coupon.get_id().to_string(),
+        form: CouponForm::from(&coupon),
+        error: None,
+        dry_run_form,
+        dry_run: Some(dry_run),
+    })
+}
+
 #[get("/delete_coupon?<coupon_id>")]
 pub async fn delete_coupon(
     coupon_id: CouponGuard,
@@ -178,3 +318,204 @@ pub async fn delete_coupon(
         .into_flash(uri!("/"))?;
     Ok(Redirect::to(uri!("/admin", coupons_page)))
 }
+
+#[derive(Debug, FromForm, Clone)]
+pub struct GiftCardForm {
+    pub currency: Currency,
+    pub amount: u32,
+    pub note: Option<String>,
+}
+
+#[post("/create_giftcard", data = "<info>")]
+pub async fn create_giftcard(
+    _role: Role<Admin>,
+    info: Form<GiftCardForm>,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    let info = info.into_inner();
+    conn.run(move |c| {
+        GiftCard::new(
+            c,
+            info.currency,
+            info.amount,
+            info.note.as_deref().filter(|n| !n.is_empty()),
+        )
+    })
+    .await
+    .into_flash(uri!("/admin", credits_page))?;
+    Ok(Redirect::to(uri!("/admin", credits_page)))
+}
+
+#[derive(Debug, FromForm, Clone)]
+pub struct GrantCreditForm {
+    pub user: String,
+    pub currency: Currency,
+    pub amount: u32,
+    pub kind: CreditKind,
+    pub note: Option<String>,
+}
+
+#[post("/grant_credit", data = "<info>")]
+pub async fn grant_credit(
+    _role: Role<Admin>,
+    info: Form<GrantCreditForm>,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    let info = info.into_inner();
+    conn.run(move |c| -> Result<_, SailsDbError> {
+        let user = UserId::find(c, &info.user)?;
+        Credits::grant(
+            c,
+            &user,
+            info.currency,
+            info.amount,
+            info.kind,
+            None,
+            info.note.as_deref().filter(|n| !n.is_empty()),
+        )
+    })
+    .await
+    .into_flash(uri!("/admin", credits_page))?;
+    Ok(Redirect::to(uri!("/admin", credits_page)))
+}
+
+#[derive(Debug, FromForm, Clone)]
+pub struct ReferralRewardForm {
+    pub recipient: ReferralRecipient,
+    pub kind: ReferralRewardKind,
+    pub currency: Currency,
+    pub amount: u32,
+}
+
+#[post("/create_referral_reward", data = "<info>")]
+pub async fn create_referral_reward(
+    _role: Role<Admin>,
+    info: Form<ReferralRewardForm>,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    let info = info.into_inner();
+    conn.run(move |c| {
+        ReferralReward::new(c, info.recipient, info.kind, info.currency, info.amount)
+    })
+    .await
+    .into_flash(uri!("/admin", referrals_page))?;
+    Ok(Redirect::to(uri!("/admin", referrals_page)))
+}
+
+#[get("/delete_referral_reward?<reward_id>")]
+pub async fn delete_referral_reward(
+    _role: Role<Admin>,
+    reward_id: String,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    conn.run(move |c| ReferralRewards::find(c, &reward_id).and_then(|r| r.delete(c)))
+        .await
+        .into_flash(uri!("/admin", referrals_page))?;
+    Ok(Redirect::to(uri!("/admin", referrals_page)))
+}
+
+// Same as the export available to users, for requests received by other means
+#[get("/export_user?<user_id>")]
+pub async fn export_user(
+    _role: Role<Admin>,
+    user_id: UserGuard,
+    conn: DbConn,
+) -> Result<Attachment, Flash<Redirect>> {
+    let user = user_id.to_id_param(&conn).await.into_flash(uri!("/"))?;
+    let filename = format!("flibrary-data-{}.json", user.id.get_id());
+    let export = conn
+        .run(move |c| UserExport::of(c, &user.id))
+        .await
+        .into_flash(uri!("/"))?;
+    Ok(Attachment {
+        filename,
+        content_type: ContentType::JSON,
+        body: serde_json::to_string_pretty(&export).into_flash(uri!("/"))?,
+    })
+}
+
+#[get("/revoke_session?<session_id>")]
+pub async fn revoke_session(
+    _role: Role<Admin>,
+    session_id: String,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    conn.run(move |c| Sessions::find(c, &session_id)?.revoke(c))
+        .await
+        .into_flash(uri!("/admin", sessions_page))?;
+    Ok(Redirect::to(uri!("/admin", sessions_page)))
+}
+
+// Log the user out of all devices
+#[get("/revoke_user_sessions?<user_id>")]
+pub async fn revoke_user_sessions(
+    _role: Role<Admin>,
+    user_id: UserGuard,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    let user = user_id.to_id_param(&conn).await.into_flash(uri!("/"))?;
+    conn.run(move |c| Sessions::delete_by_user(c, &user.id))
+        .await
+        .into_flash(uri!("/admin", sessions_page))?;
+    Ok(Redirect::to(uri!("/admin", sessions_page)))
+}
+
+// Form used by the customer service for replying to tickets
+#[derive(Debug, FromForm, Clone)]
+pub struct StaffReplyForm {
+    pub body: String,
+    // Internal notes are only visible to the customer service
+    #[field(default = false)]
+    pub internal: bool,
+}
+
+#[post("/reply_ticket?<ticket_id>", data = "<info>")]
+pub async fn reply_ticket(
+    _role: Role<CustomerService>,
+    user: UserInfoGuard<Cookie>,
+    ticket_id: String,
+    info: Form<StaffReplyForm>,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    let back = uri!("/admin", ticket_page(ticket_id.as_str()));
+    conn.run(move |c| {
+        let ticket = Tickets::find(c, &ticket_id)?;
+        Tickets::reply(c, &ticket, &user.info, &info.body, info.internal)
+    })
+    .await
+    .into_flash(back.clone())?;
+    Ok(Redirect::to(back))
+}
+
+// Assign the ticket to the user given, or put it back to the queue if none is given
+#[get("/assign_ticket?<ticket_id>&<user_id>")]
+pub async fn assign_ticket(
+    _role: Role<CustomerService>,
+    ticket_id: String,
+    user_id: Option<UserGuard>,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    let back = uri!("/admin", ticket_page(ticket_id.as_str()));
+    let assignee = match user_id {
+        Some(u) => Some(u.to_id_param(&conn).await.into_flash(back.clone())?.id),
+        None => None,
+    };
+    conn.run(move |c| Tickets::find(c, &ticket_id)?.assign(c, assignee.as_ref()))
+        .await
+        .into_flash(back.clone())?;
+    Ok(Redirect::to(back))
+}
+
+#[get("/ticket_status?<ticket_id>&<status>")]
+pub async fn ticket_status(
+    _role: Role<CustomerService>,
+    ticket_id: String,
+    status: TicketStatus,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    let back = uri!("/admin", ticket_page(ticket_id.as_str()));
+    conn.run(move |c| Tickets::find(c, &ticket_id)?.set_status(c, status))
+        .await
+        .into_flash(back.clone())?;
+    Ok(Redirect::to(back))
+}
@@ -2,14 +2,12 @@ use crate::{
     infras::{guards::*, images::*},
     IntoFlash,
 };
-use reqwest::header::ACCEPT;
 use rocket::{
     form::Form,
     http::Status,
     response::{Flash, Redirect},
     State,
 };
-use serde_json::json;
 
 // Ok(Ok(Image)) we return image directly.
 // Ok(Err(Redirect)) image unable to be found, return a redirection to a placeholder image.
@@ -69,57 +67,9 @@ pub async fn upload(
     hosting: &State<ImageHosting>,
     img: Form<Image>,
 ) -> Result<String, Status> {
-    use sha2::{Digest, Sha256};
-
     // Content types are restricted to jpeg and png, should be fine to unwrap.
     let ext = img.ctt_type.extension().unwrap();
 
-    // Calculate the hash of the image
-    let mut hasher = Sha256::new();
-    hasher.update(&img.bytes);
-    let hash = hasher.finalize();
-
-    let client = reqwest::Client::builder()
-        .user_agent("curl")
-        .build()
-        .unwrap();
-
-    // If the content doesn't exist yet, we have to create it
-    if !client
-        .get(format!(
-            "https://api.github.com/repos/flibrary/images/contents/{:x}/",
-            hash,
-        ))
-        .header(ACCEPT, "application/vnd.github.v3+json")
-        // If we don't auth, probably we will get limited further
-        .bearer_auth(&hosting.gh_token)
-        .send()
-        .await
-        .map_err(|_| Status::new(503))?
-        .status()
-        .is_success()
-    {
-        let params = json!({
-            "content": base64::encode(&img.bytes),
-            "message": format!("add: {:x}", hash),
-        });
-        // If not successful, we have to return wrong types
-        if !client
-            .put(format!(
-                "https://api.github.com/repos/flibrary/images/contents/{:x}/orig.{}",
-                hash, ext
-            ))
-            .header(ACCEPT, "application/vnd.github.v3+json")
-            .bearer_auth(&hosting.gh_token)
-            .json(&params)
-            .send()
-            .await
-            .map_err(|_| Status::new(502))?
-            .status()
-            .is_success()
-        {
-            return Err(Status::new(400));
-        }
-    }
-    Ok(uri!("/images", get_default(format!("{:x}", hash), ext.as_str())).to_string())
+    let hash = hosting.store(&img.bytes, &format!("orig.{}", ext)).await?;
+    Ok(uri!("/images", get_default(hash, ext.as_str())).to_string())
 }
@@ -8,4 +8,5 @@ pub mod msgs;
 pub mod orders;
 pub mod prods;
 pub mod root;
+pub mod tickets;
 pub mod users;
@@ -1,9 +1,35 @@
-use crate::{infras::guards::*, pages::msgs::*, DbConn, IntoFlash};
+use crate::{
+    infras::{
+        aead::AeadKey,
+        guards::*,
+        images::*,
+        msg_hub::{MessageView, MsgHub},
+    },
+    pages::msgs::*,
+    DbConn, IntoFlash,
+};
+use chacha20poly1305::Nonce;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
 use rocket::{
     form::Form,
-    response::{Flash, Redirect},
+    http::{ContentType, Status},
+    response::{
+        stream::{Event, EventStream},
+        Flash, Redirect,
+    },
+    serde::json::Json,
+    tokio::{select, sync::broadcast::error::RecvError},
+    Shutdown, State,
+};
+use sails_db::{
+    error::SailsDbError,
+    messages::{Attachments, Message, Messages},
+    users::UserId,
 };
-use sails_db::messages::Messages;
+use serde_json::{json, Value};
+
+// Attachments are encrypted and stored under this name in the image hosting
+const ATTACHMENT_NAME: &str = "attachment";
 
 // Form used for sending messages
 #[derive(FromForm)]
@@ -16,17 +42,237 @@ pub async fn send(
     user: UserIdGuard<Cookie>,
     user_id: UserGuard,
     info: Form<SendMessage>,
+    hub: &State<MsgHub>,
     conn: DbConn,
 ) -> Result<Redirect, Flash<Redirect>> {
     let receiver = user_id.to_id_param(&conn).await.into_flash(uri!("/"))?;
 
     let receiver_id = receiver.id.clone();
-    conn.run(move |c| Messages::send(c, &user.id, &receiver.id, &info.body))
+    let msg = conn
+        .run(move |c| Messages::send(c, &user.id, &receiver.id, &info.body))
         .await
         .into_flash(uri!("/"))?;
+    hub.publish(MessageView::new(msg, Vec::new()));
     Ok(Redirect::to(uri!(
         "/messages",
         chat(receiver_id.get_id()),
         "#draft_section"
     )))
 }
+
+// Only the buyer and the seller could post to the thread of the order
+#[post("/send_order?<order_id>", data = "<info>")]
+pub async fn send_order(
+    user: UserIdGuard<Cookie>,
+    order_id: OrderGuard,
+    info: Form<SendMessage>,
+    hub: &State<MsgHub>,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    let order = order_id
+        .to_info(&conn)
+        .await
+        .into_flash(uri!("/"))?
+        .order_info;
+    let back = uri!(
+        "/orders",
+        crate::pages::orders::order_info_seller(order.get_id()),
+        "#thread"
+    );
+
+    let msg = conn
+        .run(move |c| Messages::send_about(c, &user.id, &order, &info.body))
+        .await
+        .into_flash(back.clone())?;
+    hub.publish(MessageView::new(msg, Vec::new()));
+    Ok(Redirect::to(back))
+}
+
+// Form used for sending a message with an image attached
+#[derive(FromForm)]
+pub struct SendAttachment {
+    body: String,
+    attachment: Image,
+}
+
+#[post("/send_attachment?<user_id>", data = "<info>")]
+pub async fn send_attachment(
+    user: UserIdGuard<Cookie>,
+    user_id: UserGuard,
+    info: Form<SendAttachment>,
+    hosting: &State<ImageHosting>,
+    aead: &State<AeadKey>,
+    hub: &State<MsgHub>,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    let receiver = user_id.to_id_param(&conn).await.into_flash(uri!("/"))?;
+    let receiver_id = receiver.id.clone();
+    let info = info.into_inner();
+
+    // The image hosting is public, so only the ciphertext goes there
+    let mut nonce = [0u8; 12];
+    StdRng::from_entropy().fill_bytes(&mut nonce);
+    let cipher = aead
+        .encrypt(&info.attachment.bytes, &Nonce::clone_from_slice(&nonce))
+        .map_err(|_| "attachment encryption failed")
+        .into_flash(uri!("/messages", chat(receiver_id.get_id())))?;
+    let location = hosting
+        .store(&cipher, ATTACHMENT_NAME)
+        .await
+        .map_err(|s| format!("failed to store the attachment: {}", s))
+        .into_flash(uri!("/messages", chat(receiver_id.get_id())))?;
+
+    let nonce = base64::encode_config(&nonce, base64::URL_SAFE);
+    let content_type = info.attachment.ctt_type.to_string();
+    let (msg, attachment) = conn
+        .run(move |c| {
+            Attachments::send(
+                c,
+                &user.id,
+                &receiver.id,
+                &info.body,
+                &content_type,
+                &location,
+                &nonce,
+            )
+        })
+        .await
+        .into_flash(uri!("/"))?;
+    hub.publish(MessageView::new(msg, vec![attachment.get_id().to_string()]));
+    Ok(Redirect::to(uri!(
+        "/messages",
+        chat(receiver_id.get_id()),
+        "#draft_section"
+    )))
+}
+
+// Only the participants and the customer service could view the attachment
+#[get("/attachment/<id>")]
+pub async fn attachment(
+    id: String,
+    user: UserInfoGuard<Cookie>,
+    aead: &State<AeadKey>,
+    conn: DbConn,
+) -> Result<(ContentType, Vec<u8>), Status> {
+    let (attachment, readable) = conn
+        .run(move |c| -> Result<_, SailsDbError> {
+            let attachment = Attachments::find(c, &id)?;
+            let readable = attachment.readable_by(c, &user.info)?;
+            Ok((attachment, readable))
+        })
+        .await
+        .map_err(|_| Status::NotFound)?;
+    if !readable {
+        return Err(Status::Forbidden);
+    }
+
+    let cipher = ImageHosting::fetch(attachment.get_location(), ATTACHMENT_NAME)
+        .await
+        .map_err(|_| Status::BadGateway)?;
+    let nonce = base64::decode_config(attachment.get_nonce(), base64::URL_SAFE)
+        .ok()
+        .filter(|n| n.len() == 12)
+        .ok_or(Status::InternalServerError)?;
+    let plain = aead
+        .decrypt(&cipher, &Nonce::clone_from_slice(&nonce))
+        .map_err(|_| Status::InternalServerError)?;
+    let content_type =
+        ContentType::parse_flexible(attachment.get_content_type()).unwrap_or(ContentType::Binary);
+    Ok((content_type, plain))
+}
+
+// Number of unread messages for the badge in the navbar, fetched once on every page load. System messages are not counted.
+#[get("/unread")]
+pub async fn unread(
+    user: Option<UserIdGuard<Cookie>>,
+    conn: DbConn,
+) -> Result<Json<Value>, Status> {
+    let count = match user {
+        Some(user) => conn
+            .run(move |c| Messages::unread_count(c, &user.id))
+            .await
+            .map_err(|_| Status::InternalServerError)?,
+        None => 0,
+    };
+    Ok(Json(json!({ "unread": count })))
+}
+
+fn in_conv(msg: &Message, a: &UserId, b: &UserId) -> bool {
+    (msg.get_send() == a.get_id() && msg.get_recv() == b.get_id())
+        || (msg.get_send() == b.get_id() && msg.get_recv() == a.get_id())
+}
+
+// New messages of the conversation as they are sent.
+// The stream holds no database connection. Clients mark the messages read through `read`.
+#[get("/stream?<user_id>")]
+pub async fn stream(
+    user: UserIdGuard<Cookie>,
+    user_id: UserGuard,
+    hub: &State<MsgHub>,
+    conn: DbConn,
+    mut end: Shutdown,
+) -> Result<EventStream![], Status> {
+    let peer = user_id
+        .to_id_param(&conn)
+        .await
+        .map_err(|_| Status::NotFound)?
+        .id;
+    let mut rx = hub.subscribe();
+
+    Ok(EventStream! {
+        loop {
+            let view = select! {
+                view = rx.recv() => match view {
+                    Ok(view) => view,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut end => break,
+            };
+            // System messages only live in the threads of the orders
+            if view.msg.is_system() || !in_conv(&view.msg, &user.id, &peer) {
+                continue;
+            }
+            yield Event::json(&view);
+        }
+    })
+}
+
+// Mark the messages from the peer read, called by the chat page as messages arrive
+#[post("/read?<user_id>")]
+pub async fn read(
+    user: UserIdGuard<Cookie>,
+    user_id: UserGuard,
+    conn: DbConn,
+) -> Result<Status, Status> {
+    let peer = user_id
+        .to_id_param(&conn)
+        .await
+        .map_err(|_| Status::NotFound)?
+        .id;
+    conn.run(move |c| Messages::mark_read(c, &user.id, &peer))
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    Ok(Status::NoContent)
+}
+
+// The whole conversation, for clients polling instead of streaming
+#[get("/conv?<user_id>")]
+pub async fn conv(
+    user: UserIdGuard<Cookie>,
+    user_id: UserGuard,
+    conn: DbConn,
+) -> Result<Json<Vec<MessageView>>, Status> {
+    let peer = user_id
+        .to_id_param(&conn)
+        .await
+        .map_err(|_| Status::NotFound)?
+        .id;
+    conn.run(move |c| {
+        Messages::mark_read(c, &user.id, &peer)?;
+        MessageView::load(c, Messages::get_conv(c, &user.id, &peer)?)
+    })
+    .await
+    .map(Json)
+    .map_err(|_| Status::InternalServerError)
+}
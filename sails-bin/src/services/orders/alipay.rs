@@ -5,6 +5,7 @@ use crate::{
             RefundTradeResp, TradeQuery, TradeQueryResp,
         },
         guards::*,
+        msg_hub::MsgHub,
         tg_bot::TelegramBot,
     },
     pages::orders::*,
@@ -14,7 +15,10 @@ use rocket::{
     response::{Flash, Redirect},
     State,
 };
-use sails_db::{digicons::*, enums::TransactionStatus, error::SailsDbError};
+use sails_db::{
+    digicons::*, enums::TransactionStatus, error::SailsDbError, messages::Messages,
+    referrals::Referrals,
+};
 
 #[get("/cancel_order?<order_id>", rank = 1)]
 pub async fn cancel_order_alipay(
@@ -25,9 +29,11 @@ pub async fn cancel_order_alipay(
     priv_key: &State<AlipayAppPrivKey>,
     client: &State<AlipayClient>,
     bot: &State<TelegramBot>,
+    hub: &State<MsgHub>,
 ) -> Result<Redirect, Flash<Redirect>> {
     let info = order_id.to_info(&conn).await.into_flash(uri!("/"))?;
     let status = info.order_info.get_transaction_status();
+    let previous = status.clone();
     // We only allow users to cancel their orders if they have not finished them.
     match status {
         TransactionStatus::Placed => {
@@ -44,29 +50,42 @@ pub async fn cancel_order_alipay(
                 }
             }
 
-            conn.run(move |c| info.order_info.refund(c))
+            let posted = conn
+                .run(move |c| -> Result<_, SailsDbError> {
+                    let order = info.order_info.refund(c)?;
+                    Messages::post_status_change(c, &previous, &order)
+                })
                 .await
                 .into_flash(uri!("/"))?;
+            hub.publish_status_change(posted);
         }
         TransactionStatus::Paid => {
-            client
-                .request(
-                    priv_key,
-                    RefundTrade::new(
-                        info.order_info.get_id(),
-                        "用户发起无理由退款",
-                        info.order_info.get_total(),
-                    ),
-                )
-                .into_flash(uri!("/"))?
-                .send::<RefundTradeResp>(client.client())
-                .await
-                .into_flash(uri!("/"))?
-                .into_flash(uri!("/"))?;
+            // Orders fully paid with store credit have no trade on Alipay
+            if info.order_info.get_payable() > 0u32.into() {
+                client
+                    .request(
+                        priv_key,
+                        RefundTrade::new(
+                            info.order_info.get_id(),
+                            "用户发起无理由退款",
+                            info.order_info.get_payable(),
+                        ),
+                    )
+                    .into_flash(uri!("/"))?
+                    .send::<RefundTradeResp>(client.client())
+                    .await
+                    .into_flash(uri!("/"))?
+                    .into_flash(uri!("/"))?;
+            }
 
-            conn.run(move |c| info.order_info.refund(c))
+            let posted = conn
+                .run(move |c| -> Result<_, SailsDbError> {
+                    let order = info.order_info.refund(c)?;
+                    Messages::post_status_change(c, &previous, &order)
+                })
                 .await
                 .into_flash(uri!("/"))?;
+            hub.publish_status_change(posted);
         }
         _ => {
             return Err(Flash::error(
@@ -92,9 +111,15 @@ pub async fn progress_alipay(
     priv_key: &State<AlipayAppPrivKey>,
     client: &State<AlipayClient>,
     bot: &State<TelegramBot>,
+    hub: &State<MsgHub>,
 ) -> Result<Redirect, Flash<Redirect>> {
     let order = order_id.to_info(&db).await.into_flash(uri!("/"))?;
 
+    // Orders fully paid with store credit have nothing to synchronize with the payment provider
+    if order.order_info.get_payable() == 0u32.into() {
+        return Ok(Redirect::to(uri!("/orders", order_info_alipay(order_id))));
+    }
+
     let resp = client
         .request(priv_key, TradeQuery::new(order.order_info.get_id()))
         .into_flash(uri!("/"))?
@@ -135,9 +160,17 @@ pub async fn progress_alipay(
             ))
         }
     };
-    db.run(move |c| order.order_info.set_transaction_status(status).update(c))
+    let previous = order.order_info.get_transaction_status().clone();
+    let posted = db
+        .run(move |c| -> Result<_, SailsDbError> {
+            let info = order.order_info.set_transaction_status(status).update(c)?;
+            // The first finished order of a referred user triggers the referral rewards
+            Referrals::reward(c, &info)?;
+            Messages::post_status_change(c, &previous, &info)
+        })
         .await
         .into_flash(uri!("/"))?;
+    hub.publish_status_change(posted);
 
     bot.send_order_update(order_id.get_id(), &db)
         .await
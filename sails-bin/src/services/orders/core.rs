@@ -1,5 +1,5 @@
 use crate::{
-    infras::{guards::*, tg_bot::TelegramBot},
+    infras::{guards::*, msg_hub::MsgHub, tg_bot::TelegramBot},
     pages::orders::*,
     DbConn, IntoFlash,
 };
@@ -8,44 +8,80 @@ use rocket::{
     response::{Flash, Redirect},
     State,
 };
-use sails_db::{enums::Payment, transactions::*};
+use sails_db::{
+    addresses::Addresses,
+    enums::{Payment, TransactionStatus},
+    error::SailsDbError,
+    messages::Messages,
+    transactions::*,
+};
 use std::num::NonZeroU32;
 
 #[derive(FromForm)]
 pub struct CheckoutInfo {
     quantity: NonZeroU32,
+    // ID of the address in the address book, empty if nothing is to be shipped
     address: String,
     payment: Payment,
     coupon: String,
+    // Pay as much as possible with store credit before using the payment method
+    use_credit: bool,
 }
 
 #[post("/purchase?<prod_id>", data = "<info>")]
 pub async fn purchase(
     db: DbConn,
     prod_id: ProdGuard,
-    user: UserInfoGuard<Cookie>,
+    user: UserInfoGuard<CookieOrToken>,
     info: Form<Strict<CheckoutInfo>>,
     bot: &State<TelegramBot>,
+    hub: &State<MsgHub>,
 ) -> Result<Redirect, Flash<Redirect>> {
     let prod = prod_id.to_info(&db).await.into_flash(uri!("/"))?;
+    // Errors like those from coupons are displayed on the checkout page
+    let checkout_uri = uri!("/orders", checkout(prod.prod_info.get_id()));
 
-    let info = db
+    let (info, posted) = db
         // TODO: We need to allow user to specify quantity
-        .run(move |c| {
-            Transactions::buy(
+        .run(move |c| -> Result<_, SailsDbError> {
+            // Multiple coupons are separated by commas
+            let coupons = info
+                .coupon
+                .split(',')
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .collect::<Vec<&str>>();
+            let buyer = user.info.to_id();
+            let address = if info.address.is_empty() {
+                None
+            } else {
+                Some(Addresses::find(c, &buyer, &info.address)?)
+            };
+            Transactions::buy_to_address(
                 c,
                 &prod.prod_info.to_id(),
-                &user.info.to_id(),
+                &buyer,
                 info.quantity.get(),
-                &info.address,
-                &info.coupon,
+                address.as_ref(),
+                &coupons,
                 info.payment.clone(),
             )
-            .map(|t| t.get_info(c))
+            .and_then(|t| t.get_info(c))
+            .and_then(|t| {
+                // Orders paid with credit in full or free ones have no more to pay
+                if info.use_credit && (t.get_transaction_status() == &TransactionStatus::Placed) {
+                    let paid = t.apply_credit(c)?;
+                    let posted =
+                        Messages::post_status_change(c, &TransactionStatus::Placed, &paid)?;
+                    Ok((paid, posted))
+                } else {
+                    Ok((t, None))
+                }
+            })
         })
         .await
-        .into_flash(uri!("/"))?
-        .into_flash(uri!("/"))?;
+        .into_flash(checkout_uri)?;
+    hub.publish_status_change(posted);
 
     // TODO: can we make it elegant
     let id = info.get_id().to_string();
@@ -0,0 +1,48 @@
+use crate::{
+    infras::{guards::*, msg_hub::MsgHub, tg_bot::TelegramBot},
+    pages::orders::*,
+    DbConn, IntoFlash,
+};
+use rocket::{
+    response::{Flash, Redirect},
+    State,
+};
+use sails_db::{enums::TransactionStatus, error::SailsDbError, messages::Messages};
+
+#[get("/cancel_order?<order_id>", rank = 3)]
+pub async fn cancel_order_credit(
+    _is_credit: Auth<OrderWithCredit>,
+    _auth: Auth<OrderProgressable>,
+    order_id: OrderGuard,
+    conn: DbConn,
+    bot: &State<TelegramBot>,
+    hub: &State<MsgHub>,
+) -> Result<Redirect, Flash<Redirect>> {
+    let info = order_id.to_info(&conn).await.into_flash(uri!("/"))?;
+    let status = info.order_info.get_transaction_status();
+    let previous = status.clone();
+    // Orders paid with store credit are refunded straight back to the wallet.
+    match status {
+        TransactionStatus::Placed | TransactionStatus::Paid => {
+            let posted = conn
+                .run(move |c| -> Result<_, SailsDbError> {
+                    let order = info.order_info.refund(c)?;
+                    Messages::post_status_change(c, &previous, &order)
+                })
+                .await
+                .into_flash(uri!("/"))?;
+            hub.publish_status_change(posted);
+        }
+        _ => {
+            return Err(Flash::error(
+                Redirect::to(uri!("/")),
+                "refunds not allowed due to order status constraints",
+            ))
+        }
+    }
+
+    bot.send_order_update(order_id.get_id(), &conn)
+        .await
+        .into_flash(uri!("/"))?;
+    Ok(Redirect::to(uri!("/orders", order_info_credit(order_id))))
+}
@@ -1,7 +1,9 @@
 mod alipay;
 mod core;
+mod credit;
 mod paypal;
 
 pub use self::core::*;
 pub use alipay::*;
+pub use credit::*;
 pub use paypal::*;
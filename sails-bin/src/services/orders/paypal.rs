@@ -1,5 +1,5 @@
 use crate::{
-    infras::{guards::*, tg_bot::TelegramBot},
+    infras::{guards::*, msg_hub::MsgHub, tg_bot::TelegramBot},
     pages::orders::*,
     DbConn, IntoFlash,
 };
@@ -15,7 +15,10 @@ use rocket::{
     serde::json::Json,
     State,
 };
-use sails_db::{digicons::*, enums::TransactionStatus, error::SailsDbError};
+use sails_db::{
+    digicons::*, enums::TransactionStatus, error::SailsDbError, messages::Messages,
+    referrals::Referrals,
+};
 use serde::Deserialize;
 
 // This is considered appropriate in service as it is information only, not quite an infrastructure.
@@ -33,9 +36,15 @@ pub async fn progress_paypal(
     paypal_auth: &State<PaypalAuth>,
     conn: DbConn,
     bot: &State<TelegramBot>,
+    hub: &State<MsgHub>,
 ) -> Result<Redirect, Flash<Redirect>> {
     let order = order_id.to_info(&conn).await.into_flash(uri!("/"))?;
 
+    // Orders fully paid with store credit have nothing to synchronize with the payment provider
+    if order.order_info.get_payable() == 0u32.into() {
+        return Ok(Redirect::to(uri!("/orders", order_info_paypal(order_id))));
+    }
+
     let paypal_order_id = order
         .order_info
         .get_payment_detail()
@@ -104,9 +113,17 @@ pub async fn progress_paypal(
         // Still not captured
         _ => TransactionStatus::Placed,
     };
-    conn.run(move |c| order.order_info.set_transaction_status(status).update(c))
+    let previous = order.order_info.get_transaction_status().clone();
+    let posted = conn
+        .run(move |c| -> Result<_, SailsDbError> {
+            let info = order.order_info.set_transaction_status(status).update(c)?;
+            // The first finished order of a referred user triggers the referral rewards
+            Referrals::reward(c, &info)?;
+            Messages::post_status_change(c, &previous, &info)
+        })
         .await
         .into_flash(uri!("/"))?;
+    hub.publish_status_change(posted);
 
     bot.send_order_update(order_id.get_id(), &conn)
         .await
@@ -121,6 +138,7 @@ pub async fn capture_paypal_order(
     order_id: OrderGuard,
     conn: DbConn,
     bot: &State<TelegramBot>,
+    hub: &State<MsgHub>,
 ) -> Result<Json<Order>, Status> {
     let info = order_id
         .to_info(&conn)
@@ -181,9 +199,16 @@ pub async fn capture_paypal_order(
             TransactionStatus::Paid
         };
 
-        conn.run(move |c| info.order_info.set_transaction_status(status).update(c))
+        let previous = info.order_info.get_transaction_status().clone();
+        let posted = conn
+            .run(move |c| -> Result<_, SailsDbError> {
+                let info = info.order_info.set_transaction_status(status).update(c)?;
+                Referrals::reward(c, &info)?;
+                Messages::post_status_change(c, &previous, &info)
+            })
             .await
             .map_err(|_| Status::new(500))?;
+        hub.publish_status_change(posted);
     }
 
     bot.send_order_update(order_id.get_id(), &conn)
@@ -224,7 +249,7 @@ pub async fn create_paypal_order(
         .intent(Intent::Capture)
         .purchase_units(vec![PurchaseUnit::new(Amount::new(
             info.order_info.get_currency().into(),
-            &info.order_info.get_total().to_string(),
+            &info.order_info.get_payable().to_string(),
         ))])
         .build()
         .map_err(|e| {
@@ -258,15 +283,22 @@ pub async fn cancel_order_paypal(
     order_id: OrderGuard,
     conn: DbConn,
     bot: &State<TelegramBot>,
+    hub: &State<MsgHub>,
 ) -> Result<Redirect, Flash<Redirect>> {
     let info = order_id.to_info(&conn).await.into_flash(uri!("/"))?;
     let status = info.order_info.get_transaction_status();
+    let previous = status.clone();
     // We only allow users to cancel their orders if they have not finished them.
     match status {
         TransactionStatus::Placed => {
-            conn.run(move |c| info.order_info.refund(c))
+            let posted = conn
+                .run(move |c| -> Result<_, SailsDbError> {
+                    let order = info.order_info.refund(c)?;
+                    Messages::post_status_change(c, &previous, &order)
+                })
                 .await
                 .into_flash(uri!("/"))?;
+            hub.publish_status_change(posted);
         }
         _ => {
             return Err(Flash::error(
@@ -1,5 +1,9 @@
 use crate::{
-    infras::{guards::*, recaptcha::ReCaptcha},
+    infras::{
+        guards::*,
+        recaptcha::ReCaptcha,
+        root_auth::{RootAuth, RootLoginAttempts, ROOT_COOKIE_NAME},
+    },
     pages::root::*,
     DbConn, IntoFlash,
 };
@@ -9,88 +13,173 @@ use rocket::{
     response::{Flash, Redirect},
     State,
 };
-use sails_db::enums::UserStatus;
-use serde::{Deserialize, Serialize};
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct RootPasswd {
-    #[serde(rename = "root_passwd")]
-    passwd: String,
-}
-
-impl RootPasswd {
-    // There is no need for us to use hash because in this case it is not in the database.
-    pub fn verify(&self, passwd: &str) -> bool {
-        self.passwd == passwd
-    }
-}
+use sails_db::{
+    enums::UserStatus,
+    roles::{UserRole, UserRoles},
+};
 
 // Form used for validating root
 #[derive(FromForm)]
 pub struct Validation {
     password: String,
+    totp: String,
+    // Only present if reCAPTCHA is configured
     #[field(name = "g-recaptcha-response")]
-    recaptcha_token: String,
+    recaptcha_token: Option<String>,
 }
 
 #[post("/validate", data = "<info>")]
 pub async fn validate(
     jar: &CookieJar<'_>,
     info: Form<Validation>,
-    root_passwd: &State<RootPasswd>,
-    recaptcha: &State<ReCaptcha>,
+    client: ClientInfo,
+    root_auth: &State<RootAuth>,
+    attempts: &State<RootLoginAttempts>,
+    recaptcha: Option<&State<ReCaptcha>>,
 ) -> Result<Redirect, Flash<Redirect>> {
-    if !recaptcha
-        .verify(&info.recaptcha_token)
-        .await
-        .into_flash(uri!("/"))?
-        .success
-    {
+    if attempts.is_throttled(&client.ip) {
+        log::warn!("Throttled root login attempt from {:?}", client.ip);
         return Err(Flash::error(
             Redirect::to(uri!("/")),
-            "reCAPTCHA was unsuccessful".to_string(),
+            "Too many failed attempts, please try again later",
         ));
-    };
+    }
+
+    if let Some(recaptcha) = recaptcha {
+        if !recaptcha
+            .verify(info.recaptcha_token.as_deref().unwrap_or_default())
+            .await
+            .into_flash(uri!("/"))?
+            .success
+        {
+            return Err(Flash::error(
+                Redirect::to(uri!("/")),
+                "reCAPTCHA was unsuccessful".to_string(),
+            ));
+        };
+    }
 
-    if root_passwd.verify(&info.password) {
-        let cookie = Cookie::build("root_challenge", "ROOT")
-            .secure(true)
-            .same_site(SameSite::Strict)
-            .finish();
-        // Successfully validated, set private cookie.
-        jar.add_private(cookie);
-        Ok(Redirect::to(uri!("/root", root)))
+    let now = chrono::Utc::now();
+    let step = if root_auth.verify_passwd(&info.password) {
+        root_auth.verify_totp(&info.totp, now.timestamp() as u64)
     } else {
-        Err(Flash::error(Redirect::to(uri!("/")), "Incorrect password"))
+        None
+    };
+    match step {
+        Some(step) if attempts.succeed(&client.ip, step) => {
+            log::info!("Root logged in from {:?}", client.ip);
+            // The cookie holds the time the root session expires. Being private, it cannot be forged.
+            let expiry = now + root_auth.session_ttl();
+            let cookie = Cookie::build(ROOT_COOKIE_NAME, expiry.timestamp().to_string())
+                .secure(true)
+                .same_site(SameSite::Strict)
+                .max_age(rocket::time::Duration::seconds(
+                    root_auth.session_ttl().num_seconds(),
+                ))
+                .finish();
+            // Successfully validated, set private cookie.
+            jar.add_private(cookie);
+            Ok(Redirect::to(uri!("/root", root)))
+        }
+        _ => {
+            attempts.fail(&client.ip);
+            log::warn!("Failed root login attempt from {:?}", client.ip);
+            Err(Flash::error(
+                Redirect::to(uri!("/")),
+                "Incorrect password or verification code",
+            ))
+        }
     }
 }
 
 #[derive(Debug, FromForm, Clone)]
-pub struct UserStatusForm {
-    pub status: u32,
+pub struct UserRolesForm {
+    pub roles: Vec<String>,
 }
 
-// TODO: Why do we write the user_id into here?
-#[post("/user_status?<user_id>", data = "<info>")]
-pub async fn update_user_status(
+#[post("/user_roles?<user_id>", data = "<info>")]
+pub async fn update_user_roles(
     _guard: Role<Root>,
     conn: DbConn,
     user_id: UserGuard,
-    info: Form<UserStatusForm>,
+    info: Form<UserRolesForm>,
 ) -> Result<Redirect, Flash<Redirect>> {
-    let user = user_id.to_info_param(&conn).await.into_flash(uri!("/"))?;
-    let id = user.info.get_id().to_string();
-    if let Some(status) = UserStatus::from_bits(info.status) {
-        conn.run(move |c| user.info.set_user_status(status).update(c))
-            .await
-            .into_flash(uri!("/"))?;
-        // We cannot get uri macro working
-        Ok(Redirect::to(uri!("/root", user_status(id))))
-    } else {
-        Err(Flash::error(Redirect::to(uri!("/")), "invalid level"))
+    let user = user_id.to_id_param(&conn).await.into_flash(uri!("/"))?;
+    let id = user.id.get_id().to_string();
+    conn.run(move |c| {
+        let roles = info
+            .roles
+            .iter()
+            .map(|x| UserRoles::find(c, x))
+            .collect::<Result<Vec<UserRole>, _>>()?;
+        UserRoles::assign(c, &user.id, &roles)
+    })
+    .await
+    .into_flash(uri!("/"))?;
+    Ok(Redirect::to(uri!("/root", user_status(id))))
+}
+
+#[derive(Debug, FromForm, Clone)]
+pub struct RoleForm {
+    pub name: String,
+    // Bits of the permissions granted
+    pub permissions: Vec<u32>,
+}
+
+impl RoleForm {
+    fn to_status(&self) -> UserStatus {
+        self.permissions
+            .iter()
+            .fold(UserStatus::DISABLED, |acc, x| {
+                acc | UserStatus::from_bits_truncate(*x)
+            })
     }
 }
 
+#[post("/create_role", data = "<info>")]
+pub async fn create_role(
+    _guard: Role<Root>,
+    conn: DbConn,
+    info: Form<RoleForm>,
+) -> Result<Redirect, Flash<Redirect>> {
+    let info = info.into_inner();
+    conn.run(move |c| UserRole::new(c, &info.name, info.to_status()))
+        .await
+        .into_flash(uri!("/root", roles))?;
+    Ok(Redirect::to(uri!("/root", roles)))
+}
+
+#[post("/update_role?<role_id>", data = "<info>")]
+pub async fn update_role(
+    _guard: Role<Root>,
+    conn: DbConn,
+    role_id: String,
+    info: Form<RoleForm>,
+) -> Result<Redirect, Flash<Redirect>> {
+    let info = info.into_inner();
+    conn.run(move |c| {
+        UserRoles::find(c, &role_id)?
+            .set_name(&info.name)
+            .set_permissions(info.to_status())
+            .update(c)
+    })
+    .await
+    .into_flash(uri!("/root", roles))?;
+    Ok(Redirect::to(uri!("/root", roles)))
+}
+
+#[get("/delete_role?<role_id>")]
+pub async fn delete_role(
+    _guard: Role<Root>,
+    conn: DbConn,
+    role_id: String,
+) -> Result<Redirect, Flash<Redirect>> {
+    conn.run(move |c| UserRoles::find(c, &role_id)?.delete(c))
+        .await
+        .into_flash(uri!("/root", roles))?;
+    Ok(Redirect::to(uri!("/root", roles)))
+}
+
 #[get("/delete_user?<user_id>")]
 pub async fn delete_user(
     _guard: Role<Root>,
@@ -102,9 +191,21 @@ pub async fn delete_user(
     Ok(Redirect::to(uri!("/root", root)))
 }
 
+// Unlike deletion, this also removes the orders and everything else referencing the user
+#[get("/purge_user?<user_id>")]
+pub async fn purge_user(
+    _guard: Role<Root>,
+    user_id: UserGuard,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    let id = user_id.to_id_param(&conn).await.into_flash(uri!("/"))?;
+    conn.run(|c| id.id.purge(c)).await.into_flash(uri!("/"))?;
+    Ok(Redirect::to(uri!("/root", root)))
+}
+
 #[get("/logout")]
 pub async fn logout(jar: &CookieJar<'_>) -> Redirect {
-    if let Some(root_challenge) = jar.get_private("root_challenge") {
+    if let Some(root_challenge) = jar.get_private(ROOT_COOKIE_NAME) {
         jar.remove_private(root_challenge);
     } else {
         // No UID specified, do nothing
@@ -0,0 +1,60 @@
+use crate::{infras::guards::*, pages::tickets::*, DbConn, IntoFlash};
+use rocket::{
+    form::Form,
+    response::{Flash, Redirect},
+};
+use sails_db::{error::SailsDbError, tickets::*, transactions::*};
+
+// Form used for opening tickets
+#[derive(FromForm)]
+pub struct OpenTicket {
+    subject: String,
+    body: String,
+    // Empty if the ticket is not about any order
+    order_id: Option<String>,
+}
+
+#[post("/open", data = "<info>")]
+pub async fn open(
+    user: UserIdGuard<Cookie>,
+    info: Form<OpenTicket>,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    let info = info.into_inner();
+    let opened = conn
+        .run(move |c| -> Result<Ticket, SailsDbError> {
+            let order = match info.order_id.as_deref().filter(|id| !id.is_empty()) {
+                Some(id) => Some(TransactionFinder::new(c, None).id(id).first_info()?),
+                None => None,
+            };
+            Tickets::open(c, &user.id, order.as_ref(), &info.subject, &info.body)
+        })
+        .await
+        .into_flash(uri!("/tickets", tickets(_)))?;
+
+    Ok(Redirect::to(uri!("/tickets", ticket(opened.get_id()))))
+}
+
+// Form used for replying to tickets
+#[derive(FromForm)]
+pub struct TicketReplyForm {
+    body: String,
+}
+
+#[post("/reply?<ticket_id>", data = "<info>")]
+pub async fn reply(
+    user: UserInfoGuard<Cookie>,
+    ticket_id: String,
+    info: Form<TicketReplyForm>,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    let back = uri!("/tickets", ticket(ticket_id.as_str()));
+    conn.run(move |c| -> Result<TicketReply, SailsDbError> {
+        let ticket = Tickets::find(c, &ticket_id)?;
+        Tickets::reply(c, &ticket, &user.info, &info.body, false)
+    })
+    .await
+    .into_flash(back.clone())?;
+
+    Ok(Redirect::to(back))
+}
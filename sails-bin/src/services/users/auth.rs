@@ -1,17 +1,28 @@
 use crate::{
     infras::{
+        guards::{ClientInfo, SESSION_COOKIE_NAME},
         i18n::I18n,
-        oidc::{OIDCClient, OIDCIdToken, OIDCTokenResponse, ID_TOKEN_COOKIE_NAME},
+        oidc::{
+            OIDCIdToken, OIDCProviders, OIDCTokenResponse, ID_TOKEN_COOKIE_NAME,
+            PROVIDER_COOKIE_NAME,
+        },
     },
     pages::users::*,
-    DbConn, IntoFlash,
+    DbConn, IntoFlash, Msg,
 };
 use rocket::{
     http::{Cookie as HttpCookie, CookieJar, SameSite},
+    request::FlashMessage,
     response::{Flash, Redirect},
     State,
 };
-use sails_db::{error::SailsDbError, users::*};
+use sails_db::{
+    error::SailsDbError,
+    identities::{Identities, IdentityClaims},
+    referrals::Referrals,
+    roles::UserRoles,
+    sessions::{SessionPolicy, Sessions},
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -20,52 +31,148 @@ struct Claims {
     name: String,
 }
 
+// The referral code is kept until the user comes back from the identity provider
+const REFERRAL_COOKIE_NAME: &str = "referral";
+
 // This would be mounted under namespace `user` and eventually become `/user/signin`
-#[get("/signin")]
-pub async fn signin(client: &State<OIDCClient>, cookies: &CookieJar<'_>) -> Redirect {
-    client.get_redirect(cookies, &["email", "profile"])
+#[get("/signin?<provider>&<referral>", rank = 1)]
+pub async fn signin(
+    provider: String,
+    referral: Option<String>,
+    providers: &State<OIDCProviders>,
+    cookies: &CookieJar<'_>,
+) -> Result<Redirect, Flash<Redirect>> {
+    let provider = providers
+        .get(&provider)
+        .ok_or("Unknown identity provider")
+        .into_flash(uri!("/user", signin_chooser(_)))?;
+    if let Some(code) = referral {
+        cookies.add_private(
+            HttpCookie::build(REFERRAL_COOKIE_NAME, code)
+                .same_site(SameSite::Lax)
+                .finish(),
+        );
+    }
+    Ok(provider.get_redirect(cookies, &["email", "profile"]))
+}
+
+// Let the user choose the identity provider, unless there is only one
+#[get("/signin?<referral>", rank = 2)]
+pub async fn signin_chooser(
+    i18n: I18n,
+    flash: Option<FlashMessage<'_>>,
+    referral: Option<String>,
+    providers: &State<OIDCProviders>,
+) -> Result<SignInPage, Redirect> {
+    match providers.list() {
+        [provider] => Err(Redirect::to(SignInPage::link(
+            provider.get_name(),
+            referral.as_deref(),
+        ))),
+        list => Ok(SignInPage {
+            i18n,
+            providers: list
+                .iter()
+                .map(|p| (p.get_name().to_string(), p.get_display_name().to_string()))
+                .collect(),
+            referral,
+            inner: Msg::from_flash(flash),
+        }),
+    }
 }
 
 #[get("/signin_callback")]
 pub async fn signin_callback(
     i18n: I18n,
     token: OIDCTokenResponse,
+    providers: &State<OIDCProviders>,
     jar: &CookieJar<'_>,
+    client: ClientInfo,
+    policy: &State<SessionPolicy>,
     conn: DbConn,
 ) -> Result<SignInConfirmation, Flash<Redirect>> {
+    let provider = providers
+        .get(&token.provider)
+        .ok_or("Unknown identity provider")
+        .into_flash(uri!("/"))?;
     let name = token
-        .claims
-        .name()
-        .ok_or("No name provided by FLibrary ID")
+        .claim(&provider.claims.name)
+        .ok_or("No name provided by the identity provider")
         .into_flash(uri!("/"))?
-        .get(None)
-        .ok_or("Name without locale specification is not provided by FLibrary ID")
-        .into_flash(uri!("/"))?
-        .as_str()
         .to_string();
     let email = token
-        .claims
-        .email()
-        .ok_or("No name provided by FLibrary ID")
+        .claim(&provider.claims.email)
+        .ok_or("No email provided by the identity provider")
         .into_flash(uri!("/"))?
-        .as_str()
         .to_string();
+    let school = provider
+        .claims
+        .school
+        .as_deref()
+        .and_then(|s| token.claim(s))
+        .unwrap_or_default()
+        .to_string();
+    let email_verified = token.flag(&provider.claims.email_verified).unwrap_or(false);
+    let roles = provider.roles.clone();
+    let link_legacy_by_email = provider.link_legacy_by_email;
+    // Users are recognized by the issuer and the subject, which never change, unlike the email
+    let issuer = token.claims.issuer().as_str().to_string();
+    let subject = token.claims.subject().as_str().to_string();
 
     let name_cloned = name.clone();
-    let email_cloned = email.clone();
+    let policy = policy.inner().clone();
+
+    let referral = jar.get_private(REFERRAL_COOKIE_NAME).map(|c| {
+        let code = c.value().to_string();
+        jar.remove_private(c);
+        code
+    });
 
     // Create user if user not found in our local database
-    conn.run(move |c| -> Result<(), SailsDbError> {
-        if matches!(UserId::find(c, &email), Err(SailsDbError::QueryError(_))) {
-            UserForm::new(&email, &name, "", None).to_ref()?.create(c)?;
-        }
-        Ok(())
-    })
-    .await
-    .into_flash(uri!("/"))?;
+    let sid = conn
+        .run(move |c| -> Result<String, SailsDbError> {
+            let (user, created) = Identities::sign_in(
+                c,
+                &IdentityClaims {
+                    issuer: &issuer,
+                    subject: &subject,
+                    email: &email,
+                    email_verified,
+                    link_legacy_by_email,
+                    name: &name,
+                    school: &school,
+                },
+            )?;
+            // Only new users could be referred. An invalid code shall not prevent the user from signing in.
+            if let (true, Some(code)) = (created, referral) {
+                match Referrals::attribute(c, &user, &code) {
+                    Ok(_)
+                    | Err(SailsDbError::ReferralCodeNotFound)
+                    | Err(SailsDbError::ReferralNotAllowed) => {}
+                    Err(e) => {
+                        log::warn!("Failed to attribute the referral code {}: {}", code, e);
+                        return Err(e);
+                    }
+                }
+            }
+            // Roles of the provider are only granted once, so that root could revoke them later
+            if created && !roles.is_empty() {
+                let roles = roles
+                    .iter()
+                    .map(|r| UserRoles::find(c, r))
+                    .collect::<Result<Vec<_>, _>>()?;
+                UserRoles::grant(c, &user, &roles)?;
+            }
+            Sessions::delete_expired(c, &policy)?;
+            let session =
+                Sessions::create(c, &user, client.ip.as_deref(), client.user_agent.as_deref())?;
+            Ok(session.get_id().to_string())
+        })
+        .await
+        .into_flash(uri!("/"))?;
 
     // Set the private session cookie
-    let cookie = HttpCookie::build("uid", email_cloned)
+    let cookie = HttpCookie::build(SESSION_COOKIE_NAME, sid)
         .secure(true)
         .same_site(SameSite::Strict)
         .finish();
@@ -78,38 +185,57 @@ pub async fn signin_callback(
     })
 }
 
+// Revoke the session on the server side and remove the cookie
+async fn end_session(jar: &CookieJar<'_>, conn: &DbConn) {
+    if let Some(sid) = jar.get_private(SESSION_COOKIE_NAME) {
+        let id = sid.value().to_string();
+        // The session may have already expired, which is fine
+        let _ = conn
+            .run(move |c| Sessions::find(c, &id).and_then(|s| s.revoke(c)))
+            .await;
+        jar.remove_private(sid);
+    } else {
+        // No session specified, do nothing
+    }
+}
+
 #[get("/logout", rank = 1)]
 pub async fn logout(
     jar: &CookieJar<'_>,
     id_token: OIDCIdToken,
-    client: &State<OIDCClient>,
+    providers: &State<OIDCProviders>,
+    conn: DbConn,
 ) -> Redirect {
-    if let Some(uid) = jar.get_private("uid") {
+    end_session(jar, &conn).await;
+
+    if let Some(uid) = jar.get_private(ID_TOKEN_COOKIE_NAME) {
         jar.remove_private(uid);
     } else {
         // No UID specified, do nothing
     }
 
-    if let Some(uid) = jar.get_private(ID_TOKEN_COOKIE_NAME) {
-        jar.remove_private(uid);
+    // Log out from the provider the user signed in with as well, if it supports so
+    let redirect = providers
+        .of_cookies(jar)
+        .and_then(|p| p.logout_redirect_uri.as_ref())
+        .map(|uri| format!("{}&id_token_hint={}", uri, id_token.id_token));
+
+    if let Some(provider) = jar.get_private(PROVIDER_COOKIE_NAME) {
+        jar.remove_private(provider);
     } else {
-        // No UID specified, do nothing
+        // No provider cookie, do nothing
     }
 
-    // Redirect back to home
-    Redirect::to(format!(
-        "{}&id_token_hint={}",
-        client.logout_redirect_uri, id_token.id_token
-    ))
+    match redirect {
+        Some(uri) => Redirect::to(uri),
+        // Redirect back to home
+        None => Redirect::to(uri!("/")),
+    }
 }
 
 #[get("/logout", rank = 2)]
-pub async fn logout_fallback(jar: &CookieJar<'_>) -> Redirect {
-    if let Some(uid) = jar.get_private("uid") {
-        jar.remove_private(uid);
-    } else {
-        // No UID specified, do nothing
-    }
+pub async fn logout_fallback(jar: &CookieJar<'_>, conn: DbConn) -> Redirect {
+    end_session(jar, &conn).await;
 
     if let Some(id_token) = jar.get_private(ID_TOKEN_COOKIE_NAME) {
         jar.remove_private(id_token);
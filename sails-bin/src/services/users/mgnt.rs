@@ -1,8 +1,18 @@
-use crate::{infras::guards::*, sanitize_html, DbConn, IntoFlash};
+use crate::{infras::guards::*, sanitize_html, Attachment, DbConn, IntoFlash};
 use rocket::{
     form::Form,
+    http::ContentType,
     response::{Flash, Redirect},
 };
+use sails_db::{
+    addresses::{AddressForm, Addresses},
+    credits::GiftCards,
+    enums::UserStatus,
+    error::SailsDbError,
+    export::UserExport,
+    sessions::Sessions,
+    tokens::ApiTokens,
+};
 
 #[derive(Debug, FromForm, Clone)]
 pub struct PartialUserFormOwned {
@@ -31,3 +41,201 @@ pub async fn update_user(
 
     Ok(Redirect::to(uri!("/user", crate::pages::users::portal)))
 }
+
+#[derive(Debug, FromForm, Clone)]
+pub struct RedeemForm {
+    pub code: String,
+}
+
+#[post("/redeem_giftcard", data = "<info>")]
+pub async fn redeem_giftcard(
+    user: UserIdGuard<Cookie>,
+    info: Form<RedeemForm>,
+    conn: DbConn,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    let info = info.into_inner();
+    let entry = conn
+        .run(move |c| GiftCards::redeem(c, &info.code, &user.id))
+        .await
+        .into_flash(uri!("/user", crate::pages::users::credits))?;
+
+    Ok(Flash::success(
+        Redirect::to(uri!("/user", crate::pages::users::credits)),
+        format!(
+            "{:?} {} has been added to your store credit",
+            entry.get_currency(),
+            entry.get_amount()
+        ),
+    ))
+}
+
+#[derive(Debug, FromForm, Clone)]
+pub struct AddressFormOwned {
+    pub recipient: String,
+    pub phone: String,
+    pub school: String,
+    pub pickup_point: String,
+    pub country: String,
+}
+
+impl AddressFormOwned {
+    fn to_form(&self) -> AddressForm {
+        AddressForm::new(
+            &self.recipient,
+            &self.phone,
+            &self.school,
+            &self.pickup_point,
+            &self.country,
+        )
+    }
+}
+
+#[post("/create_address", data = "<info>")]
+pub async fn create_address(
+    user: UserIdGuard<Cookie>,
+    info: Form<AddressFormOwned>,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    conn.run(move |c| Addresses::create(c, &user.id, &info.to_form()))
+        .await
+        .into_flash(uri!("/user", crate::pages::users::addresses))?;
+
+    Ok(Redirect::to(uri!("/user", crate::pages::users::addresses)))
+}
+
+#[post("/update_address?<address_id>", data = "<info>")]
+pub async fn update_address(
+    user: UserIdGuard<Cookie>,
+    address_id: String,
+    info: Form<AddressFormOwned>,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    conn.run(move |c| Addresses::find(c, &user.id, &address_id)?.update(c, &info.to_form()))
+        .await
+        .into_flash(uri!("/user", crate::pages::users::addresses))?;
+
+    Ok(Redirect::to(uri!("/user", crate::pages::users::addresses)))
+}
+
+#[get("/set_default_address?<address_id>")]
+pub async fn set_default_address(
+    user: UserIdGuard<Cookie>,
+    address_id: String,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    conn.run(move |c| Addresses::find(c, &user.id, &address_id)?.set_default(c))
+        .await
+        .into_flash(uri!("/user", crate::pages::users::addresses))?;
+
+    Ok(Redirect::to(uri!("/user", crate::pages::users::addresses)))
+}
+
+#[get("/delete_address?<address_id>")]
+pub async fn delete_address(
+    user: UserIdGuard<Cookie>,
+    address_id: String,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    conn.run(move |c| Addresses::find(c, &user.id, &address_id)?.delete(c))
+        .await
+        .into_flash(uri!("/user", crate::pages::users::addresses))?;
+
+    Ok(Redirect::to(uri!("/user", crate::pages::users::addresses)))
+}
+
+#[derive(Debug, FromForm, Clone)]
+pub struct TokenForm {
+    pub name: String,
+    // Bits of the permissions granted to the token
+    pub scopes: Vec<u32>,
+}
+
+// Tokens can only be created in a browser session
+#[post("/create_token", data = "<info>")]
+pub async fn create_token(
+    user: UserInfoGuard<Cookie>,
+    info: Form<TokenForm>,
+    conn: DbConn,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    let info = info.into_inner();
+    // Scopes beyond the permissions of the user would be ineffective anyway
+    let scopes = info.scopes.iter().fold(UserStatus::DISABLED, |acc, x| {
+        acc | UserStatus::from_bits_truncate(*x)
+    }) & user.info.get_user_status();
+    let (_, plain) = conn
+        .run(move |c| ApiTokens::create(c, &user.info.to_id(), &info.name, scopes))
+        .await
+        .into_flash(uri!("/user", crate::pages::users::tokens))?;
+
+    Ok(Flash::success(
+        Redirect::to(uri!("/user", crate::pages::users::tokens)),
+        format!(
+            "Your new token is {}. Please copy it now as it won't be shown again.",
+            plain
+        ),
+    ))
+}
+
+#[get("/revoke_token?<token_id>")]
+pub async fn revoke_token(
+    user: UserIdGuard<Cookie>,
+    token_id: String,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    conn.run(move |c| ApiTokens::find(c, &user.id, &token_id)?.revoke(c))
+        .await
+        .into_flash(uri!("/user", crate::pages::users::tokens))?;
+
+    Ok(Redirect::to(uri!("/user", crate::pages::users::tokens)))
+}
+
+#[get("/revoke_session?<session_id>")]
+pub async fn revoke_session(
+    user: UserIdGuard<Cookie>,
+    session_id: String,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    conn.run(move |c| -> Result<(), SailsDbError> {
+        let session = Sessions::find(c, &session_id)?;
+        // Users can only revoke their own sessions
+        if session.get_user_id() == user.id.get_id() {
+            session.revoke(c)?;
+        }
+        Ok(())
+    })
+    .await
+    .into_flash(uri!("/user", crate::pages::users::sessions))?;
+
+    Ok(Redirect::to(uri!("/user", crate::pages::users::sessions)))
+}
+
+// Log out of all devices, including the current one
+#[get("/revoke_all_sessions")]
+pub async fn revoke_all_sessions(
+    user: UserIdGuard<Cookie>,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    conn.run(move |c| Sessions::delete_by_user(c, &user.id))
+        .await
+        .into_flash(uri!("/user", crate::pages::users::sessions))?;
+
+    Ok(Redirect::to(uri!("/")))
+}
+
+// Everything we hold about the user in a JSON file
+#[get("/export")]
+pub async fn export(
+    user: UserIdGuard<Cookie>,
+    conn: DbConn,
+) -> Result<Attachment, Flash<Redirect>> {
+    let export = conn
+        .run(move |c| UserExport::of(c, &user.id))
+        .await
+        .into_flash(uri!("/user", crate::pages::users::portal))?;
+    Ok(Attachment {
+        filename: "flibrary-data.json".to_string(),
+        content_type: ContentType::JSON,
+        body: serde_json::to_string_pretty(&export)
+            .into_flash(uri!("/user", crate::pages::users::portal))?,
+    })
+}
@@ -9,14 +9,14 @@ use sails_db::{
 
 fn login_user(c: &mut Criterion) {
     let conn = establish_connection();
-    UserForm::new("TestUser@example.org", "Kanyang Ying", "", None)
+    let user = UserForm::new("TestUser@example.org", "Kanyang Ying", "", None)
         .to_ref()
         .unwrap()
         .create(&conn)
         .unwrap();
 
     c.bench_function("login an user", |b| {
-        b.iter(|| UserId::find(&conn, "TestUser@example.org").unwrap())
+        b.iter(|| UserId::find(&conn, user.get_id()).unwrap())
     });
 }
 
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE transactions DROP COLUMN credit;
+DROP TABLE giftcards;
+DROP TABLE credits;
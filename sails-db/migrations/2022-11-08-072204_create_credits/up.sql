@@ -0,0 +1,28 @@
+-- Your SQL goes here
+-- Every change of a user's store credit is recorded as an entry. The balance is the sum of them.
+CREATE TABLE IF NOT EXISTS credits (
+  id VARCHAR(60) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(60) NOT NULL,
+  currency TEXT NOT NULL,
+  -- Positive for credits granted, negative for credits spent
+  amount BIG INT NOT NULL,
+  kind TEXT CHECK(kind IN ('gift_card', 'refund', 'compensation', 'prize', 'purchase')) NOT NULL,
+  -- Gift card code or transaction ID the entry results from
+  reference VARCHAR(60),
+  note VARCHAR(400),
+  time_created TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+CREATE TABLE IF NOT EXISTS giftcards (
+  id VARCHAR(60) NOT NULL PRIMARY KEY,
+  currency TEXT NOT NULL,
+  amount UNSIGNED BIG INT NOT NULL,
+  note VARCHAR(400),
+  time_created TIMESTAMP NOT NULL,
+  redeemed_by VARCHAR(60),
+  time_redeemed TIMESTAMP
+);
+
+-- Amount of the transaction paid with store credit
+ALTER TABLE transactions ADD COLUMN credit UNSIGNED BIG INT NOT NULL DEFAULT 0;
@@ -0,0 +1,25 @@
+-- Your SQL goes here
+-- Address book of users
+CREATE TABLE IF NOT EXISTS addresses (
+  id VARCHAR(60) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(60) NOT NULL,
+  recipient TEXT NOT NULL,
+  phone TEXT NOT NULL,
+  school TEXT NOT NULL,
+  pickup_point TEXT NOT NULL,
+  country TEXT NOT NULL,
+  is_default BOOLEAN NOT NULL,
+  time_created TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+-- The address an order ships to, as it was at checkout
+CREATE TABLE IF NOT EXISTS orderaddresses (
+  transaction_id VARCHAR(60) NOT NULL PRIMARY KEY,
+  recipient TEXT NOT NULL,
+  phone TEXT NOT NULL,
+  school TEXT NOT NULL,
+  pickup_point TEXT NOT NULL,
+  country TEXT NOT NULL,
+  FOREIGN KEY (transaction_id) REFERENCES transactions(id)
+);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS messages_unread;
+ALTER TABLE messages DROP COLUMN time_read;
@@ -0,0 +1,546 @@
+// Address books of users, and the snapshots of the addresses orders ship to.
+// Snapshots are taken at checkout so that later changes to the address book don't alter placed orders.
+
+use crate::{
+    error::{SailsDbError, SailsDbResult as Result},
+    schema::{addresses, orderaddresses},
+    transactions::TransactionId,
+    users::UserId,
+};
+use chrono::naive::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
+
+// Upper bound on the length of every field
+const MAX_FIELD_LEN: usize = 128;
+
+// A structured shipping address, validated before being saved
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressForm {
+    pub recipient: String,
+    pub phone: String,
+    // School or campus
+    pub school: String,
+    // Where the parcel is picked up, e.g. a dormitory or a classroom. Optional.
+    pub pickup_point: String,
+    pub country: String,
+}
+
+impl AddressForm {
+    pub fn new(
+        recipient: impl ToString,
+        phone: impl ToString,
+        school: impl ToString,
+        pickup_point: impl ToString,
+        country: impl ToString,
+    ) -> Self {
+        Self {
+            recipient: recipient.to_string().trim().to_string(),
+            phone: phone.to_string().trim().to_string(),
+            school: school.to_string().trim().to_string(),
+            pickup_point: pickup_point.to_string().trim().to_string(),
+            country: country.to_string().trim().to_string(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for (field, value, required) in [
+            ("recipient", &self.recipient, true),
+            ("phone", &self.phone, true),
+            ("school", &self.school, true),
+            ("pickup point", &self.pickup_point, false),
+            ("country", &self.country, true),
+        ] {
+            if required && value.is_empty() {
+                return Err(SailsDbError::InvalidAddress(format!(
+                    "{} is missing",
+                    field
+                )));
+            }
+            if value.chars().count() > MAX_FIELD_LEN {
+                return Err(SailsDbError::InvalidAddress(format!(
+                    "{} is too long",
+                    field
+                )));
+            }
+        }
+        // Phone numbers may come with a country code and separators
+        let digits = self.phone.chars().filter(char::is_ascii_digit).count();
+        if !self
+            .phone
+            .chars()
+            .all(|c| c.is_ascii_digit() || " +-()".contains(c))
+            || !(5..=20).contains(&digits)
+        {
+            return Err(SailsDbError::InvalidAddress(
+                "phone is not a valid phone number".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Rendered on a single line, which is what `TransactionInfo::get_address` holds
+impl fmt::Display for AddressForm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}), {}", self.recipient, self.phone, self.school)?;
+        if !self.pickup_point.is_empty() {
+            write!(f, ", {}", self.pickup_point)?;
+        }
+        write!(f, ", {}", self.country)
+    }
+}
+
+// A psuedo struct for managing address books
+pub struct Addresses;
+
+impl Addresses {
+    // The first address of the user becomes the default one
+    pub fn create(conn: &SqliteConnection, user: &UserId, form: &AddressForm) -> Result<Address> {
+        use crate::schema::addresses::dsl::*;
+        form.validate()?;
+        let first = Self::list_by_user(conn, user)?.is_empty();
+        let address = Address {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.get_id().to_string(),
+            recipient: form.recipient.clone(),
+            phone: form.phone.clone(),
+            school: form.school.clone(),
+            pickup_point: form.pickup_point.clone(),
+            country: form.country.clone(),
+            is_default: first,
+            time_created: chrono::offset::Local::now().naive_utc(),
+        };
+        diesel::insert_into(addresses)
+            .values(&address)
+            .execute(conn)?;
+        Ok(address)
+    }
+
+    // Addresses of the user, the default one first
+    pub fn list_by_user(conn: &SqliteConnection, user: &UserId) -> Result<Vec<Address>> {
+        use crate::schema::addresses::dsl::*;
+        Ok(addresses
+            .filter(user_id.eq(user.get_id()))
+            .order((is_default.desc(), time_created.desc()))
+            .load::<Address>(conn)?)
+    }
+
+    // Find an address of the user by its ID
+    pub fn find(conn: &SqliteConnection, user: &UserId, id_p: &str) -> Result<Address> {
+        use crate::schema::addresses::dsl::*;
+        Ok(addresses
+            .filter(user_id.eq(user.get_id()))
+            .filter(id.eq(id_p))
+            .first::<Address>(conn)?)
+    }
+
+    pub fn default_of(conn: &SqliteConnection, user: &UserId) -> Result<Option<Address>> {
+        use crate::schema::addresses::dsl::*;
+        Ok(addresses
+            .filter(user_id.eq(user.get_id()))
+            .filter(is_default.eq(true))
+            .first::<Address>(conn)
+            .optional()?)
+    }
+
+    pub fn delete_by_user(conn: &SqliteConnection, user: &UserId) -> Result<usize> {
+        use crate::schema::addresses::dsl::*;
+        Ok(diesel::delete(addresses.filter(user_id.eq(user.get_id()))).execute(conn)?)
+    }
+}
+
+/// An address in the address book, corresponding to a row in the table `addresses`
+#[derive(Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, Clone)]
+#[table_name = "addresses"]
+pub struct Address {
+    id: String,
+    user_id: String,
+    recipient: String,
+    phone: String,
+    school: String,
+    pickup_point: String,
+    country: String,
+    is_default: bool,
+    time_created: NaiveDateTime,
+}
+
+impl Address {
+    pub fn update(self, conn: &SqliteConnection, form: &AddressForm) -> Result<Self> {
+        use crate::schema::addresses::dsl::*;
+        form.validate()?;
+        diesel::update(addresses.filter(id.eq(&self.id)))
+            .set((
+                recipient.eq(&form.recipient),
+                phone.eq(&form.phone),
+                school.eq(&form.school),
+                pickup_point.eq(&form.pickup_point),
+                country.eq(&form.country),
+            ))
+            .execute(conn)?;
+        Ok(addresses.filter(id.eq(&self.id)).first::<Address>(conn)?)
+    }
+
+    pub fn set_default(self, conn: &SqliteConnection) -> Result<Self> {
+        use crate::schema::addresses::dsl::*;
+        conn.transaction::<_, SailsDbError, _>(|| {
+            diesel::update(addresses.filter(user_id.eq(&self.user_id)))
+                .set(is_default.eq(false))
+                .execute(conn)?;
+            diesel::update(addresses.filter(id.eq(&self.id)))
+                .set(is_default.eq(true))
+                .execute(conn)?;
+            Ok(Self {
+                is_default: true,
+                ..self
+            })
+        })
+    }
+
+    // If the default address is deleted, the most recent one left becomes the default
+    pub fn delete(self, conn: &SqliteConnection) -> Result<()> {
+        use crate::schema::addresses::dsl::*;
+        conn.transaction::<_, SailsDbError, _>(|| {
+            diesel::delete(addresses.filter(id.eq(&self.id))).execute(conn)?;
+            if self.is_default {
+                if let Some(next) = addresses
+                    .filter(user_id.eq(&self.user_id))
+                    .order(time_created.desc())
+                    .first::<Address>(conn)
+                    .optional()?
+                {
+                    next.set_default(conn)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    pub fn to_form(&self) -> AddressForm {
+        AddressForm {
+            recipient: self.recipient.clone(),
+            phone: self.phone.clone(),
+            school: self.school.clone(),
+            pickup_point: self.pickup_point.clone(),
+            country: self.country.clone(),
+        }
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn get_recipient(&self) -> &str {
+        &self.recipient
+    }
+
+    pub fn get_phone(&self) -> &str {
+        &self.phone
+    }
+
+    pub fn get_school(&self) -> &str {
+        &self.school
+    }
+
+    pub fn get_pickup_point(&self) -> &str {
+        &self.pickup_point
+    }
+
+    pub fn get_country(&self) -> &str {
+        &self.country
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.is_default
+    }
+
+    pub fn get_time_created(&self) -> &NaiveDateTime {
+        &self.time_created
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_form().fmt(f)
+    }
+}
+
+// A psuedo struct for managing the addresses of orders
+pub struct OrderAddresses;
+
+impl OrderAddresses {
+    pub(crate) fn snapshot(
+        conn: &SqliteConnection,
+        tx: &TransactionId,
+        form: &AddressForm,
+    ) -> Result<OrderAddress> {
+        use crate::schema::orderaddresses::dsl::*;
+        form.validate()?;
+        let address = OrderAddress {
+            transaction_id: tx.get_id().to_string(),
+            recipient: form.recipient.clone(),
+            phone: form.phone.clone(),
+            school: form.school.clone(),
+            pickup_point: form.pickup_point.clone(),
+            country: form.country.clone(),
+        };
+        diesel::insert_into(orderaddresses)
+            .values(&address)
+            .execute(conn)?;
+        Ok(address)
+    }
+
+    // Orders without any shipping address, e.g. digital ones, have none
+    pub fn of(conn: &SqliteConnection, tx: &TransactionId) -> Result<Option<OrderAddress>> {
+        use crate::schema::orderaddresses::dsl::*;
+        Ok(orderaddresses
+            .filter(transaction_id.eq(tx.get_id()))
+            .first::<OrderAddress>(conn)
+            .optional()?)
+    }
+
+    pub(crate) fn delete_by_transactions(conn: &SqliteConnection, ids: &[String]) -> Result<usize> {
+        use crate::schema::orderaddresses::dsl::*;
+        Ok(diesel::delete(orderaddresses.filter(transaction_id.eq_any(ids))).execute(conn)?)
+    }
+}
+
+/// The address an order ships to, corresponding to a row in the table `orderaddresses`
+#[derive(Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, Clone)]
+#[primary_key(transaction_id)]
+#[table_name = "orderaddresses"]
+pub struct OrderAddress {
+    transaction_id: String,
+    recipient: String,
+    phone: String,
+    school: String,
+    pickup_point: String,
+    country: String,
+}
+
+impl OrderAddress {
+    pub fn get_transaction_id(&self) -> &str {
+        &self.transaction_id
+    }
+
+    pub fn get_recipient(&self) -> &str {
+        &self.recipient
+    }
+
+    pub fn get_phone(&self) -> &str {
+        &self.phone
+    }
+
+    pub fn get_school(&self) -> &str {
+        &self.school
+    }
+
+    pub fn get_pickup_point(&self) -> &str {
+        &self.pickup_point
+    }
+
+    pub fn get_country(&self) -> &str {
+        &self.country
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        categories::{Category, CtgTrait},
+        enums::{Currency, Payment, ProductStatus},
+        products::IncompleteProduct,
+        test_utils::establish_connection,
+        transactions::Transactions,
+        users::UserForm,
+    };
+
+    #[test]
+    fn address_book() {
+        let conn = establish_connection();
+        let user = UserForm::new("TestUser@example.org", "Kanyang Ying", "NFLS", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+        assert!(Addresses::default_of(&conn, &user).unwrap().is_none());
+
+        let dorm = Addresses::create(
+            &conn,
+            &user,
+            &AddressForm::new(
+                "Kanyang Ying",
+                "+86 138-0000-0000",
+                "NFLS",
+                "Dormitory 3",
+                "China",
+            ),
+        )
+        .unwrap();
+        // The first address is the default
+        assert!(dorm.is_default());
+        assert_eq!(
+            dorm.to_string(),
+            "Kanyang Ying (+86 138-0000-0000), NFLS, Dormitory 3, China"
+        );
+
+        let classroom = Addresses::create(
+            &conn,
+            &user,
+            &AddressForm::new("Kanyang Ying", "13800000000", "NFLS", "S2202", "China"),
+        )
+        .unwrap();
+        assert!(!classroom.is_default());
+        let classroom = classroom.set_default(&conn).unwrap();
+        assert_eq!(
+            Addresses::default_of(&conn, &user)
+                .unwrap()
+                .unwrap()
+                .get_id(),
+            classroom.get_id()
+        );
+        // Exactly one default address
+        assert_eq!(
+            Addresses::list_by_user(&conn, &user)
+                .unwrap()
+                .iter()
+                .filter(|x| x.is_default())
+                .count(),
+            1
+        );
+
+        // Invalid addresses are rejected
+        for form in [
+            AddressForm::new("", "13800000000", "NFLS", "", "China"),
+            AddressForm::new("Kanyang Ying", "call me", "NFLS", "", "China"),
+            AddressForm::new("Kanyang Ying", "1234", "NFLS", "", "China"),
+            AddressForm::new("Kanyang Ying", "13800000000", "", "", "China"),
+        ] {
+            assert!(matches!(
+                Addresses::create(&conn, &user, &form).err().unwrap(),
+                SailsDbError::InvalidAddress(_)
+            ));
+        }
+
+        // Deleting the default one promotes another one
+        classroom.delete(&conn).unwrap();
+        assert_eq!(
+            Addresses::default_of(&conn, &user)
+                .unwrap()
+                .unwrap()
+                .get_id(),
+            dorm.get_id()
+        );
+        let dorm = Addresses::find(&conn, &user, dorm.get_id())
+            .unwrap()
+            .update(
+                &conn,
+                &AddressForm::new("Kanyang Ying", "13800000000", "NFLS", "", "China"),
+            )
+            .unwrap();
+        assert_eq!(dorm.get_pickup_point(), "");
+        assert!(dorm.is_default());
+    }
+
+    #[test]
+    fn order_snapshot() {
+        let conn = establish_connection();
+        let seller = UserForm::new("TestUser@example.org", "Kanyang Ying", "NFLS", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+        let buyer = UserForm::new("TestUser2@example.org", "Mick Zhang", "NFLS", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+        let econ = Category::create(&conn, "Economics", 1)
+            .and_then(Category::into_leaf)
+            .unwrap();
+        let book =
+            IncompleteProduct::new(&econ, "Economics", 100, 2, "A horrible book", Currency::CNY)
+                .unwrap()
+                .create(&conn, &seller)
+                .unwrap();
+        book.get_info(&conn)
+            .unwrap()
+            .set_product_status(ProductStatus::Verified)
+            .update(&conn)
+            .unwrap();
+
+        let address = Addresses::create(
+            &conn,
+            &buyer,
+            &AddressForm::new("Mick Zhang", "13800000000", "NFLS", "S2202", "China"),
+        )
+        .unwrap();
+        // Addresses of others cannot be used
+        let others = Addresses::create(
+            &conn,
+            &seller,
+            &AddressForm::new("Kanyang Ying", "13800000000", "NFLS", "", "China"),
+        )
+        .unwrap();
+        assert!(matches!(
+            Transactions::buy_to_address(
+                &conn,
+                &book,
+                &buyer,
+                1,
+                Some(&others),
+                &[],
+                Payment::Alipay
+            )
+            .err()
+            .unwrap(),
+            SailsDbError::InvalidAddress(_)
+        ));
+
+        let tx = Transactions::buy_to_address(
+            &conn,
+            &book,
+            &buyer,
+            1,
+            Some(&address),
+            &[],
+            Payment::Alipay,
+        )
+        .unwrap();
+        assert_eq!(
+            tx.get_info(&conn).unwrap().get_address(),
+            "Mick Zhang (13800000000), NFLS, S2202, China"
+        );
+        // The snapshot is unaffected by later changes to the address book
+        address
+            .update(
+                &conn,
+                &AddressForm::new("Mick Zhang", "13800000000", "NFLS", "Dormitory 3", "China"),
+            )
+            .unwrap();
+        let snapshot = OrderAddresses::of(&conn, &tx).unwrap().unwrap();
+        assert_eq!(snapshot.get_pickup_point(), "S2202");
+
+        // Orders may go without an address
+        let tx = Transactions::buy_to_address(&conn, &book, &buyer, 1, None, &[], Payment::Alipay)
+            .unwrap();
+        assert!(OrderAddresses::of(&conn, &tx).unwrap().is_none());
+
+        // The snapshot is dropped along with the personal data of the buyer
+        buyer.delete(&conn).unwrap();
+        assert_eq!(
+            orderaddresses::table
+                .count()
+                .get_result::<i64>(&conn)
+                .unwrap(),
+            0
+        );
+    }
+}
@@ -3,7 +3,7 @@ use crate::{
     schema::categories,
 };
 use delegate_attr::delegate;
-use diesel::prelude::*;
+use diesel::{prelude::*, sql_types::Text};
 use rocket::FromForm;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, num::NonZeroU32, sync::Arc};
@@ -67,6 +67,39 @@ impl Categories {
         }
     }
 
+    // The category itself and all the categories below it, resolved in a single recursive query.
+    pub fn subtree(conn: &SqliteConnection, root: &impl CtgTrait) -> Result<Vec<Category>> {
+        Ok(diesel::sql_query(
+            "WITH RECURSIVE subtree(id) AS (
+                SELECT id FROM categories WHERE id = ?
+                UNION
+                SELECT categories.id FROM categories JOIN subtree ON categories.parent_id = subtree.id
+            )
+            SELECT categories.* FROM categories JOIN subtree ON categories.id = subtree.id
+            ORDER BY categories.priority ASC",
+        )
+        .bind::<Text, _>(root.id())
+        .load::<Category>(conn)?)
+    }
+
+    // All the ancestors of the category, starting from the top level one (the category itself excluded).
+    // This is the path used in breadcrumbs.
+    pub fn ancestors(conn: &SqliteConnection, node: &impl CtgTrait) -> Result<Vec<Category>> {
+        Ok(diesel::sql_query(
+            "WITH RECURSIVE ancestors(id, parent_id, depth) AS (
+                SELECT id, parent_id, 0 FROM categories WHERE id = ?
+                UNION
+                SELECT categories.id, categories.parent_id, ancestors.depth + 1
+                FROM categories JOIN ancestors ON categories.id = ancestors.parent_id
+            )
+            SELECT categories.* FROM categories JOIN ancestors ON categories.id = ancestors.id
+            WHERE ancestors.depth > 0
+            ORDER BY ancestors.depth DESC",
+        )
+        .bind::<Text, _>(node.id())
+        .load::<Category>(conn)?)
+    }
+
     pub fn find_by_id(conn: &SqliteConnection, id_provided: &str) -> Result<Category> {
         use crate::schema::categories::dsl::*;
         Ok(categories
@@ -146,7 +179,16 @@ impl CtgTrait for LeafCategory {
 }
 
 #[derive(
-    Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, AsChangeset, Clone, FromForm,
+    Debug,
+    Serialize,
+    Deserialize,
+    Queryable,
+    QueryableByName,
+    Identifiable,
+    Insertable,
+    AsChangeset,
+    Clone,
+    FromForm,
 )]
 #[table_name = "categories"]
 pub struct Category {
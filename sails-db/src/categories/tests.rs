@@ -94,3 +94,34 @@ fn manipulate_category() {
     assert_eq!(books.subcategory(&conn).unwrap().len(), 2);
     assert_eq!(economics.subcategory(&conn).unwrap().len(), 0);
 }
+
+#[test]
+fn subtree_and_ancestors() {
+    let conn = establish_connection();
+    let mut knowledge = Category::create(&conn, "Knowledge", 1).unwrap();
+    let mut nonelec = Category::create(&conn, "Non-electronic", 1).unwrap();
+    let mut books = Category::create(&conn, "Books", 1).unwrap();
+    let mut economics = Category::create(&conn, "Economics", 2).unwrap();
+    let mut physics = Category::create(&conn, "Physics", 1).unwrap();
+
+    // Knowledge -> Non-electronic -> Books -> (Econ, Phys)
+    nonelec.insert(&conn, &mut knowledge).unwrap();
+    books.insert(&conn, &mut nonelec).unwrap();
+    economics.insert(&conn, &mut books).unwrap();
+    physics.insert(&conn, &mut books).unwrap();
+
+    // Subtree includes the node itself
+    assert_eq!(Categories::subtree(&conn, &knowledge).unwrap().len(), 5);
+    assert_eq!(Categories::subtree(&conn, &books).unwrap().len(), 3);
+    let leaf_subtree = Categories::subtree(&conn, &physics).unwrap();
+    assert_eq!(leaf_subtree.len(), 1);
+    assert_eq!(leaf_subtree[0].id(), physics.id());
+
+    // Ancestors are ordered from the top level
+    let path = Categories::ancestors(&conn, &economics).unwrap();
+    assert_eq!(
+        path.iter().map(|c| c.name()).collect::<Vec<&str>>(),
+        vec!["Knowledge", "Non-electronic", "Books"]
+    );
+    assert!(Categories::ancestors(&conn, &knowledge).unwrap().is_empty());
+}
@@ -3,28 +3,201 @@
 // All discount amount returned by coupon script should NOT exceed the original total and NEVER be negative
 
 use crate::{
+    categories::{Categories, CtgTrait},
+    enums::{CouponStacking, Currency, Payment, TransactionStatus},
     error::{SailsDbError, SailsDbResult as Result},
     products::ProductInfo,
-    schema::coupons,
-    script::CouponPackage,
-    users::UserInfo,
+    schema::{couponapplications, coupons, transactions},
+    script,
+    tags::TagMappingFinder,
+    transactions::{PurchaseHistory, TransactionFinder, TransactionInfo},
+    users::{UserId, UserInfo},
+    Cmp,
+};
+use chrono::{
+    naive::{NaiveDate, NaiveDateTime},
+    offset::Local,
+    TimeZone,
 };
 use diesel::{prelude::*, sqlite::Sqlite};
-use rhai::{packages::Package, Engine, Scope};
+use rhai::{Array, Dynamic, EvalAltResult, ImmutableString, Position, Scope};
 use rocket::FromForm;
 use rust_decimal::{prelude::*, Decimal};
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryFrom,
+};
+
+// The format used by HTML `datetime-local` inputs
+const TIME_INPUT_FMT: &str = "%Y-%m-%dT%H:%M";
 
 // A pseudo struct for managing the coupons table.
 pub struct Coupons;
 
+impl Coupons {
+    // Usage of every coupon ever applied, including the deleted ones. Most used coupons come first.
+    pub fn stats(conn: &SqliteConnection) -> Result<Vec<CouponStats>> {
+        let mut stats: HashMap<String, CouponStats> = HashMap::new();
+        for (coupon_id, discount, tx) in Self::applications(conn, None)? {
+            stats
+                .entry(coupon_id.clone())
+                .or_insert_with(|| CouponStats::new(coupon_id))
+                .add(discount, &tx)?;
+        }
+        let mut stats = stats.into_values().collect::<Vec<CouponStats>>();
+        stats.sort_by(|a, b| b.uses.cmp(&a.uses).then_with(|| a.coupon.cmp(&b.coupon)));
+        Ok(stats)
+    }
+
+    pub fn stats_of(conn: &SqliteConnection, coupon_id: &str) -> Result<CouponStats> {
+        let mut stats = CouponStats::new(coupon_id);
+        for (_, discount, tx) in Self::applications(conn, Some(coupon_id))? {
+            stats.add(discount, &tx)?;
+        }
+        Ok(stats)
+    }
+
+    // Usage of the coupon aggregated by the day (in UTC) the orders were placed, in chronological order.
+    pub fn daily_stats(
+        conn: &SqliteConnection,
+        coupon_id: &str,
+    ) -> Result<Vec<(NaiveDate, CouponStats)>> {
+        let mut stats: BTreeMap<NaiveDate, CouponStats> = BTreeMap::new();
+        for (_, discount, tx) in Self::applications(conn, Some(coupon_id))? {
+            stats
+                .entry(tx.get_time_sent().date())
+                .or_insert_with(|| CouponStats::new(coupon_id))
+                .add(discount, &tx)?;
+        }
+        Ok(stats.into_iter().collect())
+    }
+
+    // Coupons applied along with the discount and the transaction they were applied to
+    fn applications(
+        conn: &SqliteConnection,
+        coupon_id: Option<&str>,
+    ) -> Result<Vec<(String, i64, TransactionInfo)>> {
+        let mut query = couponapplications::table
+            .inner_join(transactions::table)
+            .select((
+                couponapplications::coupon,
+                couponapplications::discount,
+                transactions::all_columns,
+            ))
+            // The built-in coupon is only a marker of no coupon applied
+            .filter(couponapplications::coupon.ne("_BUILTIN_"))
+            .into_boxed();
+        if let Some(c) = coupon_id {
+            query = query.filter(couponapplications::coupon.eq(c));
+        }
+        Ok(query.load::<(String, i64, TransactionInfo)>(conn)?)
+    }
+}
+
+// Performance of a coupon. Only orders that have been paid and not refunded count towards discount and revenue.
+#[derive(Debug, Clone, Default)]
+pub struct CouponStats {
+    pub coupon: String,
+    // Number of orders the coupon was applied to, regardless of their status
+    pub uses: usize,
+    // Number of orders refunded after payment. Orders cancelled before payment are not counted.
+    pub refunded: usize,
+    // Number of orders that have been paid, including the refunded ones
+    paid: usize,
+    buyers: HashSet<String>,
+    // Keyed by the currency
+    discount: HashMap<Currency, i64>,
+    revenue: HashMap<Currency, i64>,
+}
+
+impl CouponStats {
+    fn new(coupon: impl ToString) -> Self {
+        Self {
+            coupon: coupon.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, discount: i64, tx: &TransactionInfo) -> Result<()> {
+        self.uses += 1;
+        self.buyers.insert(tx.get_buyer().to_string());
+        match tx.get_transaction_status() {
+            TransactionStatus::Refunded if tx.is_paid() => {
+                self.paid += 1;
+                self.refunded += 1;
+            }
+            TransactionStatus::Paid | TransactionStatus::Finished => {
+                self.paid += 1;
+                *self.discount.entry(tx.get_currency().clone()).or_default() += discount;
+                *self.revenue.entry(tx.get_currency().clone()).or_default() +=
+                    i64::try_from(tx.get_total()).map_err(|_| SailsDbError::Overflow)?;
+            }
+            TransactionStatus::Placed | TransactionStatus::Refunded => {}
+        }
+        Ok(())
+    }
+
+    pub fn get_unique_buyers(&self) -> usize {
+        self.buyers.len()
+    }
+
+    // Orders refunded after payment over all orders ever paid, between 0 and 1.
+    // Orders still unpaid, or cancelled before payment, are not counted.
+    pub fn get_refund_rate(&self) -> f64 {
+        if self.paid == 0 {
+            0.0
+        } else {
+            self.refunded as f64 / self.paid as f64
+        }
+    }
+
+    // Total discount granted in each currency, sorted by the currency
+    pub fn get_discount(&self) -> Vec<(Currency, i64)> {
+        Self::sorted(&self.discount)
+    }
+
+    // Total of the orders in each currency, sorted by the currency
+    pub fn get_revenue(&self) -> Vec<(Currency, i64)> {
+        Self::sorted(&self.revenue)
+    }
+
+    fn sorted(amounts: &HashMap<Currency, i64>) -> Vec<(Currency, i64)> {
+        let mut amounts = amounts
+            .iter()
+            .map(|(c, a)| (c.clone(), *a))
+            .collect::<Vec<(Currency, i64)>>();
+        amounts.sort_by_key(|(c, _)| format!("{:?}", c));
+        amounts
+    }
+}
+
 #[derive(
-    Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, AsChangeset, Clone, FromForm,
+    Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, AsChangeset, Clone,
 )]
 #[table_name = "coupons"]
+// We need to be able to lift restrictions
+#[changeset_options(treat_none_as_null = "true")]
 pub struct Coupon {
     id: String,
     script: String,
+    description: Option<String>,
+    // Both are in UTC
+    valid_from: Option<NaiveDateTime>,
+    valid_until: Option<NaiveDateTime>,
+    max_total_uses: Option<i64>,
+    max_buyer_uses: Option<i64>,
+    // In the currency of the product being purchased
+    min_subtotal: Option<i64>,
+    // Comma-separated IDs. Categories cover all of their subcategories.
+    allowed_categories: Option<String>,
+    allowed_tags: Option<String>,
+    allowed_sellers: Option<String>,
+    // Bumped on every update to invalidate the compiled script
+    version: i64,
+    stacking: CouponStacking,
+    // Coupons with lower priority are applied first
+    priority: i64,
 }
 
 pub struct CouponContext {
@@ -36,6 +209,117 @@ pub struct CouponContext {
     pub buyer_used: i64,
     // Number of time this coupon has been used in total
     pub total_used: i64,
+    // Tag IDs of the product
+    pub tags: Vec<String>,
+    // Category IDs from the top level down to the product's category
+    pub category_path: Vec<String>,
+    pub history: PurchaseHistory,
+    pub payment: Payment,
+    pub currency: Currency,
+    // In UTC
+    pub now: NaiveDateTime,
+    // Price times quantity of the whole order
+    pub subtotal: i64,
+    // Discount given by the coupons applied before this one
+    pub discounted: i64,
+}
+
+impl CouponContext {
+    pub fn build(
+        conn: &SqliteConnection,
+        coupon: &Coupon,
+        product: &ProductInfo,
+        buyer: &UserId,
+        quantity: u32,
+        payment: &Payment,
+    ) -> Result<Self> {
+        let prod_id = product.to_id();
+        let ctg = Categories::find_by_id(conn, product.get_category_id())?;
+        let mut category_path = Categories::ancestors(conn, &ctg)?
+            .iter()
+            .map(|c| c.id().to_string())
+            .collect::<Vec<String>>();
+        category_path.push(ctg.id().to_string());
+
+        Ok(Self {
+            buyer: buyer.get_info(conn)?,
+            seller: UserId::find(conn, product.get_seller_id())?.get_info(conn)?,
+            product: product.clone(),
+            quantity: quantity as i64,
+            buyer_used: TransactionFinder::new(conn, None)
+                .buyer(buyer)
+                .coupon(coupon.get_id())
+                .status(TransactionStatus::Refunded, Cmp::NotEqual)
+                .count_i64()?,
+            total_used: TransactionFinder::new(conn, None)
+                .coupon(coupon.get_id())
+                .status(TransactionStatus::Refunded, Cmp::NotEqual)
+                .count_i64()?,
+            tags: TagMappingFinder::new(conn, None)
+                .product(&prod_id)
+                .search()?
+                .iter()
+                .map(|m| m.get_tag().to_string())
+                .collect(),
+            category_path,
+            history: PurchaseHistory::of(conn, buyer)?,
+            payment: payment.clone(),
+            currency: product.get_currency().clone(),
+            now: Local::now().naive_utc(),
+            subtotal: (product.get_price() as i64) * (quantity as i64),
+            discounted: 0,
+        })
+    }
+}
+
+fn split_ids(ids: &Option<String>) -> Vec<&str> {
+    ids.as_deref()
+        .map(|s| {
+            s.split(',')
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn join_ids(ids: &[impl ToString]) -> Option<String> {
+    if ids.is_empty() {
+        None
+    } else {
+        Some(
+            ids.iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>()
+                .join(","),
+        )
+    }
+}
+
+// Local time as entered by admins to UTC
+fn parse_time_input(s: &str) -> Result<Option<NaiveDateTime>> {
+    if s.trim().is_empty() {
+        return Ok(None);
+    }
+    let local = NaiveDateTime::parse_from_str(s.trim(), TIME_INPUT_FMT)?;
+    Ok(Some(
+        Local
+            .from_local_datetime(&local)
+            .earliest()
+            .ok_or(SailsDbError::IllegalQuery)?
+            .naive_utc(),
+    ))
+}
+
+// UTC to the local time displayed to admins
+fn format_time_input(t: &Option<NaiveDateTime>) -> String {
+    t.map(|t| {
+        Local
+            .from_utc_datetime(&t)
+            .format(TIME_INPUT_FMT)
+            .to_string()
+    })
+    .unwrap_or_default()
 }
 
 impl Coupon {
@@ -43,6 +327,18 @@ impl Coupon {
         Self {
             id: id.to_string(),
             script: script.to_string(),
+            description: None,
+            valid_from: None,
+            valid_until: None,
+            max_total_uses: None,
+            max_buyer_uses: None,
+            min_subtotal: None,
+            allowed_categories: None,
+            allowed_tags: None,
+            allowed_sellers: None,
+            version: 0,
+            stacking: CouponStacking::Exclusive,
+            priority: 0,
         }
     }
 
@@ -51,31 +347,24 @@ impl Coupon {
         id_p: impl ToString,
         script_p: impl ToString,
     ) -> Result<Self> {
-        use crate::schema::coupons::dsl::*;
-        let value = Self {
-            id: id_p.to_string(),
-            script: script_p.to_string(),
-        };
-
-        // We don't allow creating reserved coupons
-        if (value.get_id() == "_NO_COUPON_APPLIED_") || (value.get_id() == "_BUILTIN_") {
-            return Err(SailsDbError::CouponIDReserved);
-        }
-
-        diesel::insert_into(coupons)
-            .values(value.clone())
-            .execute(conn)?;
+        let value = Self::new_without_db(id_p, script_p);
+        value.create(conn)?;
         Ok(value)
     }
 
     pub fn create(&self, conn: &SqliteConnection) -> Result<usize> {
         use crate::schema::coupons::dsl::*;
+        // We don't allow creating reserved coupons
+        if (self.get_id() == "_NO_COUPON_APPLIED_") || (self.get_id() == "_BUILTIN_") {
+            return Err(SailsDbError::CouponIDReserved);
+        }
         Ok(diesel::insert_into(coupons).values(self).execute(conn)?)
     }
 
     pub fn delete(self, conn: &SqliteConnection) -> Result<()> {
         use crate::schema::coupons::dsl::*;
         diesel::delete(coupons.filter(id.eq(&self.id))).execute(conn)?;
+        crate::script::invalidate(&self.id);
         Ok(())
     }
 
@@ -87,15 +376,183 @@ impl Coupon {
         &self.script
     }
 
+    pub fn get_version(&self) -> i64 {
+        self.version
+    }
+
+    // Check that the script compiles. The compiled script is not cached, as the coupon may not be saved yet.
+    pub fn compile(&self) -> Result<()> {
+        script::check(&self.script)
+    }
+
+    pub fn get_stacking(&self) -> &CouponStacking {
+        &self.stacking
+    }
+
+    pub fn get_priority(&self) -> i64 {
+        self.priority
+    }
+
+    pub fn get_description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub fn get_valid_from(&self) -> Option<&NaiveDateTime> {
+        self.valid_from.as_ref()
+    }
+
+    pub fn get_valid_until(&self) -> Option<&NaiveDateTime> {
+        self.valid_until.as_ref()
+    }
+
+    // Values to be filled in the `datetime-local` inputs
+    pub fn get_valid_from_input(&self) -> String {
+        format_time_input(&self.valid_from)
+    }
+
+    pub fn get_valid_until_input(&self) -> String {
+        format_time_input(&self.valid_until)
+    }
+
+    pub fn get_max_total_uses(&self) -> Option<i64> {
+        self.max_total_uses
+    }
+
+    pub fn get_max_buyer_uses(&self) -> Option<i64> {
+        self.max_buyer_uses
+    }
+
+    pub fn get_min_subtotal(&self) -> Option<i64> {
+        self.min_subtotal
+    }
+
+    pub fn get_allowed_categories(&self) -> Vec<&str> {
+        split_ids(&self.allowed_categories)
+    }
+
+    pub fn get_allowed_tags(&self) -> Vec<&str> {
+        split_ids(&self.allowed_tags)
+    }
+
+    pub fn get_allowed_sellers(&self) -> Vec<&str> {
+        split_ids(&self.allowed_sellers)
+    }
+
+    pub fn set_script(mut self, script: impl ToString) -> Self {
+        self.script = script.to_string();
+        self
+    }
+
+    pub fn set_stacking(mut self, stacking: CouponStacking) -> Self {
+        self.stacking = stacking;
+        self
+    }
+
+    pub fn set_priority(mut self, priority: i64) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn set_description(mut self, description: Option<impl ToString>) -> Self {
+        self.description = description.map(|x| x.to_string());
+        self
+    }
+
+    pub fn set_valid_from(mut self, valid_from: Option<NaiveDateTime>) -> Self {
+        self.valid_from = valid_from;
+        self
+    }
+
+    pub fn set_valid_until(mut self, valid_until: Option<NaiveDateTime>) -> Self {
+        self.valid_until = valid_until;
+        self
+    }
+
+    pub fn set_max_total_uses(mut self, max_total_uses: Option<u32>) -> Self {
+        self.max_total_uses = max_total_uses.map(|x| x as i64);
+        self
+    }
+
+    pub fn set_max_buyer_uses(mut self, max_buyer_uses: Option<u32>) -> Self {
+        self.max_buyer_uses = max_buyer_uses.map(|x| x as i64);
+        self
+    }
+
+    pub fn set_min_subtotal(mut self, min_subtotal: Option<u32>) -> Self {
+        self.min_subtotal = min_subtotal.map(|x| x as i64);
+        self
+    }
+
+    pub fn set_allowed_categories(mut self, ids: &[impl ToString]) -> Self {
+        self.allowed_categories = join_ids(ids);
+        self
+    }
+
+    pub fn set_allowed_tags(mut self, ids: &[impl ToString]) -> Self {
+        self.allowed_tags = join_ids(ids);
+        self
+    }
+
+    pub fn set_allowed_sellers(mut self, ids: &[impl ToString]) -> Self {
+        self.allowed_sellers = join_ids(ids);
+        self
+    }
+
+    // Enforce the structured restrictions. These are checked before the script runs.
+    pub fn check(&self, ctx: &CouponContext) -> Result<()> {
+        let now = ctx.now;
+        if matches!(self.valid_from, Some(t) if now < t) {
+            return Err(SailsDbError::CouponNotYetValid);
+        }
+        if matches!(self.valid_until, Some(t) if now > t) {
+            return Err(SailsDbError::CouponExpired);
+        }
+        if matches!(self.max_total_uses, Some(max) if ctx.total_used >= max) {
+            return Err(SailsDbError::CouponUsageExceeded);
+        }
+        if matches!(self.max_buyer_uses, Some(max) if ctx.buyer_used >= max) {
+            return Err(SailsDbError::CouponBuyerUsageExceeded);
+        }
+        if matches!(self.min_subtotal, Some(min) if ctx.subtotal < min) {
+            return Err(SailsDbError::CouponMinSubtotalNotMet);
+        }
+
+        let sellers = self.get_allowed_sellers();
+        if !sellers.is_empty() && !sellers.contains(&ctx.seller.get_id()) {
+            return Err(SailsDbError::CouponNotApplicable);
+        }
+
+        let ctgs = self.get_allowed_categories();
+        // The product is applicable if any category on the path is allowed
+        if !ctgs.is_empty() && !ctx.category_path.iter().any(|c| ctgs.contains(&c.as_str())) {
+            return Err(SailsDbError::CouponNotApplicable);
+        }
+
+        let tags = self.get_allowed_tags();
+        if !tags.is_empty() && !ctx.tags.iter().any(|t| tags.contains(&t.as_str())) {
+            return Err(SailsDbError::CouponNotApplicable);
+        }
+        Ok(())
+    }
+
     pub fn exec(&self, ctx: CouponContext) -> Result<i64> {
-        let mut engine = Engine::new();
-        engine.register_global_module(CouponPackage::new().as_shared_module());
-        // .on_print(|x| log::info!("{}", x))
-        // .on_debug(|x, src, pos| log::debug!("{} at {}: {}", x, src.unwrap_or("unkown"), pos));
+        self.eval(ctx).0
+    }
 
-        // Then turn it into an immutable instance
-        let engine = engine;
+    // Evaluate the coupon against the context without placing any order.
+    // Unlike `exec`, the restrictions are reported but not enforced.
+    pub fn dry_run(&self, ctx: CouponContext) -> DryRun {
+        let restriction = self.check(&ctx);
+        let (discount, operations) = self.eval(ctx);
+        DryRun {
+            restriction,
+            discount,
+            operations,
+        }
+    }
 
+    // Returns the discount and the number of operations consumed
+    fn eval(&self, ctx: CouponContext) -> (Result<i64>, u64) {
         let mut scope = Scope::new();
 
         scope.push_constant("buyer", ctx.buyer);
@@ -104,16 +561,59 @@ impl Coupon {
         scope.push_constant("quantity", ctx.quantity);
         scope.push_constant("buyer_used", ctx.buyer_used);
         scope.push_constant("total_used", ctx.total_used);
+        scope.push_constant(
+            "tags",
+            ctx.tags
+                .into_iter()
+                .map(|t| Dynamic::from(ImmutableString::from(t)))
+                .collect::<Array>(),
+        );
+        scope.push_constant(
+            "category_path",
+            ctx.category_path
+                .into_iter()
+                .map(|c| Dynamic::from(ImmutableString::from(c)))
+                .collect::<Array>(),
+        );
+        scope.push_constant("history", ctx.history);
+        // Payment and currency are displayed in the same way as the product currency, e.g. "Paypal", "CNY"
+        scope.push_constant(
+            "payment",
+            ImmutableString::from(format!("{:?}", ctx.payment)),
+        );
+        scope.push_constant(
+            "currency",
+            ImmutableString::from(format!("{:?}", ctx.currency)),
+        );
+        scope.push_constant("now", ctx.now);
+        scope.push_constant("subtotal", ctx.subtotal);
+        scope.push_constant("discounted", ctx.discounted);
 
-        let ast = engine.compile(&self.script)?;
+        let ast = match script::compile(&self.id, self.version, &self.script) {
+            Ok(ast) => ast,
+            Err(e) => return (Err(e), 0),
+        };
+
+        let (result, operations) = script::eval_with_limits(|| {
+            script::ENGINE.eval_ast_with_scope::<Dynamic>(&mut scope, &ast)
+        });
 
-        // Try both output type: i64 and decimal
-        let result = match engine.eval_ast_with_scope::<i64>(&mut scope, &ast) {
-            Ok(r) => r,
-            Err(_) => engine
-                .eval_ast_with_scope::<Decimal>(&mut scope, &ast)?
-                .to_i64()
-                .ok_or(SailsDbError::Overflow)?,
+        (result.and_then(Self::to_discount), operations)
+    }
+
+    fn to_discount(result: Dynamic) -> Result<i64> {
+        // Accept both output type: i64 and decimal
+        let result = if let Some(r) = result.clone().try_cast::<i64>() {
+            r
+        } else if let Some(r) = result.clone().try_cast::<Decimal>() {
+            r.to_i64().ok_or(SailsDbError::Overflow)?
+        } else {
+            return Err(Box::new(EvalAltResult::ErrorMismatchOutputType(
+                "i64".into(),
+                result.type_name().into(),
+                Position::NONE,
+            ))
+            .into());
         };
 
         // We should always return a non-negative discount
@@ -124,11 +624,96 @@ impl Coupon {
         }
     }
 
-    pub fn update(self, conn: &SqliteConnection) -> Result<Self> {
+    pub fn update(mut self, conn: &SqliteConnection) -> Result<Self> {
+        use crate::schema::coupons::dsl::*;
+        let current = coupons
+            .filter(id.eq(&self.id))
+            .select(version)
+            .first::<i64>(conn)?;
+        self.version = current + 1;
         Ok(self.save_changes::<Coupon>(conn)?)
     }
 }
 
+// Operations a coupon script may consume before being terminated
+pub const MAX_SCRIPT_OPERATIONS: u64 = script::MAX_OPERATIONS;
+
+// Report of a dry run
+#[derive(Debug)]
+pub struct DryRun {
+    pub restriction: Result<()>,
+    pub discount: Result<i64>,
+    pub operations: u64,
+}
+
+// The coupon submitted by admins
+#[derive(Debug, Clone, Default, FromForm)]
+pub struct CouponForm {
+    pub id: String,
+    pub script: String,
+    pub description: String,
+    // Local time in the format of `datetime-local` inputs. Empty means unrestricted.
+    pub valid_from: String,
+    pub valid_until: String,
+    pub max_total_uses: Option<u32>,
+    pub max_buyer_uses: Option<u32>,
+    pub min_subtotal: Option<u32>,
+    // Comma-separated IDs
+    pub allowed_categories: String,
+    pub allowed_tags: String,
+    pub allowed_sellers: String,
+    pub stacking: CouponStacking,
+    pub priority: i64,
+}
+
+impl From<&Coupon> for CouponForm {
+    fn from(coupon: &Coupon) -> Self {
+        Self {
+            id: coupon.id.clone(),
+            script: coupon.script.clone(),
+            description: coupon.description.clone().unwrap_or_default(),
+            valid_from: coupon.get_valid_from_input(),
+            valid_until: coupon.get_valid_until_input(),
+            max_total_uses: coupon.max_total_uses.map(|x| x as u32),
+            max_buyer_uses: coupon.max_buyer_uses.map(|x| x as u32),
+            min_subtotal: coupon.min_subtotal.map(|x| x as u32),
+            allowed_categories: coupon.get_allowed_categories().join(","),
+            allowed_tags: coupon.get_allowed_tags().join(","),
+            allowed_sellers: coupon.get_allowed_sellers().join(","),
+            stacking: coupon.stacking.clone(),
+            priority: coupon.priority,
+        }
+    }
+}
+
+impl CouponForm {
+    pub fn to_coupon(&self) -> Result<Coupon> {
+        let ids = |s: &str| -> Vec<String> {
+            split_ids(&Some(s.to_string()))
+                .into_iter()
+                .map(|x| x.to_string())
+                .collect()
+        };
+        let description = if self.description.trim().is_empty() {
+            None
+        } else {
+            Some(&self.description)
+        };
+        Ok(Coupon::new_without_db(&self.id, &self.script)
+            .set_description(description)
+            .set_valid_from(parse_time_input(&self.valid_from)?)
+            .set_valid_until(parse_time_input(&self.valid_until)?)
+            .set_max_total_uses(self.max_total_uses)
+            .set_max_buyer_uses(self.max_buyer_uses)
+            .set_min_subtotal(self.min_subtotal)
+            .set_allowed_categories(&ids(&self.allowed_categories))
+            .set_allowed_tags(&ids(&self.allowed_tags))
+            .set_allowed_sellers(&ids(&self.allowed_sellers))
+            .set_stacking(self.stacking.clone())
+            .set_priority(self.priority))
+    }
+}
+
 type BoxedQuery<'a> = coupons::BoxedQuery<'a, Sqlite, coupons::SqlType>;
 
 /// A search query helper (builder)
@@ -173,9 +758,9 @@ impl<'a> CouponFinder<'a> {
 mod tests {
     use super::*;
     use crate::{
-        categories::{Category, CtgTrait},
+        categories::{Category, CtgTrait, LeafCategory},
         enums::*,
-        products::IncompleteProduct,
+        products::{IncompleteProduct, ProductId},
         test_utils::establish_connection,
         transactions::*,
         users::*,
@@ -231,7 +816,7 @@ mod tests {
         Coupon::new(
             &conn,
             "100OFF",
-            r#"if buyer.get_id() == "AtypicalBuyer@example.org" { 100 } else { 0 }"#,
+            r#"if buyer.get_email() == "AtypicalBuyer@example.org" { 100 } else { 0 }"#,
         )
         .unwrap();
 
@@ -385,4 +970,387 @@ mod tests {
             SailsDbError::InvalidDiscountAmount
         ));
     }
+
+    fn user(conn: &SqliteConnection, email: &str) -> UserId {
+        UserForm::new(email, "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(conn)
+            .unwrap()
+    }
+
+    // A buyer and a verified book of the seller under "Economics Books"
+    struct Fixture {
+        conn: SqliteConnection,
+        buyer: UserId,
+        econ: LeafCategory,
+        book_id: ProductId,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let conn = establish_connection();
+            let seller = user(&conn, "TestUser@example.org");
+            let buyer = user(&conn, "AtypicalBuyer@example.org");
+            let econ = Category::create(&conn, "Economics Books", 1)
+                .and_then(Category::into_leaf)
+                .unwrap();
+            let book_id = IncompleteProduct::new(
+                &econ,
+                "Krugman's Economics 2nd Edition",
+                700,
+                10,
+                "A very great book on the subject of Economics",
+                crate::enums::Currency::USD,
+            )
+            .unwrap()
+            .create(&conn, &seller)
+            .unwrap();
+            book_id
+                .get_info(&conn)
+                .unwrap()
+                .set_product_status(ProductStatus::Verified)
+                .update(&conn)
+                .unwrap();
+            Self {
+                conn,
+                buyer,
+                econ,
+                book_id,
+            }
+        }
+
+        fn buy(&self, coupon: &str, qty: u32) -> Result<TransactionId> {
+            self.buy_as(&self.buyer, coupon, qty)
+        }
+
+        fn buy_as(&self, buyer: &UserId, coupon: &str, qty: u32) -> Result<TransactionId> {
+            Transactions::buy(
+                &self.conn,
+                &self.book_id,
+                buyer,
+                qty,
+                "258 Huanhu South Road, Dongqian Lake, Ningbo, China",
+                coupon,
+                Payment::Paypal,
+            )
+        }
+
+        fn buy_stacked(&self, coupons: &[&str], qty: u32) -> Result<TransactionId> {
+            Transactions::buy_with_coupons(
+                &self.conn,
+                &self.book_id,
+                &self.buyer,
+                qty,
+                "258 Huanhu South Road, Dongqian Lake, Ningbo, China",
+                coupons,
+                Payment::Paypal,
+            )
+        }
+
+        fn discount(&self, coupon: &str, qty: u32) -> i64 {
+            self.buy(coupon, qty)
+                .unwrap()
+                .get_info(&self.conn)
+                .unwrap()
+                .get_discount()
+        }
+    }
+
+    #[test]
+    fn coupon_restrictions() {
+        let mut fx = Fixture::new();
+        let conn = &fx.conn;
+
+        // Books -> Economics Books
+        let mut books = Category::create(conn, "Books", 1).unwrap();
+        fx.econ.insert(conn, &mut books).unwrap();
+        let phys = Category::create(conn, "Physics Books", 1)
+            .and_then(Category::into_leaf)
+            .unwrap();
+
+        // Unknown coupons
+        assert!(matches!(
+            fx.buy("NOTEXIST", 1).err().unwrap(),
+            SailsDbError::CouponNotFound
+        ));
+
+        // Once per buyer, only for books
+        Coupon::new(conn, "ONCE", "100")
+            .unwrap()
+            .set_max_buyer_uses(Some(1))
+            .set_allowed_categories(&[books.id()])
+            .update(conn)
+            .unwrap();
+        assert_eq!(fx.discount("ONCE", 1), 100);
+        assert!(matches!(
+            fx.buy("ONCE", 1).err().unwrap(),
+            SailsDbError::CouponBuyerUsageExceeded
+        ));
+
+        // Only for physics books
+        Coupon::new(conn, "PHYS", "100")
+            .unwrap()
+            .set_allowed_categories(&[phys.id()])
+            .update(conn)
+            .unwrap();
+        assert!(matches!(
+            fx.buy("PHYS", 1).err().unwrap(),
+            SailsDbError::CouponNotApplicable
+        ));
+
+        // Expired yesterday
+        Coupon::new(conn, "EXPIRED", "100")
+            .unwrap()
+            .set_valid_until(Some(
+                chrono::offset::Local::now().naive_utc() - chrono::Duration::days(1),
+            ))
+            .update(conn)
+            .unwrap();
+        assert!(matches!(
+            fx.buy("EXPIRED", 1).err().unwrap(),
+            SailsDbError::CouponExpired
+        ));
+
+        // Spend 1000 to get 100 off
+        Coupon::new(conn, "SPEND1000", "100")
+            .unwrap()
+            .set_min_subtotal(Some(1000))
+            .update(conn)
+            .unwrap();
+        assert!(matches!(
+            fx.buy("SPEND1000", 1).err().unwrap(),
+            SailsDbError::CouponMinSubtotalNotMet
+        ));
+        assert_eq!(fx.discount("SPEND1000", 2), 100);
+
+        // Default coupon never blocks the purchase
+        Coupon::new(conn, "DEFAULT", "100")
+            .unwrap()
+            .set_allowed_sellers(&["NotTheSeller@example.org"])
+            .update(conn)
+            .unwrap();
+        let tx = fx.buy("", 1).unwrap().get_info(conn).unwrap();
+        assert_eq!(tx.get_coupon(), "_BUILTIN_");
+        assert_eq!(tx.get_discount(), 0);
+    }
+
+    #[test]
+    fn sandboxed_coupons() {
+        let fx = Fixture::new();
+        let conn = &fx.conn;
+
+        // Infinite loops are terminated
+        Coupon::new(conn, "LOOP", "loop {}").unwrap();
+        assert!(matches!(
+            fx.buy("LOOP", 1).err().unwrap(),
+            SailsDbError::ScriptTimeout
+        ));
+
+        // So is unbounded recursion
+        Coupon::new(conn, "RECURSION", "fn f(x) { f(x) } f(1)").unwrap();
+        assert!(matches!(
+            fx.buy("RECURSION", 1).err().unwrap(),
+            SailsDbError::ScriptEvalError(_)
+        ));
+
+        // Parse errors are caught before saving
+        assert!(matches!(
+            Coupon::new_without_db("BROKEN", "let x = ;")
+                .compile()
+                .err()
+                .unwrap(),
+            SailsDbError::ScriptParseError(_)
+        ));
+    }
+
+    #[test]
+    fn cached_scripts() {
+        let fx = Fixture::new();
+        let conn = &fx.conn;
+
+        // Updating the coupon invalidates the compiled script
+        let coupon = Coupon::new(conn, "CACHED", "100").unwrap();
+        assert_eq!(fx.discount("CACHED", 1), 100);
+        let coupon = coupon.set_script("200").update(conn).unwrap();
+        assert_eq!(coupon.get_version(), 1);
+        assert_eq!(fx.discount("CACHED", 1), 200);
+    }
+
+    #[test]
+    fn dry_run() {
+        let fx = Fixture::new();
+        let conn = &fx.conn;
+
+        // Dry runs report restrictions without enforcing them and place no order
+        let book = fx.book_id.get_info(conn).unwrap();
+        let coupon = Coupon::new(conn, "DRY", "let x = 0; for i in 0..10 { x += i } x")
+            .unwrap()
+            .set_max_total_uses(Some(0))
+            .update(conn)
+            .unwrap();
+        let ctx =
+            CouponContext::build(conn, &coupon, &book, &fx.buyer, 1, &Payment::Paypal).unwrap();
+        let report = coupon.dry_run(ctx);
+        assert!(matches!(
+            report.restriction,
+            Err(SailsDbError::CouponUsageExceeded)
+        ));
+        assert_eq!(report.discount.unwrap(), 45);
+        assert!(report.operations > 0);
+        assert_eq!(
+            TransactionFinder::new(conn, None)
+                .coupon("DRY")
+                .count_i64()
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn coupon_stacking() {
+        let fx = Fixture::new();
+        let conn = &fx.conn;
+        let discount = |coupons: &[&str], qty: u32| {
+            fx.buy_stacked(coupons, qty)
+                .unwrap()
+                .get_info(conn)
+                .unwrap()
+                .get_discount()
+        };
+
+        let stackable = |id: &str, script: &str, priority: i64| {
+            Coupon::new_without_db(id, script)
+                .set_stacking(CouponStacking::Stackable)
+                .set_priority(priority)
+                .create(conn)
+                .unwrap();
+        };
+        stackable("10OFF", "10", 1);
+        stackable("HALF", "(subtotal - discounted) / 2", 0);
+        // Spend 1000 get 50 off
+        stackable("SPEND1000", "if subtotal >= 1000 { 50 } else { 0 }", 2);
+        Coupon::new(conn, "EXCLUSIVE", "100").unwrap();
+
+        // Coupons are applied by priority rather than the order entered
+        let tx = fx
+            .buy_stacked(&["10OFF", "HALF"], 1)
+            .unwrap()
+            .get_info(conn)
+            .unwrap();
+        assert_eq!(tx.get_discount(), 350 + 10);
+        assert_eq!(tx.get_coupon(), "HALF,10OFF");
+        let applications = tx.get_coupon_applications(conn).unwrap();
+        assert_eq!(applications.len(), 2);
+        assert_eq!(applications[0].get_coupon(), "HALF");
+        assert_eq!(applications[0].get_discount(), 350);
+        assert_eq!(applications[1].get_coupon(), "10OFF");
+        assert_eq!(applications[1].get_discount(), 10);
+
+        // Order-level promotions see the subtotal of the whole order
+        assert_eq!(discount(&["SPEND1000", "10OFF"], 1), 10);
+        assert_eq!(discount(&["SPEND1000", "10OFF"], 2), 10 + 50);
+
+        // Exclusive coupons cannot be combined
+        assert!(matches!(
+            fx.buy_stacked(&["EXCLUSIVE", "10OFF"], 1).err().unwrap(),
+            SailsDbError::CouponNotStackable
+        ));
+        assert_eq!(discount(&["EXCLUSIVE"], 1), 100);
+
+        // A stackable default coupon joins those entered
+        stackable("DEFAULT", "1", 3);
+        let tx = fx
+            .buy_stacked(&["10OFF"], 1)
+            .unwrap()
+            .get_info(conn)
+            .unwrap();
+        assert_eq!(tx.get_coupon(), "10OFF,DEFAULT");
+        assert_eq!(tx.get_discount(), 11);
+        // But not an exclusive one
+        let tx = fx
+            .buy_stacked(&["EXCLUSIVE"], 1)
+            .unwrap()
+            .get_info(conn)
+            .unwrap();
+        assert_eq!(tx.get_coupon(), "EXCLUSIVE");
+
+        // Usage is counted by the coupons applied
+        assert_eq!(
+            TransactionFinder::new(conn, None)
+                .coupon("10OFF")
+                .count_i64()
+                .unwrap(),
+            4
+        );
+
+        // Stacked coupons never discount more than the subtotal
+        stackable("MOST", "600", 5);
+        stackable("REST", "600", 6);
+        stackable("EXTRA", "600", 7);
+        let tx = fx
+            .buy_stacked(&["MOST", "REST", "EXTRA"], 1)
+            .unwrap()
+            .get_info(conn)
+            .unwrap();
+        assert_eq!(tx.get_discount(), 700);
+        assert_eq!(tx.get_total(), 0u32.into());
+        let applications = tx.get_coupon_applications(conn).unwrap();
+        assert_eq!(
+            applications
+                .iter()
+                .map(|a| (a.get_coupon(), a.get_discount()))
+                .collect::<Vec<_>>(),
+            vec![("DEFAULT", 1), ("MOST", 600), ("REST", 99), ("EXTRA", 0)]
+        );
+    }
+
+    #[test]
+    fn coupon_stats() {
+        let fx = Fixture::new();
+        let conn = &fx.conn;
+        let alice = &fx.buyer;
+        let bob = user(conn, "Bob@example.org");
+
+        let buy = |buyer: &UserId, coupon: &str| {
+            fx.buy_as(buyer, coupon, 1).unwrap().get_info(conn).unwrap()
+        };
+        Coupon::new(conn, "10OFF", "10").unwrap();
+
+        // Paid
+        buy(alice, "10OFF")
+            .set_transaction_status(TransactionStatus::Paid)
+            .update(conn)
+            .unwrap();
+        // Placed but never paid
+        buy(alice, "10OFF");
+        // Refunded after payment
+        let refunded = buy(&bob, "10OFF")
+            .set_transaction_status(TransactionStatus::Paid)
+            .update(conn)
+            .unwrap();
+        refunded.refund(conn).unwrap();
+        // Cancelled before payment
+        buy(&bob, "10OFF").refund(conn).unwrap();
+        // No coupon applied
+        buy(&bob, "");
+
+        let stats = Coupons::stats_of(conn, "10OFF").unwrap();
+        assert_eq!(stats.uses, 4);
+        assert_eq!(stats.refunded, 1);
+        assert_eq!(stats.get_unique_buyers(), 2);
+        assert!((stats.get_refund_rate() - 0.5).abs() < f64::EPSILON);
+        assert_eq!(stats.get_discount(), vec![(Currency::USD, 10)]);
+        assert_eq!(stats.get_revenue(), vec![(Currency::USD, 690)]);
+
+        // The built-in coupon is not reported
+        let all = Coupons::stats(conn).unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].coupon, "10OFF");
+
+        let daily = Coupons::daily_stats(conn, "10OFF").unwrap();
+        assert_eq!(daily.len(), 1);
+        assert_eq!(daily[0].1.uses, 4);
+        assert!(Coupons::daily_stats(conn, "NOTHING").unwrap().is_empty());
+    }
 }
@@ -0,0 +1,575 @@
+use crate::{
+    enums::{CreditKind, Currency},
+    error::{SailsDbError, SailsDbResult as Result},
+    schema::{credits, giftcards},
+    users::UserId,
+};
+use chrono::naive::NaiveDateTime;
+use diesel::{prelude::*, sqlite::Sqlite};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// A psuedo struct for managing the store credit ledger
+pub struct Credits;
+
+impl Credits {
+    pub fn balance(conn: &SqliteConnection, user: &UserId, currency_p: &Currency) -> Result<i64> {
+        use crate::schema::credits::dsl::*;
+        Ok(credits
+            .filter(user_id.eq(user.get_id()))
+            .filter(currency.eq(currency_p.clone()))
+            .select(amount)
+            .load::<i64>(conn)?
+            .iter()
+            .sum())
+    }
+
+    // Balances of all currencies the user has ever had credit in
+    pub fn balances(conn: &SqliteConnection, user: &UserId) -> Result<HashMap<Currency, i64>> {
+        use crate::schema::credits::dsl::*;
+        let mut balances = HashMap::new();
+        for (c, a) in credits
+            .filter(user_id.eq(user.get_id()))
+            .select((currency, amount))
+            .load::<(Currency, i64)>(conn)?
+        {
+            *balances.entry(c).or_default() += a;
+        }
+        Ok(balances)
+    }
+
+    pub fn grant(
+        conn: &SqliteConnection,
+        user: &UserId,
+        currency_p: Currency,
+        amount_p: u32,
+        kind_p: CreditKind,
+        reference_p: Option<&str>,
+        note_p: Option<&str>,
+    ) -> Result<CreditEntry> {
+        use crate::schema::credits::dsl::*;
+        if amount_p == 0 {
+            return Err(SailsDbError::IllegalPriceOrQuantity);
+        }
+        let entry = CreditEntry::new(
+            user,
+            currency_p,
+            amount_p as i64,
+            kind_p,
+            reference_p,
+            note_p,
+        );
+        diesel::insert_into(credits).values(&entry).execute(conn)?;
+        Ok(entry)
+    }
+
+    // Spend the credit on the transaction given
+    pub(crate) fn spend(
+        conn: &SqliteConnection,
+        user: &UserId,
+        currency_p: Currency,
+        amount_p: u32,
+        transaction: &str,
+    ) -> Result<CreditEntry> {
+        use crate::schema::credits::dsl::*;
+        if amount_p == 0 {
+            return Err(SailsDbError::IllegalPriceOrQuantity);
+        }
+        // The balance must not be changed between the check and the spending
+        conn.transaction::<_, SailsDbError, _>(|| {
+            if Self::balance(conn, user, &currency_p)? < amount_p as i64 {
+                return Err(SailsDbError::InsufficientCredit);
+            }
+            let entry = CreditEntry::new(
+                user,
+                currency_p,
+                -(amount_p as i64),
+                CreditKind::Purchase,
+                Some(transaction),
+                None,
+            );
+            diesel::insert_into(credits).values(&entry).execute(conn)?;
+            Ok(entry)
+        })
+    }
+
+    // Take back what has been granted of the kind and for the reference given, even if the balance goes negative.
+    // What has been taken back already is not taken back again.
+    pub(crate) fn take_back(
+        conn: &SqliteConnection,
+        kind_p: CreditKind,
+        reference_p: &str,
+        note_p: Option<&str>,
+    ) -> Result<Vec<CreditEntry>> {
+        use crate::schema::credits::dsl::*;
+        let mut granted: HashMap<(String, Currency), i64> = HashMap::new();
+        for (u, c, a) in credits
+            .filter(kind.eq(kind_p.clone()))
+            .filter(reference.eq(reference_p))
+            .select((user_id, currency, amount))
+            .load::<(String, Currency, i64)>(conn)?
+        {
+            *granted.entry((u, c)).or_default() += a;
+        }
+
+        let mut entries = Vec::new();
+        for ((u, c), a) in granted.into_iter().filter(|(_, a)| *a > 0) {
+            let entry = CreditEntry::new(
+                &UserId::find(conn, &u)?,
+                c,
+                -a,
+                kind_p.clone(),
+                Some(reference_p),
+                note_p,
+            );
+            diesel::insert_into(credits).values(&entry).execute(conn)?;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    pub fn delete_by_user(conn: &SqliteConnection, user: &UserId) -> Result<usize> {
+        use crate::schema::credits::dsl::*;
+        Ok(diesel::delete(credits.filter(user_id.eq(user.get_id()))).execute(conn)?)
+    }
+
+    // Move the ledger of a deleted user to its tombstone so that the totals stay intact
+    pub(crate) fn hand_over(conn: &SqliteConnection, from: &UserId, to: &UserId) -> Result<usize> {
+        use crate::schema::credits::dsl::*;
+        Ok(diesel::update(credits.filter(user_id.eq(from.get_id())))
+            .set(user_id.eq(to.get_id()))
+            .execute(conn)?)
+    }
+}
+
+/// A single change of the store credit, corresponding to a row in the table `credits`
+#[derive(Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, Clone)]
+#[table_name = "credits"]
+pub struct CreditEntry {
+    id: String,
+    user_id: String,
+    currency: Currency,
+    amount: i64,
+    kind: CreditKind,
+    reference: Option<String>,
+    note: Option<String>,
+    time_created: NaiveDateTime,
+}
+
+impl CreditEntry {
+    fn new(
+        user: &UserId,
+        currency: Currency,
+        amount: i64,
+        kind: CreditKind,
+        reference: Option<&str>,
+        note: Option<&str>,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.get_id().to_string(),
+            currency,
+            amount,
+            kind,
+            reference: reference.map(ToString::to_string),
+            note: note.map(ToString::to_string),
+            time_created: chrono::offset::Local::now().naive_utc(),
+        }
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn get_currency(&self) -> &Currency {
+        &self.currency
+    }
+
+    // Negative if the credit is spent
+    pub fn get_amount(&self) -> i64 {
+        self.amount
+    }
+
+    pub fn get_kind(&self) -> &CreditKind {
+        &self.kind
+    }
+
+    pub fn get_reference(&self) -> Option<&str> {
+        self.reference.as_deref()
+    }
+
+    pub fn get_note(&self) -> Option<&str> {
+        self.note.as_deref()
+    }
+
+    pub fn get_time_created(&self) -> &NaiveDateTime {
+        &self.time_created
+    }
+}
+
+type BoxedQuery<'a> = credits::BoxedQuery<'a, Sqlite, credits::SqlType>;
+
+/// A search query helper (builder)
+pub struct CreditFinder<'a> {
+    conn: &'a SqliteConnection,
+    query: BoxedQuery<'a>,
+}
+
+impl<'a> CreditFinder<'a> {
+    pub fn new(conn: &'a SqliteConnection, query: Option<BoxedQuery<'a>>) -> Self {
+        use crate::schema::credits::dsl::*;
+        if let Some(q) = query {
+            Self { conn, query: q }
+        } else {
+            Self {
+                conn,
+                query: credits.into_boxed(),
+            }
+        }
+    }
+
+    // Most recent entries come first
+    pub fn search(self) -> Result<Vec<CreditEntry>> {
+        use crate::schema::credits::dsl::*;
+        Ok(self
+            .query
+            .order(time_created.desc())
+            .load::<CreditEntry>(self.conn)?)
+    }
+
+    pub fn user(mut self, user: &'a UserId) -> Self {
+        use crate::schema::credits::dsl::*;
+        self.query = self.query.filter(user_id.eq(user.get_id()));
+        self
+    }
+
+    pub fn currency(mut self, currency_p: Currency) -> Self {
+        use crate::schema::credits::dsl::*;
+        self.query = self.query.filter(currency.eq(currency_p));
+        self
+    }
+
+    pub fn kind(mut self, kind_p: CreditKind) -> Self {
+        use crate::schema::credits::dsl::*;
+        self.query = self.query.filter(kind.eq(kind_p));
+        self
+    }
+
+    pub fn reference(mut self, reference_p: &'a str) -> Self {
+        use crate::schema::credits::dsl::*;
+        self.query = self.query.filter(reference.eq(reference_p));
+        self
+    }
+}
+
+// A psuedo struct for managing gift cards
+pub struct GiftCards;
+
+impl GiftCards {
+    pub fn list(conn: &SqliteConnection) -> Result<Vec<GiftCard>> {
+        use crate::schema::giftcards::dsl::*;
+        Ok(giftcards
+            .order(time_created.desc())
+            .load::<GiftCard>(conn)?)
+    }
+
+    // Redeem the gift card into the store credit of the user
+    pub fn redeem(conn: &SqliteConnection, code: &str, user: &UserId) -> Result<CreditEntry> {
+        use crate::schema::giftcards::dsl::*;
+        let code = GiftCard::normalize(code);
+        conn.transaction::<_, SailsDbError, _>(|| {
+            let card = match giftcards.filter(id.eq(&code)).first::<GiftCard>(conn) {
+                Err(diesel::result::Error::NotFound) => Err(SailsDbError::GiftCardNotFound),
+                r => Ok(r?),
+            }?;
+            if card.redeemed_by.is_some() {
+                return Err(SailsDbError::GiftCardRedeemed);
+            }
+            diesel::update(giftcards.filter(id.eq(&code)))
+                .set((
+                    redeemed_by.eq(Some(user.get_id())),
+                    time_redeemed.eq(Some(chrono::offset::Local::now().naive_utc())),
+                ))
+                .execute(conn)?;
+            Credits::grant(
+                conn,
+                user,
+                card.currency,
+                card.amount as u32,
+                CreditKind::GiftCard,
+                Some(&code),
+                None,
+            )
+        })
+    }
+}
+
+/// A gift card, corresponding to a row in the table `giftcards`
+#[derive(Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, Clone)]
+#[table_name = "giftcards"]
+pub struct GiftCard {
+    // The code to redeem
+    id: String,
+    currency: Currency,
+    amount: i64,
+    note: Option<String>,
+    time_created: NaiveDateTime,
+    redeemed_by: Option<String>,
+    time_redeemed: Option<NaiveDateTime>,
+}
+
+impl GiftCard {
+    pub fn new(
+        conn: &SqliteConnection,
+        currency: Currency,
+        amount: u32,
+        note: Option<&str>,
+    ) -> Result<Self> {
+        use crate::schema::giftcards::dsl::*;
+        if amount == 0 {
+            return Err(SailsDbError::IllegalPriceOrQuantity);
+        }
+        // Codes look like `1A2B-3C4D-5E6F-7A8B`
+        let code = Uuid::new_v4().simple().to_string()[..16]
+            .to_uppercase()
+            .as_bytes()
+            .chunks(4)
+            .map(|c| String::from_utf8_lossy(c).to_string())
+            .collect::<Vec<String>>()
+            .join("-");
+        let card = Self {
+            id: code,
+            currency,
+            amount: amount as i64,
+            note: note.map(ToString::to_string),
+            time_created: chrono::offset::Local::now().naive_utc(),
+            redeemed_by: None,
+            time_redeemed: None,
+        };
+        diesel::insert_into(giftcards).values(&card).execute(conn)?;
+        Ok(card)
+    }
+
+    // Codes are case-insensitive and surrounding spaces are ignored
+    fn normalize(code: &str) -> String {
+        code.trim().to_uppercase()
+    }
+
+    pub fn get_code(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_currency(&self) -> &Currency {
+        &self.currency
+    }
+
+    pub fn get_amount(&self) -> u32 {
+        self.amount as u32
+    }
+
+    pub fn get_note(&self) -> Option<&str> {
+        self.note.as_deref()
+    }
+
+    pub fn get_time_created(&self) -> &NaiveDateTime {
+        &self.time_created
+    }
+
+    pub fn get_redeemed_by(&self) -> Option<&str> {
+        self.redeemed_by.as_deref()
+    }
+
+    pub fn get_time_redeemed(&self) -> Option<&NaiveDateTime> {
+        self.time_redeemed.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        categories::{Category, CtgTrait},
+        enums::{Payment, ProductStatus, TransactionStatus},
+        products::IncompleteProduct,
+        test_utils::establish_connection,
+        transactions::*,
+        users::*,
+    };
+
+    #[test]
+    fn gift_cards() {
+        let conn = establish_connection();
+        let user = UserForm::new("TestUser@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        let card = GiftCard::new(&conn, Currency::USD, 500, Some("Prize")).unwrap();
+        assert_eq!(card.get_code().len(), 19);
+        assert_eq!(GiftCards::list(&conn).unwrap().len(), 1);
+
+        // Codes are case-insensitive
+        GiftCards::redeem(&conn, &card.get_code().to_lowercase(), &user).unwrap();
+        assert_eq!(Credits::balance(&conn, &user, &Currency::USD).unwrap(), 500);
+        assert_eq!(Credits::balance(&conn, &user, &Currency::CNY).unwrap(), 0);
+        assert_eq!(
+            GiftCards::list(&conn).unwrap()[0].get_redeemed_by(),
+            Some(user.get_id())
+        );
+
+        assert!(matches!(
+            GiftCards::redeem(&conn, card.get_code(), &user).unwrap_err(),
+            SailsDbError::GiftCardRedeemed
+        ));
+        assert!(matches!(
+            GiftCards::redeem(&conn, "NOT-A-CARD", &user).unwrap_err(),
+            SailsDbError::GiftCardNotFound
+        ));
+        assert!(GiftCard::new(&conn, Currency::USD, 0, None).is_err());
+    }
+
+    #[test]
+    fn pay_and_refund_with_credit() {
+        let conn = establish_connection();
+        let seller = UserForm::new("TestUser@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        let buyer = UserForm::new("AtypicalBuyer@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        let econ = Category::create(&conn, "Economics Books", 1)
+            .and_then(Category::into_leaf)
+            .unwrap();
+        let book_id = IncompleteProduct::new(
+            &econ,
+            "Krugman's Economics 2nd Edition",
+            700,
+            10,
+            "A very great book on the subject of Economics",
+            Currency::USD,
+        )
+        .unwrap()
+        .create(&conn, &seller)
+        .unwrap();
+        book_id
+            .get_info(&conn)
+            .unwrap()
+            .set_product_status(ProductStatus::Verified)
+            .update(&conn)
+            .unwrap();
+
+        let buy = |payment: Payment| {
+            Transactions::buy(
+                &conn,
+                &book_id,
+                &buyer,
+                1,
+                "258 Huanhu South Road, Dongqian Lake, Ningbo, China",
+                "",
+                payment,
+            )
+        };
+
+        Credits::grant(
+            &conn,
+            &buyer,
+            Currency::USD,
+            500,
+            CreditKind::Compensation,
+            None,
+            Some("Late delivery"),
+        )
+        .unwrap();
+
+        // Not enough to pay in full
+        assert!(matches!(
+            buy(Payment::Credit).unwrap_err(),
+            SailsDbError::InsufficientCredit
+        ));
+        assert_eq!(TransactionFinder::list(&conn).unwrap().len(), 0);
+
+        Credits::grant(
+            &conn,
+            &buyer,
+            Currency::USD,
+            300,
+            CreditKind::Prize,
+            None,
+            None,
+        )
+        .unwrap();
+        let paid = buy(Payment::Credit).unwrap().get_info(&conn).unwrap();
+        assert_eq!(paid.get_transaction_status(), &TransactionStatus::Paid);
+        assert_eq!(paid.get_credit(), 700);
+        assert_eq!(paid.get_payable(), 0u32.into());
+        assert_eq!(
+            Credits::balance(&conn, &buyer, &Currency::USD).unwrap(),
+            100
+        );
+
+        // Partial payment with the rest of the credit
+        let partial = buy(Payment::Paypal)
+            .unwrap()
+            .get_info(&conn)
+            .unwrap()
+            .apply_credit(&conn)
+            .unwrap();
+        assert_eq!(partial.get_transaction_status(), &TransactionStatus::Placed);
+        assert_eq!(partial.get_credit(), 100);
+        assert_eq!(partial.get_payable(), 600u32.into());
+        assert_eq!(Credits::balance(&conn, &buyer, &Currency::USD).unwrap(), 0);
+
+        // Orders not paid in full could not be refunded to credit
+        assert!(matches!(
+            partial.refund_to_credit(&conn).unwrap_err(),
+            SailsDbError::IllegalQuery
+        ));
+
+        // The credit part returns to the credit
+        partial.refund(&conn).unwrap();
+        assert_eq!(
+            Credits::balance(&conn, &buyer, &Currency::USD).unwrap(),
+            100
+        );
+
+        // Refund the whole order to credit
+        paid.refund_to_credit(&conn).unwrap();
+        assert_eq!(
+            Credits::balance(&conn, &buyer, &Currency::USD).unwrap(),
+            800
+        );
+        // Orders cannot be refunded twice
+        assert!(paid.refund_to_credit(&conn).is_err());
+
+        let entries = CreditFinder::new(&conn, None)
+            .user(&buyer)
+            .search()
+            .unwrap();
+        assert_eq!(entries.len(), 6);
+        assert_eq!(
+            CreditFinder::new(&conn, None)
+                .user(&buyer)
+                .kind(CreditKind::Refund)
+                .search()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            Credits::balances(&conn, &buyer).unwrap()[&Currency::USD],
+            800
+        );
+    }
+}
@@ -64,6 +64,23 @@ impl Digicons {
         Ok(authorized)
     }
 
+    pub fn list_by_creator(conn: &SqliteConnection, creator: &UserId) -> Result<Vec<Digicon>> {
+        use crate::schema::digicons::dsl::*;
+        Ok(digicons
+            .filter(creator_id.eq(creator.get_id()))
+            .load::<Digicon>(conn)?)
+    }
+
+    // Digicons of a deleted user stay available to the buyers under its tombstone
+    pub(crate) fn hand_over(conn: &SqliteConnection, from: &UserId, to: &UserId) -> Result<usize> {
+        use crate::schema::digicons::dsl::*;
+        Ok(
+            diesel::update(digicons.filter(creator_id.eq(from.get_id())))
+                .set(creator_id.eq(to.get_id()))
+                .execute(conn)?,
+        )
+    }
+
     pub fn find_by_id(conn: &SqliteConnection, id_provided: &str) -> Result<Digicon> {
         use crate::schema::digicons::dsl::*;
         Ok(digicons
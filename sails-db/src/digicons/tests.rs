@@ -39,7 +39,7 @@ fn create_digicons() {
         physics_done_wrong.get_id(),
         "eea1dc23-5494-4293-8c48-e03c168aad8e"
     );
-    assert_eq!(physics_done_wrong.get_creator_id(), "TestUser@example.org");
+    assert_eq!(physics_done_wrong.get_creator_id(), user_id.get_id());
     assert_eq!(physics_done_wrong.get_name(), "GitHub");
     assert_eq!(physics_done_wrong.get_storage_detail().is_none(), true);
 }
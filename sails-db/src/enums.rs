@@ -1,4 +1,5 @@
 #![allow(clippy::unusual_byte_groupings)]
+use crate::error::SailsDbError;
 use bitflags::bitflags;
 use diesel_derive_enum::DbEnum;
 use paypal_rs::data::common::Currency as PayPalCurrency;
@@ -95,6 +96,44 @@ impl Default for UserStatus {
     }
 }
 
+impl UserStatus {
+    // Every single permission along with its name, e.g. for listing them in forms
+    pub fn flags() -> Vec<(&'static str, Self)> {
+        vec![
+            ("USER_SELF_READABLE", Self::USER_SELF_READABLE),
+            ("USER_SELF_WRITABLE", Self::USER_SELF_WRITABLE),
+            ("USER_OTHERS_READABLE", Self::USER_OTHERS_READABLE),
+            ("USER_OTHERS_WRITABLE", Self::USER_OTHERS_WRITABLE),
+            ("PROD_SELF_READABLE", Self::PROD_SELF_READABLE),
+            ("PROD_SELF_WRITABLE", Self::PROD_SELF_WRITABLE),
+            ("PROD_SELF_REMOVABLE", Self::PROD_SELF_REMOVABLE),
+            ("PROD_OTHERS_READABLE", Self::PROD_OTHERS_READABLE),
+            ("PROD_OTHERS_WRITABLE", Self::PROD_OTHERS_WRITABLE),
+            ("PROD_OTHERS_REMOVABLE", Self::PROD_OTHERS_REMOVABLE),
+            ("DIGICON_SELF_READABLE", Self::DIGICON_SELF_READABLE),
+            ("DIGICON_SELF_WRITABLE", Self::DIGICON_SELF_WRITABLE),
+            ("DIGICON_SELF_REMOVABLE", Self::DIGICON_SELF_REMOVABLE),
+            ("DIGICON_OTHERS_READABLE", Self::DIGICON_OTHERS_READABLE),
+            ("DIGICON_OTHERS_WRITABLE", Self::DIGICON_OTHERS_WRITABLE),
+            ("DIGICON_OTHERS_REMOVABLE", Self::DIGICON_OTHERS_REMOVABLE),
+            ("TX_BUYER_READABLE", Self::TX_BUYER_READABLE),
+            ("TX_BUYER_PROGRESSABLE", Self::TX_BUYER_PROGRESSABLE),
+            ("TX_BUYER_FINISHABLE", Self::TX_BUYER_FINISHABLE),
+            ("TX_BUYER_REFUNDABLE", Self::TX_BUYER_REFUNDABLE),
+            ("TX_SELLER_READABLE", Self::TX_SELLER_READABLE),
+            ("TX_SELLER_PROGRESSABLE", Self::TX_SELLER_PROGRESSABLE),
+            ("TX_SELLER_FINISHABLE", Self::TX_SELLER_FINISHABLE),
+            ("TX_SELLER_REFUNDABLE", Self::TX_SELLER_REFUNDABLE),
+            ("TX_OTHERS_READABLE", Self::TX_OTHERS_READABLE),
+            ("TX_OTHERS_PROGRESSABLE", Self::TX_OTHERS_PROGRESSABLE),
+            ("TX_OTHERS_FINISHABLE", Self::TX_OTHERS_FINISHABLE),
+            ("TX_OTHERS_REFUNDABLE", Self::TX_OTHERS_REFUNDABLE),
+            ("PROD_ADMIN", Self::PROD_ADMIN),
+            ("TAG_WRITABLE", Self::TAG_WRITABLE),
+        ]
+    }
+}
+
 #[derive(DbEnum, Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum ProductStatus {
     // The product is currently disabled (not showing up in the store, etc.)
@@ -163,6 +202,62 @@ impl Status for TransactionStatus {
     }
 }
 
+#[derive(DbEnum, Debug, Clone, Serialize, Deserialize, PartialEq, Eq, FromFormField)]
+pub enum CreditKind {
+    GiftCard,
+    Refund,
+    Compensation,
+    Prize,
+    // Credit spent on an order
+    Purchase,
+    Referral,
+}
+
+#[derive(DbEnum, Debug, Clone, Serialize, Deserialize, PartialEq, Eq, FromFormField)]
+pub enum ReferralRecipient {
+    // The user who shared the referral code
+    Referrer,
+    // The user who signed up with the referral code
+    Referee,
+}
+
+#[derive(DbEnum, Debug, Clone, Serialize, Deserialize, PartialEq, Eq, FromFormField)]
+pub enum ReferralRewardKind {
+    // Store credit
+    Credit,
+    // A single-use coupon only usable by the recipient
+    Coupon,
+}
+
+#[derive(DbEnum, Debug, Clone, Serialize, Deserialize, PartialEq, Eq, FromFormField)]
+pub enum CouponStacking {
+    // Cannot be combined with any other coupon
+    Exclusive,
+    // Can be combined with other stackable coupons
+    Stackable,
+}
+
+impl Default for CouponStacking {
+    fn default() -> Self {
+        Self::Exclusive
+    }
+}
+
+#[derive(DbEnum, Debug, Clone, Serialize, Deserialize, PartialEq, Eq, FromFormField)]
+pub enum TicketStatus {
+    // Waiting for the customer service
+    Open,
+    // Waiting for the user to reply
+    Pending,
+    Resolved,
+}
+
+impl Default for TicketStatus {
+    fn default() -> Self {
+        Self::Open
+    }
+}
+
 #[derive(DbEnum, Debug, Clone, Serialize, Deserialize, PartialEq, Eq, FromFormField)]
 pub enum StorageType {
     // Store files in github release asset
@@ -177,6 +272,8 @@ pub enum StorageType {
 pub enum Payment {
     Alipay,
     Paypal,
+    // Paid entirely with store credit
+    Credit,
 }
 
 impl Payment {
@@ -188,6 +285,8 @@ impl Payment {
             // Paypal cannot receive CNY
             (Self::Paypal, Currency::CNY) => false,
             (Self::Paypal, _) => true,
+            // Credit is kept per currency
+            (Self::Credit, _) => true,
         }
     }
 }
@@ -210,6 +309,24 @@ pub enum Currency {
     USD,
 }
 
+impl std::str::FromStr for Currency {
+    type Err = SailsDbError;
+
+    // Case-insensitive, in the same form as it is displayed (e.g. "CNY")
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "CNY" => Ok(Self::CNY),
+            "EUR" => Ok(Self::EUR),
+            "HKD" => Ok(Self::HKD),
+            "JPY" => Ok(Self::JPY),
+            "GBP" => Ok(Self::GBP),
+            "CHF" => Ok(Self::CHF),
+            "USD" => Ok(Self::USD),
+            _ => Err(SailsDbError::IllegalQuery),
+        }
+    }
+}
+
 impl From<Currency> for PayPalCurrency {
     fn from(currency: Currency) -> Self {
         match currency {
@@ -26,6 +26,9 @@ pub enum SailsDbError {
     #[error("parsing script failed: {0}")]
     ScriptParseError(#[from] rhai::ParseError),
 
+    #[error("script exceeded the limit of time or operations")]
+    ScriptTimeout,
+
     #[error("email has already been registered")]
     UserRegistered,
 
@@ -86,6 +89,63 @@ pub enum SailsDbError {
     #[error("the coupon ID is reserved and not valid")]
     CouponIDReserved,
 
+    #[error("the coupon doesn't exist")]
+    CouponNotFound,
+
+    #[error("the coupon is not valid yet")]
+    CouponNotYetValid,
+
+    #[error("the coupon has expired")]
+    CouponExpired,
+
+    #[error("the coupon has reached its maximum number of uses")]
+    CouponUsageExceeded,
+
+    #[error("you have reached the maximum number of uses of the coupon")]
+    CouponBuyerUsageExceeded,
+
+    #[error("the coupon is not applicable to the product")]
+    CouponNotApplicable,
+
+    #[error("the subtotal has not reached the minimum required by the coupon")]
+    CouponMinSubtotalNotMet,
+
+    #[error("the coupon cannot be combined with other coupons")]
+    CouponNotStackable,
+
+    #[error("insufficient store credit")]
+    InsufficientCredit,
+
+    #[error("gift card not found")]
+    GiftCardNotFound,
+
+    #[error("gift card has already been redeemed")]
+    GiftCardRedeemed,
+
+    #[error("referral code not found")]
+    ReferralCodeNotFound,
+
+    #[error("the referral is not allowed")]
+    ReferralNotAllowed,
+
+    #[error("role already existed")]
+    RoleExisted,
+
+    #[error("invalid or revoked API token")]
+    InvalidToken,
+
+    #[error("the session has expired or been revoked")]
+    SessionExpired,
+
+    #[error("ticket not found")]
+    TicketNotFound,
+
+    #[error("invalid address: {0}")]
+    InvalidAddress(String),
+
+    #[error("failed to parse time: {0}")]
+    TimeParseError(#[from] chrono::ParseError),
+
     #[error("other errors: {0}")]
     Anyhow(#[from] anyhow::Error),
 }
@@ -0,0 +1,232 @@
+// Personal data export. Everything we hold about a user is collected with the existing finders.
+
+use crate::{
+    addresses::{Address, Addresses},
+    credits::{CreditEntry, CreditFinder},
+    digicons::{DigiconMappingFinder, Digicons},
+    enums::{StorageType, TransactionStatus},
+    error::SailsDbResult as Result,
+    identities::{Identities, Identity},
+    messages::{Message, Messages},
+    products::{ProductFinder, ProductInfo},
+    referrals::{Referral, ReferralCodes, ReferralFinder},
+    tickets::{Ticket, TicketFinder, TicketReply, Tickets},
+    transactions::{TransactionFinder, TransactionInfo},
+    users::{UserId, UserInfo},
+};
+use chrono::naive::NaiveDateTime;
+use diesel::prelude::*;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct UserExport {
+    pub time_exported: NaiveDateTime,
+    pub user: UserInfo,
+    pub identities: Vec<Identity>,
+    pub addresses: Vec<Address>,
+    pub products: Vec<ProductInfo>,
+    // Transactions as the buyer
+    pub purchases: Vec<TransactionInfo>,
+    // Transactions as the seller
+    pub sales: Vec<TransactionInfo>,
+    pub messages: Vec<Message>,
+    pub digicons_created: Vec<DigiconRecord>,
+    // Digital contents obtained through finished purchases, i.e. the ones the user has been able to download
+    pub digicons_obtained: Vec<DigiconRecord>,
+    pub credits: Vec<CreditEntry>,
+    pub referral_code: Option<String>,
+    pub referrals: Vec<Referral>,
+    pub tickets: Vec<Ticket>,
+    // Internal notes of the customer service are left out
+    pub ticket_replies: Vec<TicketReply>,
+}
+
+// Where the digicon is stored is our internal detail and is left out
+#[derive(Debug, Serialize)]
+pub struct DigiconRecord {
+    pub id: String,
+    pub name: String,
+    pub creator_id: String,
+    pub storage_type: StorageType,
+    pub time_created: NaiveDateTime,
+    pub time_modified: NaiveDateTime,
+    // The purchase through which the digicon was obtained
+    pub transaction_id: Option<String>,
+}
+
+impl UserExport {
+    pub fn of(conn: &SqliteConnection, user: &UserId) -> Result<Self> {
+        let purchases = TransactionFinder::new(conn, None)
+            .buyer(user)
+            .search_info()?;
+
+        let mut digicons_obtained = Vec::new();
+        for tx in purchases
+            .iter()
+            .filter(|tx| tx.get_transaction_status() == &TransactionStatus::Finished)
+        {
+            // The product might have been deleted by the seller
+            let prod = match ProductFinder::new(conn, None).id(tx.get_product()).first() {
+                Ok(prod) => prod,
+                Err(_) => continue,
+            };
+            for d in DigiconMappingFinder::new(conn, None)
+                .product(&prod)
+                .search_digicon()?
+            {
+                digicons_obtained.push(DigiconRecord {
+                    id: d.get_id().to_string(),
+                    name: d.get_name().to_string(),
+                    creator_id: d.get_creator_id().to_string(),
+                    storage_type: d.get_storage_type().clone(),
+                    time_created: *d.get_time_created(),
+                    time_modified: *d.get_time_modified(),
+                    transaction_id: Some(tx.get_id().to_string()),
+                });
+            }
+        }
+
+        let digicons_created = Digicons::list_all(conn)?
+            .into_iter()
+            .filter(|d| d.get_creator_id() == user.get_id())
+            .map(|d| DigiconRecord {
+                id: d.get_id().to_string(),
+                name: d.get_name().to_string(),
+                creator_id: d.get_creator_id().to_string(),
+                storage_type: d.get_storage_type().clone(),
+                time_created: *d.get_time_created(),
+                time_modified: *d.get_time_modified(),
+                transaction_id: None,
+            })
+            .collect();
+
+        let tickets = TicketFinder::new(conn, None).user(user).search()?;
+        let mut ticket_replies = Vec::new();
+        for ticket in tickets.iter() {
+            ticket_replies.extend(Tickets::replies(conn, ticket, false)?);
+        }
+
+        let mut referrals = ReferralFinder::new(conn, None).referrer(user).search()?;
+        referrals.extend(ReferralFinder::new(conn, None).referee(user).search()?);
+
+        Ok(Self {
+            time_exported: chrono::offset::Local::now().naive_utc(),
+            user: user.get_info(conn)?,
+            identities: Identities::list_by_user(conn, user)?,
+            addresses: Addresses::list_by_user(conn, user)?,
+            products: ProductFinder::new(conn, None).seller(user).search_info()?,
+            purchases,
+            sales: TransactionFinder::new(conn, None)
+                .seller(user)
+                .search_info()?,
+            messages: Messages::list_with_user(conn, user)?,
+            digicons_created,
+            digicons_obtained,
+            credits: CreditFinder::new(conn, None).user(user).search()?,
+            // We don't create a code just for the export
+            referral_code: ReferralCodes::find_by_user(conn, user)?
+                .map(|c| c.get_code().to_string()),
+            referrals,
+            tickets,
+            ticket_replies,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        categories::{Category, CtgTrait},
+        digicons::{DigiconMapping, IncompleteDigicon},
+        enums::{Currency, Payment, ProductStatus},
+        products::IncompleteProduct,
+        test_utils::establish_connection,
+        transactions::Transactions,
+        users::UserForm,
+    };
+
+    #[test]
+    fn export_user() {
+        let conn = establish_connection();
+        let seller = UserForm::new("TestUser@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        let buyer = UserForm::new("AtypicalBuyer@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        let econ = Category::create(&conn, "Economics Books", 1)
+            .and_then(Category::into_leaf)
+            .unwrap();
+        let book_id = IncompleteProduct::new(
+            &econ,
+            "Krugman's Economics 2nd Edition",
+            700,
+            10,
+            "A very great book on the subject of Economics",
+            Currency::USD,
+        )
+        .unwrap()
+        .create(&conn, &seller)
+        .unwrap();
+        book_id
+            .get_info(&conn)
+            .unwrap()
+            .set_product_status(ProductStatus::Verified)
+            .update(&conn)
+            .unwrap();
+
+        let digicon = IncompleteDigicon {
+            name: "Krugman's Economics 2nd Edition PDF".to_string(),
+            storage_type: StorageType::S3,
+        }
+        .create(&conn, &seller)
+        .unwrap();
+        DigiconMapping::create(&conn, &digicon, &book_id).unwrap();
+
+        Transactions::buy(
+            &conn,
+            &book_id,
+            &buyer,
+            1,
+            "258 Huanhu South Road, Dongqian Lake, Ningbo, China",
+            "",
+            Payment::Paypal,
+        )
+        .unwrap()
+        .get_info(&conn)
+        .unwrap()
+        .set_transaction_status(TransactionStatus::Finished)
+        .update(&conn)
+        .unwrap();
+        Messages::send(&conn, &buyer, &seller, "Thanks!").unwrap();
+        Tickets::open(&conn, &buyer, None, "Invoice", "Could I get an invoice?").unwrap();
+
+        let export = UserExport::of(&conn, &buyer).unwrap();
+        assert_eq!(export.user.get_id(), buyer.get_id());
+        assert_eq!(export.purchases.len(), 1);
+        assert!(export.sales.is_empty());
+        assert!(export.products.is_empty());
+        assert_eq!(export.messages.len(), 1);
+        assert_eq!(export.digicons_obtained.len(), 1);
+        assert_eq!(export.digicons_obtained[0].id, digicon.get_id());
+        assert!(export.referral_code.is_none());
+        assert_eq!(export.tickets.len(), 1);
+        assert_eq!(export.ticket_replies.len(), 1);
+
+        let export = UserExport::of(&conn, &seller).unwrap();
+        assert_eq!(export.products.len(), 1);
+        assert_eq!(export.sales.len(), 1);
+        assert_eq!(export.messages.len(), 1);
+        assert_eq!(export.digicons_created.len(), 1);
+        assert!(export.digicons_obtained.is_empty());
+        assert!(export.tickets.is_empty());
+        assert!(serde_json::to_string(&export).is_ok());
+    }
+}
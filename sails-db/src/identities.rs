@@ -0,0 +1,269 @@
+// Identities at OpenID Connect providers, given by the issuer and the subject (`sub`) of the ID token.
+// Users are recognized by their identities instead of their emails, so changing the email at the provider doesn't make a new user.
+
+use crate::{
+    error::{SailsDbError, SailsDbResult as Result},
+    schema::identities,
+    users::{UserFinder, UserForm, UserId},
+};
+use chrono::naive::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// What the provider tells us about the user signing in
+#[derive(Debug, Clone)]
+pub struct IdentityClaims<'a> {
+    pub issuer: &'a str,
+    pub subject: &'a str,
+    pub email: &'a str,
+    pub email_verified: bool,
+    // Whether the issuer is trusted to link users from before identities were introduced by their emails
+    pub link_legacy_by_email: bool,
+    pub name: &'a str,
+    // Only used when creating the user, as users may change their school afterwards
+    pub school: &'a str,
+}
+
+// A psuedo struct for managing identities
+pub struct Identities;
+
+impl Identities {
+    // Find the user signing in, creating one if the identity is new. Returns whether the user was created as well.
+    // Users from before identities were introduced are linked on their first sign-in by their verified email,
+    // if the issuer is trusted to do so and the user has no identity yet.
+    pub fn sign_in(conn: &SqliteConnection, claims: &IdentityClaims) -> Result<(UserId, bool)> {
+        conn.transaction::<_, SailsDbError, _>(|| {
+            if let Some(identity) = Self::find(conn, claims.issuer, claims.subject)? {
+                let user = UserId::find(conn, identity.get_user_id())?;
+                // Keep the email up to date. If the new one is taken by someone else, we stay with the old one.
+                let info = user.get_info(conn)?;
+                if claims.email_verified && (info.get_email() != claims.email) {
+                    match info.set_email(claims.email, true)?.update(conn) {
+                        Ok(_) | Err(SailsDbError::UserRegistered) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                return Ok((user, false));
+            }
+
+            if claims.link_legacy_by_email && claims.email_verified {
+                if let Ok(user) = UserFinder::new(conn, None)
+                    .email(claims.email)
+                    .email_verified(true)
+                    .first()
+                {
+                    if Self::list_by_user(conn, &user)?.is_empty() {
+                        Self::link(conn, &user, claims.issuer, claims.subject)?;
+                        return Ok((user, false));
+                    }
+                }
+            }
+
+            // A verified email taken by someone else is kept unverified, so that it doesn't stop the user from signing in
+            let create = |verified| {
+                UserForm::new(claims.email, claims.name, claims.school, None)
+                    .email_verified(verified)
+                    .to_ref()?
+                    .create(conn)
+            };
+            let user = match create(claims.email_verified) {
+                Err(SailsDbError::UserRegistered) => create(false)?,
+                r => r?,
+            };
+            Self::link(conn, &user, claims.issuer, claims.subject)?;
+            Ok((user, true))
+        })
+    }
+
+    pub fn link(
+        conn: &SqliteConnection,
+        user: &UserId,
+        issuer_p: &str,
+        subject_p: &str,
+    ) -> Result<Identity> {
+        use crate::schema::identities::dsl::*;
+        let identity = Identity {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.get_id().to_string(),
+            issuer: issuer_p.to_string(),
+            subject: subject_p.to_string(),
+            time_created: chrono::offset::Local::now().naive_utc(),
+        };
+        diesel::insert_into(identities)
+            .values(&identity)
+            .execute(conn)?;
+        Ok(identity)
+    }
+
+    pub fn find(
+        conn: &SqliteConnection,
+        issuer_p: &str,
+        subject_p: &str,
+    ) -> Result<Option<Identity>> {
+        use crate::schema::identities::dsl::*;
+        Ok(identities
+            .filter(issuer.eq(issuer_p))
+            .filter(subject.eq(subject_p))
+            .first::<Identity>(conn)
+            .optional()?)
+    }
+
+    pub fn list_by_user(conn: &SqliteConnection, user: &UserId) -> Result<Vec<Identity>> {
+        use crate::schema::identities::dsl::*;
+        Ok(identities
+            .filter(user_id.eq(user.get_id()))
+            .order(time_created.asc())
+            .load::<Identity>(conn)?)
+    }
+
+    pub fn delete_by_user(conn: &SqliteConnection, user: &UserId) -> Result<usize> {
+        use crate::schema::identities::dsl::*;
+        Ok(diesel::delete(identities.filter(user_id.eq(user.get_id()))).execute(conn)?)
+    }
+}
+
+/// An identity linked to a user, corresponding to a row in the table `identities`
+#[derive(Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, Clone)]
+#[table_name = "identities"]
+pub struct Identity {
+    id: String,
+    user_id: String,
+    issuer: String,
+    subject: String,
+    time_created: NaiveDateTime,
+}
+
+impl Identity {
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn get_issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    pub fn get_subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub fn get_time_created(&self) -> &NaiveDateTime {
+        &self.time_created
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::establish_connection;
+
+    const ISSUER: &str = "https://id.flibrary.info/realms/Customers";
+
+    fn claims<'a>(subject: &'a str, email: &'a str) -> IdentityClaims<'a> {
+        IdentityClaims {
+            issuer: ISSUER,
+            subject,
+            email,
+            email_verified: true,
+            link_legacy_by_email: true,
+            name: "Kanyang Ying",
+            school: "NFLS",
+        }
+    }
+
+    #[test]
+    fn sign_in() {
+        let conn = establish_connection();
+
+        let (user, created) =
+            Identities::sign_in(&conn, &claims("f3b2", "TestUser@example.org")).unwrap();
+        assert!(created);
+        assert_eq!(user.get_info(&conn).unwrap().get_school(), "NFLS");
+        // IDs are not emails
+        assert_ne!(user.get_id(), "TestUser@example.org");
+        assert_eq!(
+            user.get_info(&conn).unwrap().get_email(),
+            "TestUser@example.org"
+        );
+
+        // Changing the email at the provider keeps the same user
+        let (same, created) =
+            Identities::sign_in(&conn, &claims("f3b2", "Changed@example.org")).unwrap();
+        assert!(!created);
+        assert_eq!(same.get_id(), user.get_id());
+        let info = user.get_info(&conn).unwrap();
+        assert_eq!(info.get_email(), "Changed@example.org");
+        assert!(info.is_email_verified());
+
+        // Another subject with the old email is someone else
+        let (other, created) =
+            Identities::sign_in(&conn, &claims("a9c0", "TestUser@example.org")).unwrap();
+        assert!(created);
+        assert_ne!(other.get_id(), user.get_id());
+
+        // Taking the email of someone else leaves the email unchanged
+        Identities::sign_in(&conn, &claims("a9c0", "Changed@example.org")).unwrap();
+        assert_eq!(
+            other.get_info(&conn).unwrap().get_email(),
+            "TestUser@example.org"
+        );
+    }
+
+    #[test]
+    fn link_legacy_user() {
+        let conn = establish_connection();
+        // Users created before identities, which have no identity linked
+        {
+            use crate::schema::users::dsl::*;
+            diesel::insert_into(users)
+                .values((
+                    id.eq("5d0e6a3c-8f1b-4c52-9a7e-2b4f6c8d1e03"),
+                    name.eq("Kanyang Ying"),
+                    school.eq("NFLS"),
+                    user_status.eq(crate::enums::UserStatus::NORMAL.bits() as i64),
+                    email.eq("TestUser@example.org"),
+                    email_verified.eq(true),
+                ))
+                .execute(&conn)
+                .unwrap();
+        }
+
+        // Unverified emails are not trusted for linking, nor do they reserve the email
+        let unverified = IdentityClaims {
+            email_verified: false,
+            ..claims("06b1", "TestUser@example.org")
+        };
+        let (stranger, created) = Identities::sign_in(&conn, &unverified).unwrap();
+        assert!(created);
+        assert_ne!(stranger.get_id(), "5d0e6a3c-8f1b-4c52-9a7e-2b4f6c8d1e03");
+        assert!(!stranger.get_info(&conn).unwrap().is_email_verified());
+
+        // Neither are issuers not trusted to link by emails. The email taken is kept unverified.
+        let untrusted = IdentityClaims {
+            issuer: "https://accounts.example.org",
+            link_legacy_by_email: false,
+            ..claims("f3b2", "TestUser@example.org")
+        };
+        let (stranger, created) = Identities::sign_in(&conn, &untrusted).unwrap();
+        assert!(created);
+        assert_ne!(stranger.get_id(), "5d0e6a3c-8f1b-4c52-9a7e-2b4f6c8d1e03");
+        assert!(!stranger.get_info(&conn).unwrap().is_email_verified());
+
+        let (user, created) =
+            Identities::sign_in(&conn, &claims("f3b2", "TestUser@example.org")).unwrap();
+        assert!(!created);
+        assert_eq!(user.get_id(), "5d0e6a3c-8f1b-4c52-9a7e-2b4f6c8d1e03");
+        assert_eq!(Identities::list_by_user(&conn, &user).unwrap().len(), 1);
+
+        // Once linked, the user could not be taken over by another subject with the same email
+        let (other, created) =
+            Identities::sign_in(&conn, &claims("77de", "TestUser@example.org")).unwrap();
+        assert!(created);
+        assert_ne!(other.get_id(), user.get_id());
+        assert!(!other.get_info(&conn).unwrap().is_email_verified());
+    }
+}
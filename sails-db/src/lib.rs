@@ -13,12 +13,21 @@ pub mod messages;
 pub mod products;
 #[rustfmt::skip]
 mod schema;
+pub mod addresses;
 pub mod categories;
 pub mod coupons;
+pub mod credits;
 pub mod digicons;
+pub mod export;
+pub mod identities;
+pub mod referrals;
+pub mod roles;
 mod script;
+pub mod sessions;
 pub mod tags;
 pub mod test_utils;
+pub mod tickets;
+pub mod tokens;
 pub mod transactions;
 pub mod users;
 
@@ -1,7 +1,14 @@
-use crate::{error::SailsDbResult as Result, schema::messages, users::UserId};
+use crate::{
+    enums::{TransactionStatus, UserStatus},
+    error::{SailsDbError, SailsDbResult as Result},
+    schema::{messageattachments, messages},
+    transactions::TransactionInfo,
+    users::{UserId, UserInfo},
+};
 use chrono::naive::NaiveDateTime;
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use uuid::Uuid;
 
 // A psuedo struct for managing messages
@@ -24,6 +31,8 @@ impl Messages {
                     .eq(participant_b.get_id())
                     .and(recv.eq(participant_a.get_id()))),
             )
+            // System messages are only shown in the threads of the orders
+            .filter(is_system.eq(false))
             .order(time_sent.asc())
             .load::<Message>(conn)?)
     }
@@ -33,27 +42,173 @@ impl Messages {
         use crate::schema::messages::dsl::*;
         Ok(messages
             .filter(recv.eq(receiver.get_id()))
+            .filter(is_system.eq(false))
             .group_by(send)
             .order(diesel::dsl::max(time_sent).desc())
             .load::<Message>(conn)?)
     }
 
+    // Number of unread messages in each conversation, by the sender
+    pub fn unread_by_sender(
+        conn: &SqliteConnection,
+        receiver: &UserId,
+    ) -> Result<HashMap<String, i64>> {
+        use crate::schema::messages::dsl::*;
+        Ok(messages
+            .filter(recv.eq(receiver.get_id()))
+            .filter(time_read.is_null())
+            .filter(is_system.eq(false))
+            .group_by(send)
+            .select((send, diesel::dsl::count_star()))
+            .load::<(String, i64)>(conn)?
+            .into_iter()
+            .collect())
+    }
+
+    // Number of unread messages of the user in all conversations
+    pub fn unread_count(conn: &SqliteConnection, receiver: &UserId) -> Result<i64> {
+        use crate::schema::messages::dsl::*;
+        Ok(messages
+            .filter(recv.eq(receiver.get_id()))
+            .filter(time_read.is_null())
+            .filter(is_system.eq(false))
+            .count()
+            .get_result(conn)?)
+    }
+
+    // Mark all the messages sent from `sender` to `reader` as read
+    pub fn mark_read(conn: &SqliteConnection, reader: &UserId, sender: &UserId) -> Result<usize> {
+        use crate::schema::messages::dsl::*;
+        Ok(diesel::update(
+            messages
+                .filter(recv.eq(reader.get_id()))
+                .filter(send.eq(sender.get_id()))
+                .filter(time_read.is_null()),
+        )
+        .set(time_read.eq(chrono::offset::Local::now().naive_utc()))
+        .execute(conn)?)
+    }
+
     pub fn send<T: ToString>(
         conn: &SqliteConnection,
         sender: &UserId,
         receiver: &UserId,
         body_provided: T,
-    ) -> Result<()> {
+    ) -> Result<Message> {
         use crate::schema::messages::dsl::*;
 
         let msg = Message::new(sender, receiver, body_provided);
-        diesel::insert_into(messages).values(msg).execute(conn)?;
-        Ok(())
+        diesel::insert_into(messages).values(&msg).execute(conn)?;
+        Ok(msg)
+    }
+
+    // Send a message in the thread of the order, to the other party of it
+    pub fn send_about<T: ToString>(
+        conn: &SqliteConnection,
+        sender: &UserId,
+        order: &TransactionInfo,
+        body_provided: T,
+    ) -> Result<Message> {
+        use crate::schema::messages::dsl::*;
+
+        let receiver = if sender.get_id() == order.get_buyer() {
+            order.get_seller()
+        } else if sender.get_id() == order.get_seller() {
+            order.get_buyer()
+        } else {
+            return Err(SailsDbError::IllegalQuery);
+        };
+        let mut msg = Message::new(sender, &UserId::find(conn, receiver)?, body_provided);
+        msg.transaction_id = Some(order.get_id().to_string());
+        diesel::insert_into(messages).values(&msg).execute(conn)?;
+        Ok(msg)
+    }
+
+    // Let both parties know in the thread of the order that its status has changed from the one given.
+    // Nothing is posted if the status stays the same.
+    pub fn post_status_change(
+        conn: &SqliteConnection,
+        previous: &TransactionStatus,
+        order: &TransactionInfo,
+    ) -> Result<Option<Message>> {
+        use crate::schema::messages::dsl::*;
+
+        if previous == order.get_transaction_status() {
+            return Ok(None);
+        }
+        let msg = Message {
+            id: Uuid::new_v4().to_string(),
+            send: order.get_seller().to_string(),
+            recv: order.get_buyer().to_string(),
+            // The change is rendered from the statuses in the language of the reader
+            body: String::new(),
+            time_sent: chrono::offset::Local::now().naive_utc(),
+            time_read: None,
+            transaction_id: Some(order.get_id().to_string()),
+            is_system: true,
+            old_status: Some(previous.clone()),
+            new_status: Some(order.get_transaction_status().clone()),
+        };
+        diesel::insert_into(messages).values(&msg).execute(conn)?;
+        Ok(Some(msg))
+    }
+
+    // Messages about the order in a chronological order, including the ones from the system
+    pub fn thread(conn: &SqliteConnection, order: &TransactionInfo) -> Result<Vec<Message>> {
+        use crate::schema::messages::dsl::*;
+        Ok(messages
+            .filter(transaction_id.eq(order.get_id()))
+            .order(time_sent.asc())
+            .load::<Message>(conn)?)
+    }
+
+    // Mark the messages in the thread sent to `reader` as read
+    pub fn mark_thread_read(
+        conn: &SqliteConnection,
+        reader: &UserId,
+        order: &TransactionInfo,
+    ) -> Result<usize> {
+        use crate::schema::messages::dsl::*;
+        Ok(diesel::update(
+            messages
+                .filter(transaction_id.eq(order.get_id()))
+                .filter(recv.eq(reader.get_id()))
+                .filter(time_read.is_null()),
+        )
+        .set(time_read.eq(chrono::offset::Local::now().naive_utc()))
+        .execute(conn)?)
+    }
+
+    pub(crate) fn delete_by_transactions(conn: &SqliteConnection, ids: &[String]) -> Result<usize> {
+        use crate::schema::messages::dsl::*;
+
+        let msgs = messages
+            .filter(transaction_id.eq_any(ids))
+            .select(id)
+            .load::<String>(conn)?;
+        Attachments::delete_by_messages(conn, &msgs)?;
+        Ok(diesel::delete(messages.filter(id.eq_any(&msgs))).execute(conn)?)
+    }
+
+    // All messages sent or received by the user in a chronological order.
+    // System messages are left out, as they only tell what the orders already do.
+    pub fn list_with_user(conn: &SqliteConnection, user: &UserId) -> Result<Vec<Message>> {
+        use crate::schema::messages::dsl::*;
+        Ok(messages
+            .filter((send.eq(user.get_id())).or(recv.eq(user.get_id())))
+            .filter(is_system.eq(false))
+            .order(time_sent.asc())
+            .load::<Message>(conn)?)
     }
 
     pub fn delete_msg_with_user(conn: &SqliteConnection, user: &UserId) -> Result<usize> {
         use crate::schema::messages::dsl::*;
 
+        let ids = messages
+            .filter((send.eq(user.get_id())).or(recv.eq(user.get_id())))
+            .select(id)
+            .load::<String>(conn)?;
+        Attachments::delete_by_messages(conn, &ids)?;
         Ok(
             diesel::delete(messages.filter((send.eq(user.get_id())).or(recv.eq(user.get_id()))))
                 .execute(conn)?,
@@ -61,6 +216,61 @@ impl Messages {
     }
 }
 
+// A psuedo struct for managing attachments of messages
+pub struct Attachments;
+
+impl Attachments {
+    // Send a message with a file attached. Where and how the file is stored is up to the caller.
+    pub fn send<T: ToString>(
+        conn: &SqliteConnection,
+        sender: &UserId,
+        receiver: &UserId,
+        body: T,
+        content_type: &str,
+        location: &str,
+        nonce: &str,
+    ) -> Result<(Message, Attachment)> {
+        use crate::schema::messageattachments::dsl::messageattachments;
+        conn.transaction::<_, SailsDbError, _>(|| {
+            let msg = Messages::send(conn, sender, receiver, body)?;
+            let attachment = Attachment {
+                id: Uuid::new_v4().to_string(),
+                message_id: msg.id.clone(),
+                content_type: content_type.to_string(),
+                location: location.to_string(),
+                nonce: nonce.to_string(),
+                time_created: chrono::offset::Local::now().naive_utc(),
+            };
+            diesel::insert_into(messageattachments)
+                .values(&attachment)
+                .execute(conn)?;
+            Ok((msg, attachment))
+        })
+    }
+
+    pub fn find(conn: &SqliteConnection, id_provided: &str) -> Result<Attachment> {
+        use crate::schema::messageattachments::dsl::*;
+        Ok(messageattachments
+            .filter(id.eq(id_provided))
+            .first::<Attachment>(conn)?)
+    }
+
+    // Attachments of the messages given, in the order they were attached
+    pub fn of_messages(conn: &SqliteConnection, msgs: &[Message]) -> Result<Vec<Attachment>> {
+        use crate::schema::messageattachments::dsl::*;
+        Ok(messageattachments
+            .filter(message_id.eq_any(msgs.iter().map(|m| m.get_id())))
+            .order(time_created.asc())
+            .load::<Attachment>(conn)?)
+    }
+
+    // The files themselves are left to the caller
+    fn delete_by_messages(conn: &SqliteConnection, ids: &[String]) -> Result<usize> {
+        use crate::schema::messageattachments::dsl::*;
+        Ok(diesel::delete(messageattachments.filter(message_id.eq_any(ids))).execute(conn)?)
+    }
+}
+
 #[derive(
     Debug,
     Serialize,
@@ -78,6 +288,12 @@ pub struct Message {
     recv: String,
     body: String,
     time_sent: NaiveDateTime,
+    time_read: Option<NaiveDateTime>,
+    transaction_id: Option<String>,
+    is_system: bool,
+    // Status of the order before and after the change, for messages posted when the status changes
+    old_status: Option<TransactionStatus>,
+    new_status: Option<TransactionStatus>,
 }
 
 impl Message {
@@ -89,9 +305,18 @@ impl Message {
             body: body.to_string(),
             // This might have some issue with UTC
             time_sent: chrono::offset::Local::now().naive_utc(),
+            time_read: None,
+            transaction_id: None,
+            is_system: false,
+            old_status: None,
+            new_status: None,
         }
     }
 
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
     /// Get a reference to the message's send.
     pub fn get_send(&self) -> &str {
         &self.send
@@ -111,11 +336,92 @@ impl Message {
     pub fn get_time_sent(&self) -> &NaiveDateTime {
         &self.time_sent
     }
+
+    /// Get a reference to the time the receiver read the message, if read.
+    pub fn get_time_read(&self) -> Option<&NaiveDateTime> {
+        self.time_read.as_ref()
+    }
+
+    pub fn is_read(&self) -> bool {
+        self.time_read.is_some()
+    }
+
+    /// Get the order the message is about, if any.
+    pub fn get_transaction_id(&self) -> Option<&str> {
+        self.transaction_id.as_deref()
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.is_system
+    }
+
+    /// Get the status of the order before the change, if the message is about the change.
+    pub fn get_old_status(&self) -> Option<&TransactionStatus> {
+        self.old_status.as_ref()
+    }
+
+    /// Get the status of the order after the change, if the message is about the change.
+    pub fn get_new_status(&self) -> Option<&TransactionStatus> {
+        self.new_status.as_ref()
+    }
+}
+
+/// A file attached to a message, corresponding to a row in the table `messageattachments`
+#[derive(Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, Clone)]
+#[table_name = "messageattachments"]
+pub struct Attachment {
+    id: String,
+    message_id: String,
+    content_type: String,
+    location: String,
+    nonce: String,
+    time_created: NaiveDateTime,
+}
+
+impl Attachment {
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_message_id(&self) -> &str {
+        &self.message_id
+    }
+
+    pub fn get_content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    pub fn get_location(&self) -> &str {
+        &self.location
+    }
+
+    pub fn get_nonce(&self) -> &str {
+        &self.nonce
+    }
+
+    pub fn get_time_created(&self) -> &NaiveDateTime {
+        &self.time_created
+    }
+
+    // Only the participants of the conversation and the customer service could view the attachment
+    pub fn readable_by(&self, conn: &SqliteConnection, user: &UserInfo) -> Result<bool> {
+        use crate::schema::messages::dsl::*;
+        if user
+            .get_user_status()
+            .contains(UserStatus::CUSTOMER_SERVICE)
+        {
+            return Ok(true);
+        }
+        let msg = messages
+            .filter(id.eq(&self.message_id))
+            .first::<Message>(conn)?;
+        Ok((msg.send == user.get_id()) || (msg.recv == user.get_id()))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Messages;
+    use super::{Attachments, Messages};
     use crate::{test_utils::establish_connection, users::*};
 
     #[test]
@@ -155,8 +461,8 @@ mod tests {
         Messages::send(&conn, &sender2, &receiver, "Can you hear me?").unwrap();
 
         let list = Messages::get_list(&conn, &receiver).unwrap();
-        assert_eq!(list.get(0).unwrap().send, "AnotherSender@example.org");
-        assert_eq!(list.get(1).unwrap().send, "TestUser@example.org");
+        assert_eq!(list.get(0).unwrap().send, sender2.get_id());
+        assert_eq!(list.get(1).unwrap().send, sender.get_id());
     }
 
     #[test]
@@ -272,4 +578,197 @@ mod tests {
             0
         );
     }
+
+    #[test]
+    fn read_state() {
+        let conn = establish_connection();
+        let sender = UserForm::new("TestUser@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        let sender2 = UserForm::new("AnotherSender@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        let receiver = UserForm::new("Him@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        Messages::send(&conn, &sender, &receiver, "Hello").unwrap();
+        Messages::send(&conn, &sender, &receiver, "Are you there?").unwrap();
+        Messages::send(&conn, &sender2, &receiver, "Hello?").unwrap();
+        Messages::send(&conn, &receiver, &sender, "Yes!").unwrap();
+
+        assert_eq!(Messages::unread_count(&conn, &receiver).unwrap(), 3);
+        assert_eq!(Messages::unread_count(&conn, &sender).unwrap(), 1);
+        let unread = Messages::unread_by_sender(&conn, &receiver).unwrap();
+        assert_eq!(unread.get(sender.get_id()), Some(&2));
+        assert_eq!(unread.get(sender2.get_id()), Some(&1));
+
+        // Reading the conversation with one sender leaves the others unread
+        assert_eq!(Messages::mark_read(&conn, &receiver, &sender).unwrap(), 2);
+        assert_eq!(Messages::unread_count(&conn, &receiver).unwrap(), 1);
+        assert_eq!(Messages::unread_count(&conn, &sender).unwrap(), 1);
+        let conv = Messages::get_conv(&conn, &sender, &receiver).unwrap();
+        assert!(conv
+            .iter()
+            .all(|m| m.is_read() == (m.get_recv() == receiver.get_id())));
+
+        // Nothing left to be marked
+        assert_eq!(Messages::mark_read(&conn, &receiver, &sender).unwrap(), 0);
+    }
+
+    #[test]
+    fn attachments() {
+        let conn = establish_connection();
+        let sender = UserForm::new("TestUser@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        let receiver = UserForm::new("Him@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        let stranger = UserForm::new("Stranger@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        Messages::send(&conn, &sender, &receiver, "Hello").unwrap();
+        let (msg, attachment) = Attachments::send(
+            &conn,
+            &sender,
+            &receiver,
+            "The book arrived damaged",
+            "image/png",
+            "5f3c",
+            "AAAAAAAAAAAAAAAA",
+        )
+        .unwrap();
+        assert_eq!(attachment.get_message_id(), msg.get_id());
+
+        let conv = Messages::get_conv(&conn, &sender, &receiver).unwrap();
+        assert_eq!(conv.len(), 2);
+        let attached = Attachments::of_messages(&conn, &conv).unwrap();
+        assert_eq!(attached.len(), 1);
+        assert_eq!(attached[0].get_location(), "5f3c");
+
+        // Only the participants and the customer service could see it
+        let attachment = Attachments::find(&conn, attachment.get_id()).unwrap();
+        assert!(attachment
+            .readable_by(&conn, &sender.get_info(&conn).unwrap())
+            .unwrap());
+        assert!(attachment
+            .readable_by(&conn, &receiver.get_info(&conn).unwrap())
+            .unwrap());
+        let stranger_info = stranger.get_info(&conn).unwrap();
+        assert!(!attachment.readable_by(&conn, &stranger_info).unwrap());
+        let staff = stranger_info
+            .set_user_status(crate::enums::UserStatus::CUSTOMER_SERVICE)
+            .update(&conn)
+            .unwrap();
+        assert!(attachment.readable_by(&conn, &staff).unwrap());
+
+        // Attachments go with the messages
+        Messages::delete_msg_with_user(&conn, &sender).unwrap();
+        assert!(Attachments::find(&conn, attachment.get_id()).is_err());
+    }
+
+    #[test]
+    fn order_thread() {
+        use crate::{
+            categories::{Category, CtgTrait},
+            enums::{Currency, Payment, ProductStatus, TransactionStatus},
+            products::IncompleteProduct,
+            transactions::Transactions,
+        };
+
+        let conn = establish_connection();
+        let seller = UserForm::new("TestUser@example.org", "Kanyang Ying", "NFLS", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+        let buyer = UserForm::new("TestUser2@example.org", "Mick Zhang", "NFLS", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+        let stranger = UserForm::new("Stranger@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+        let econ = Category::create(&conn, "Economics", 1)
+            .and_then(Category::into_leaf)
+            .unwrap();
+        let book =
+            IncompleteProduct::new(&econ, "Economics", 100, 2, "A horrible book", Currency::CNY)
+                .unwrap()
+                .create(&conn, &seller)
+                .unwrap();
+        book.get_info(&conn)
+            .unwrap()
+            .set_product_status(ProductStatus::Verified)
+            .update(&conn)
+            .unwrap();
+        let order = Transactions::buy(&conn, &book, &buyer, 1, "NFLS", "", Payment::Alipay)
+            .unwrap()
+            .get_info(&conn)
+            .unwrap();
+
+        Messages::send(&conn, &buyer, &seller, "Hi").unwrap();
+        let msg = Messages::send_about(&conn, &buyer, &order, "When will it ship?").unwrap();
+        assert_eq!(msg.get_recv(), seller.get_id());
+        assert_eq!(msg.get_transaction_id(), Some(order.get_id()));
+        Messages::send_about(&conn, &seller, &order, "Tomorrow").unwrap();
+        // Only the parties of the order could post
+        assert!(Messages::send_about(&conn, &stranger, &order, "Hello").is_err());
+
+        // Status changes are posted along with the statuses
+        let order = order
+            .set_transaction_status(TransactionStatus::Paid)
+            .update(&conn)
+            .unwrap();
+        assert!(
+            Messages::post_status_change(&conn, &TransactionStatus::Placed, &order)
+                .unwrap()
+                .is_some()
+        );
+        // Unless the status stays the same
+        assert!(
+            Messages::post_status_change(&conn, &TransactionStatus::Paid, &order)
+                .unwrap()
+                .is_none()
+        );
+        let thread = Messages::thread(&conn, &order).unwrap();
+        assert_eq!(thread.len(), 3);
+        assert!(thread[2].is_system());
+        assert_eq!(thread[2].get_old_status(), Some(&TransactionStatus::Placed));
+        assert_eq!(thread[2].get_new_status(), Some(&TransactionStatus::Paid));
+
+        // System messages stay out of the conversations and the unread counts
+        assert_eq!(Messages::get_conv(&conn, &buyer, &seller).unwrap().len(), 3);
+        assert_eq!(Messages::unread_count(&conn, &buyer).unwrap(), 1);
+        assert_eq!(
+            Messages::mark_thread_read(&conn, &buyer, &order).unwrap(),
+            2
+        );
+        assert_eq!(Messages::unread_count(&conn, &buyer).unwrap(), 0);
+
+        // Threads go with the orders
+        buyer.purge(&conn).unwrap();
+        assert_eq!(Messages::thread(&conn, &order).unwrap().len(), 0);
+    }
 }
@@ -1,20 +1,23 @@
 // We have to ensure: all the places where category can be supplied has to be using type Category instead of String.
 // If we cannot ensure on derivations like those did by serde, we then have to use isolation types to ensure it on a type level
 
-use std::{collections::HashSet, num::NonZeroU32};
+use std::{
+    collections::{HashMap, HashSet},
+    num::NonZeroU32,
+};
 
 use crate::{
     categories::{Categories, CtgTrait, LeafCategory},
     digicons::DigiconMappingFinder,
     enums::{Currency, ProductStatus, UserStatus},
     error::{SailsDbError, SailsDbResult as Result},
-    schema::products,
+    schema::{products, tagmappings},
     tags::TagMappingFinder,
     transactions::TransactionFinder,
     users::UserId,
     Cmp, Order,
 };
-use diesel::{prelude::*, sql_types::Bool, sqlite::Sqlite};
+use diesel::{prelude::*, sqlite::Sqlite};
 use rocket::FromForm;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -74,10 +77,28 @@ pub struct Products;
 impl Products {
     pub fn delete_by_seller(conn: &SqliteConnection, seller: &UserId) -> Result<()> {
         for p in ProductFinder::new(conn, None).seller(seller).search()? {
+            DigiconMappingFinder::new(conn, None).delete_by_product(&p)?;
             p.delete(conn)?;
         }
         Ok(())
     }
+
+    // Products that have ever been ordered are handed over to the tombstone of a deleted seller and taken off the store.
+    // The rest are deleted.
+    pub(crate) fn hand_over(conn: &SqliteConnection, from: &UserId, to: &UserId) -> Result<()> {
+        for p in ProductFinder::new(conn, None).seller(from).search()? {
+            if TransactionFinder::new(conn, None).product(&p).count_i64()? > 0 {
+                p.get_info(conn)?
+                    .set_seller_id(to.get_id())
+                    .set_product_status(ProductStatus::Disabled)
+                    .update(conn)?;
+            } else {
+                DigiconMappingFinder::new(conn, None).delete_by_product(&p)?;
+                p.delete(conn)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 type BoxedQuery<'a> = products::BoxedQuery<'a, Sqlite, products::SqlType>;
@@ -202,14 +223,15 @@ impl<'a> ProductFinder<'a> {
         self
     }
 
+    // Products under any category node, including everything in its subtree.
     pub fn category(mut self, category_provided: &'a impl CtgTrait) -> Result<Self> {
-        use crate::schema::products::{dsl::*, table};
-        let mut criteria: Box<dyn BoxableExpression<table, Sqlite, SqlType = Bool>> =
-            Box::new(false.into_sql::<Bool>());
-        for ctg in Categories::list_leaves(self.conn, Some(category_provided))? {
-            criteria = Box::new(criteria.or(category.eq(CtgTrait::id(&ctg).to_string())));
-        }
-        self.query = self.query.filter(criteria);
+        use crate::schema::products::dsl::*;
+        // Products can only be attached to leaves, but it is harmless to include the non-leaf ones.
+        let ids = Categories::subtree(self.conn, category_provided)?
+            .into_iter()
+            .map(|ctg| CtgTrait::id(&ctg).to_string())
+            .collect::<Vec<String>>();
+        self.query = self.query.filter(category.eq_any(ids));
         Ok(self)
     }
 
@@ -254,6 +276,104 @@ impl<'a> ProductFinder<'a> {
             .filter(product_status.ne(ProductStatus::Disabled));
         self
     }
+
+    // Products carrying the given tag. Chaining multiple calls requires all of the tags to be present.
+    pub fn tag(mut self, tag_provided: &'a str) -> Self {
+        use crate::schema::products::dsl::*;
+        let tagged = tagmappings::table
+            .select(tagmappings::product)
+            .filter(tagmappings::tag.eq(tag_provided));
+        self.query = self.query.filter(id.eq_any(tagged));
+        self
+    }
+
+    pub fn currency(mut self, currency_provided: Currency) -> Self {
+        use crate::schema::products::dsl::*;
+        self.query = self.query.filter(currency.eq(currency_provided));
+        self
+    }
+
+    pub fn in_stock(mut self) -> Self {
+        use crate::schema::products::dsl::*;
+        self.query = self.query.filter(quantity.gt(0));
+        self
+    }
+
+    // Search and count the results by category, tag, currency, and seller.
+    // The finder is built twice, as the tags are counted with the search as a subquery.
+    pub fn facets(build: impl Fn() -> Result<Self>) -> Result<(Vec<ProductInfo>, ProductFacets)> {
+        let prods = build()?.search_info()?;
+        let mut facets = ProductFacets {
+            total: prods.len(),
+            ..Default::default()
+        };
+        for p in &prods {
+            *facets.categories.entry(p.category.clone()).or_default() += 1;
+            *facets.currencies.entry(p.currency.clone()).or_default() += 1;
+            *facets.sellers.entry(p.seller_id.clone()).or_default() += 1;
+        }
+
+        let finder = build()?;
+        let ids = finder.query.select(products::id);
+        // Each product can only be mapped to a tag once, so counting the mappings is counting the products.
+        for t in tagmappings::table
+            .select(tagmappings::tag)
+            .filter(tagmappings::product.eq_any(ids))
+            .load::<String>(finder.conn)?
+        {
+            *facets.tags.entry(t).or_default() += 1;
+        }
+        Ok((prods, facets))
+    }
+}
+
+/// Number of products matching each of the facets within a search result.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProductFacets {
+    total: usize,
+    // Keyed by leaf category IDs
+    categories: HashMap<String, usize>,
+    tags: HashMap<String, usize>,
+    currencies: HashMap<Currency, usize>,
+    sellers: HashMap<String, usize>,
+}
+
+impl ProductFacets {
+    pub fn get_total(&self) -> usize {
+        self.total
+    }
+
+    // Number of products under the given category, including all of its subcategories.
+    pub fn category(&self, conn: &SqliteConnection, ctg: &impl CtgTrait) -> Result<usize> {
+        Ok(Categories::subtree(conn, ctg)?
+            .iter()
+            .map(|c| self.categories.get(c.id()).copied().unwrap_or(0))
+            .sum())
+    }
+
+    pub fn tag(&self, tag_id: &str) -> usize {
+        self.tags.get(tag_id).copied().unwrap_or(0)
+    }
+
+    pub fn currency(&self, currency: &Currency) -> usize {
+        self.currencies.get(currency).copied().unwrap_or(0)
+    }
+
+    pub fn seller(&self, seller_id: &str) -> usize {
+        self.sellers.get(seller_id).copied().unwrap_or(0)
+    }
+
+    pub fn get_tags(&self) -> &HashMap<String, usize> {
+        &self.tags
+    }
+
+    pub fn get_currencies(&self) -> &HashMap<Currency, usize> {
+        &self.currencies
+    }
+
+    pub fn get_sellers(&self) -> &HashMap<String, usize> {
+        &self.sellers
+    }
 }
 
 pub trait ToSafe<T> {
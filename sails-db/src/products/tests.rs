@@ -265,3 +265,123 @@ fn product_status() {
         0
     );
 }
+
+#[test]
+fn faceted_search() {
+    let conn = establish_connection();
+    TagsBuilder::new(HashMap::new()).build(&conn).unwrap();
+
+    let alice = UserForm::new("Alice@example.org", "NFLS", "", None)
+        .to_ref()
+        .unwrap()
+        .create(&conn)
+        .unwrap();
+    let bob = UserForm::new("Bob@example.org", "NFLS", "", None)
+        .to_ref()
+        .unwrap()
+        .create(&conn)
+        .unwrap();
+
+    let mut books = Category::create(&conn, "Books", 1).unwrap();
+    let mut econ = Category::create(&conn, "Economics Books", 1)
+        .and_then(Category::into_leaf)
+        .unwrap();
+    let mut phys = Category::create(&conn, "Physics Books", 1)
+        .and_then(Category::into_leaf)
+        .unwrap();
+    econ.insert(&conn, &mut books).unwrap();
+    phys.insert(&conn, &mut books).unwrap();
+
+    let krugman = IncompleteProduct::new(
+        &econ,
+        "Krugman's Economics 2nd Edition",
+        700,
+        1,
+        "A very great book on the subject of Economics",
+        Currency::CNY,
+    )
+    .unwrap()
+    .create(&conn, &alice)
+    .unwrap();
+    let mankiw = IncompleteProduct::new(
+        &econ,
+        "Principles of Economics",
+        400,
+        2,
+        "Mankiw's classic",
+        Currency::CNY,
+    )
+    .unwrap()
+    .create(&conn, &bob)
+    .unwrap();
+    let feynman = IncompleteProduct::new(
+        &phys,
+        "Feynman's Lecture on Physics",
+        900,
+        1,
+        "A very masterpiece on the theory of the universe",
+        Currency::USD,
+    )
+    .unwrap()
+    .create(&conn, &alice)
+    .unwrap();
+
+    let sales = Tags::find_by_id(&conn, "sales").unwrap();
+    let digicon = Tags::find_by_id(&conn, "digicon").unwrap();
+    TagMapping::create(&conn, &sales, &krugman).unwrap();
+    TagMapping::create(&conn, &sales, &feynman).unwrap();
+    TagMapping::create(&conn, &digicon, &feynman).unwrap();
+
+    // Facets over all the products
+    let (prods, facets) = ProductFinder::facets(|| Ok(ProductFinder::new(&conn, None))).unwrap();
+    assert_eq!(prods.len(), 3);
+    assert_eq!(facets.get_total(), 3);
+    assert_eq!(facets.category(&conn, &books).unwrap(), 3);
+    assert_eq!(facets.category(&conn, &econ).unwrap(), 2);
+    assert_eq!(facets.tag("sales"), 2);
+    assert_eq!(facets.tag("digicon"), 1);
+    assert_eq!(facets.currency(&Currency::CNY), 2);
+    assert_eq!(facets.seller(alice.get_id()), 2);
+
+    // Multiple tags have to be present at the same time
+    assert_eq!(
+        ProductFinder::new(&conn, None)
+            .tag("sales")
+            .tag("digicon")
+            .search()
+            .unwrap(),
+        vec![feynman.clone()]
+    );
+
+    // Category subtree, price range in a given currency, and seller combined
+    let (prods, facets) = ProductFinder::facets(|| {
+        Ok(ProductFinder::new(&conn, None)
+            .category(&books)?
+            .currency(Currency::CNY)
+            .price(300, Cmp::GreaterEqual)
+            .price(800, Cmp::LessEqual)
+            .seller(&alice))
+    })
+    .unwrap();
+    assert_eq!(prods.len(), 1);
+    assert_eq!(prods[0].to_id(), krugman);
+    assert_eq!(facets.tag("sales"), 1);
+    assert_eq!(facets.tag("digicon"), 0);
+
+    // Sold out products are left out
+    mankiw
+        .get_info(&conn)
+        .unwrap()
+        .sub_quantity(2)
+        .unwrap()
+        .update(&conn)
+        .unwrap();
+    assert_eq!(
+        ProductFinder::facets(|| Ok(ProductFinder::new(&conn, None).in_stock()))
+            .unwrap()
+            .1
+            .category(&conn, &econ)
+            .unwrap(),
+        1
+    );
+}
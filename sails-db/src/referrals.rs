@@ -0,0 +1,774 @@
+// Referral program: every user can share a code, and users who signed up with it are attributed to the owner.
+// Rewards are granted once the first paid order of the referee gets finished.
+
+use crate::{
+    coupons::{Coupon, CouponFinder},
+    credits::Credits,
+    enums::{
+        CouponStacking, CreditKind, Currency, ReferralRecipient, ReferralRewardKind,
+        TransactionStatus,
+    },
+    error::{SailsDbError, SailsDbResult as Result},
+    schema::{referralcodes, referralrewards, referrals},
+    transactions::TransactionInfo,
+    users::UserId,
+};
+use chrono::naive::NaiveDateTime;
+use diesel::{prelude::*, sqlite::Sqlite};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, convert::TryFrom};
+use uuid::Uuid;
+
+// A psuedo struct for managing referrals
+pub struct Referrals;
+
+impl Referrals {
+    // Attribute a newly created user to the owner of the referral code
+    pub fn attribute(
+        conn: &SqliteConnection,
+        referee_p: &UserId,
+        code_p: &str,
+    ) -> Result<Referral> {
+        use crate::schema::referrals::dsl::*;
+        let code_p = ReferralCode::normalize(code_p);
+        let owner = ReferralCodes::find(conn, &code_p)?;
+        if owner.user_id == referee_p.get_id() {
+            return Err(SailsDbError::ReferralNotAllowed);
+        }
+        // Users can only be referred once
+        if ReferralFinder::new(conn, None)
+            .referee(referee_p)
+            .first()
+            .is_ok()
+        {
+            return Err(SailsDbError::ReferralNotAllowed);
+        }
+
+        let referral = Referral {
+            referee: referee_p.get_id().to_string(),
+            referrer: owner.user_id,
+            code: owner.id,
+            time_created: chrono::offset::Local::now().naive_utc(),
+            transaction_id: None,
+            time_rewarded: None,
+            referrer_coupons: None,
+            referee_coupons: None,
+        };
+        diesel::insert_into(referrals)
+            .values(&referral)
+            .execute(conn)?;
+        Ok(referral)
+    }
+
+    // Grant the rewards if the order given is the first finished order of a referred user.
+    // Returns the referral rewarded, if any.
+    pub fn reward(conn: &SqliteConnection, tx: &TransactionInfo) -> Result<Option<Referral>> {
+        use crate::schema::referrals::dsl::*;
+        // Free orders don't count, otherwise the program is trivially abused
+        if (tx.get_transaction_status() != &TransactionStatus::Finished)
+            || (tx.get_total() == 0u32.into())
+        {
+            return Ok(None);
+        }
+        conn.transaction::<_, SailsDbError, _>(|| {
+            let mut referral = match referrals
+                .filter(referee.eq(tx.get_buyer()))
+                .filter(time_rewarded.is_null())
+                .first::<Referral>(conn)
+            {
+                Err(diesel::result::Error::NotFound) => return Ok(None),
+                r => r?,
+            };
+
+            for rule in ReferralRewards::list(conn)? {
+                let recipient_id = match rule.recipient {
+                    ReferralRecipient::Referrer => referral.referrer.clone(),
+                    ReferralRecipient::Referee => referral.referee.clone(),
+                };
+                let recipient = UserId::find(conn, &recipient_id)?;
+                if let Some(coupon) = rule.grant(conn, &recipient, &referral)? {
+                    referral = referral.add_coupon(&rule.recipient, coupon);
+                }
+            }
+
+            let referral = referral
+                .set_transaction_id(tx.get_id())
+                .set_time_rewarded(chrono::offset::Local::now().naive_utc());
+            diesel::update(referrals.filter(referee.eq(&referral.referee)))
+                .set((
+                    transaction_id.eq(&referral.transaction_id),
+                    time_rewarded.eq(&referral.time_rewarded),
+                    referrer_coupons.eq(&referral.referrer_coupons),
+                    referee_coupons.eq(&referral.referee_coupons),
+                ))
+                .execute(conn)?;
+            Ok(Some(referral))
+        })
+    }
+
+    // Revoke the rewards granted for the order given, which is being refunded.
+    // Credits are taken back and coupons expire right away. The next finished order of the referee could be rewarded again.
+    // Returns the referral revoked, if any.
+    pub fn revoke(conn: &SqliteConnection, tx: &TransactionInfo) -> Result<Option<Referral>> {
+        use crate::schema::referrals::dsl::*;
+        conn.transaction::<_, SailsDbError, _>(|| {
+            let referral = match referrals
+                .filter(transaction_id.eq(tx.get_id()))
+                .first::<Referral>(conn)
+                .optional()?
+            {
+                Some(r) => r,
+                None => return Ok(None),
+            };
+
+            Credits::take_back(
+                conn,
+                CreditKind::Referral,
+                &referral.referee,
+                Some("Referral reward revoked as the order was refunded"),
+            )?;
+            let now = chrono::offset::Local::now().naive_utc();
+            for coupon in referral
+                .get_coupons(&ReferralRecipient::Referrer)
+                .into_iter()
+                .chain(referral.get_coupons(&ReferralRecipient::Referee))
+            {
+                CouponFinder::new(conn, None)
+                    .id(coupon)
+                    .first()?
+                    .set_valid_until(Some(now))
+                    .update(conn)?;
+            }
+
+            diesel::update(referrals.filter(referee.eq(&referral.referee)))
+                .set((
+                    transaction_id.eq(None::<String>),
+                    time_rewarded.eq(None::<NaiveDateTime>),
+                    referrer_coupons.eq(None::<String>),
+                    referee_coupons.eq(None::<String>),
+                ))
+                .execute(conn)?;
+            Ok(Some(referral))
+        })
+    }
+
+    // Referrals aggregated by referrer, most referrals first
+    pub fn report(conn: &SqliteConnection) -> Result<Vec<ReferralReport>> {
+        let mut reports: HashMap<String, ReferralReport> = HashMap::new();
+        // Schools are looked up once per user
+        let mut schools: HashMap<String, String> = HashMap::new();
+        let mut school_of = |id: &str| -> Result<String> {
+            if let Some(s) = schools.get(id) {
+                return Ok(s.clone());
+            }
+            let s = UserId::find(conn, id)?
+                .get_info(conn)?
+                .get_school()
+                .to_string();
+            schools.insert(id.to_string(), s.clone());
+            Ok(s)
+        };
+
+        for referral in ReferralFinder::new(conn, None).search()? {
+            let referrer_school = school_of(&referral.referrer)?;
+            let referee_school = school_of(&referral.referee)?;
+            let report =
+                reports
+                    .entry(referral.referrer.clone())
+                    .or_insert_with(|| ReferralReport {
+                        referrer: referral.referrer.clone(),
+                        school: referrer_school.clone(),
+                        ..Default::default()
+                    });
+            report.referred += 1;
+            if referral.time_rewarded.is_some() {
+                report.rewarded += 1;
+            }
+            if !referrer_school.is_empty() && (referrer_school == referee_school) {
+                report.same_school += 1;
+            }
+        }
+
+        let mut reports = reports.into_values().collect::<Vec<ReferralReport>>();
+        reports.sort_by(|a, b| {
+            b.referred
+                .cmp(&a.referred)
+                .then_with(|| a.referrer.cmp(&b.referrer))
+        });
+        Ok(reports)
+    }
+
+    pub fn delete_by_user(conn: &SqliteConnection, user: &UserId) -> Result<()> {
+        diesel::delete(
+            referrals::table.filter(
+                referrals::referee
+                    .eq(user.get_id())
+                    .or(referrals::referrer.eq(user.get_id())),
+            ),
+        )
+        .execute(conn)?;
+        diesel::delete(referralcodes::table.filter(referralcodes::user_id.eq(user.get_id())))
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReferralReport {
+    pub referrer: String,
+    pub school: String,
+    // Number of users signed up with the code
+    pub referred: usize,
+    // Number of referees who have got their first order finished
+    pub rewarded: usize,
+    // Number of referees from the same school as the referrer
+    pub same_school: usize,
+}
+
+// A psuedo struct for managing referral codes
+pub struct ReferralCodes;
+
+impl ReferralCodes {
+    // Get the referral code of the user, creating one if there is none
+    pub fn of(conn: &SqliteConnection, user: &UserId) -> Result<ReferralCode> {
+        use crate::schema::referralcodes::dsl::*;
+        match referralcodes
+            .filter(user_id.eq(user.get_id()))
+            .first::<ReferralCode>(conn)
+        {
+            Err(diesel::result::Error::NotFound) => {
+                let code = ReferralCode {
+                    id: Uuid::new_v4().simple().to_string()[..8].to_uppercase(),
+                    user_id: user.get_id().to_string(),
+                    time_created: chrono::offset::Local::now().naive_utc(),
+                };
+                diesel::insert_into(referralcodes)
+                    .values(&code)
+                    .execute(conn)?;
+                Ok(code)
+            }
+            r => Ok(r?),
+        }
+    }
+
+    // The referral code of the user without creating one
+    pub fn find_by_user(conn: &SqliteConnection, user: &UserId) -> Result<Option<ReferralCode>> {
+        use crate::schema::referralcodes::dsl::*;
+        Ok(referralcodes
+            .filter(user_id.eq(user.get_id()))
+            .first::<ReferralCode>(conn)
+            .optional()?)
+    }
+
+    pub fn find(conn: &SqliteConnection, code: &str) -> Result<ReferralCode> {
+        use crate::schema::referralcodes::dsl::*;
+        match referralcodes
+            .filter(id.eq(ReferralCode::normalize(code)))
+            .first::<ReferralCode>(conn)
+        {
+            Err(diesel::result::Error::NotFound) => Err(SailsDbError::ReferralCodeNotFound),
+            r => Ok(r?),
+        }
+    }
+}
+
+/// A referral code, corresponding to a row in the table `referralcodes`
+#[derive(Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, Clone)]
+#[table_name = "referralcodes"]
+pub struct ReferralCode {
+    id: String,
+    user_id: String,
+    time_created: NaiveDateTime,
+}
+
+impl ReferralCode {
+    // Codes are case-insensitive and surrounding spaces are ignored
+    fn normalize(code: &str) -> String {
+        code.trim().to_uppercase()
+    }
+
+    pub fn get_code(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn get_time_created(&self) -> &NaiveDateTime {
+        &self.time_created
+    }
+}
+
+/// A referral, corresponding to a row in the table `referrals`
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "referrals"]
+pub struct Referral {
+    referee: String,
+    referrer: String,
+    code: String,
+    time_created: NaiveDateTime,
+    transaction_id: Option<String>,
+    time_rewarded: Option<NaiveDateTime>,
+    // Comma-separated IDs of the coupons granted as rewards
+    referrer_coupons: Option<String>,
+    referee_coupons: Option<String>,
+}
+
+impl Referral {
+    fn add_coupon(mut self, recipient: &ReferralRecipient, coupon: String) -> Self {
+        let coupons = match recipient {
+            ReferralRecipient::Referrer => &mut self.referrer_coupons,
+            ReferralRecipient::Referee => &mut self.referee_coupons,
+        };
+        *coupons = Some(match coupons.take() {
+            Some(c) => format!("{},{}", c, coupon),
+            None => coupon,
+        });
+        self
+    }
+
+    fn set_transaction_id(mut self, transaction_id: impl ToString) -> Self {
+        self.transaction_id = Some(transaction_id.to_string());
+        self
+    }
+
+    fn set_time_rewarded(mut self, time_rewarded: NaiveDateTime) -> Self {
+        self.time_rewarded = Some(time_rewarded);
+        self
+    }
+
+    pub fn get_referee(&self) -> &str {
+        &self.referee
+    }
+
+    pub fn get_referrer(&self) -> &str {
+        &self.referrer
+    }
+
+    pub fn get_code(&self) -> &str {
+        &self.code
+    }
+
+    pub fn get_time_created(&self) -> &NaiveDateTime {
+        &self.time_created
+    }
+
+    pub fn get_transaction_id(&self) -> Option<&str> {
+        self.transaction_id.as_deref()
+    }
+
+    pub fn get_time_rewarded(&self) -> Option<&NaiveDateTime> {
+        self.time_rewarded.as_ref()
+    }
+
+    // Coupons granted to the recipient given
+    pub fn get_coupons(&self, recipient: &ReferralRecipient) -> Vec<&str> {
+        match recipient {
+            ReferralRecipient::Referrer => &self.referrer_coupons,
+            ReferralRecipient::Referee => &self.referee_coupons,
+        }
+        .as_deref()
+        .map(|c| c.split(',').collect())
+        .unwrap_or_default()
+    }
+}
+
+type BoxedQuery<'a> = referrals::BoxedQuery<'a, Sqlite, referrals::SqlType>;
+
+/// A search query helper (builder)
+pub struct ReferralFinder<'a> {
+    conn: &'a SqliteConnection,
+    query: BoxedQuery<'a>,
+}
+
+impl<'a> ReferralFinder<'a> {
+    pub fn new(conn: &'a SqliteConnection, query: Option<BoxedQuery<'a>>) -> Self {
+        use crate::schema::referrals::dsl::*;
+        if let Some(q) = query {
+            Self { conn, query: q }
+        } else {
+            Self {
+                conn,
+                query: referrals.into_boxed(),
+            }
+        }
+    }
+
+    // Most recent referrals come first
+    pub fn search(self) -> Result<Vec<Referral>> {
+        use crate::schema::referrals::dsl::*;
+        Ok(self
+            .query
+            .order(time_created.desc())
+            .load::<Referral>(self.conn)?)
+    }
+
+    pub fn first(self) -> Result<Referral> {
+        Ok(self.query.first::<Referral>(self.conn)?)
+    }
+
+    pub fn referrer(mut self, user: &'a UserId) -> Self {
+        use crate::schema::referrals::dsl::*;
+        self.query = self.query.filter(referrer.eq(user.get_id()));
+        self
+    }
+
+    pub fn referee(mut self, user: &'a UserId) -> Self {
+        use crate::schema::referrals::dsl::*;
+        self.query = self.query.filter(referee.eq(user.get_id()));
+        self
+    }
+}
+
+// A psuedo struct for managing the reward rules
+pub struct ReferralRewards;
+
+impl ReferralRewards {
+    pub fn list(conn: &SqliteConnection) -> Result<Vec<ReferralReward>> {
+        use crate::schema::referralrewards::dsl::*;
+        Ok(referralrewards.load::<ReferralReward>(conn)?)
+    }
+
+    pub fn find(conn: &SqliteConnection, id_p: &str) -> Result<ReferralReward> {
+        use crate::schema::referralrewards::dsl::*;
+        Ok(referralrewards
+            .filter(id.eq(id_p))
+            .first::<ReferralReward>(conn)?)
+    }
+}
+
+/// A reward rule, corresponding to a row in the table `referralrewards`
+#[derive(Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, Clone)]
+#[table_name = "referralrewards"]
+pub struct ReferralReward {
+    id: String,
+    recipient: ReferralRecipient,
+    kind: ReferralRewardKind,
+    currency: Currency,
+    amount: i64,
+}
+
+impl ReferralReward {
+    pub fn new(
+        conn: &SqliteConnection,
+        recipient: ReferralRecipient,
+        kind: ReferralRewardKind,
+        currency: Currency,
+        amount: u32,
+    ) -> Result<Self> {
+        use crate::schema::referralrewards::dsl::*;
+        if amount == 0 {
+            return Err(SailsDbError::IllegalPriceOrQuantity);
+        }
+        let reward = Self {
+            id: Uuid::new_v4().to_string(),
+            recipient,
+            kind,
+            currency,
+            amount: amount as i64,
+        };
+        diesel::insert_into(referralrewards)
+            .values(&reward)
+            .execute(conn)?;
+        Ok(reward)
+    }
+
+    pub fn delete(self, conn: &SqliteConnection) -> Result<()> {
+        use crate::schema::referralrewards::dsl::*;
+        diesel::delete(referralrewards.filter(id.eq(&self.id))).execute(conn)?;
+        Ok(())
+    }
+
+    // Returns the ID of the coupon created, if any
+    fn grant(
+        &self,
+        conn: &SqliteConnection,
+        user: &UserId,
+        referral: &Referral,
+    ) -> Result<Option<String>> {
+        let amount = u32::try_from(self.amount).map_err(|_| SailsDbError::Overflow)?;
+        match self.kind {
+            ReferralRewardKind::Credit => {
+                Credits::grant(
+                    conn,
+                    user,
+                    self.currency.clone(),
+                    amount,
+                    CreditKind::Referral,
+                    Some(referral.get_referee()),
+                    None,
+                )?;
+                Ok(None)
+            }
+            ReferralRewardKind::Coupon => {
+                // A single-use coupon which could only be used by the recipient in the given currency
+                let script = format!(
+                    r#"if buyer.get_id() != {:?} || currency != "{:?}" {{ throw "this coupon is not applicable"; }}
+let left = subtotal - discounted;
+if left < {} {{ left }} else {{ {} }}"#,
+                    user.get_id(),
+                    self.currency,
+                    amount,
+                    amount
+                );
+                let coupon_id = format!(
+                    "REF-{}",
+                    Uuid::new_v4().simple().to_string()[..8].to_uppercase()
+                );
+                Coupon::new_without_db(&coupon_id, script)
+                    .set_description(Some(format!(
+                        "Referral reward for {}",
+                        referral.get_referee()
+                    )))
+                    .set_max_total_uses(Some(1))
+                    .set_stacking(CouponStacking::Stackable)
+                    .create(conn)?;
+                Ok(Some(coupon_id))
+            }
+        }
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_recipient(&self) -> &ReferralRecipient {
+        &self.recipient
+    }
+
+    pub fn get_kind(&self) -> &ReferralRewardKind {
+        &self.kind
+    }
+
+    pub fn get_currency(&self) -> &Currency {
+        &self.currency
+    }
+
+    pub fn get_amount(&self) -> i64 {
+        self.amount
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        categories::{Category, CtgTrait},
+        coupons::CouponFinder,
+        credits::CreditFinder,
+        enums::{Payment, ProductStatus},
+        products::IncompleteProduct,
+        test_utils::establish_connection,
+        transactions::*,
+        users::*,
+    };
+
+    #[test]
+    fn referral_rewards() {
+        let conn = establish_connection();
+        let seller = UserForm::new("TestUser@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        let referrer = UserForm::new("Referrer@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        let referee = UserForm::new("Referee@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        let code = ReferralCodes::of(&conn, &referrer).unwrap();
+        // The code is stable
+        assert_eq!(
+            ReferralCodes::of(&conn, &referrer).unwrap().get_code(),
+            code.get_code()
+        );
+
+        // Self-referral is not allowed
+        assert!(matches!(
+            Referrals::attribute(&conn, &referrer, code.get_code()).unwrap_err(),
+            SailsDbError::ReferralNotAllowed
+        ));
+        assert!(matches!(
+            Referrals::attribute(&conn, &referee, "NOTACODE").unwrap_err(),
+            SailsDbError::ReferralCodeNotFound
+        ));
+        // Codes are case-insensitive
+        Referrals::attribute(&conn, &referee, &code.get_code().to_lowercase()).unwrap();
+        // Users can only be referred once
+        assert!(matches!(
+            Referrals::attribute(&conn, &referee, code.get_code()).unwrap_err(),
+            SailsDbError::ReferralNotAllowed
+        ));
+
+        ReferralReward::new(
+            &conn,
+            ReferralRecipient::Referrer,
+            ReferralRewardKind::Credit,
+            Currency::USD,
+            50,
+        )
+        .unwrap();
+        ReferralReward::new(
+            &conn,
+            ReferralRecipient::Referee,
+            ReferralRewardKind::Coupon,
+            Currency::USD,
+            100,
+        )
+        .unwrap();
+
+        let econ = Category::create(&conn, "Economics Books", 1)
+            .and_then(Category::into_leaf)
+            .unwrap();
+        let book_id = IncompleteProduct::new(
+            &econ,
+            "Krugman's Economics 2nd Edition",
+            700,
+            10,
+            "A very great book on the subject of Economics",
+            Currency::USD,
+        )
+        .unwrap()
+        .create(&conn, &seller)
+        .unwrap();
+        book_id
+            .get_info(&conn)
+            .unwrap()
+            .set_product_status(ProductStatus::Verified)
+            .update(&conn)
+            .unwrap();
+
+        let buy = |buyer: &UserId, coupon: &str| {
+            Transactions::buy(
+                &conn,
+                &book_id,
+                buyer,
+                1,
+                "258 Huanhu South Road, Dongqian Lake, Ningbo, China",
+                coupon,
+                Payment::Paypal,
+            )
+            .unwrap()
+            .get_info(&conn)
+            .unwrap()
+        };
+
+        // Nothing is granted until the order gets finished
+        let order = buy(&referee, "")
+            .set_transaction_status(TransactionStatus::Paid)
+            .update(&conn)
+            .unwrap();
+        assert!(Referrals::reward(&conn, &order).unwrap().is_none());
+        assert_eq!(
+            Credits::balance(&conn, &referrer, &Currency::USD).unwrap(),
+            0
+        );
+        let order = order
+            .set_transaction_status(TransactionStatus::Finished)
+            .update(&conn)
+            .unwrap();
+        assert!(Referrals::reward(&conn, &order).unwrap().is_some());
+        assert_eq!(
+            Credits::balance(&conn, &referrer, &Currency::USD).unwrap(),
+            50
+        );
+        assert_eq!(
+            CreditFinder::new(&conn, None)
+                .kind(CreditKind::Referral)
+                .search()
+                .unwrap()[0]
+                .get_reference(),
+            Some(referee.get_id())
+        );
+
+        let referral = ReferralFinder::new(&conn, None)
+            .referee(&referee)
+            .first()
+            .unwrap();
+        assert!(referral.get_time_rewarded().is_some());
+        assert!(referral
+            .get_coupons(&ReferralRecipient::Referrer)
+            .is_empty());
+        let coupons = referral.get_coupons(&ReferralRecipient::Referee);
+        assert_eq!(coupons.len(), 1);
+        assert!(CouponFinder::new(&conn, None)
+            .id(coupons[0])
+            .first()
+            .is_ok());
+
+        // Only the first finished order counts
+        let second = buy(&referee, "")
+            .set_transaction_status(TransactionStatus::Finished)
+            .update(&conn)
+            .unwrap();
+        assert!(Referrals::reward(&conn, &second).unwrap().is_none());
+        assert_eq!(
+            Credits::balance(&conn, &referrer, &Currency::USD).unwrap(),
+            50
+        );
+
+        // The coupon is personal and single-use
+        assert!(Transactions::buy(
+            &conn,
+            &book_id,
+            &referrer,
+            1,
+            "258 Huanhu South Road, Dongqian Lake, Ningbo, China",
+            coupons[0],
+            Payment::Paypal,
+        )
+        .is_err());
+        assert_eq!(buy(&referee, coupons[0]).get_discount(), 100);
+        assert!(Transactions::buy(
+            &conn,
+            &book_id,
+            &referee,
+            1,
+            "258 Huanhu South Road, Dongqian Lake, Ningbo, China",
+            coupons[0],
+            Payment::Paypal,
+        )
+        .is_err());
+
+        let report = Referrals::report(&conn).unwrap();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].referrer, referrer.get_id());
+        assert_eq!(report[0].referred, 1);
+        assert_eq!(report[0].rewarded, 1);
+        assert_eq!(report[0].same_school, 1);
+
+        // Refunding the rewarded order revokes the rewards
+        order.refund(&conn).unwrap();
+        assert_eq!(
+            Credits::balance(&conn, &referrer, &Currency::USD).unwrap(),
+            0
+        );
+        let referral = ReferralFinder::new(&conn, None)
+            .referee(&referee)
+            .first()
+            .unwrap();
+        assert!(referral.get_time_rewarded().is_none());
+        assert!(referral.get_coupons(&ReferralRecipient::Referee).is_empty());
+        assert!(CouponFinder::new(&conn, None)
+            .id(coupons[0])
+            .first()
+            .unwrap()
+            .get_valid_until()
+            .is_some());
+        // Refunding other orders changes nothing
+        second.refund(&conn).unwrap();
+        assert_eq!(
+            Credits::balance(&conn, &referrer, &Currency::USD).unwrap(),
+            0
+        );
+    }
+}
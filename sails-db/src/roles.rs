@@ -0,0 +1,296 @@
+// Roles are named sets of permissions (bits of `UserStatus`) that can be assigned to users.
+// The union of the permissions of all the roles assigned is materialized into `users.user_status`,
+// so everything reading the user status sees the effective permissions.
+
+use crate::{
+    enums::UserStatus,
+    error::{SailsDbError, SailsDbResult as Result},
+    schema::{roleassignments, userroles},
+    users::UserId,
+};
+use chrono::naive::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// The role given to every newly created user, if it still exists
+pub const DEFAULT_ROLE: &str = "normal";
+
+// A psuedo struct for managing roles
+pub struct UserRoles;
+
+impl UserRoles {
+    pub fn list(conn: &SqliteConnection) -> Result<Vec<UserRole>> {
+        use crate::schema::userroles::dsl::*;
+        Ok(userroles.order(permissions.asc()).load::<UserRole>(conn)?)
+    }
+
+    pub fn find(conn: &SqliteConnection, id_provided: &str) -> Result<UserRole> {
+        use crate::schema::userroles::dsl::*;
+        Ok(userroles
+            .filter(id.eq(id_provided))
+            .first::<UserRole>(conn)?)
+    }
+
+    // Roles assigned to the user
+    pub fn of(conn: &SqliteConnection, user: &UserId) -> Result<Vec<UserRole>> {
+        Ok(userroles::table
+            .inner_join(roleassignments::table)
+            .filter(roleassignments::user_id.eq(user.get_id()))
+            .select(userroles::all_columns)
+            .order(userroles::permissions.asc())
+            .load::<UserRole>(conn)?)
+    }
+
+    // The union of the permissions of all the roles assigned to the user
+    pub fn permissions(conn: &SqliteConnection, user: &UserId) -> Result<UserStatus> {
+        Ok(Self::of(conn, user)?
+            .iter()
+            .fold(UserStatus::DISABLED, |acc, r| acc | r.get_permissions()))
+    }
+
+    // Replace the roles assigned to the user and update the effective permissions.
+    // Assigning no role at all disables the user.
+    pub fn assign(
+        conn: &SqliteConnection,
+        user: &UserId,
+        roles: &[UserRole],
+    ) -> Result<UserStatus> {
+        use crate::schema::roleassignments::dsl::*;
+        conn.transaction::<_, SailsDbError, _>(|| {
+            diesel::delete(roleassignments.filter(user_id.eq(user.get_id()))).execute(conn)?;
+            for role in roles {
+                diesel::insert_into(roleassignments)
+                    .values((
+                        id.eq(Uuid::new_v4().to_string()),
+                        user_id.eq(user.get_id()),
+                        role_id.eq(role.get_id()),
+                    ))
+                    .execute(conn)?;
+            }
+            Self::materialize(conn, user)
+        })
+    }
+
+    // Add roles to the user, keeping the ones already assigned, and update the effective permissions
+    pub fn grant(conn: &SqliteConnection, user: &UserId, roles: &[UserRole]) -> Result<UserStatus> {
+        conn.transaction::<_, SailsDbError, _>(|| {
+            let mut assigned = Self::of(conn, user)?;
+            for role in roles {
+                if assigned.iter().all(|r| r.get_id() != role.get_id()) {
+                    assigned.push(role.clone());
+                }
+            }
+            Self::assign(conn, user, &assigned)
+        })
+    }
+
+    // Write the effective permissions of the user into the user status
+    fn materialize(conn: &SqliteConnection, user: &UserId) -> Result<UserStatus> {
+        let status = Self::permissions(conn, user)?;
+        user.get_info(conn)?.set_user_status(status).update(conn)?;
+        Ok(status)
+    }
+
+    pub fn delete_by_user(conn: &SqliteConnection, user: &UserId) -> Result<usize> {
+        use crate::schema::roleassignments::dsl::*;
+        Ok(diesel::delete(roleassignments.filter(user_id.eq(user.get_id()))).execute(conn)?)
+    }
+}
+
+/// A role, corresponding to a row in the table `userroles`
+#[derive(Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, Clone)]
+#[table_name = "userroles"]
+pub struct UserRole {
+    id: String,
+    name: String,
+    permissions: i64,
+    time_created: NaiveDateTime,
+}
+
+impl UserRole {
+    pub fn new(conn: &SqliteConnection, name_p: &str, permissions_p: UserStatus) -> Result<Self> {
+        use crate::schema::userroles::dsl::*;
+        if let Ok(0) = userroles.filter(name.eq(name_p)).count().get_result(conn) {
+            let role = Self {
+                id: Uuid::new_v4().to_string(),
+                name: name_p.to_string(),
+                permissions: permissions_p.bits() as i64,
+                time_created: chrono::offset::Local::now().naive_utc(),
+            };
+            diesel::insert_into(userroles).values(&role).execute(conn)?;
+            Ok(role)
+        } else {
+            Err(SailsDbError::RoleExisted)
+        }
+    }
+
+    // Update the role and the effective permissions of everyone holding it
+    pub fn update(self, conn: &SqliteConnection) -> Result<Self> {
+        use crate::schema::userroles::dsl::*;
+        conn.transaction::<_, SailsDbError, _>(|| {
+            diesel::update(userroles.filter(id.eq(&self.id)))
+                .set((name.eq(&self.name), permissions.eq(self.permissions)))
+                .execute(conn)?;
+            for user in self.holders(conn)? {
+                UserRoles::materialize(conn, &user)?;
+            }
+            Ok(self)
+        })
+    }
+
+    // Delete the role, revoking it from everyone holding it
+    pub fn delete(self, conn: &SqliteConnection) -> Result<()> {
+        use crate::schema::userroles::dsl::*;
+        conn.transaction::<_, SailsDbError, _>(|| {
+            let holders = self.holders(conn)?;
+            diesel::delete(roleassignments::table.filter(roleassignments::role_id.eq(&self.id)))
+                .execute(conn)?;
+            diesel::delete(userroles.filter(id.eq(&self.id))).execute(conn)?;
+            for user in holders {
+                UserRoles::materialize(conn, &user)?;
+            }
+            Ok(())
+        })
+    }
+
+    // Users the role is assigned to
+    pub fn holders(&self, conn: &SqliteConnection) -> Result<Vec<UserId>> {
+        use crate::schema::roleassignments::dsl::*;
+        roleassignments
+            .filter(role_id.eq(&self.id))
+            .select(user_id)
+            .load::<String>(conn)?
+            .iter()
+            .map(|x| UserId::find(conn, x))
+            .collect()
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_name(mut self, name: impl ToString) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn get_permissions(&self) -> UserStatus {
+        UserStatus::from_bits_truncate(self.permissions as u32)
+    }
+
+    pub fn set_permissions(mut self, permissions: UserStatus) -> Self {
+        self.permissions = permissions.bits() as i64;
+        self
+    }
+
+    pub fn get_time_created(&self) -> &NaiveDateTime {
+        &self.time_created
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils::establish_connection, users::UserForm};
+
+    #[test]
+    fn builtin_roles() {
+        let conn = establish_connection();
+        // The roles seeded by the migration match the former profiles
+        for (role_id, status) in [
+            ("normal", UserStatus::NORMAL),
+            ("customer_service", UserStatus::CUSTOMER_SERVICE),
+            ("store_keeper", UserStatus::STORE_KEEPER),
+            ("content_creator", UserStatus::CONTENT_CREATOR),
+            ("admin", UserStatus::ADMIN),
+        ] {
+            assert_eq!(
+                UserRoles::find(&conn, role_id).unwrap().get_permissions(),
+                status
+            );
+        }
+
+        // Every permission is listed for the forms
+        assert_eq!(
+            UserStatus::flags()
+                .into_iter()
+                .fold(UserStatus::DISABLED, |acc, (_, f)| acc | f),
+            UserStatus::ADMIN
+        );
+
+        // New users are given the default role
+        let user = UserForm::new("TestUser@example.org", "Kanyang Ying", "NFLS", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+        let roles = UserRoles::of(&conn, &user).unwrap();
+        assert_eq!(roles.len(), 1);
+        assert_eq!(roles[0].get_id(), DEFAULT_ROLE);
+    }
+
+    #[test]
+    fn assign_roles() {
+        let conn = establish_connection();
+        let user = UserForm::new("TestUser@example.org", "Kanyang Ying", "NFLS", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        let tagger = UserRole::new(&conn, "Tagger", UserStatus::TAG_WRITABLE).unwrap();
+        // Names are unique
+        assert!(matches!(
+            UserRole::new(&conn, "Tagger", UserStatus::PROD_ADMIN)
+                .err()
+                .unwrap(),
+            SailsDbError::RoleExisted
+        ));
+
+        let normal = UserRoles::find(&conn, DEFAULT_ROLE).unwrap();
+        UserRoles::assign(&conn, &user, &[normal, tagger.clone()]).unwrap();
+        assert_eq!(
+            user.get_info(&conn).unwrap().get_user_status(),
+            UserStatus::NORMAL | UserStatus::TAG_WRITABLE
+        );
+
+        // Changing the role changes the permissions of its holders
+        let tagger = tagger
+            .set_permissions(UserStatus::TAG_WRITABLE | UserStatus::PROD_ADMIN)
+            .update(&conn)
+            .unwrap();
+        assert_eq!(
+            user.get_info(&conn).unwrap().get_user_status(),
+            UserStatus::NORMAL | UserStatus::TAG_WRITABLE | UserStatus::PROD_ADMIN
+        );
+
+        // And so does deleting it
+        tagger.delete(&conn).unwrap();
+        assert_eq!(
+            user.get_info(&conn).unwrap().get_user_status(),
+            UserStatus::NORMAL
+        );
+
+        // Granting keeps the roles assigned, and granting twice makes no difference
+        let reviewer = UserRole::new(&conn, "Reviewer", UserStatus::TAG_WRITABLE).unwrap();
+        UserRoles::grant(&conn, &user, &[reviewer.clone()]).unwrap();
+        UserRoles::grant(&conn, &user, &[reviewer]).unwrap();
+        assert_eq!(UserRoles::of(&conn, &user).unwrap().len(), 2);
+        assert_eq!(
+            user.get_info(&conn).unwrap().get_user_status(),
+            UserStatus::NORMAL | UserStatus::TAG_WRITABLE
+        );
+
+        // No role at all
+        UserRoles::assign(&conn, &user, &[]).unwrap();
+        assert_eq!(
+            user.get_info(&conn).unwrap().get_user_status(),
+            UserStatus::DISABLED
+        );
+    }
+}
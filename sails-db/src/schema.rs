@@ -1,3 +1,29 @@
+table! {
+    addresses (id) {
+        id -> Text,
+        user_id -> Text,
+        recipient -> Text,
+        phone -> Text,
+        school -> Text,
+        pickup_point -> Text,
+        country -> Text,
+        is_default -> Bool,
+        time_created -> Timestamp,
+    }
+}
+
+table! {
+    apitokens (id) {
+        id -> Text,
+        user_id -> Text,
+        name -> Text,
+        secret_hash -> Text,
+        scopes -> BigInt,
+        time_created -> Timestamp,
+        time_last_used -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     categories (id) {
         id -> Text,
@@ -9,9 +35,51 @@ table! {
 }
 
 table! {
+    couponapplications (id) {
+        id -> Text,
+        transaction_id -> Text,
+        coupon -> Text,
+        discount -> BigInt,
+        credit -> BigInt,
+        position -> BigInt,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::enums::*;
+
     coupons (id) {
         id -> Text,
         script -> Text,
+        description -> Nullable<Text>,
+        valid_from -> Nullable<Timestamp>,
+        valid_until -> Nullable<Timestamp>,
+        max_total_uses -> Nullable<BigInt>,
+        max_buyer_uses -> Nullable<BigInt>,
+        min_subtotal -> Nullable<BigInt>,
+        allowed_categories -> Nullable<Text>,
+        allowed_tags -> Nullable<Text>,
+        allowed_sellers -> Nullable<Text>,
+        version -> BigInt,
+        stacking -> CouponStackingMapping,
+        priority -> BigInt,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::enums::*;
+
+    credits (id) {
+        id -> Text,
+        user_id -> Text,
+        currency -> CurrencyMapping,
+        amount -> BigInt,
+        kind -> CreditKindMapping,
+        reference -> Nullable<Text>,
+        note -> Nullable<Text>,
+        time_created -> Timestamp,
     }
 }
 
@@ -39,12 +107,67 @@ table! {
 }
 
 table! {
+    use diesel::sql_types::*;
+    use crate::enums::*;
+
+    giftcards (id) {
+        id -> Text,
+        currency -> CurrencyMapping,
+        amount -> BigInt,
+        note -> Nullable<Text>,
+        time_created -> Timestamp,
+        redeemed_by -> Nullable<Text>,
+        time_redeemed -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    identities (id) {
+        id -> Text,
+        user_id -> Text,
+        issuer -> Text,
+        subject -> Text,
+        time_created -> Timestamp,
+    }
+}
+
+table! {
+    messageattachments (id) {
+        id -> Text,
+        message_id -> Text,
+        content_type -> Text,
+        location -> Text,
+        nonce -> Text,
+        time_created -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::enums::*;
+
     messages (id) {
         id -> Text,
         send -> Text,
         recv -> Text,
         body -> Text,
         time_sent -> Timestamp,
+        time_read -> Nullable<Timestamp>,
+        transaction_id -> Nullable<Text>,
+        is_system -> Bool,
+        old_status -> Nullable<TransactionStatusMapping>,
+        new_status -> Nullable<TransactionStatusMapping>,
+    }
+}
+
+table! {
+    orderaddresses (transaction_id) {
+        transaction_id -> Text,
+        recipient -> Text,
+        phone -> Text,
+        school -> Text,
+        pickup_point -> Text,
+        country -> Text,
     }
 }
 
@@ -66,6 +189,59 @@ table! {
     }
 }
 
+table! {
+    referralcodes (id) {
+        id -> Text,
+        user_id -> Text,
+        time_created -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::enums::*;
+
+    referralrewards (id) {
+        id -> Text,
+        recipient -> ReferralRecipientMapping,
+        kind -> ReferralRewardKindMapping,
+        currency -> CurrencyMapping,
+        amount -> BigInt,
+    }
+}
+
+table! {
+    referrals (referee) {
+        referee -> Text,
+        referrer -> Text,
+        code -> Text,
+        time_created -> Timestamp,
+        transaction_id -> Nullable<Text>,
+        time_rewarded -> Nullable<Timestamp>,
+        referrer_coupons -> Nullable<Text>,
+        referee_coupons -> Nullable<Text>,
+    }
+}
+
+table! {
+    roleassignments (id) {
+        id -> Text,
+        user_id -> Text,
+        role_id -> Text,
+    }
+}
+
+table! {
+    sessions (id) {
+        id -> Text,
+        user_id -> Text,
+        time_created -> Timestamp,
+        time_last_seen -> Timestamp,
+        ip -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+    }
+}
+
 table! {
     tagmappings (id) {
         id -> Text,
@@ -83,6 +259,35 @@ table! {
     }
 }
 
+table! {
+    ticketreplies (id) {
+        id -> Text,
+        ticket_id -> Text,
+        author -> Text,
+        body -> Text,
+        is_internal -> Bool,
+        time_created -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::enums::*;
+
+    tickets (id) {
+        id -> Text,
+        user_id -> Text,
+        transaction_id -> Nullable<Text>,
+        subject -> Text,
+        status -> TicketStatusMapping,
+        assignee -> Nullable<Text>,
+        time_created -> Timestamp,
+        time_updated -> Timestamp,
+        time_first_response -> Nullable<Timestamp>,
+        time_resolved -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     use diesel::sql_types::*;
     use crate::enums::*;
@@ -103,6 +308,17 @@ table! {
         payment_detail -> Nullable<Text>,
         coupon -> Text,
         discount -> BigInt,
+        credit -> BigInt,
+        time_paid -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    userroles (id) {
+        id -> Text,
+        name -> Text,
+        permissions -> BigInt,
+        time_created -> Timestamp,
     }
 }
 
@@ -113,27 +329,60 @@ table! {
         school -> Text,
         description -> Nullable<Text>,
         user_status -> BigInt,
+        email -> Text,
+        email_verified -> Bool,
     }
 }
 
+joinable!(addresses -> users (user_id));
+joinable!(apitokens -> users (user_id));
+joinable!(couponapplications -> transactions (transaction_id));
+joinable!(credits -> users (user_id));
 joinable!(digiconmappings -> digicons (digicon));
 joinable!(digiconmappings -> products (product));
 joinable!(digicons -> users (creator_id));
+joinable!(identities -> users (user_id));
+joinable!(messageattachments -> messages (message_id));
+joinable!(messages -> transactions (transaction_id));
+joinable!(orderaddresses -> transactions (transaction_id));
 joinable!(products -> categories (category));
 joinable!(products -> users (seller_id));
+joinable!(referralcodes -> users (user_id));
+joinable!(roleassignments -> userroles (role_id));
+joinable!(roleassignments -> users (user_id));
+joinable!(sessions -> users (user_id));
 joinable!(tagmappings -> products (product));
 joinable!(tagmappings -> tags (tag));
+joinable!(ticketreplies -> tickets (ticket_id));
+joinable!(ticketreplies -> users (author));
+joinable!(tickets -> transactions (transaction_id));
 joinable!(transactions -> products (product));
 
 allow_tables_to_appear_in_same_query!(
+    addresses,
+    apitokens,
     categories,
+    couponapplications,
     coupons,
+    credits,
     digiconmappings,
     digicons,
+    giftcards,
+    identities,
+    messageattachments,
     messages,
+    orderaddresses,
     products,
+    referralcodes,
+    referralrewards,
+    referrals,
+    roleassignments,
+    sessions,
     tagmappings,
     tags,
+    ticketreplies,
+    tickets,
     transactions,
+    userroles,
     users,
 );
@@ -1,11 +1,113 @@
 // A module to centralize all interfaces to rhai scripts
 
+use crate::error::{SailsDbError, SailsDbResult};
 use diesel::prelude::*;
+use once_cell::sync::Lazy;
 use rhai::{
-    def_package, export_module, packages::StandardPackage, plugin::*, EvalAltResult,
-    ImmutableString,
+    def_package, export_module,
+    packages::{Package, StandardPackage},
+    plugin::*,
+    Dynamic, Engine, EvalAltResult, ImmutableString, AST,
 };
-use std::sync::Arc;
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+// Limits on coupon scripts so that a bad script cannot hang the checkout
+pub(crate) const MAX_OPERATIONS: u64 = 100_000;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_EXPR_DEPTH: usize = 64;
+const MAX_FN_EXPR_DEPTH: usize = 32;
+const MAX_STRING_SIZE: usize = 4096;
+const MAX_ARRAY_SIZE: usize = 1024;
+const MAX_MAP_SIZE: usize = 1024;
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+thread_local! {
+    // Deadline of the script being evaluated on the current thread
+    static DEADLINE: Cell<Option<Instant>> = Cell::new(None);
+    // Operations consumed by the script being evaluated on the current thread
+    static OPERATIONS: Cell<u64> = Cell::new(0);
+}
+
+// Build an engine with all the limits applied.
+pub(crate) fn new_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .register_global_module(CouponPackage::new().as_shared_module())
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(MAX_CALL_LEVELS)
+        .set_max_expr_depths(MAX_EXPR_DEPTH, MAX_FN_EXPR_DEPTH)
+        .set_max_string_size(MAX_STRING_SIZE)
+        .set_max_array_size(MAX_ARRAY_SIZE)
+        .set_max_map_size(MAX_MAP_SIZE)
+        .on_progress(|ops| {
+            OPERATIONS.with(|o| o.set(ops));
+            // Terminate the script once the wall-clock deadline has passed
+            match DEADLINE.with(|d| d.get()) {
+                Some(deadline) if Instant::now() > deadline => Some(Dynamic::UNIT),
+                _ => None,
+            }
+        });
+    // Scripts are not allowed to evaluate arbitrary strings
+    engine.disable_symbol("eval");
+    engine
+}
+
+// The engine shared by all the coupon evaluations
+pub(crate) static ENGINE: Lazy<Engine> = Lazy::new(new_engine);
+
+// Compiled scripts keyed by coupon ID, together with the version and script they are compiled from.
+#[allow(clippy::type_complexity)]
+static AST_CACHE: Lazy<RwLock<HashMap<String, (i64, String, Arc<AST>)>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+pub(crate) fn compile(id: &str, version: i64, script: &str) -> SailsDbResult<Arc<AST>> {
+    if let Some((v, s, ast)) = AST_CACHE.read().unwrap().get(id) {
+        if (*v == version) && (s == script) {
+            return Ok(ast.clone());
+        }
+    }
+    let ast = Arc::new(ENGINE.compile(script)?);
+    AST_CACHE
+        .write()
+        .unwrap()
+        .insert(id.to_string(), (version, script.to_string(), ast.clone()));
+    Ok(ast)
+}
+
+// Compile without touching the cache, for checking scripts which are not saved yet
+pub(crate) fn check(script: &str) -> SailsDbResult<()> {
+    ENGINE.compile(script)?;
+    Ok(())
+}
+
+pub(crate) fn invalidate(id: &str) {
+    AST_CACHE.write().unwrap().remove(id);
+}
+
+// Evaluate with the wall-clock deadline in place. Operations consumed are returned as well.
+pub(crate) fn eval_with_limits<T>(
+    f: impl FnOnce() -> Result<T, Box<EvalAltResult>>,
+) -> (SailsDbResult<T>, u64) {
+    DEADLINE.with(|d| d.set(Some(Instant::now() + TIMEOUT)));
+    OPERATIONS.with(|o| o.set(0));
+    let result = f();
+    DEADLINE.with(|d| d.set(None));
+    let operations = OPERATIONS.with(|o| o.get());
+    (
+        result.map_err(|e| match *e {
+            EvalAltResult::ErrorTooManyOperations(_) | EvalAltResult::ErrorTerminated(_, _) => {
+                SailsDbError::ScriptTimeout
+            }
+            _ => SailsDbError::ScriptEvalError(e),
+        }),
+        operations,
+    )
+}
 
 trait IntoEvalAltResultError<T> {
     fn into_evalrst_err(self) -> Result<T, Box<EvalAltResult>>;
@@ -65,6 +167,11 @@ pub mod rhai_mod {
                 info.get_id().into()
             }
 
+            #[rhai_fn(pure)]
+            pub fn get_email(info: &mut UserInfo) -> ImmutableString {
+                info.get_email().into()
+            }
+
             #[rhai_fn(pure)]
             pub fn get_school(info: &mut UserInfo) -> ImmutableString {
                 info.get_school().into()
@@ -142,7 +249,7 @@ pub mod rhai_mod {
 
             #[rhai_fn(pure)]
             pub fn get_quantity(info: &mut ProductInfo) -> i64 {
-                info.get_price() as i64
+                info.get_quantity() as i64
             }
 
             #[rhai_fn(pure)]
@@ -152,5 +259,205 @@ pub mod rhai_mod {
         }
     }
 
-    pub mod transactions {}
+    pub mod transactions {
+        use crate::{enums::Currency, transactions::PurchaseHistory};
+        use std::str::FromStr;
+
+        pub mod purchase_history {
+            // Number of orders under the category
+            #[rhai_fn(pure)]
+            pub fn count(history: &mut PurchaseHistory, category: ImmutableString) -> i64 {
+                history.count(&category)
+            }
+
+            #[rhai_fn(pure, name = "count")]
+            pub fn count_all(history: &mut PurchaseHistory) -> i64 {
+                history.count_all()
+            }
+
+            // Amount spent under the category in the currency (e.g. "CNY")
+            #[rhai_fn(pure, return_raw)]
+            pub fn spent(
+                history: &mut PurchaseHistory,
+                category: ImmutableString,
+                currency: ImmutableString,
+            ) -> Result<i64, Box<EvalAltResult>> {
+                Ok(history.spent(
+                    &category,
+                    &Currency::from_str(&currency).into_evalrst_err()?,
+                ))
+            }
+
+            #[rhai_fn(pure, return_raw, name = "spent")]
+            pub fn spent_all(
+                history: &mut PurchaseHistory,
+                currency: ImmutableString,
+            ) -> Result<i64, Box<EvalAltResult>> {
+                Ok(history.spent_all(&Currency::from_str(&currency).into_evalrst_err()?))
+            }
+        }
+    }
+
+    pub mod time {
+        use chrono::{naive::NaiveDateTime, Datelike, Timelike};
+
+        // All in UTC
+        pub mod naive_date_time {
+            #[rhai_fn(pure)]
+            pub fn get_timestamp(time: &mut NaiveDateTime) -> i64 {
+                time.timestamp()
+            }
+
+            #[rhai_fn(pure)]
+            pub fn get_year(time: &mut NaiveDateTime) -> i64 {
+                time.year() as i64
+            }
+
+            #[rhai_fn(pure)]
+            pub fn get_month(time: &mut NaiveDateTime) -> i64 {
+                time.month() as i64
+            }
+
+            #[rhai_fn(pure)]
+            pub fn get_day(time: &mut NaiveDateTime) -> i64 {
+                time.day() as i64
+            }
+
+            // Monday is 1 and Sunday is 7
+            #[rhai_fn(pure)]
+            pub fn get_weekday(time: &mut NaiveDateTime) -> i64 {
+                time.weekday().number_from_monday() as i64
+            }
+
+            #[rhai_fn(pure)]
+            pub fn get_hour(time: &mut NaiveDateTime) -> i64 {
+                time.hour() as i64
+            }
+
+            #[rhai_fn(pure)]
+            pub fn get_minute(time: &mut NaiveDateTime) -> i64 {
+                time.minute() as i64
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        categories::{Category, CtgTrait},
+        coupons::{Coupon, CouponContext},
+        enums::*,
+        products::IncompleteProduct,
+        tags::*,
+        test_utils::establish_connection,
+        transactions::*,
+        users::*,
+    };
+    use chrono::NaiveDateTime;
+    use std::collections::HashMap;
+
+    #[test]
+    fn coupon_bindings() {
+        let conn = establish_connection();
+        TagsBuilder::new(HashMap::new()).build(&conn).unwrap();
+        let seller = UserForm::new("TestUser@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        let buyer = UserForm::new("AtypicalBuyer@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        // Books -> Economics Books
+        let mut books = Category::create_with_id(&conn, "Books", 1, "books").unwrap();
+        let mut econ = Category::create_with_id(&conn, "Economics Books", 1, "econ")
+            .and_then(Category::into_leaf)
+            .unwrap();
+        econ.insert(&conn, &mut books).unwrap();
+
+        let book_id = IncompleteProduct::new(
+            &econ,
+            "Krugman's Economics 2nd Edition",
+            700,
+            10,
+            "A very great book on the subject of Economics",
+            Currency::USD,
+        )
+        .unwrap()
+        .create(&conn, &seller)
+        .unwrap();
+        book_id
+            .get_info(&conn)
+            .unwrap()
+            .set_product_status(ProductStatus::Verified)
+            .update(&conn)
+            .unwrap();
+        let sales = Tags::find_by_id(&conn, "sales").unwrap();
+        TagMapping::create(&conn, &sales, &book_id).unwrap();
+
+        let buy = |coupon: &str| {
+            Transactions::buy(
+                &conn,
+                &book_id,
+                &buyer,
+                1,
+                "258 Huanhu South Road, Dongqian Lake, Ningbo, China",
+                coupon,
+                Payment::Paypal,
+            )
+            .unwrap()
+            .get_info(&conn)
+            .unwrap()
+        };
+
+        // Product getters
+        Coupon::new(&conn, "QUANTITY", "product.get_quantity()").unwrap();
+        assert_eq!(buy("QUANTITY").get_discount(), 10);
+
+        // Tags, category path, payment, and currency
+        Coupon::new(
+            &conn,
+            "CONTEXT",
+            r#"if tags.contains("sales") && category_path == ["books", "econ"] && payment == "Paypal" && currency == "USD" { 50 } else { 0 }"#,
+        )
+        .unwrap();
+        assert_eq!(buy("CONTEXT").get_discount(), 50);
+
+        // Time, at a fixed moment in UTC: 2022-11-21 03:06:40, a Monday
+        let at = |script: &str| {
+            let coupon = Coupon::new_without_db("TIME", script);
+            let book = book_id.get_info(&conn).unwrap();
+            let mut ctx =
+                CouponContext::build(&conn, &coupon, &book, &buyer, 1, &Payment::Paypal).unwrap();
+            ctx.now = NaiveDateTime::from_timestamp(1_669_000_000, 0);
+            coupon.dry_run(ctx).discount.unwrap()
+        };
+        assert_eq!(at("now.get_timestamp()"), 1_669_000_000);
+        assert_eq!(at("now.get_year()"), 2022);
+        assert_eq!(at("now.get_month()"), 11);
+        assert_eq!(at("now.get_day()"), 21);
+        assert_eq!(at("now.get_weekday()"), 1);
+        assert_eq!(at("now.get_hour()"), 3);
+        assert_eq!(at("now.get_minute()"), 6);
+
+        // Purchase history, only paid orders count
+        Coupon::new(
+            &conn,
+            "LOYALTY",
+            r#"history.count("books") * 1000 + history.count() * 100 + history.spent("econ", "USD")"#,
+        )
+        .unwrap();
+        assert_eq!(buy("LOYALTY").get_discount(), 0);
+
+        let paid = buy("");
+        paid.set_transaction_status(TransactionStatus::Paid)
+            .update(&conn)
+            .unwrap();
+        assert_eq!(buy("LOYALTY").get_discount(), 1000 + 100 + 700);
+    }
 }
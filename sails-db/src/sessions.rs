@@ -0,0 +1,271 @@
+// Server-side sessions. The session cookie only holds the ID of a row in the table `sessions`,
+// so sessions can expire and be revoked independently of the cookie.
+
+use crate::{
+    error::{SailsDbError, SailsDbResult as Result},
+    schema::sessions,
+    users::UserId,
+};
+use chrono::{naive::NaiveDateTime, Duration};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// How long sessions last, in minutes
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionPolicy {
+    // Sessions not seen for this long expire
+    #[serde(default = "SessionPolicy::default_idle_timeout")]
+    session_idle_timeout: i64,
+    // Sessions expire this long after signing in, no matter how active they are
+    #[serde(default = "SessionPolicy::default_absolute_timeout")]
+    session_absolute_timeout: i64,
+}
+
+impl SessionPolicy {
+    pub fn new(idle_timeout: i64, absolute_timeout: i64) -> Self {
+        Self {
+            session_idle_timeout: idle_timeout,
+            session_absolute_timeout: absolute_timeout,
+        }
+    }
+
+    fn default_idle_timeout() -> i64 {
+        // A week
+        7 * 24 * 60
+    }
+
+    fn default_absolute_timeout() -> i64 {
+        // 30 days
+        30 * 24 * 60
+    }
+
+    fn expired(&self, session: &Session, now: NaiveDateTime) -> bool {
+        (now - session.time_last_seen > Duration::minutes(self.session_idle_timeout))
+            || (now - session.time_created > Duration::minutes(self.session_absolute_timeout))
+    }
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        Self::new(
+            Self::default_idle_timeout(),
+            Self::default_absolute_timeout(),
+        )
+    }
+}
+
+// A psuedo struct for managing sessions
+pub struct Sessions;
+
+impl Sessions {
+    pub fn create(
+        conn: &SqliteConnection,
+        user: &UserId,
+        ip_p: Option<&str>,
+        user_agent_p: Option<&str>,
+    ) -> Result<Session> {
+        use crate::schema::sessions::dsl::*;
+        let now = chrono::offset::Local::now().naive_utc();
+        let session = Session {
+            id: Uuid::new_v4().simple().to_string(),
+            user_id: user.get_id().to_string(),
+            time_created: now,
+            time_last_seen: now,
+            ip: ip_p.map(ToString::to_string),
+            user_agent: user_agent_p.map(ToString::to_string),
+        };
+        diesel::insert_into(sessions)
+            .values(&session)
+            .execute(conn)?;
+        Ok(session)
+    }
+
+    // Find the session unless it has expired, and record the activity
+    pub fn verify(conn: &SqliteConnection, id_p: &str, policy: &SessionPolicy) -> Result<Session> {
+        use crate::schema::sessions::dsl::*;
+        let session = match sessions.filter(id.eq(id_p)).first::<Session>(conn) {
+            Err(diesel::result::Error::NotFound) => return Err(SailsDbError::SessionExpired),
+            r => r?,
+        };
+        let now = chrono::offset::Local::now().naive_utc();
+        if policy.expired(&session, now) {
+            diesel::delete(sessions.filter(id.eq(id_p))).execute(conn)?;
+            return Err(SailsDbError::SessionExpired);
+        }
+        // Avoid writing on every single request
+        if now - session.time_last_seen > Duration::minutes(1) {
+            diesel::update(sessions.filter(id.eq(id_p)))
+                .set(time_last_seen.eq(now))
+                .execute(conn)?;
+            Ok(Session {
+                time_last_seen: now,
+                ..session
+            })
+        } else {
+            Ok(session)
+        }
+    }
+
+    // Active sessions of the user, most recently seen first
+    pub fn list_by_user(conn: &SqliteConnection, user: &UserId) -> Result<Vec<Session>> {
+        use crate::schema::sessions::dsl::*;
+        Ok(sessions
+            .filter(user_id.eq(user.get_id()))
+            .order(time_last_seen.desc())
+            .load::<Session>(conn)?)
+    }
+
+    pub fn list(conn: &SqliteConnection) -> Result<Vec<Session>> {
+        use crate::schema::sessions::dsl::*;
+        Ok(sessions
+            .order(time_last_seen.desc())
+            .load::<Session>(conn)?)
+    }
+
+    pub fn find(conn: &SqliteConnection, id_p: &str) -> Result<Session> {
+        use crate::schema::sessions::dsl::*;
+        Ok(sessions.filter(id.eq(id_p)).first::<Session>(conn)?)
+    }
+
+    // Log the user out of all devices
+    pub fn delete_by_user(conn: &SqliteConnection, user: &UserId) -> Result<usize> {
+        use crate::schema::sessions::dsl::*;
+        Ok(diesel::delete(sessions.filter(user_id.eq(user.get_id()))).execute(conn)?)
+    }
+
+    pub fn delete_expired(conn: &SqliteConnection, policy: &SessionPolicy) -> Result<usize> {
+        use crate::schema::sessions::dsl::*;
+        let now = chrono::offset::Local::now().naive_utc();
+        Ok(diesel::delete(
+            sessions.filter(
+                time_last_seen
+                    .lt(now - Duration::minutes(policy.session_idle_timeout))
+                    .or(time_created.lt(now - Duration::minutes(policy.session_absolute_timeout))),
+            ),
+        )
+        .execute(conn)?)
+    }
+}
+
+/// A session, corresponding to a row in the table `sessions`
+#[derive(Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, Clone)]
+#[table_name = "sessions"]
+pub struct Session {
+    id: String,
+    user_id: String,
+    time_created: NaiveDateTime,
+    time_last_seen: NaiveDateTime,
+    ip: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Session {
+    // Revoking a session deletes it
+    pub fn revoke(self, conn: &SqliteConnection) -> Result<()> {
+        use crate::schema::sessions::dsl::*;
+        diesel::delete(sessions.filter(id.eq(&self.id))).execute(conn)?;
+        Ok(())
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn get_time_created(&self) -> &NaiveDateTime {
+        &self.time_created
+    }
+
+    pub fn get_time_last_seen(&self) -> &NaiveDateTime {
+        &self.time_last_seen
+    }
+
+    pub fn get_ip(&self) -> Option<&str> {
+        self.ip.as_deref()
+    }
+
+    pub fn get_user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils::establish_connection, users::UserForm};
+
+    #[test]
+    fn sessions() {
+        let conn = establish_connection();
+        let user = UserForm::new("TestUser@example.org", "Kanyang Ying", "NFLS", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+        let policy = SessionPolicy::default();
+
+        let session =
+            Sessions::create(&conn, &user, Some("127.0.0.1"), Some("curl/7.85.0")).unwrap();
+        Sessions::create(&conn, &user, None, None).unwrap();
+        assert_eq!(
+            Sessions::verify(&conn, session.get_id(), &policy)
+                .unwrap()
+                .get_user_id(),
+            user.get_id()
+        );
+        assert_eq!(Sessions::list_by_user(&conn, &user).unwrap().len(), 2);
+
+        // Revoked sessions are gone
+        Sessions::find(&conn, session.get_id())
+            .unwrap()
+            .revoke(&conn)
+            .unwrap();
+        assert!(matches!(
+            Sessions::verify(&conn, session.get_id(), &policy)
+                .err()
+                .unwrap(),
+            SailsDbError::SessionExpired
+        ));
+
+        // A session past the idle timeout is expired
+        let session = Sessions::create(&conn, &user, None, None).unwrap();
+        {
+            use crate::schema::sessions::dsl::*;
+            diesel::update(sessions.filter(id.eq(session.get_id())))
+                .set(time_last_seen.eq(session.time_last_seen - Duration::days(8)))
+                .execute(&conn)
+                .unwrap();
+        }
+        assert_eq!(Sessions::delete_expired(&conn, &policy).unwrap(), 1);
+        assert!(Sessions::verify(&conn, session.get_id(), &policy).is_err());
+
+        // Log out of all devices
+        Sessions::delete_by_user(&conn, &user).unwrap();
+        assert_eq!(Sessions::list_by_user(&conn, &user).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn session_timeouts() {
+        let conn = establish_connection();
+        let user = UserForm::new("TestUser@example.org", "Kanyang Ying", "NFLS", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+        let session = Sessions::create(&conn, &user, None, None).unwrap();
+
+        // Active but too old
+        assert!(matches!(
+            Sessions::verify(&conn, session.get_id(), &SessionPolicy::new(60, -1))
+                .err()
+                .unwrap(),
+            SailsDbError::SessionExpired
+        ));
+        // Expired sessions are deleted on the way
+        assert!(Sessions::find(&conn, session.get_id()).is_err());
+    }
+}
@@ -0,0 +1,615 @@
+// Support tickets. Users open tickets, optionally about one of their orders, and the customer service works them from a queue.
+
+use crate::{
+    enums::{TicketStatus, UserStatus},
+    error::{SailsDbError, SailsDbResult as Result},
+    schema::{ticketreplies, tickets},
+    transactions::TransactionInfo,
+    users::{UserId, UserInfo},
+    Order,
+};
+use chrono::{naive::NaiveDateTime, Duration};
+use diesel::{dsl::count, prelude::*, sqlite::Sqlite};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// A psuedo struct for managing tickets
+pub struct Tickets;
+
+impl Tickets {
+    // Open a ticket, the body being its first reply
+    pub fn open<T: ToString>(
+        conn: &SqliteConnection,
+        user: &UserId,
+        order: Option<&TransactionInfo>,
+        subject_p: T,
+        body_p: T,
+    ) -> Result<Ticket> {
+        use crate::schema::tickets::dsl::*;
+        let subject_p = subject_p.to_string();
+        if subject_p.trim().is_empty() {
+            return Err(SailsDbError::IllegalQuery);
+        }
+        // Only the parties of the order could open tickets about it
+        if let Some(order) = order {
+            if (order.get_buyer() != user.get_id()) && (order.get_seller() != user.get_id()) {
+                return Err(SailsDbError::IllegalQuery);
+            }
+        }
+
+        let now = chrono::offset::Local::now().naive_utc();
+        let ticket = Ticket {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.get_id().to_string(),
+            transaction_id: order.map(|o| o.get_id().to_string()),
+            subject: subject_p.trim().to_string(),
+            status: TicketStatus::Open,
+            assignee: None,
+            time_created: now,
+            time_updated: now,
+            time_first_response: None,
+            time_resolved: None,
+        };
+        conn.transaction::<_, SailsDbError, _>(|| {
+            diesel::insert_into(tickets).values(&ticket).execute(conn)?;
+            TicketReply::new(&ticket, user, body_p, false).create(conn)?;
+            Ok(ticket)
+        })
+    }
+
+    pub fn find(conn: &SqliteConnection, id_provided: &str) -> Result<Ticket> {
+        use crate::schema::tickets::dsl::*;
+        match tickets.filter(id.eq(id_provided)).first::<Ticket>(conn) {
+            Err(diesel::result::Error::NotFound) => Err(SailsDbError::TicketNotFound),
+            r => Ok(r?),
+        }
+    }
+
+    // Reply to the ticket as the user who opened it or as the customer service.
+    // A reply from the customer service puts the ticket on the user, while a reply from the user reopens it.
+    // Internal notes are only for the customer service and leave the status as it is.
+    pub fn reply<T: ToString>(
+        conn: &SqliteConnection,
+        ticket: &Ticket,
+        author: &UserInfo,
+        body_p: T,
+        internal: bool,
+    ) -> Result<TicketReply> {
+        use crate::schema::tickets::dsl::*;
+        let is_staff = author
+            .get_user_status()
+            .contains(UserStatus::CUSTOMER_SERVICE);
+        let is_opener = author.get_id() == ticket.user_id;
+        if !(is_staff || is_opener) || (internal && !is_staff) {
+            return Err(SailsDbError::IllegalQuery);
+        }
+
+        conn.transaction::<_, SailsDbError, _>(|| {
+            let reply = TicketReply::new(ticket, &author.to_id(), body_p, internal).create(conn)?;
+            let target = tickets.filter(id.eq(&ticket.id));
+            if internal {
+                diesel::update(target)
+                    .set(time_updated.eq(reply.time_created))
+                    .execute(conn)?;
+            } else if is_opener {
+                diesel::update(target)
+                    .set((
+                        status.eq(TicketStatus::Open),
+                        time_updated.eq(reply.time_created),
+                        time_resolved.eq(None::<NaiveDateTime>),
+                    ))
+                    .execute(conn)?;
+            } else {
+                diesel::update(target)
+                    .set((
+                        status.eq(TicketStatus::Pending),
+                        time_updated.eq(reply.time_created),
+                        time_first_response
+                            .eq(ticket.time_first_response.or(Some(reply.time_created))),
+                    ))
+                    .execute(conn)?;
+            }
+            Ok(reply)
+        })
+    }
+
+    // Replies in a chronological order
+    pub fn replies(
+        conn: &SqliteConnection,
+        ticket: &Ticket,
+        include_internal: bool,
+    ) -> Result<Vec<TicketReply>> {
+        use crate::schema::ticketreplies::dsl::*;
+        let mut query = ticketreplies.filter(ticket_id.eq(&ticket.id)).into_boxed();
+        if !include_internal {
+            query = query.filter(is_internal.eq(false));
+        }
+        Ok(query.order(time_created.asc()).load::<TicketReply>(conn)?)
+    }
+
+    // Tickets about the orders are kept when the orders are gone
+    pub(crate) fn detach_transactions(conn: &SqliteConnection, ids: &[String]) -> Result<usize> {
+        use crate::schema::tickets::dsl::*;
+        Ok(diesel::update(tickets.filter(transaction_id.eq_any(ids)))
+            .set(transaction_id.eq(None::<String>))
+            .execute(conn)?)
+    }
+
+    // Delete the tickets opened by the user and everything the user has written in others.
+    // Tickets assigned to the user go back to the queue.
+    pub fn delete_by_user(conn: &SqliteConnection, user: &UserId) -> Result<()> {
+        let ids = tickets::table
+            .select(tickets::id)
+            .filter(tickets::user_id.eq(user.get_id()))
+            .load::<String>(conn)?;
+        diesel::delete(
+            ticketreplies::table.filter(
+                ticketreplies::ticket_id
+                    .eq_any(&ids)
+                    .or(ticketreplies::author.eq(user.get_id())),
+            ),
+        )
+        .execute(conn)?;
+        diesel::delete(tickets::table.filter(tickets::id.eq_any(&ids))).execute(conn)?;
+        diesel::update(tickets::table.filter(tickets::assignee.eq(user.get_id())))
+            .set(tickets::assignee.eq(None::<String>))
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+/// A support ticket, corresponding to a row in the table `tickets`
+#[derive(Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, Clone)]
+#[table_name = "tickets"]
+pub struct Ticket {
+    id: String,
+    user_id: String,
+    transaction_id: Option<String>,
+    subject: String,
+    status: TicketStatus,
+    assignee: Option<String>,
+    time_created: NaiveDateTime,
+    time_updated: NaiveDateTime,
+    time_first_response: Option<NaiveDateTime>,
+    time_resolved: Option<NaiveDateTime>,
+}
+
+impl Ticket {
+    // Assign the ticket to someone from the customer service, or put it back to the queue
+    pub fn assign(self, conn: &SqliteConnection, assignee_p: Option<&UserId>) -> Result<Self> {
+        use crate::schema::tickets::dsl::*;
+        if let Some(user) = assignee_p {
+            if !user
+                .get_info(conn)?
+                .get_user_status()
+                .contains(UserStatus::CUSTOMER_SERVICE)
+            {
+                return Err(SailsDbError::IllegalQuery);
+            }
+        }
+        let ticket = Self {
+            assignee: assignee_p.map(|u| u.get_id().to_string()),
+            time_updated: chrono::offset::Local::now().naive_utc(),
+            ..self
+        };
+        diesel::update(tickets.filter(id.eq(&ticket.id)))
+            .set((
+                assignee.eq(&ticket.assignee),
+                time_updated.eq(ticket.time_updated),
+            ))
+            .execute(conn)?;
+        Ok(ticket)
+    }
+
+    pub fn set_status(self, conn: &SqliteConnection, status_p: TicketStatus) -> Result<Self> {
+        use crate::schema::tickets::dsl::*;
+        let now = chrono::offset::Local::now().naive_utc();
+        let ticket = Self {
+            time_resolved: if status_p == TicketStatus::Resolved {
+                self.time_resolved.or(Some(now))
+            } else {
+                None
+            },
+            status: status_p,
+            time_updated: now,
+            ..self
+        };
+        diesel::update(tickets.filter(id.eq(&ticket.id)))
+            .set((
+                status.eq(ticket.status.clone()),
+                time_updated.eq(ticket.time_updated),
+                time_resolved.eq(ticket.time_resolved),
+            ))
+            .execute(conn)?;
+        Ok(ticket)
+    }
+
+    // Only the user who opened the ticket and the customer service could read it
+    pub fn readable_by(&self, user: &UserInfo) -> bool {
+        (user.get_id() == self.user_id)
+            || user
+                .get_user_status()
+                .contains(UserStatus::CUSTOMER_SERVICE)
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_shortid(&self) -> &str {
+        &self.id[..8]
+    }
+
+    pub fn get_user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn get_transaction_id(&self) -> Option<&str> {
+        self.transaction_id.as_deref()
+    }
+
+    pub fn get_subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub fn get_status(&self) -> &TicketStatus {
+        &self.status
+    }
+
+    pub fn is_resolved(&self) -> bool {
+        self.status == TicketStatus::Resolved
+    }
+
+    pub fn get_assignee(&self) -> Option<&str> {
+        self.assignee.as_deref()
+    }
+
+    pub fn get_time_created(&self) -> &NaiveDateTime {
+        &self.time_created
+    }
+
+    pub fn get_time_updated(&self) -> &NaiveDateTime {
+        &self.time_updated
+    }
+
+    pub fn get_time_first_response(&self) -> Option<&NaiveDateTime> {
+        self.time_first_response.as_ref()
+    }
+
+    pub fn get_time_resolved(&self) -> Option<&NaiveDateTime> {
+        self.time_resolved.as_ref()
+    }
+}
+
+/// A reply or an internal note to a ticket, corresponding to a row in the table `ticketreplies`
+#[derive(Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, Clone)]
+#[table_name = "ticketreplies"]
+pub struct TicketReply {
+    id: String,
+    ticket_id: String,
+    author: String,
+    body: String,
+    is_internal: bool,
+    time_created: NaiveDateTime,
+}
+
+impl TicketReply {
+    fn new<T: ToString>(ticket: &Ticket, author: &UserId, body: T, is_internal: bool) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            ticket_id: ticket.id.clone(),
+            author: author.get_id().to_string(),
+            body: body.to_string(),
+            is_internal,
+            time_created: chrono::offset::Local::now().naive_utc(),
+        }
+    }
+
+    fn create(self, conn: &SqliteConnection) -> Result<Self> {
+        use crate::schema::ticketreplies::dsl::*;
+        diesel::insert_into(ticketreplies)
+            .values(&self)
+            .execute(conn)?;
+        Ok(self)
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_ticket_id(&self) -> &str {
+        &self.ticket_id
+    }
+
+    pub fn get_author(&self) -> &str {
+        &self.author
+    }
+
+    pub fn get_body(&self) -> &str {
+        &self.body
+    }
+
+    pub fn is_internal(&self) -> bool {
+        self.is_internal
+    }
+
+    pub fn get_time_created(&self) -> &NaiveDateTime {
+        &self.time_created
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TicketStats {
+    pub open: usize,
+    pub pending: usize,
+    pub resolved: usize,
+    // Tickets not resolved and not assigned to anyone
+    pub unassigned: usize,
+    // Tickets not resolved and never replied by the customer service
+    pub awaiting_response: usize,
+    // Average time from opening to the first reply of the customer service
+    pub avg_first_response: Option<Duration>,
+    // Average time from opening to resolution
+    pub avg_resolution: Option<Duration>,
+}
+
+type BoxedQuery<'a> = tickets::BoxedQuery<'a, Sqlite, tickets::SqlType>;
+
+/// A search query helper (builder)
+pub struct TicketFinder<'a> {
+    conn: &'a SqliteConnection,
+    query: BoxedQuery<'a>,
+}
+
+impl<'a> TicketFinder<'a> {
+    pub fn new(conn: &'a SqliteConnection, query: Option<BoxedQuery<'a>>) -> Self {
+        use crate::schema::tickets::dsl::*;
+        if let Some(q) = query {
+            Self { conn, query: q }
+        } else {
+            Self {
+                conn,
+                query: tickets.into_boxed(),
+            }
+        }
+    }
+
+    pub fn stats(conn: &'a SqliteConnection) -> Result<TicketStats> {
+        fn average(durations: Vec<Duration>) -> Option<Duration> {
+            if durations.is_empty() {
+                None
+            } else {
+                let total = durations.iter().map(Duration::num_seconds).sum::<i64>();
+                Some(Duration::seconds(total / durations.len() as i64))
+            }
+        }
+
+        let all = Self::new(conn, None).search()?;
+        let with_status = |s: TicketStatus| all.iter().filter(|t| t.status == s).count();
+        let unresolved = || all.iter().filter(|t| !t.is_resolved());
+
+        Ok(TicketStats {
+            open: with_status(TicketStatus::Open),
+            pending: with_status(TicketStatus::Pending),
+            resolved: with_status(TicketStatus::Resolved),
+            unassigned: unresolved().filter(|t| t.assignee.is_none()).count(),
+            awaiting_response: unresolved()
+                .filter(|t| t.time_first_response.is_none())
+                .count(),
+            avg_first_response: average(
+                all.iter()
+                    .filter_map(|t| t.time_first_response.map(|r| r - t.time_created))
+                    .collect(),
+            ),
+            avg_resolution: average(
+                all.iter()
+                    .filter_map(|t| t.time_resolved.map(|r| r - t.time_created))
+                    .collect(),
+            ),
+        })
+    }
+
+    pub fn search(self) -> Result<Vec<Ticket>> {
+        Ok(self.query.load::<Ticket>(self.conn)?)
+    }
+
+    pub fn count(self) -> Result<usize> {
+        use crate::schema::tickets::dsl::*;
+        Ok(self.query.select(count(id)).first::<i64>(self.conn)? as usize)
+    }
+
+    pub fn user(mut self, user: &'a UserId) -> Self {
+        use crate::schema::tickets::dsl::*;
+        self.query = self.query.filter(user_id.eq(user.get_id()));
+        self
+    }
+
+    pub fn transaction(mut self, order: &'a TransactionInfo) -> Self {
+        use crate::schema::tickets::dsl::*;
+        self.query = self.query.filter(transaction_id.eq(order.get_id()));
+        self
+    }
+
+    pub fn status(mut self, status_p: TicketStatus) -> Self {
+        use crate::schema::tickets::dsl::*;
+        self.query = self.query.filter(status.eq(status_p));
+        self
+    }
+
+    pub fn unresolved(mut self) -> Self {
+        use crate::schema::tickets::dsl::*;
+        self.query = self.query.filter(status.ne(TicketStatus::Resolved));
+        self
+    }
+
+    pub fn assignee(mut self, user: &'a UserId) -> Self {
+        use crate::schema::tickets::dsl::*;
+        self.query = self.query.filter(assignee.eq(user.get_id()));
+        self
+    }
+
+    pub fn unassigned(mut self) -> Self {
+        use crate::schema::tickets::dsl::*;
+        self.query = self.query.filter(assignee.is_null());
+        self
+    }
+
+    // By the time of the last update, the queue is worked with the oldest first
+    pub fn order_by_time(mut self, order: Order) -> Self {
+        use crate::schema::tickets::dsl::*;
+        match order {
+            Order::Asc => self.query = self.query.order(time_updated.asc()),
+            Order::Desc => self.query = self.query.order(time_updated.desc()),
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        categories::{Category, CtgTrait},
+        enums::{Currency, Payment, ProductStatus},
+        products::IncompleteProduct,
+        test_utils::establish_connection,
+        transactions::Transactions,
+        users::UserForm,
+    };
+
+    #[test]
+    fn ticket_lifecycle() {
+        let conn = establish_connection();
+        let seller = UserForm::new("TestUser@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+        let buyer = UserForm::new("AtypicalBuyer@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+        let staff = UserForm::new("Staff@example.org", "NFLS", "", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap()
+            .get_info(&conn)
+            .unwrap()
+            .set_user_status(UserStatus::CUSTOMER_SERVICE)
+            .update(&conn)
+            .unwrap();
+
+        let econ = Category::create(&conn, "Economics Books", 1)
+            .and_then(Category::into_leaf)
+            .unwrap();
+        let book_id = IncompleteProduct::new(
+            &econ,
+            "Krugman's Economics 2nd Edition",
+            700,
+            10,
+            "A very great book on the subject of Economics",
+            Currency::USD,
+        )
+        .unwrap()
+        .create(&conn, &seller)
+        .unwrap();
+        book_id
+            .get_info(&conn)
+            .unwrap()
+            .set_product_status(ProductStatus::Verified)
+            .update(&conn)
+            .unwrap();
+        let order = Transactions::buy(
+            &conn,
+            &book_id,
+            &buyer,
+            1,
+            "258 Huanhu South Road, Dongqian Lake, Ningbo, China",
+            "",
+            Payment::Paypal,
+        )
+        .unwrap()
+        .get_info(&conn)
+        .unwrap();
+
+        // Only the parties of the order could open tickets about it
+        assert!(matches!(
+            Tickets::open(&conn, &staff.to_id(), Some(&order), "Hi", "Hello").unwrap_err(),
+            SailsDbError::IllegalQuery
+        ));
+        let ticket = Tickets::open(
+            &conn,
+            &buyer,
+            Some(&order),
+            "Not delivered",
+            "Where is my book?",
+        )
+        .unwrap();
+        assert_eq!(ticket.get_status(), &TicketStatus::Open);
+        assert_eq!(ticket.get_transaction_id(), Some(order.get_id()));
+
+        let buyer_info = buyer.get_info(&conn).unwrap();
+        let seller_info = seller.get_info(&conn).unwrap();
+        assert!(ticket.readable_by(&buyer_info));
+        assert!(ticket.readable_by(&staff));
+        assert!(!ticket.readable_by(&seller_info));
+
+        // Users could neither write internal notes nor reply to tickets of others
+        assert!(Tickets::reply(&conn, &ticket, &buyer_info, "Note", true).is_err());
+        assert!(Tickets::reply(&conn, &ticket, &seller_info, "Hi", false).is_err());
+        // Only the customer service could be assigned
+        assert!(ticket.clone().assign(&conn, Some(&seller)).is_err());
+
+        let ticket = ticket.assign(&conn, Some(&staff.to_id())).unwrap();
+        assert_eq!(
+            TicketFinder::new(&conn, None)
+                .assignee(&staff.to_id())
+                .count()
+                .unwrap(),
+            1
+        );
+        assert_eq!(TicketFinder::stats(&conn).unwrap().awaiting_response, 1);
+
+        Tickets::reply(&conn, &ticket, &staff, "Seller contacted", true).unwrap();
+        let ticket = Tickets::find(&conn, ticket.get_id()).unwrap();
+        assert_eq!(ticket.get_status(), &TicketStatus::Open);
+        assert!(ticket.get_time_first_response().is_none());
+
+        Tickets::reply(&conn, &ticket, &staff, "It is on its way", false).unwrap();
+        let ticket = Tickets::find(&conn, ticket.get_id()).unwrap();
+        assert_eq!(ticket.get_status(), &TicketStatus::Pending);
+        assert!(ticket.get_time_first_response().is_some());
+
+        // Internal notes are hidden from the user
+        assert_eq!(Tickets::replies(&conn, &ticket, false).unwrap().len(), 2);
+        assert_eq!(Tickets::replies(&conn, &ticket, true).unwrap().len(), 3);
+
+        let ticket = ticket.set_status(&conn, TicketStatus::Resolved).unwrap();
+        assert!(ticket.get_time_resolved().is_some());
+        let stats = TicketFinder::stats(&conn).unwrap();
+        assert_eq!(stats.resolved, 1);
+        assert_eq!(stats.awaiting_response, 0);
+        assert!(stats.avg_first_response.is_some());
+        assert!(stats.avg_resolution.is_some());
+
+        // Replying reopens the ticket
+        Tickets::reply(&conn, &ticket, &buyer_info, "Still not here", false).unwrap();
+        let ticket = Tickets::find(&conn, ticket.get_id()).unwrap();
+        assert_eq!(ticket.get_status(), &TicketStatus::Open);
+        assert!(ticket.get_time_resolved().is_none());
+
+        // The staff leaves, and the ticket goes back to the queue
+        Tickets::delete_by_user(&conn, &staff.to_id()).unwrap();
+        let ticket = Tickets::find(&conn, ticket.get_id()).unwrap();
+        assert!(ticket.get_assignee().is_none());
+        assert_eq!(Tickets::replies(&conn, &ticket, true).unwrap().len(), 2);
+
+        Tickets::delete_by_user(&conn, &buyer).unwrap();
+        assert!(matches!(
+            Tickets::find(&conn, ticket.get_id()).unwrap_err(),
+            SailsDbError::TicketNotFound
+        ));
+    }
+}
@@ -0,0 +1,200 @@
+// Personal API tokens, allowing scripts and devices to act on behalf of a user without a browser session.
+// A token looks like `sails_<id>.<secret>`. The ID is used for the lookup, and only the bcrypt hash of the secret is stored.
+
+use crate::{
+    enums::UserStatus,
+    error::{SailsDbError, SailsDbResult as Result},
+    schema::apitokens,
+    users::UserId,
+};
+use chrono::naive::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const TOKEN_PREFIX: &str = "sails_";
+
+// A psuedo struct for managing API tokens
+pub struct ApiTokens;
+
+impl ApiTokens {
+    // Create a token for the user. The plain token is returned along with it and can never be retrieved again.
+    pub fn create(
+        conn: &SqliteConnection,
+        user: &UserId,
+        name_p: &str,
+        scopes_p: UserStatus,
+    ) -> Result<(ApiToken, String)> {
+        use crate::schema::apitokens::dsl::*;
+        let id_p = Uuid::new_v4().simple().to_string()[..12].to_string();
+        let secret = Uuid::new_v4().simple().to_string();
+        let token = ApiToken {
+            id: id_p.clone(),
+            user_id: user.get_id().to_string(),
+            name: name_p.to_string(),
+            secret_hash: bcrypt::hash(&secret, bcrypt::DEFAULT_COST)?,
+            scopes: scopes_p.bits() as i64,
+            time_created: chrono::offset::Local::now().naive_utc(),
+            time_last_used: None,
+        };
+        diesel::insert_into(apitokens)
+            .values(&token)
+            .execute(conn)?;
+        Ok((token, format!("{}{}.{}", TOKEN_PREFIX, id_p, secret)))
+    }
+
+    // Find the token given in plain and record its use
+    pub fn verify(conn: &SqliteConnection, plain: &str) -> Result<ApiToken> {
+        use crate::schema::apitokens::dsl::*;
+        let (id_p, secret) = plain
+            .strip_prefix(TOKEN_PREFIX)
+            .and_then(|x| x.split_once('.'))
+            .ok_or(SailsDbError::InvalidToken)?;
+        let token = match apitokens.filter(id.eq(id_p)).first::<ApiToken>(conn) {
+            Err(diesel::result::Error::NotFound) => return Err(SailsDbError::InvalidToken),
+            r => r?,
+        };
+        if !bcrypt::verify(secret, &token.secret_hash)? {
+            return Err(SailsDbError::InvalidToken);
+        }
+        let now = chrono::offset::Local::now().naive_utc();
+        diesel::update(apitokens.filter(id.eq(id_p)))
+            .set(time_last_used.eq(Some(now)))
+            .execute(conn)?;
+        Ok(ApiToken {
+            time_last_used: Some(now),
+            ..token
+        })
+    }
+
+    pub fn list_by_user(conn: &SqliteConnection, user: &UserId) -> Result<Vec<ApiToken>> {
+        use crate::schema::apitokens::dsl::*;
+        Ok(apitokens
+            .filter(user_id.eq(user.get_id()))
+            .order(time_created.desc())
+            .load::<ApiToken>(conn)?)
+    }
+
+    // Find a token of the user by its ID
+    pub fn find(conn: &SqliteConnection, user: &UserId, id_p: &str) -> Result<ApiToken> {
+        use crate::schema::apitokens::dsl::*;
+        Ok(apitokens
+            .filter(user_id.eq(user.get_id()))
+            .filter(id.eq(id_p))
+            .first::<ApiToken>(conn)?)
+    }
+
+    pub fn delete_by_user(conn: &SqliteConnection, user: &UserId) -> Result<usize> {
+        use crate::schema::apitokens::dsl::*;
+        Ok(diesel::delete(apitokens.filter(user_id.eq(user.get_id()))).execute(conn)?)
+    }
+}
+
+/// An API token, corresponding to a row in the table `apitokens`
+#[derive(Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, Clone)]
+#[table_name = "apitokens"]
+pub struct ApiToken {
+    id: String,
+    user_id: String,
+    name: String,
+    #[serde(skip_serializing)]
+    secret_hash: String,
+    scopes: i64,
+    time_created: NaiveDateTime,
+    time_last_used: Option<NaiveDateTime>,
+}
+
+impl ApiToken {
+    // Revoking a token deletes it
+    pub fn revoke(self, conn: &SqliteConnection) -> Result<()> {
+        use crate::schema::apitokens::dsl::*;
+        diesel::delete(apitokens.filter(id.eq(&self.id))).execute(conn)?;
+        Ok(())
+    }
+
+    // The permissions of the user that the token is allowed to exercise
+    pub fn effective(&self, status: UserStatus) -> UserStatus {
+        status & self.get_scopes()
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_scopes(&self) -> UserStatus {
+        UserStatus::from_bits_truncate(self.scopes as u32)
+    }
+
+    pub fn get_time_created(&self) -> &NaiveDateTime {
+        &self.time_created
+    }
+
+    pub fn get_time_last_used(&self) -> Option<&NaiveDateTime> {
+        self.time_last_used.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils::establish_connection, users::UserForm};
+
+    #[test]
+    fn api_tokens() {
+        let conn = establish_connection();
+        let user = UserForm::new("TestUser@example.org", "Kanyang Ying", "NFLS", None)
+            .to_ref()
+            .unwrap()
+            .create(&conn)
+            .unwrap();
+
+        let (token, plain) = ApiTokens::create(
+            &conn,
+            &user,
+            "Fulfilment tablet",
+            UserStatus::TX_BUYER_READABLE | UserStatus::TAG_WRITABLE,
+        )
+        .unwrap();
+        assert!(plain.starts_with(TOKEN_PREFIX));
+
+        let verified = ApiTokens::verify(&conn, &plain).unwrap();
+        assert_eq!(verified.get_id(), token.get_id());
+        assert_eq!(verified.get_user_id(), user.get_id());
+        assert!(verified.get_time_last_used().is_some());
+        // Scopes cannot grant what the user doesn't have
+        assert_eq!(
+            verified.effective(UserStatus::NORMAL),
+            UserStatus::TX_BUYER_READABLE
+        );
+
+        // Wrong secrets and malformed tokens are rejected
+        let forged = format!("{}{}.{}", TOKEN_PREFIX, token.get_id(), "0".repeat(32));
+        assert!(matches!(
+            ApiTokens::verify(&conn, &forged).err().unwrap(),
+            SailsDbError::InvalidToken
+        ));
+        assert!(matches!(
+            ApiTokens::verify(&conn, "not a token").err().unwrap(),
+            SailsDbError::InvalidToken
+        ));
+
+        assert_eq!(ApiTokens::list_by_user(&conn, &user).unwrap().len(), 1);
+        ApiTokens::find(&conn, &user, token.get_id())
+            .unwrap()
+            .revoke(&conn)
+            .unwrap();
+        assert!(matches!(
+            ApiTokens::verify(&conn, &plain).err().unwrap(),
+            SailsDbError::InvalidToken
+        ));
+        assert_eq!(ApiTokens::list_by_user(&conn, &user).unwrap().len(), 0);
+    }
+}
@@ -1,17 +1,30 @@
 use crate::{
+    addresses::{Address, OrderAddresses},
+    categories::{Categories, CtgTrait},
     coupons::{Coupon, CouponContext, CouponFinder},
-    enums::{Currency, Payment, ProductStatus, TransactionStatus, UserStatus},
+    credits::Credits,
+    digicons::DigiconMappingFinder,
+    enums::{
+        CouponStacking, CreditKind, Currency, Payment, ProductStatus, TransactionStatus, UserStatus,
+    },
     error::{SailsDbError, SailsDbResult as Result},
+    messages::Messages,
     products::{ProductFinder, ProductId},
-    schema::transactions,
-    users::UserId,
+    referrals::Referrals,
+    schema::{couponapplications, transactions},
+    tickets::Tickets,
+    users::{UserId, UserInfo},
     Cmp, Order,
 };
 use chrono::naive::NaiveDateTime;
 use diesel::{dsl::count, prelude::*, sqlite::Sqlite};
 use num_bigint::{BigUint, ToBigUint};
 use serde::{Deserialize, Serialize};
-use std::num::NonZeroU32;
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    num::NonZeroU32,
+};
 use uuid::Uuid;
 
 // A psuedo struct for managing transactions
@@ -27,102 +40,232 @@ impl Transactions {
         coupon_p: &str,
         payment_p: Payment,
     ) -> Result<TransactionId> {
-        let qty = NonZeroU32::new(qty).ok_or(SailsDbError::IllegalPriceOrQuantity)?;
+        let coupons_p = if coupon_p.is_empty() {
+            vec![]
+        } else {
+            vec![coupon_p]
+        };
+        Self::buy_with_coupons(conn, product_p, buyer_p, qty, addr, &coupons_p, payment_p)
+    }
 
-        use crate::schema::transactions::dsl::*;
+    // Coupons are applied in the order of their priority.
+    // Exclusive coupons cannot be combined with any other one.
+    pub fn buy_with_coupons(
+        conn: &SqliteConnection,
+        product_p: &ProductId,
+        buyer_p: &UserId,
+        qty: u32,
+        addr: impl ToString,
+        coupons_p: &[&str],
+        payment_p: Payment,
+    ) -> Result<TransactionId> {
+        // The order is placed as a whole, or not at all
+        conn.transaction::<_, SailsDbError, _>(|| {
+            let qty = NonZeroU32::new(qty).ok_or(SailsDbError::IllegalPriceOrQuantity)?;
 
-        let product_info = product_p.get_info(conn)?;
+            use crate::schema::transactions::dsl::*;
 
-        // Seller should be able to purchase their own products
-        if product_info.get_seller_id() == buyer_p.get_id() {
-            return Err(SailsDbError::SelfPurchaseNotAllowed);
-        }
+            let product_info = product_p.get_info(conn)?;
 
-        // If payment is incompatible with the currency indicated, we shall not proceed
-        if !payment_p.compatible_with(product_info.get_currency()) {
-            return Err(SailsDbError::PaymentIncompatible);
-        }
+            // Seller should be able to purchase their own products
+            if product_info.get_seller_id() == buyer_p.get_id() {
+                return Err(SailsDbError::SelfPurchaseNotAllowed);
+            }
+
+            // If payment is incompatible with the currency indicated, we shall not proceed
+            if !payment_p.compatible_with(product_info.get_currency()) {
+                return Err(SailsDbError::PaymentIncompatible);
+            }
 
-        if product_info.get_product_status() == &ProductStatus::Verified {
-            let coupon_p = if coupon_p.is_empty() {
-                // Try find the default coupon
-                if let Ok(r) = CouponFinder::new(conn, None).id("DEFAULT").first() {
-                    // Use default coupon
-                    // Default coupon should not error on exec otherwise users cannot proceed transaction without a coupon
-                    r
-                } else {
+            if product_info.get_product_status() == &ProductStatus::Verified {
+                let coupon_context = |coupon: &Coupon| {
+                    CouponContext::build(
+                        conn,
+                        coupon,
+                        &product_info,
+                        buyer_p,
+                        qty.get(),
+                        &payment_p,
+                    )
+                };
+
+                let mut applied: Vec<(Coupon, CouponContext)> = Vec::new();
+                for code in coupons_p {
+                    // The same coupon only applies once
+                    if applied.iter().any(|(c, _)| c.get_id() == *code) {
+                        continue;
+                    }
+                    // Search for the specific coupon
+                    let c = match CouponFinder::new(conn, None).id(code).first() {
+                        Err(SailsDbError::QueryError(diesel::result::Error::NotFound)) => {
+                            Err(SailsDbError::CouponNotFound)
+                        }
+                        r => r,
+                    }?;
+                    let ctx = coupon_context(&c)?;
+                    c.check(&ctx)?;
+                    applied.push((c, ctx));
+                }
+
+                if applied.len() > 1
+                    && applied
+                        .iter()
+                        .any(|(c, _)| c.get_stacking() == &CouponStacking::Exclusive)
+                {
+                    return Err(SailsDbError::CouponNotStackable);
+                }
+
+                // Try find the default coupon. It applies if no coupon is entered or if it stacks with those entered.
+                // Default coupon should not error otherwise users cannot proceed transaction without a coupon.
+                // Therefore, if its restrictions are not met, we simply skip it.
+                if applied
+                    .iter()
+                    .all(|(c, _)| c.get_stacking() == &CouponStacking::Stackable)
+                {
+                    let default = CouponFinder::new(conn, None)
+                        .id("DEFAULT")
+                        .first()
+                        .ok()
+                        .filter(|c| {
+                            applied.is_empty() || c.get_stacking() == &CouponStacking::Stackable
+                        })
+                        .filter(|c| !applied.iter().any(|(a, _)| a.get_id() == c.get_id()))
+                        .map(|c| -> Result<(Coupon, CouponContext)> {
+                            let ctx = coupon_context(&c)?;
+                            c.check(&ctx)?;
+                            Ok((c, ctx))
+                        })
+                        .and_then(Result::ok);
+                    applied.extend(default);
+                }
+
+                if applied.is_empty() {
                     // If "DEFAULT"  coupon is not available, use builtin coupon
-                    Coupon::new_without_db("_BUILTIN_", "0")
+                    let c = Coupon::new_without_db("_BUILTIN_", "0");
+                    let ctx = coupon_context(&c)?;
+                    applied.push((c, ctx));
                 }
-            } else {
-                // Search for the specific coupon
-                CouponFinder::new(conn, None).id(coupon_p).first()?
-            };
 
-            let coupon_ctx = CouponContext {
-                buyer: buyer_p.get_info(conn)?,
-                seller: UserId::find(conn, product_info.get_seller_id())?.get_info(conn)?,
-                product: product_info.clone(),
-                quantity: qty.get() as i64,
-                buyer_used: TransactionFinder::new(conn, None)
-                    .buyer(buyer_p)
-                    .coupon(coupon_p.get_id())
-                    .status(TransactionStatus::Refunded, Cmp::NotEqual)
-                    .count_i64()?,
-                total_used: TransactionFinder::new(conn, None)
-                    .coupon(coupon_p.get_id())
-                    .status(TransactionStatus::Refunded, Cmp::NotEqual)
-                    .count_i64()?,
-            };
+                // Sorting is stable, coupons of the same priority are applied in the order entered
+                applied.sort_by_key(|(c, _)| c.get_priority());
+
+                let id_cloned = Uuid::new_v4();
+                let shortid_str = id_cloned.as_fields().0.to_string();
+
+                let mut total_discount = 0i64;
+                let mut applications = Vec::new();
+                for (pos, (c, mut ctx)) in applied.into_iter().enumerate() {
+                    ctx.discounted = total_discount;
+                    // No coupon discounts more than what is left of the subtotal
+                    let left = (ctx.subtotal - total_discount).max(0);
+                    let d = c.exec(ctx)?.min(left);
+                    total_discount = total_discount
+                        .checked_add(d)
+                        .ok_or(SailsDbError::Overflow)?;
+                    applications.push(CouponApplication {
+                        id: Uuid::new_v4().to_string(),
+                        transaction_id: id_cloned.to_string(),
+                        coupon: c.get_id().to_string(),
+                        discount: d,
+                        position: pos as i64,
+                    });
+                }
 
-            let id_cloned = Uuid::new_v4();
-            let shortid_str = id_cloned.as_fields().0.to_string();
-            let mut tx = TransactionInfo {
-                id: id_cloned.to_string(),
-                shortid: shortid_str,
-                seller: product_info.get_seller_id().to_string(),
-                product: product_p.get_id().to_string(),
-                price: product_info.get_price() as i64,
-                quantity: qty.get() as i64,
-                address: addr.to_string(),
-                payment: payment_p,
-                payment_detail: None,
-                buyer: buyer_p.get_id().to_string(),
-                time_sent: chrono::offset::Local::now().naive_utc(),
-                currency: product_info.get_currency().clone(),
-                coupon: coupon_p.get_id().to_string(),
-                discount: coupon_p.exec(coupon_ctx)?,
-                transaction_status: TransactionStatus::Placed,
-            };
+                let mut tx = TransactionInfo {
+                    id: id_cloned.to_string(),
+                    shortid: shortid_str,
+                    seller: product_info.get_seller_id().to_string(),
+                    product: product_p.get_id().to_string(),
+                    price: product_info.get_price() as i64,
+                    quantity: qty.get() as i64,
+                    address: addr.to_string(),
+                    payment: payment_p,
+                    payment_detail: None,
+                    buyer: buyer_p.get_id().to_string(),
+                    time_sent: chrono::offset::Local::now().naive_utc(),
+                    currency: product_info.get_currency().clone(),
+                    coupon: applications
+                        .iter()
+                        .map(|a| a.coupon.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(","),
+                    discount: total_discount,
+                    transaction_status: TransactionStatus::Placed,
+                    credit: 0,
+                    time_paid: None,
+                };
+
+                // Orders paid with store credit must be covered by the balance of the buyer
+                let pay_with_credit =
+                    (tx.payment == Payment::Credit) && (tx.get_total() > 0u32.into());
+                if pay_with_credit
+                    && BigUint::try_from(Credits::balance(conn, buyer_p, &tx.currency)?.max(0))
+                        .map_err(|_| SailsDbError::Overflow)?
+                        < tx.get_total()
+                {
+                    return Err(SailsDbError::InsufficientCredit);
+                }
 
-            if tx.get_total() == 0u32.into() {
-                // If the product is free, we just finish the transaction
-                // Ideally, we should set this to paid. However, since most free products are digital content and digital content are not obtainable until order gets finished, we set order status "finished" to expedite the process.
-                tx = tx.set_transaction_status(TransactionStatus::Finished);
-            }
+                if tx.get_total() == 0u32.into() {
+                    // If the product is free, we just finish the transaction
+                    // Ideally, we should set this to paid. However, since most free products are digital content and digital content are not obtainable until order gets finished, we set order status "finished" to expedite the process.
+                    tx = tx.set_transaction_status(TransactionStatus::Finished);
+                }
 
-            // Create transaction record
-            diesel::insert_into(transactions).values(tx).execute(conn)?;
+                // Create transaction record along with the coupons applied
+                diesel::insert_into(transactions).values(tx).execute(conn)?;
+                diesel::insert_into(couponapplications::table)
+                    .values(&applications)
+                    .execute(conn)?;
 
-            // Sub product quantity
-            // IMPORTANT We shall not use ? operator here because that abort the function and doesn't clean up
-            if product_info
-                .sub_quantity(qty.get())
-                .map(|s| s.update(conn))
-                .is_ok()
-            {
-                // Return the transaction ID
-                Ok(TransactionId {
+                // Sub product quantity. Failing to do so rolls back the order.
+                product_info
+                    .sub_quantity(qty.get())
+                    .and_then(|s| s.update(conn))
+                    .map_err(|_| SailsDbError::FailedAlterProductQuantity)?;
+
+                let tx_id = TransactionId {
                     id: id_cloned.to_string(),
-                })
+                };
+                if pay_with_credit {
+                    // The order is rolled back as well if the balance has changed since the check
+                    tx_id.get_info(conn)?.apply_credit(conn)?;
+                }
+                // Return the transaction ID
+                Ok(tx_id)
             } else {
-                // There are some issues changing the status of the book, and we shall delete the transaction
-                diesel::delete(transactions.filter(id.eq(&id_cloned.to_string()))).execute(conn)?;
-                Err(SailsDbError::FailedAlterProductQuantity)
+                Err(SailsDbError::OrderOnUnverified)
             }
-        } else {
-            Err(SailsDbError::OrderOnUnverified)
-        }
+        })
+    }
+
+    // Same as `buy_with_coupons`, shipping to an address from the address book of the buyer.
+    // A snapshot of the address is kept along with the order. Orders of digital contents may go without one.
+    pub fn buy_to_address(
+        conn: &SqliteConnection,
+        product_p: &ProductId,
+        buyer_p: &UserId,
+        qty: u32,
+        address_p: Option<&Address>,
+        coupons_p: &[&str],
+        payment_p: Payment,
+    ) -> Result<TransactionId> {
+        conn.transaction::<_, SailsDbError, _>(|| {
+            let form = match address_p {
+                Some(a) if a.get_user_id() == buyer_p.get_id() => Some(a.to_form()),
+                Some(_) => {
+                    return Err(SailsDbError::InvalidAddress("not your address".to_string()))
+                }
+                None => None,
+            };
+            let addr = form.as_ref().map(ToString::to_string).unwrap_or_default();
+            let tx =
+                Self::buy_with_coupons(conn, product_p, buyer_p, qty, addr, coupons_p, payment_p)?;
+            if let Some(form) = form {
+                OrderAddresses::snapshot(conn, &tx, &form)?;
+            }
+            Ok(tx)
+        })
     }
 
     pub fn buyer_refundable(conn: &SqliteConnection, buyer: &UserId) -> Result<bool> {
@@ -133,6 +276,122 @@ impl Transactions {
             .len()
             > 2)
     }
+
+    // Orders of a deleted user are kept for accounting under its tombstone, without the shipping address
+    pub(crate) fn hand_over(conn: &SqliteConnection, from: &UserId, to: &UserId) -> Result<()> {
+        use crate::schema::transactions::dsl::*;
+        let purchases = transactions
+            .select(id)
+            .filter(buyer.eq(from.get_id()))
+            .load::<String>(conn)?;
+        OrderAddresses::delete_by_transactions(conn, &purchases)?;
+        diesel::update(transactions.filter(buyer.eq(from.get_id())))
+            .set((buyer.eq(to.get_id()), address.eq("")))
+            .execute(conn)?;
+        diesel::update(transactions.filter(seller.eq(from.get_id())))
+            .set(seller.eq(to.get_id()))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    // Delete every order placed by the user or made on its products, along with the coupons applied.
+    pub fn delete_by_user(conn: &SqliteConnection, user: &UserId) -> Result<usize> {
+        use crate::schema::{products, transactions::dsl::*};
+        let ids = transactions
+            .select(id)
+            .filter(
+                buyer
+                    .eq(user.get_id())
+                    .or(seller.eq(user.get_id()))
+                    .or(product.eq_any(
+                        products::table
+                            .select(products::id)
+                            .filter(products::seller_id.eq(user.get_id())),
+                    )),
+            )
+            .load::<String>(conn)?;
+        diesel::delete(
+            couponapplications::table.filter(couponapplications::transaction_id.eq_any(&ids)),
+        )
+        .execute(conn)?;
+        OrderAddresses::delete_by_transactions(conn, &ids)?;
+        Messages::delete_by_transactions(conn, &ids)?;
+        Tickets::detach_transactions(conn, &ids)?;
+        Ok(diesel::delete(transactions.filter(id.eq_any(&ids))).execute(conn)?)
+    }
+}
+
+// Paid purchases of a user, aggregated by every category along the path to the product's category.
+#[derive(Debug, Clone, Default)]
+pub struct PurchaseHistory {
+    count: HashMap<String, i64>,
+    spent: HashMap<(String, Currency), i64>,
+    count_all: i64,
+    spent_all: HashMap<Currency, i64>,
+}
+
+impl PurchaseHistory {
+    pub fn of(conn: &SqliteConnection, buyer_p: &UserId) -> Result<Self> {
+        use crate::schema::products;
+        // Only effective orders count
+        let txs = transactions::table
+            .inner_join(products::table)
+            .filter(transactions::buyer.eq(buyer_p.get_id()))
+            .filter(transactions::transaction_status.ne(TransactionStatus::Placed))
+            .filter(transactions::transaction_status.ne(TransactionStatus::Refunded))
+            .select((transactions::all_columns, products::category))
+            .load::<(TransactionInfo, String)>(conn)?;
+
+        // The path to each distinct category, walked only once
+        let mut paths: HashMap<String, Vec<String>> = HashMap::new();
+        for (_, ctg_id) in &txs {
+            if !paths.contains_key(ctg_id) {
+                let ctg = Categories::find_by_id(conn, ctg_id)?;
+                let mut path = Categories::ancestors(conn, &ctg)?
+                    .iter()
+                    .map(|c| c.id().to_string())
+                    .collect::<Vec<String>>();
+                path.push(ctg_id.clone());
+                paths.insert(ctg_id.clone(), path);
+            }
+        }
+
+        let mut history = Self::default();
+        for (tx, ctg_id) in txs {
+            let spent = (tx.price * tx.quantity - tx.discount).max(0);
+            for c in &paths[&ctg_id] {
+                *history.count.entry(c.clone()).or_default() += 1;
+                *history
+                    .spent
+                    .entry((c.clone(), tx.currency.clone()))
+                    .or_default() += spent;
+            }
+            history.count_all += 1;
+            *history.spent_all.entry(tx.currency.clone()).or_default() += spent;
+        }
+        Ok(history)
+    }
+
+    // Number of orders under the category (including its subcategories)
+    pub fn count(&self, category: &str) -> i64 {
+        self.count.get(category).copied().unwrap_or(0)
+    }
+
+    // Amount spent under the category (including its subcategories) in the given currency
+    pub fn spent(&self, category: &str, currency: &Currency) -> i64 {
+        self.spent
+            .get(&(category.to_string(), currency.clone()))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn count_all(&self) -> i64 {
+        self.count_all
+    }
+
+    pub fn spent_all(&self, currency: &Currency) -> i64 {
+        self.spent_all.get(currency).copied().unwrap_or(0)
+    }
 }
 
 // The ID referencing a single transaction
@@ -158,11 +417,40 @@ impl TransactionId {
             .first::<TransactionInfo>(conn)?)
     }
 
-    pub fn refund(&self, conn: &SqliteConnection) -> Result<()> {
+    pub fn refund(&self, conn: &SqliteConnection) -> Result<TransactionInfo> {
         self.get_info(conn)?.refund(conn)
     }
 }
 
+/// A coupon applied to a transaction, corresponding to a row in the table `couponapplications`
+#[derive(Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, Clone)]
+#[table_name = "couponapplications"]
+pub struct CouponApplication {
+    id: String,
+    transaction_id: String,
+    coupon: String,
+    discount: i64,
+    position: i64,
+}
+
+impl CouponApplication {
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_transaction_id(&self) -> &str {
+        &self.transaction_id
+    }
+
+    pub fn get_coupon(&self) -> &str {
+        &self.coupon
+    }
+
+    pub fn get_discount(&self) -> i64 {
+        self.discount
+    }
+}
+
 /// A single transaction info entry, corresponding to a row in the table `transactions`
 #[derive(
     Debug, Serialize, Deserialize, Queryable, Identifiable, Insertable, AsChangeset, Clone,
@@ -184,6 +472,10 @@ pub struct TransactionInfo {
     payment_detail: Option<String>,
     coupon: String,
     discount: i64,
+    // Amount paid with store credit
+    credit: i64,
+    // NULL until the order is paid. Orders cancelled before payment are refunded with this unset.
+    time_paid: Option<NaiveDateTime>,
 }
 
 impl TransactionInfo {
@@ -196,17 +488,108 @@ impl TransactionInfo {
         &self.id
     }
 
-    pub fn refund(&self, conn: &SqliteConnection) -> Result<()> {
+    // The part paid with store credit is returned to the credit.
+    // The payable part should be refunded through the payment provider before calling this.
+    pub fn refund(&self, conn: &SqliteConnection) -> Result<Self> {
+        conn.transaction::<_, SailsDbError, _>(|| {
+            // The order might have changed since it was loaded
+            let current = TransactionFinder::new(conn, None)
+                .id(self.get_id())
+                .first_info()?;
+            let credit_p = current.get_credit();
+            current.refund_with_credit(conn, credit_p)
+        })
+    }
+
+    // Refund the whole order to store credit instead of the payment provider.
+    // Only orders paid in full could be refunded this way, and only the amount paid is credited.
+    pub fn refund_to_credit(&self, conn: &SqliteConnection) -> Result<Self> {
+        conn.transaction::<_, SailsDbError, _>(|| {
+            let current = TransactionFinder::new(conn, None)
+                .id(self.get_id())
+                .first_info()?;
+            if !matches!(
+                current.transaction_status,
+                TransactionStatus::Paid | TransactionStatus::Finished
+            ) {
+                return Err(SailsDbError::IllegalQuery);
+            }
+            let paid = u32::try_from(current.get_payable() + current.get_credit())
+                .map_err(|_| SailsDbError::Overflow)?;
+            current.refund_with_credit(conn, paid)
+        })
+    }
+
+    // Should be called in a transaction on the latest state of the order
+    fn refund_with_credit(self, conn: &SqliteConnection, credit_p: u32) -> Result<Self> {
+        if self.transaction_status == TransactionStatus::Refunded {
+            return Err(SailsDbError::IllegalQuery);
+        }
         // Return the products to `verified` state.
         ProductFinder::new(conn, None)
             .id(self.get_product())
             .first_info()?
             .add_quantity(self.quantity as u32)?
             .update(conn)?;
-        self.clone()
-            .set_transaction_status(TransactionStatus::Refunded)
+        if credit_p > 0 {
+            Credits::grant(
+                conn,
+                &UserId::find(conn, self.get_buyer())?,
+                self.currency.clone(),
+                credit_p,
+                CreditKind::Refund,
+                Some(self.get_id()),
+                None,
+            )?;
+        }
+        // Rewards are not kept for orders which don't stay finished
+        Referrals::revoke(conn, &self)?;
+        self.set_transaction_status(TransactionStatus::Refunded)
             .update(conn)
-            .map(|_| ())
+    }
+
+    // Pay as much as possible of a placed order with the store credit of the buyer.
+    // The order is paid if the credit covers the whole amount.
+    pub fn apply_credit(self, conn: &SqliteConnection) -> Result<Self> {
+        conn.transaction::<_, SailsDbError, _>(|| {
+            if self.transaction_status != TransactionStatus::Placed {
+                return Err(SailsDbError::IllegalQuery);
+            }
+            let buyer = UserId::find(conn, self.get_buyer())?;
+            let balance = Credits::balance(conn, &buyer, &self.currency)?.max(0);
+            let payable = u32::try_from(self.get_payable()).map_err(|_| SailsDbError::Overflow)?;
+            let amount = payable.min(balance.try_into().unwrap_or(u32::MAX));
+            if amount == 0 {
+                return Ok(self);
+            }
+            Credits::spend(conn, &buyer, self.currency.clone(), amount, self.get_id())?;
+
+            let mut tx = self;
+            tx.credit += amount as i64;
+            if amount == payable {
+                let status = tx.paid_status(conn)?;
+                tx = tx.set_transaction_status(status);
+            }
+            let tx = tx.update(conn)?;
+            // Orders of digital contents are finished once paid, which may trigger the referral rewards
+            Referrals::reward(conn, &tx)?;
+            Ok(tx)
+        })
+    }
+
+    // Digital contents are not obtainable until order gets finished. Therefore, we finish these orders once paid.
+    fn paid_status(&self, conn: &SqliteConnection) -> Result<TransactionStatus> {
+        let prod_id = ProductFinder::new(conn, None)
+            .id(self.get_product())
+            .first()?;
+        let digicons = DigiconMappingFinder::new(conn, None)
+            .product(&prod_id)
+            .count()?;
+        Ok(if digicons > 0 {
+            TransactionStatus::Finished
+        } else {
+            TransactionStatus::Paid
+        })
     }
 
     /// Get a reference to the transaction info's shortid.
@@ -230,6 +613,22 @@ impl TransactionInfo {
         qty * price
     }
 
+    pub fn get_credit(&self) -> u32 {
+        self.credit as u32
+    }
+
+    // Amount to be paid through the payment provider
+    pub fn get_payable(&self) -> BigUint {
+        let total = self.get_total();
+        let credit: BigUint = self.get_credit().into();
+        // Don't panic on underflow
+        if total >= credit {
+            total - credit
+        } else {
+            0u32.into()
+        }
+    }
+
     pub fn get_total(&self) -> BigUint {
         let subtotal = self.get_subtotal();
         let discount: BigUint = self.get_discount().into();
@@ -272,10 +671,31 @@ impl TransactionInfo {
         &self.time_sent
     }
 
+    pub fn get_time_paid(&self) -> Option<&NaiveDateTime> {
+        self.time_paid.as_ref()
+    }
+
+    // Whether the order has been paid, even if it was refunded afterwards
+    pub fn is_paid(&self) -> bool {
+        self.time_paid.is_some()
+    }
+
+    // Comma-separated IDs of the coupons applied, in the order of application
     pub fn get_coupon(&self) -> &str {
         &self.coupon
     }
 
+    pub fn get_coupon_applications(
+        &self,
+        conn: &SqliteConnection,
+    ) -> Result<Vec<CouponApplication>> {
+        use crate::schema::couponapplications::dsl::*;
+        Ok(couponapplications
+            .filter(transaction_id.eq(&self.id))
+            .order(position.asc())
+            .load::<CouponApplication>(conn)?)
+    }
+
     pub fn get_discount(&self) -> u32 {
         self.discount as u32
     }
@@ -291,7 +711,15 @@ impl TransactionInfo {
     }
 
     /// Set the transaction info's transaction status.
+    /// The time of payment is recorded the first time the order becomes paid or finished.
     pub fn set_transaction_status(mut self, transaction_status: TransactionStatus) -> Self {
+        if matches!(
+            transaction_status,
+            TransactionStatus::Paid | TransactionStatus::Finished
+        ) && self.time_paid.is_none()
+        {
+            self.time_paid = Some(chrono::offset::Local::now().naive_utc());
+        }
         self.transaction_status = transaction_status;
         self
     }
@@ -302,135 +730,136 @@ impl TransactionInfo {
     }
 
     pub fn readable(&self, conn: &SqliteConnection, user: &UserId) -> Result<bool> {
-        let info = user.get_info(conn)?;
+        Ok(self.readable_by(&user.get_info(conn)?))
+    }
 
-        Ok(
-            match (
-                info.get_id() == self.get_buyer(),
-                info.get_id() == self.get_seller(),
-            ) {
-                (true, false)
-                    if info
-                        .get_user_status()
-                        .contains(UserStatus::TX_BUYER_READABLE) =>
-                {
-                    true
-                }
-                (false, true)
-                    if info
-                        .get_user_status()
-                        .contains(UserStatus::TX_SELLER_READABLE) =>
-                {
-                    true
-                }
-                _ if info
+    // Check against the given user status, which may be narrowed down by the scopes of an API token
+    pub fn readable_by(&self, info: &UserInfo) -> bool {
+        match (
+            info.get_id() == self.get_buyer(),
+            info.get_id() == self.get_seller(),
+        ) {
+            (true, false)
+                if info
                     .get_user_status()
-                    .contains(UserStatus::TX_OTHERS_READABLE) =>
-                {
-                    true
-                }
-                _ => false,
-            },
-        )
+                    .contains(UserStatus::TX_BUYER_READABLE) =>
+            {
+                true
+            }
+            (false, true)
+                if info
+                    .get_user_status()
+                    .contains(UserStatus::TX_SELLER_READABLE) =>
+            {
+                true
+            }
+            _ if info
+                .get_user_status()
+                .contains(UserStatus::TX_OTHERS_READABLE) =>
+            {
+                true
+            }
+            _ => false,
+        }
     }
 
     pub fn progressable(&self, conn: &SqliteConnection, user: &UserId) -> Result<bool> {
-        let info = user.get_info(conn)?;
+        Ok(self.progressable_by(&user.get_info(conn)?))
+    }
 
-        Ok(
-            match (
-                info.get_id() == self.get_buyer(),
-                info.get_id() == self.get_seller(),
-            ) {
-                (true, false)
-                    if info
-                        .get_user_status()
-                        .contains(UserStatus::TX_BUYER_PROGRESSABLE) =>
-                {
-                    true
-                }
-                (false, true)
-                    if info
-                        .get_user_status()
-                        .contains(UserStatus::TX_SELLER_PROGRESSABLE) =>
-                {
-                    true
-                }
-                _ if info
+    pub fn progressable_by(&self, info: &UserInfo) -> bool {
+        match (
+            info.get_id() == self.get_buyer(),
+            info.get_id() == self.get_seller(),
+        ) {
+            (true, false)
+                if info
                     .get_user_status()
-                    .contains(UserStatus::TX_OTHERS_PROGRESSABLE) =>
-                {
-                    true
-                }
-                _ => false,
-            },
-        )
+                    .contains(UserStatus::TX_BUYER_PROGRESSABLE) =>
+            {
+                true
+            }
+            (false, true)
+                if info
+                    .get_user_status()
+                    .contains(UserStatus::TX_SELLER_PROGRESSABLE) =>
+            {
+                true
+            }
+            _ if info
+                .get_user_status()
+                .contains(UserStatus::TX_OTHERS_PROGRESSABLE) =>
+            {
+                true
+            }
+            _ => false,
+        }
     }
 
     pub fn finishable(&self, conn: &SqliteConnection, user: &UserId) -> Result<bool> {
-        let info = user.get_info(conn)?;
+        Ok(self.finishable_by(&user.get_info(conn)?))
+    }
 
-        Ok(
-            match (
-                info.get_id() == self.get_buyer(),
-                info.get_id() == self.get_seller(),
-            ) {
-                (true, false)
-                    if info
-                        .get_user_status()
-                        .contains(UserStatus::TX_BUYER_FINISHABLE) =>
-                {
-                    true
-                }
-                (false, true)
-                    if info
-                        .get_user_status()
-                        .contains(UserStatus::TX_SELLER_FINISHABLE) =>
-                {
-                    true
-                }
-                _ if info
+    pub fn finishable_by(&self, info: &UserInfo) -> bool {
+        match (
+            info.get_id() == self.get_buyer(),
+            info.get_id() == self.get_seller(),
+        ) {
+            (true, false)
+                if info
                     .get_user_status()
-                    .contains(UserStatus::TX_OTHERS_FINISHABLE) =>
-                {
-                    true
-                }
-                _ => false,
-            },
-        )
+                    .contains(UserStatus::TX_BUYER_FINISHABLE) =>
+            {
+                true
+            }
+            (false, true)
+                if info
+                    .get_user_status()
+                    .contains(UserStatus::TX_SELLER_FINISHABLE) =>
+            {
+                true
+            }
+            _ if info
+                .get_user_status()
+                .contains(UserStatus::TX_OTHERS_FINISHABLE) =>
+            {
+                true
+            }
+            _ => false,
+        }
     }
 
     pub fn refundable(&self, conn: &SqliteConnection, user: &UserId) -> Result<bool> {
-        let info = user.get_info(conn)?;
+        Ok(self.refundable_by(&user.get_info(conn)?))
+    }
 
-        Ok(
-            match (
-                info.get_id() == self.get_buyer(),
-                info.get_id() == self.get_seller(),
-            ) {
-                (true, false)
-                    if info
-                        .get_user_status()
-                        .contains(UserStatus::TX_BUYER_REFUNDABLE) =>
-                {
-                    true
-                }
-                (false, true)
-                    if info
-                        .get_user_status()
-                        .contains(UserStatus::TX_SELLER_REFUNDABLE) =>
-                {
-                    true
-                }
-                _ if info
+    pub fn refundable_by(&self, info: &UserInfo) -> bool {
+        match (
+            info.get_id() == self.get_buyer(),
+            info.get_id() == self.get_seller(),
+        ) {
+            (true, false)
+                if info
                     .get_user_status()
-                    .contains(UserStatus::TX_OTHERS_REFUNDABLE) =>
-                {
-                    true
-                }
-                _ => false,
-            },
-        )
+                    .contains(UserStatus::TX_BUYER_REFUNDABLE) =>
+            {
+                true
+            }
+            (false, true)
+                if info
+                    .get_user_status()
+                    .contains(UserStatus::TX_SELLER_REFUNDABLE) =>
+            {
+                true
+            }
+            _ if info
+                .get_user_status()
+                .contains(UserStatus::TX_OTHERS_REFUNDABLE) =>
+            {
+                true
+            }
+            _ => false,
+        }
     }
 }
 
@@ -608,9 +1037,16 @@ impl<'a> TransactionFinder<'a> {
         self
     }
 
+    // Transactions to which the coupon has been applied, possibly along with others
     pub fn coupon(mut self, coupon_id: &'a str) -> Self {
         use crate::schema::transactions::dsl::*;
-        self.query = self.query.filter(coupon.eq(coupon_id));
+        self.query = self.query.filter(
+            id.eq_any(
+                couponapplications::table
+                    .select(couponapplications::transaction_id)
+                    .filter(couponapplications::coupon.eq(coupon_id)),
+            ),
+        );
         self
     }
 
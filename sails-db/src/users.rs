@@ -1,14 +1,30 @@
 use crate::{
+    addresses::Addresses,
+    credits::{CreditFinder, Credits},
+    digicons::Digicons,
     enums::UserStatus,
     error::{SailsDbError, SailsDbResult as Result},
+    identities::Identities,
     messages::Messages,
     products::Products,
+    referrals::Referrals,
+    roles::{UserRoles, DEFAULT_ROLE},
     schema::users,
+    sessions::Sessions,
+    tickets::Tickets,
+    tokens::ApiTokens,
+    transactions::{TransactionFinder, Transactions},
     Cmp,
 };
 use diesel::{dsl::count, prelude::*, sqlite::Sqlite};
 use rocket::FromForm;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+// IDs of tombstones standing in for deleted users.
+// Real IDs are UUIDs, or email addresses for users created before identities were introduced. Neither starts with it.
+pub const TOMBSTONE_PREFIX: &str = "_deleted_";
 
 /// An user
 #[derive(Debug, Serialize, Deserialize, Identifiable, Queryable, Clone)]
@@ -28,12 +44,88 @@ impl UserId {
         UserFinder::new(conn, None).id(id).first()
     }
 
+    // Delete the account and all the personal data in it.
+    // Orders, sold products, digicons and the credit ledger are kept for accounting under an anonymous tombstone.
     pub fn delete(self, conn: &SqliteConnection) -> Result<()> {
         use crate::schema::users::dsl::*;
-        Products::delete_by_seller(conn, &self)?;
-        Messages::delete_msg_with_user(conn, &self)?;
-        diesel::delete(users.filter(id.eq(&self.id))).execute(conn)?;
-        Ok(())
+        conn.transaction::<_, SailsDbError, _>(|| {
+            if self.has_records(conn)? {
+                let tombstone = Self::create_tombstone(conn)?;
+                Transactions::hand_over(conn, &self, &tombstone)?;
+                Products::hand_over(conn, &self, &tombstone)?;
+                Digicons::hand_over(conn, &self, &tombstone)?;
+                Credits::hand_over(conn, &self, &tombstone)?;
+            } else {
+                Products::delete_by_seller(conn, &self)?;
+            }
+            Messages::delete_msg_with_user(conn, &self)?;
+            Tickets::delete_by_user(conn, &self)?;
+            Addresses::delete_by_user(conn, &self)?;
+            Referrals::delete_by_user(conn, &self)?;
+            UserRoles::delete_by_user(conn, &self)?;
+            ApiTokens::delete_by_user(conn, &self)?;
+            Sessions::delete_by_user(conn, &self)?;
+            Identities::delete_by_user(conn, &self)?;
+            diesel::delete(users.filter(id.eq(&self.id))).execute(conn)?;
+            Ok(())
+        })
+    }
+
+    // Delete the user along with every row depending on it, including the orders it took part in.
+    // Unlike `delete`, this leaves no trace in the accounting.
+    pub fn purge(self, conn: &SqliteConnection) -> Result<()> {
+        use crate::schema::users::dsl::*;
+        conn.transaction::<_, SailsDbError, _>(|| {
+            Transactions::delete_by_user(conn, &self)?;
+            Products::delete_by_seller(conn, &self)?;
+            for digicon in Digicons::list_by_creator(conn, &self)? {
+                digicon.delete(conn)?;
+            }
+            Messages::delete_msg_with_user(conn, &self)?;
+            Tickets::delete_by_user(conn, &self)?;
+            Credits::delete_by_user(conn, &self)?;
+            Addresses::delete_by_user(conn, &self)?;
+            Referrals::delete_by_user(conn, &self)?;
+            UserRoles::delete_by_user(conn, &self)?;
+            ApiTokens::delete_by_user(conn, &self)?;
+            Sessions::delete_by_user(conn, &self)?;
+            Identities::delete_by_user(conn, &self)?;
+            diesel::delete(users.filter(id.eq(&self.id))).execute(conn)?;
+            Ok(())
+        })
+    }
+
+    // Whether anything the user leaves behind has to be kept after the account is deleted
+    fn has_records(&self, conn: &SqliteConnection) -> Result<bool> {
+        Ok(
+            TransactionFinder::new(conn, None).buyer(self).count_i64()? > 0
+                || TransactionFinder::new(conn, None)
+                    .seller(self)
+                    .count_i64()?
+                    > 0
+                || !Digicons::list_by_creator(conn, self)?.is_empty()
+                || !CreditFinder::new(conn, None)
+                    .user(self)
+                    .search()?
+                    .is_empty(),
+        )
+    }
+
+    fn create_tombstone(conn: &SqliteConnection) -> Result<Self> {
+        use crate::schema::users::dsl::*;
+        let id_p = format!("{}{}", TOMBSTONE_PREFIX, Uuid::new_v4().simple());
+        diesel::insert_into(users)
+            .values(UserInfoRef {
+                id: id_p.clone(),
+                name: "Deleted user",
+                school: "",
+                description: None,
+                user_status: UserStatus::DISABLED.bits() as i64,
+                email: "",
+                email_verified: false,
+            })
+            .execute(conn)?;
+        Ok(Self { id: id_p })
     }
 
     /// Get a reference to the user id's id.
@@ -60,6 +152,17 @@ pub struct UserStats {
     pub admin: usize,
 }
 
+// Names of users, shown in place of their IDs
+#[derive(Debug, Default, Clone)]
+pub struct UserNames(HashMap<String, String>);
+
+impl UserNames {
+    // Users not loaded are shown by their IDs
+    pub fn get<'b>(&'b self, id: &'b str) -> &'b str {
+        self.0.get(id).map(String::as_str).unwrap_or(id)
+    }
+}
+
 impl<'a> UserFinder<'a> {
     pub fn list(conn: &'a SqliteConnection) -> Result<Vec<UserId>> {
         Self::new(conn, None).search()
@@ -69,6 +172,26 @@ impl<'a> UserFinder<'a> {
         Self::new(conn, None).search_info()
     }
 
+    pub fn names<'b>(
+        conn: &'a SqliteConnection,
+        ids: impl IntoIterator<Item = &'b str>,
+    ) -> Result<UserNames> {
+        use crate::schema::users::dsl::*;
+        let ids = ids.into_iter().collect::<HashSet<&str>>();
+        let ids = ids.into_iter().collect::<Vec<&str>>();
+        let mut names = HashMap::new();
+        // SQLite limits the number of variables bound in a query
+        for chunk in ids.chunks(500) {
+            for user in users
+                .filter(id.eq_any(chunk.to_vec()))
+                .load::<UserInfo>(conn)?
+            {
+                names.insert(user.id.clone(), user.get_display_name().to_string());
+            }
+        }
+        Ok(UserNames(names))
+    }
+
     pub fn count(self) -> Result<usize> {
         use crate::schema::users::dsl::*;
         Ok(self.query.select(count(id)).first::<i64>(self.conn)? as usize)
@@ -155,6 +278,18 @@ impl<'a> UserFinder<'a> {
         self
     }
 
+    pub fn email(mut self, email_provided: &'a str) -> Self {
+        use crate::schema::users::dsl::*;
+        self.query = self.query.filter(email.eq(email_provided));
+        self
+    }
+
+    pub fn email_verified(mut self, verified: bool) -> Self {
+        use crate::schema::users::dsl::*;
+        self.query = self.query.filter(email_verified.eq(verified));
+        self
+    }
+
     pub fn school(mut self, school_provided: &'a str) -> Self {
         use crate::schema::users::dsl::*;
         self.query = self.query.filter(school.eq(school_provided));
@@ -201,6 +336,8 @@ pub struct UserInfo {
     school: String,
     description: Option<String>,
     user_status: i64,
+    email: String,
+    email_verified: bool,
 }
 
 impl UserInfo {
@@ -215,6 +352,25 @@ impl UserInfo {
         &self.id
     }
 
+    /// Get a reference to the user info's email. Tombstones have none.
+    pub fn get_email(&self) -> &str {
+        &self.email
+    }
+
+    /// See if the email has been verified by the identity provider
+    pub fn is_email_verified(&self) -> bool {
+        self.email_verified
+    }
+
+    /// Set the user info's email.
+    pub fn set_email(mut self, email: impl ToString, verified: bool) -> Result<Self> {
+        let email = email.to_string();
+        email.parse::<lettre::Address>()?;
+        self.email = email;
+        self.email_verified = verified;
+        Ok(self)
+    }
+
     /// Get a reference to the user info's school.
     pub fn get_school(&self) -> &str {
         &self.school
@@ -227,6 +383,20 @@ impl UserInfo {
     }
 
     pub fn update(self, conn: &SqliteConnection) -> Result<Self> {
+        use crate::schema::users::dsl::*;
+        // Verified emails are unique among users. Unverified ones are not reserved.
+        if self.email_verified
+            && !self.email.is_empty()
+            && users
+                .filter(email.eq(&self.email))
+                .filter(email_verified.eq(true))
+                .filter(id.ne(&self.id))
+                .count()
+                .get_result::<i64>(conn)?
+                > 0
+        {
+            return Err(SailsDbError::UserRegistered);
+        }
         Ok(self.save_changes::<UserInfo>(conn)?)
     }
 
@@ -235,6 +405,15 @@ impl UserInfo {
         &self.name
     }
 
+    /// The name of the user, or the email if the user has no name
+    pub fn get_display_name(&self) -> &str {
+        if self.name.is_empty() {
+            &self.email
+        } else {
+            &self.name
+        }
+    }
+
     /// Set the user info's name.
     pub fn set_name(mut self, name: impl ToString) -> Self {
         self.name = name.to_string();
@@ -252,6 +431,11 @@ impl UserInfo {
         self
     }
 
+    /// See if the user is a tombstone left by a deleted account
+    pub fn is_deleted(&self) -> bool {
+        self.id.starts_with(TOMBSTONE_PREFIX)
+    }
+
     /// See if the user is admin or not
     pub fn is_admin(&self) -> bool {
         UserStatus::from_bits_truncate(self.user_status as u32).contains(UserStatus::ADMIN)
@@ -273,25 +457,38 @@ impl UserInfo {
 #[derive(Debug, Serialize, Deserialize, Insertable, AsChangeset, Identifiable, Clone)]
 #[table_name = "users"]
 pub struct UserInfoRef<'a> {
-    id: &'a str,
+    // These are owned because they were created when convert to UserInfoRef
+    id: String,
     name: &'a str,
     school: &'a str,
     description: Option<&'a str>,
-    // This is owned because it was created when convert to UserInfoRef
     user_status: i64,
+    email: &'a str,
+    email_verified: bool,
 }
 
 impl<'a> UserInfoRef<'a> {
     pub fn create(self, conn: &SqliteConnection) -> Result<UserId> {
         use crate::schema::users::dsl::*;
-        let id_cloned = self.id.to_string();
-        if let Ok(0) = users.filter(id.eq(self.id)).count().get_result(conn) {
-            // This means that there is no user existed before
-            diesel::insert_into(users).values(self).execute(conn)?
-        } else {
+        let id_cloned = self.id.clone();
+        // Only verified emails are reserved
+        let taken = self.email_verified
+            && !self.email.is_empty()
+            && users
+                .filter(email.eq(self.email))
+                .filter(email_verified.eq(true))
+                .count()
+                .get_result::<i64>(conn)?
+                > 0;
+        if taken {
             return Err(SailsDbError::UserRegistered);
-        };
-        Ok(UserId { id: id_cloned })
+        }
+        diesel::insert_into(users).values(self).execute(conn)?;
+        let user = UserId { id: id_cloned };
+        if let Ok(role) = UserRoles::find(conn, DEFAULT_ROLE) {
+            UserRoles::assign(conn, &user, &[role])?;
+        }
+        Ok(user)
     }
 
     pub fn update(self, conn: &SqliteConnection) -> Result<UserInfo> {
@@ -302,29 +499,31 @@ impl<'a> UserInfoRef<'a> {
 // This can be created by rocket and can be converted into insertable user
 #[derive(Debug, Serialize, Deserialize, FromForm, Clone)]
 pub struct UserFormOwned {
-    #[field(name = "email")]
-    pub id: String,
+    pub email: String,
     pub name: String,
     pub school: String,
     pub description: Option<String>,
+    pub email_verified: bool,
 }
 
 impl UserFormOwned {
-    pub fn new<T: ToString>(id: T, name: T, school: T, description: Option<T>) -> Self {
+    pub fn new<T: ToString>(email: T, name: T, school: T, description: Option<T>) -> Self {
         Self {
-            id: id.to_string(),
+            email: email.to_string(),
             school: school.to_string(),
             name: name.to_string(),
             description: description.map(|x| x.to_string()),
+            email_verified: false,
         }
     }
 
     pub fn to_ref(&self) -> Result<UserInfoRef> {
         let form = UserForm {
-            id: &self.id,
+            email: &self.email,
             school: &self.school,
             name: &self.name,
             description: self.description.as_deref(),
+            email_verified: self.email_verified,
         };
         form.to_ref()
     }
@@ -333,33 +532,47 @@ impl UserFormOwned {
 // This can be created by rocket and can be converted into insertable user
 #[derive(Debug, Serialize, Deserialize, FromForm, Clone)]
 pub struct UserForm<'a> {
-    #[field(name = "email")]
-    pub id: &'a str,
+    pub email: &'a str,
     pub name: &'a str,
     pub school: &'a str,
     pub description: Option<&'a str>,
+    pub email_verified: bool,
 }
 
 impl<'a> UserForm<'a> {
-    pub fn new(id: &'a str, name: &'a str, school: &'a str, description: Option<&'a str>) -> Self {
+    pub fn new(
+        email: &'a str,
+        name: &'a str,
+        school: &'a str,
+        description: Option<&'a str>,
+    ) -> Self {
         Self {
-            id,
+            email,
             name,
             school,
             description,
+            email_verified: false,
         }
     }
 
+    pub fn email_verified(mut self, verified: bool) -> Self {
+        self.email_verified = verified;
+        self
+    }
+
     // Warning: this should not be used to update user!
     // Otherwise the account role gets cleaned up to default.
+    // Users are given a random ID, which never changes even if the email does.
     pub fn to_ref(&self) -> Result<UserInfoRef<'a>> {
-        self.id.parse::<lettre::Address>()?;
+        self.email.parse::<lettre::Address>()?;
         Ok(UserInfoRef {
-            id: self.id,
+            id: Uuid::new_v4().to_string(),
             school: self.school,
             name: self.name,
             description: self.description,
             user_status: UserStatus::default().bits() as i64,
+            email: self.email,
+            email_verified: self.email_verified,
         })
     }
 }
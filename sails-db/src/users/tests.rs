@@ -1,9 +1,12 @@
 use super::*;
 use crate::{
     categories::{Category, CtgTrait},
+    credits::CreditFinder,
+    enums::{CreditKind, Currency, Payment, ProductStatus},
     products::*,
     tags::*,
     test_utils::establish_connection,
+    transactions::*,
 };
 use std::collections::HashMap;
 
@@ -22,6 +25,7 @@ fn create_user() {
 fn create_user_existed() {
     let conn = establish_connection();
     UserForm::new("TestUser@example.org", "Kanyang Ying", "NFLS", None)
+        .email_verified(true)
         .to_ref()
         .unwrap()
         .create(&conn)
@@ -31,11 +35,44 @@ fn create_user_existed() {
     // Comparison should be case-insensitive
     assert!(
         UserForm::new("testUser@example.org", "Mick Zhang", "NFLS", None,)
+            .email_verified(true)
             .to_ref()
             .unwrap()
             .create(&conn)
             .is_err()
     );
+
+    // Unverified emails are not reserved
+    UserForm::new("testUser@example.org", "Mick Zhang", "NFLS", None)
+        .to_ref()
+        .unwrap()
+        .create(&conn)
+        .unwrap();
+}
+
+#[test]
+fn user_names() {
+    let conn = establish_connection();
+    let named = UserForm::new("TestUser@example.org", "Kanyang Ying", "NFLS", None)
+        .to_ref()
+        .unwrap()
+        .create(&conn)
+        .unwrap();
+    let unnamed = UserForm::new("Unnamed@example.org", "", "NFLS", None)
+        .to_ref()
+        .unwrap()
+        .create(&conn)
+        .unwrap();
+
+    let names = UserFinder::names(
+        &conn,
+        vec![named.get_id(), unnamed.get_id(), named.get_id()],
+    )
+    .unwrap();
+    assert_eq!(names.get(named.get_id()), "Kanyang Ying");
+    // Users without a name are shown by their email, and unknown ones by their IDs
+    assert_eq!(names.get(unnamed.get_id()), "Unnamed@example.org");
+    assert_eq!(names.get("unknown"), "unknown");
 }
 
 #[test]
@@ -47,7 +84,11 @@ fn login_user() {
         .create(&conn)
         .unwrap();
 
-    assert!(UserId::find(&conn, "TestUser@example.org").is_ok());
+    let user = UserFinder::new(&conn, None)
+        .email("TestUser@example.org")
+        .first()
+        .unwrap();
+    assert!(UserId::find(&conn, user.get_id()).is_ok());
 }
 
 #[test]
@@ -122,6 +163,121 @@ fn delete_user() {
     assert_eq!(TagMappingFinder::new(&conn, None).count().unwrap(), 0);
 }
 
+// A seller with a sold book and a book on the shelf, and a buyer of the former with some store credit
+fn create_order(conn: &SqliteConnection) -> (UserId, UserId, ProductId, ProductId, TransactionId) {
+    let seller = UserForm::new("TestUser@example.org", "Kanyang Ying", "NFLS", None)
+        .to_ref()
+        .unwrap()
+        .create(conn)
+        .unwrap();
+
+    let buyer = UserForm::new("TestUser2@example.org", "Mick Zhang", "NFLS", None)
+        .to_ref()
+        .unwrap()
+        .create(conn)
+        .unwrap();
+
+    let econ = Category::create(conn, "Economics", 1)
+        .and_then(Category::into_leaf)
+        .unwrap();
+    let sold = IncompleteProduct::new(&econ, "Economics", 100, 1, "A horrible book", Currency::CNY)
+        .unwrap()
+        .create(conn, &seller)
+        .unwrap();
+    sold.get_info(conn)
+        .unwrap()
+        .set_product_status(ProductStatus::Verified)
+        .update(conn)
+        .unwrap();
+
+    let unsold = IncompleteProduct::new(
+        &econ,
+        "The Economics",
+        100,
+        1,
+        "Another horrible book",
+        Currency::CNY,
+    )
+    .unwrap()
+    .create(conn, &seller)
+    .unwrap();
+
+    let order = Transactions::buy(
+        conn,
+        &sold,
+        &buyer,
+        1,
+        "258 Huanhu South Road, Dongqian Lake, Ningbo, China",
+        "",
+        Payment::Alipay,
+    )
+    .unwrap();
+    Credits::grant(
+        conn,
+        &buyer,
+        Currency::CNY,
+        50,
+        CreditKind::Prize,
+        None,
+        None,
+    )
+    .unwrap();
+
+    (seller, buyer, sold, unsold, order)
+}
+
+#[test]
+fn delete_user_with_orders() {
+    let conn = establish_connection();
+    let (seller, buyer, sold, unsold, order) = create_order(&conn);
+
+    let buyer_id = buyer.get_id().to_string();
+    buyer.delete(&conn).unwrap();
+    // The order is kept under a tombstone without the address
+    let info = order.get_info(&conn).unwrap();
+    let tombstone = UserId::find(&conn, info.get_buyer()).unwrap();
+    let tombstone_info = tombstone.get_info(&conn).unwrap();
+    assert!(tombstone_info.is_deleted());
+    assert_eq!(tombstone_info.get_name(), "Deleted user");
+    assert_eq!(tombstone_info.get_user_status(), UserStatus::DISABLED);
+    assert_eq!(info.get_address(), "");
+    // So is the credit ledger
+    assert_eq!(
+        Credits::balance(&conn, &tombstone, &Currency::CNY).unwrap(),
+        50
+    );
+    assert!(UserId::find(&conn, &buyer_id).is_err());
+
+    seller.delete(&conn).unwrap();
+    // The sold book is taken off the store, while the other one is deleted
+    let sold = sold.get_info(&conn).unwrap();
+    assert_eq!(sold.get_product_status(), &ProductStatus::Disabled);
+    assert!(sold.get_seller_id().starts_with(TOMBSTONE_PREFIX));
+    assert!(unsold.get_info(&conn).is_err());
+    assert_eq!(
+        order.get_info(&conn).unwrap().get_seller(),
+        sold.get_seller_id()
+    );
+    // Only the two tombstones are left
+    assert_eq!(UserFinder::list(&conn).unwrap().len(), 2);
+}
+
+#[test]
+fn purge_user() {
+    let conn = establish_connection();
+    let (seller, buyer, sold, _, order) = create_order(&conn);
+
+    seller.purge(&conn).unwrap();
+    // The order is gone along with the products
+    assert!(order.get_info(&conn).is_err());
+    assert!(sold.get_info(&conn).is_err());
+    assert_eq!(ProductFinder::list(&conn).unwrap().len(), 0);
+
+    buyer.purge(&conn).unwrap();
+    assert_eq!(CreditFinder::new(&conn, None).search().unwrap().len(), 0);
+    assert_eq!(UserFinder::list(&conn).unwrap().len(), 0);
+}
+
 #[test]
 fn update_user() {
     let conn = establish_connection();